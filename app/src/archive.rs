@@ -0,0 +1,151 @@
+use crate::crypto;
+use crate::crypto::Cipher;
+use crate::retention;
+use chrono::Utc;
+use log::warn;
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+
+/// Configuration of the raw response archive
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Config {
+
+    /// Directory with a sub-directory of responses per device
+    pub directory: PathBuf,
+
+    /// Number of the most recent responses kept per device
+    #[serde(default = "Config::default_responses_per_device")]
+    pub responses_per_device: usize,
+
+    /// Limits of the whole archive
+    #[serde(flatten)]
+    pub retention: retention::Policy,
+
+    /// Encrypt the responses with this key, since they reveal when
+    /// the appliances are used
+    pub encryption: Option<crypto::Config>,
+}
+
+impl Config {
+    fn default_responses_per_device() -> usize { 100 }
+}
+
+/// Bounded archive of raw responses of one device
+///
+/// Each response is kept in a file named by the time it was received,
+/// so that it can be inspected or fed to the `parse` command.
+/// Encrypted responses have the ".enc" extension.
+pub struct Archive {
+    directory: PathBuf,
+    capacity: usize,
+    files: VecDeque<PathBuf>,
+    cipher: Option<Cipher>,
+}
+
+impl Archive {
+
+    /// Open the archive of the device, creating its directory if needed
+    pub fn open(archive_config: &Config, device_name: &str) -> Result<Archive, String> {
+        let cipher = archive_config.encryption.as_ref().map(Cipher::new).transpose()?;
+        let directory = archive_config.directory.join(sanitize(device_name));
+        std::fs::create_dir_all(&directory)
+            .map_err(|err| format!("{} can not be created: {}", directory.display(), err))?;
+
+        let mut files: Vec<PathBuf> = std::fs::read_dir(&directory)
+            .map_err(|err| format!("{} can not be listed: {}", directory.display(), err))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| is_response(path))
+            .collect();
+        files.sort(); // names are timestamps
+
+        let mut archive = Archive {
+            directory,
+            capacity: archive_config.responses_per_device.max(1),
+            files: files.into(),
+            cipher,
+        };
+        archive.trim();
+        Ok(archive)
+    }
+
+    /// Keep the response, dropping the oldest one if the archive is full
+    pub fn store(&mut self, response: &[u8]) {
+        let mut name = Utc::now().format("%Y%m%dT%H%M%S%.3fZ.json").to_string();
+        let written = match &self.cipher {
+            Some(cipher) => {
+                name.push_str(".enc");
+                std::fs::write(self.directory.join(&name), cipher.encrypt(response))
+            },
+            None => std::fs::write(self.directory.join(&name), response),
+        };
+        let path = self.directory.join(name);
+        match written {
+            Ok(_) => self.files.push_back(path),
+            Err(err) => warn!("response can not be archived in {}: {}", path.display(), err),
+        }
+        self.trim();
+    }
+
+    /// Delete the oldest responses over the capacity
+    fn trim(&mut self) {
+        while self.files.len() > self.capacity {
+            if let Some(oldest) = self.files.pop_front() {
+                match std::fs::remove_file(&oldest) {
+                    Ok(_) => (),
+                    // already deleted by the retention policy
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+                    Err(err) => warn!("archived response {} can not be deleted: {}",
+                        oldest.display(), err),
+                }
+            }
+        }
+    }
+}
+
+/// Whether the file is an archived response, e.g. "20240701T120000.000Z.json"
+/// (or ".json.enc"), so that the retention policy deletes nothing else
+pub fn is_response(path: &Path) -> bool {
+    let name = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name,
+        None => return false,
+    };
+    let time = match name.strip_suffix(".json").or_else(|| name.strip_suffix(".json.enc")) {
+        Some(time) => time,
+        None => return false,
+    };
+    chrono::NaiveDateTime::parse_from_str(time, "%Y%m%dT%H%M%S%.3fZ").is_ok()
+}
+
+/// Make the device name usable as a directory name, distinct for each name:
+/// other bytes than letters, digits, '-', '_' and a '.' which does not start
+/// the name are percent-encoded (e.g. "living room" to "living%20room")
+fn sanitize(device_name: &str) -> String {
+    let mut directory = String::with_capacity(device_name.len());
+    for (index, byte) in device_name.bytes().enumerate() {
+        let kept = byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_'
+            || (byte == b'.' && index > 0);
+        if kept {
+            directory.push(byte as char);
+        } else {
+            directory.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    directory
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_names_give_distinct_directories() {
+        assert_eq!(sanitize("fridge-1.2"), "fridge-1.2");
+        assert_eq!(sanitize("living room"), "living%20room");
+        assert_eq!(sanitize("living_room"), "living_room");
+        assert_eq!(sanitize("living%20room"), "living%2520room");
+        assert_eq!(sanitize("a/b"), "a%2Fb");
+        assert_eq!(sanitize(".."), "%2E.");
+        assert_eq!(sanitize("kuchyň"), "kuchy%C5%88");
+    }
+}
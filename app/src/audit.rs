@@ -0,0 +1,150 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+/// Audit log configuration
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Config {
+
+    /// File to which the control actions are appended, one JSON per line
+    pub path: PathBuf,
+}
+
+/// Hash preceding the first entry
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Switching of a relay, or another command sent to a device
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Action {
+    pub time: DateTime<Utc>,
+    /// Who switched the relay, e.g. `cli:alice`
+    pub actor: String,
+    /// Rule which made the actor switch the relay, if any
+    pub rule: Option<String>,
+    pub device: String,
+    pub host: String,
+    /// Command other than a switch of the relay, e.g. `reboot`; not serialized
+    /// for switches, so that the hashes of the older entries stay the same
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    /// State before the switch, if known
+    pub was_on: Option<bool>,
+    /// State after the switch; not set for other commands
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub is_on: Option<bool>,
+}
+
+/// Line of the log; each entry includes the hash of the previous one,
+/// so that modifying or removing entries breaks the chain
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    #[serde(flatten)]
+    action: Action,
+    prev: String,
+    hash: String,
+}
+
+impl Entry {
+
+    fn new(action: Action, prev: String) -> Entry {
+        let hash = hash(&action, &prev);
+        Entry { action, prev, hash }
+    }
+}
+
+/// Hash of the action chained to the previous hash, as hexadecimal digits
+fn hash(action: &Action, prev: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(prev.as_bytes());
+    hasher.update(serde_json::to_vec(action).expect("action can be serialized"));
+    hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Tamper-evident log of control actions, separate from the application log
+pub struct AuditLog {
+    path: PathBuf,
+    last_hash: String,
+}
+
+impl AuditLog {
+
+    /// Open the log, checking that the entries are chained
+    pub fn open(audit_config: &Config) -> Result<AuditLog, String> {
+        let entries = read(&audit_config.path)
+            .map_err(|err| format!("{} is broken: {}", audit_config.path.display(), err))?;
+        let last_hash = entries.last().map(|entry| entry.hash.clone())
+            .unwrap_or_else(|| GENESIS.to_string());
+        Ok(AuditLog { path: audit_config.path.clone(), last_hash })
+    }
+
+    /// Append the action to the log
+    pub fn append(&mut self, action: Action) -> Result<(), String> {
+        let entry = Entry::new(action, self.last_hash.clone());
+        let mut line = serde_json::to_string(&entry).expect("entry can be serialized");
+        line.push('\n');
+        OpenOptions::new().create(true).append(true).open(&self.path)
+            // One write, so that concurrent writers do not interleave the lines
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .map_err(|err| format!("{} can not be written: {}", self.path.display(), err))?;
+        self.last_hash = entry.hash;
+        Ok(())
+    }
+}
+
+/// Entries of the log, checking the chain; a missing log is empty
+fn read(path: &Path) -> Result<Vec<Entry>, String> {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(format!("{} can not be read: {}", path.display(), err)),
+    };
+    let mut entries: Vec<Entry> = vec![];
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|err| format!("{} can not be read: {}", path.display(), err))?;
+        let entry: Entry = serde_json::from_str(&line)
+            .map_err(|err| format!("line {} is not an entry: {}", index + 1, err))?;
+        let prev = entries.last().map(|prev| prev.hash.as_str()).unwrap_or(GENESIS);
+        if entry.prev != prev {
+            return Err(format!("line {} does not follow the previous entry, \
+                entries were removed or reordered", index + 1));
+        }
+        if entry.hash != hash(&entry.action, &entry.prev) {
+            return Err(format!("line {} does not match its hash, it was modified", index + 1));
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Print the log, failing if the chain is broken
+pub fn print(audit_config: &Config) -> Result<(), String> {
+    let entries = read(&audit_config.path)
+        .map_err(|err| format!("{} is broken: {}", audit_config.path.display(), err))?;
+    let state = |on: bool| if on { "on" } else { "off" };
+    for entry in &entries {
+        let action = &entry.action;
+        let done = match &action.command {
+            Some(command) => command.clone(),
+            None => format!("{} -> {}", action.was_on.map(state).unwrap_or("?"),
+                action.is_on.map(state).unwrap_or("?")),
+        };
+        println!("{} {} {} ({}) {}{}",
+            action.time.to_rfc3339(), action.actor, action.device, action.host, done,
+            action.rule.as_ref().map(|rule| format!(" rule={}", rule)).unwrap_or_default());
+    }
+    // Removing entries from the end can only be detected by comparing the last hash
+    println!("{} entries, the chain is intact, the last hash is {}", entries.len(),
+        entries.last().map(|entry| entry.hash.as_str()).unwrap_or(GENESIS));
+    Ok(())
+}
+
+/// User running the command, for the actor of the actions done from the command line
+pub fn cli_actor() -> String {
+    let user = ["SUDO_USER", "USER", "LOGNAME"].iter()
+        .find_map(|name| std::env::var(name).ok())
+        .unwrap_or_else(|| "unknown".to_string());
+    format!("cli:{}", user)
+}
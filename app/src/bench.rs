@@ -0,0 +1,111 @@
+use crate::config;
+use crate::influx;
+use crate::line_protocol::Encoder;
+use crate::point::Datum;
+use crate::point::Measurement::instantaneous_consumption_in_w;
+use log::{info, warn};
+use std::sync::Arc;
+use std::sync::mpsc::channel;
+use std::time::{Duration, Instant};
+
+/// Parameters of the synthetic load
+pub struct Load {
+    /// Number of simulated devices
+    pub devices: u32,
+    /// Data-points generated per second (over all devices)
+    pub rate: f64,
+    /// How long the data-points are generated
+    pub duration: Duration,
+}
+
+/// Generate synthetic data-points, write them into the sink and report its performance
+pub fn run(app_config: &config::Config, load: &Load) -> Result<(), String> {
+    let database_config = app_config.database.as_ref()
+        .ok_or("benchmark needs the 'database' sink in the config")?;
+    if load.devices == 0 || load.rate <= 0.0 {
+        return Err("benchmark needs at least 1 device and a positive rate".to_string());
+    }
+
+    let (tx, rx) = channel::<Datum>();
+    let devices = load.devices;
+    let period = Duration::from_secs_f64(1.0 / load.rate);
+    let duration = load.duration;
+
+    info!("Generating {:.1} points/s from {} devices for {}s",
+        load.rate, load.devices, load.duration.as_secs());
+
+    let generator = std::thread::spawn(move || {
+        let names: Vec<(Arc<str>, Arc<str>)> = (0..devices)
+            .map(|device| (
+                format!("bench-{}", device).into(),
+                format!("bench-{}.invalid", device).into()))
+            .collect();
+        let started = Instant::now();
+        let mut generated: u64 = 0;
+        while started.elapsed() < duration {
+            let (name, host) = &names[(generated % devices as u64) as usize];
+            let datum = Datum {
+                measured_on: chrono::Utc::now(),
+                measurement: instantaneous_consumption_in_w,
+                device_name: name.clone(),
+                device_host: host.clone(),
+                value: 100.0 + 50.0 * (generated as f32 / 10.0).sin(),
+                valid: true,
+            };
+            if tx.send(datum).is_err() {
+                break;
+            }
+            generated += 1;
+
+            // Keep the pace even if the previous sleep overshot
+            let deadline = period.mul_f64(generated as f64);
+            if let Some(remaining) = deadline.checked_sub(started.elapsed()) {
+                std::thread::sleep(remaining);
+            }
+        }
+        generated
+    });
+
+    let mut connection = influx::Connection::new(database_config)?;
+    let mut encoder = Encoder::with_precision(influx::PRECISION);
+    let started = Instant::now();
+    let mut latencies: Vec<Duration> = vec![];
+    let mut failures: u64 = 0;
+    for datum in rx {
+        let write_started = Instant::now();
+        let mut line = String::new();
+        encoder.encode(&datum, &mut line);
+        match connection.write(std::slice::from_ref(&datum), &line) {
+            Ok(_) => latencies.push(write_started.elapsed()),
+            Err(err) => {
+                failures += 1;
+                warn!("benchmark write failed: {}", err);
+            }
+        }
+    }
+    let elapsed = started.elapsed();
+    let generated = generator.join()
+        .map_err(|_| "generator thread could not be joined".to_string())?;
+
+    latencies.sort();
+    println!("generated: {} points", generated);
+    println!("written: {} points, {} failed", latencies.len(), failures);
+    println!("throughput: {:.1} points/s",
+        latencies.len() as f64 / elapsed.as_secs_f64());
+    for (label, quantile) in [("p50", 0.5), ("p90", 0.9), ("p99", 0.99), ("max", 1.0)] {
+        match percentile(&latencies, quantile) {
+            Some(latency) => println!("latency {}: {:.1}ms", label, latency.as_secs_f64() * 1000.0),
+            None => println!("latency {}: n/a", label),
+        }
+    }
+    Ok(())
+}
+
+/// Nearest-rank percentile of sorted durations
+fn percentile(sorted: &[Duration], quantile: f64) -> Option<Duration> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (quantile * sorted.len() as f64).ceil() as usize;
+    Some(sorted[rank.clamp(1, sorted.len()) - 1])
+}
@@ -0,0 +1,181 @@
+use chrono::{DateTime, Datelike, NaiveDate, NaiveDateTime, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Deserializer};
+
+/// Days (and billing cycles) by which the consumption is totalled
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Calendar {
+
+    /// Time zone of the days, e.g. "Europe/Prague"; UTC if not set
+    #[serde(default)]
+    time_zone: Option<Tz>,
+
+    /// Hour (0 to 23, local) at which a day starts, e.g. 6 for days from 06:00 to 06:00
+    #[serde(default, deserialize_with = "deserialize_hour")]
+    day_start_hour: u32,
+
+    /// Day of the month (1 to 31) on which a billing cycle starts, e.g. 12 for
+    /// cycles from the 12th to the 11th; on the last day of shorter months
+    #[serde(default = "Calendar::default_billing_cycle_start_day",
+        deserialize_with = "deserialize_day_of_month")]
+    billing_cycle_start_day: u32,
+}
+
+impl Default for Calendar {
+    fn default() -> Calendar {
+        Calendar {
+            time_zone: None,
+            day_start_hour: 0,
+            billing_cycle_start_day: Calendar::default_billing_cycle_start_day(),
+        }
+    }
+}
+
+impl Calendar {
+
+    fn default_billing_cycle_start_day() -> u32 { 1 }
+
+    /// Day to which the time belongs, named by the date on which it starts
+    pub fn day_of(&self, time: DateTime<Utc>) -> NaiveDate {
+        (self.local(time) - chrono::Duration::hours(self.day_start_hour as i64)).date()
+    }
+
+    /// Wall-clock time in the time zone of the calendar
+    pub fn local(&self, time: DateTime<Utc>) -> NaiveDateTime {
+        match self.time_zone {
+            Some(time_zone) => time.with_timezone(&time_zone).naive_local(),
+            None => time.naive_utc(),
+        }
+    }
+
+    /// First and last day of the billing cycle containing the day
+    pub fn billing_cycle_of(&self, day: NaiveDate) -> (NaiveDate, NaiveDate) {
+        let started_this_month = self.billing_cycle_start_in(day);
+        if day >= started_this_month {
+            self.billing_cycle_starting_in(day)
+        } else {
+            let previous_month = day.with_day(1).and_then(|first| first.pred_opt())
+                .expect("dates do not run out");
+            self.billing_cycle_starting_in(previous_month)
+        }
+    }
+
+    /// First and last day of the billing cycle starting in the month of the day
+    pub fn billing_cycle_starting_in(&self, month: NaiveDate) -> (NaiveDate, NaiveDate) {
+        let next_month = last_day_of_month(month).succ_opt().expect("dates do not run out");
+        (self.billing_cycle_start_in(month),
+            self.billing_cycle_start_in(next_month).pred_opt().expect("dates do not run out"))
+    }
+
+    /// Day on which a billing cycle starts in the month of the day
+    fn billing_cycle_start_in(&self, month: NaiveDate) -> NaiveDate {
+        let last_day = last_day_of_month(month);
+        last_day.with_day(self.billing_cycle_start_day.min(last_day.day()))
+            .expect("days up to the last one exist")
+    }
+}
+
+/// Last day of the month of the day
+fn last_day_of_month(day: NaiveDate) -> NaiveDate {
+    let (year, month) = if day.month() == 12 {
+        (day.year() + 1, 1)
+    } else {
+        (day.year(), day.month() + 1)
+    };
+    NaiveDate::from_ymd_opt(year, month, 1).expect("months have a first day")
+        .pred_opt().expect("dates do not run out")
+}
+
+fn deserialize_hour<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    let hour = u32::deserialize(deserializer)?;
+    if hour >= 24 {
+        return Err(serde::de::Error::custom(format!("{} is not an hour of the day", hour)));
+    }
+    Ok(hour)
+}
+
+fn deserialize_day_of_month<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    let day = u32::deserialize(deserializer)?;
+    if !(1..=31).contains(&day) {
+        return Err(serde::de::Error::custom(format!("{} is not a day of a month", day)));
+    }
+    Ok(day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    fn utc(time: &str) -> DateTime<Utc> {
+        time.parse().unwrap()
+    }
+
+    fn prague(day_start_hour: u32) -> Calendar {
+        Calendar { time_zone: Some(chrono_tz::Europe::Prague), day_start_hour,
+            ..Calendar::default() }
+    }
+
+    #[test]
+    fn cycles_starting_on_the_31st_start_on_the_last_day_of_shorter_months() {
+        let calendar = Calendar { billing_cycle_start_day: 31, ..Calendar::default() };
+        assert_eq!(calendar.billing_cycle_starting_in(date(2026, 2, 10)),
+            (date(2026, 2, 28), date(2026, 3, 30)));
+        assert_eq!(calendar.billing_cycle_starting_in(date(2024, 2, 10)),
+            (date(2024, 2, 29), date(2024, 3, 30)));
+        assert_eq!(calendar.billing_cycle_of(date(2026, 2, 27)),
+            (date(2026, 1, 31), date(2026, 2, 27)));
+        assert_eq!(calendar.billing_cycle_of(date(2026, 2, 28)),
+            (date(2026, 2, 28), date(2026, 3, 30)));
+        assert_eq!(calendar.billing_cycle_of(date(2026, 3, 30)),
+            (date(2026, 2, 28), date(2026, 3, 30)));
+        assert_eq!(calendar.billing_cycle_of(date(2026, 3, 31)),
+            (date(2026, 3, 31), date(2026, 4, 29)));
+    }
+
+    #[test]
+    fn cycles_cross_the_year() {
+        let calendar = Calendar { billing_cycle_start_day: 12, ..Calendar::default() };
+        assert_eq!(calendar.billing_cycle_of(date(2026, 1, 5)),
+            (date(2025, 12, 12), date(2026, 1, 11)));
+        assert_eq!(calendar.billing_cycle_starting_in(date(2025, 12, 1)),
+            (date(2025, 12, 12), date(2026, 1, 11)));
+        assert_eq!(Calendar::default().billing_cycle_of(date(2026, 12, 31)),
+            (date(2026, 12, 1), date(2026, 12, 31)));
+    }
+
+    #[test]
+    fn days_start_at_the_day_start_hour() {
+        let calendar = Calendar { day_start_hour: 6, ..Calendar::default() };
+        assert_eq!(calendar.day_of(utc("2026-03-10T05:59:59Z")), date(2026, 3, 9));
+        assert_eq!(calendar.day_of(utc("2026-03-10T06:00:00Z")), date(2026, 3, 10));
+        assert_eq!(calendar.day_of(utc("2026-01-01T05:00:00Z")), date(2025, 12, 31));
+    }
+
+    #[test]
+    fn days_follow_the_daylight_saving_time() {
+        // Summer time (UTC+2) starts on 2026-03-29 at 01:00 UTC, so the day is 23 hours
+        let calendar = prague(0);
+        assert_eq!(calendar.day_of(utc("2026-03-28T22:59:59Z")), date(2026, 3, 28));
+        assert_eq!(calendar.day_of(utc("2026-03-28T23:00:00Z")), date(2026, 3, 29));
+        assert_eq!(calendar.day_of(utc("2026-03-29T21:59:59Z")), date(2026, 3, 29));
+        assert_eq!(calendar.day_of(utc("2026-03-29T22:00:00Z")), date(2026, 3, 30));
+        // and ends on 2026-10-25 at 01:00 UTC, so the day is 25 hours
+        assert_eq!(calendar.day_of(utc("2026-10-24T21:59:59Z")), date(2026, 10, 24));
+        assert_eq!(calendar.day_of(utc("2026-10-24T22:00:00Z")), date(2026, 10, 25));
+        assert_eq!(calendar.day_of(utc("2026-10-25T22:59:59Z")), date(2026, 10, 25));
+        assert_eq!(calendar.day_of(utc("2026-10-25T23:00:00Z")), date(2026, 10, 26));
+    }
+
+    #[test]
+    fn day_start_hour_skipped_by_the_daylight_saving_time() {
+        // 02:00 does not exist on 2026-03-29, so the day starts at 03:00 (01:00 UTC)
+        let calendar = prague(2);
+        assert_eq!(calendar.day_of(utc("2026-03-29T00:59:59Z")), date(2026, 3, 28));
+        assert_eq!(calendar.day_of(utc("2026-03-29T01:00:00Z")), date(2026, 3, 29));
+        assert_eq!(calendar.day_of(utc("2026-03-30T00:00:00Z")), date(2026, 3, 30));
+    }
+}
@@ -0,0 +1,266 @@
+use crate::config;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+#[cfg(feature = "sqlite")]
+use chrono::{DateTime, NaiveDate, Utc};
+use std::path::PathBuf;
+
+/// Service that logs Shelly Plug metering statistics into InfluxDB 2
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Args {
+
+    /// Write synthetic data-points into the configured sink and report its performance
+    #[arg(long)]
+    pub bench_sink: bool,
+
+    /// Number of simulated devices in the '--bench-sink' mode
+    #[arg(long, default_value_t = 200, requires = "bench_sink")]
+    pub bench_devices: u32,
+
+    /// Data-points generated per second in the '--bench-sink' mode
+    #[arg(long, default_value_t = 100.0, requires = "bench_sink")]
+    pub bench_rate: f64,
+
+    /// Duration of the '--bench-sink' mode in seconds
+    #[arg(long, default_value_t = 60, requires = "bench_sink")]
+    pub bench_duration_s: u64,
+
+    /// Config file, in JSON, TOML ("*.toml") or YAML ("*.yaml")
+    #[arg(long, short, global = true, env = "SHELLY_LOGGER_CONFIG",
+        default_value = config::DEFAULT_FILE)]
+    pub config: PathBuf,
+
+    /// Filter of the logged messages, e.g. "debug" or "info,shelly_logger::plug=debug",
+    /// overriding RUST_LOG
+    #[arg(long, global = true)]
+    pub log_level: Option<String>,
+
+    /// Check the config and probe the devices, then stop without metering them
+    #[arg(long)]
+    pub dry_run: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Optional Cargo features compiled into this binary
+pub const ENABLED_FEATURES: &[(&str, bool)] = &[
+    ("influxdb2", cfg!(feature = "influxdb2")),
+    ("sqlite", cfg!(feature = "sqlite")),
+    ("postgres", cfg!(feature = "postgres")),
+    ("mqtt", cfg!(feature = "mqtt")),
+    ("domoticz", cfg!(feature = "domoticz")),
+    ("openhab", cfg!(feature = "openhab")),
+    ("zabbix", cfg!(feature = "zabbix")),
+    ("icinga", cfg!(feature = "icinga")),
+    ("evcc", cfg!(feature = "evcc")),
+    ("prometheus", cfg!(feature = "prometheus")),
+    ("health", cfg!(feature = "health")),
+    ("grafana-live", cfg!(feature = "grafana-live")),
+    ("emoncms", cfg!(feature = "emoncms")),
+    ("exec", cfg!(feature = "exec")),
+    ("files", cfg!(feature = "files")),
+    ("encryption", cfg!(feature = "encryption")),
+    ("keyring", cfg!(feature = "keyring")),
+    ("client-certificates", cfg!(feature = "client-certificates")),
+    ("https", cfg!(feature = "https")),
+    ("sandbox", cfg!(feature = "sandbox")),
+    ("reports", cfg!(feature = "reports")),
+    ("toml", cfg!(feature = "toml")),
+    ("yaml", cfg!(feature = "yaml")),
+];
+
+impl Args {
+
+    /// Parse the command line; the long version lists the compiled-in features
+    pub fn parse_with_features() -> Args {
+        let features: Vec<&str> = ENABLED_FEATURES.iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(name, _)| *name)
+            .collect();
+        let command = Args::command().long_version(format!("{} (features: {})",
+            env!("CARGO_PKG_VERSION"), features.join(", ")));
+        Args::from_arg_matches(&command.get_matches())
+            .unwrap_or_else(|err| err.exit())
+    }
+}
+
+/// Commands other than running the logger itself
+#[derive(Subcommand, Debug)]
+pub enum Command {
+
+    /// Parse a saved device response and print the derived data-points
+    Parse {
+        /// JSON response of a Gen1 "/meter/0" or Gen2 "Switch.GetStatus" call
+        file: PathBuf,
+
+        /// Device name used for the derived data-points
+        #[arg(long, default_value = "triage")]
+        name: String,
+
+        /// Device host used for the derived data-points
+        #[arg(long, default_value = "localhost")]
+        host: String,
+    },
+
+    /// Print data-points from the local store
+    #[cfg(feature = "sqlite")]
+    Query {
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+
+    /// Export data-points from the local store into a line-protocol file
+    #[cfg(feature = "sqlite")]
+    Export {
+        /// Line-protocol file to create
+        output: PathBuf,
+
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+
+    /// Upload data-points from the local store to the database, resuming where
+    /// the previous sync stopped
+    #[cfg(feature = "sqlite")]
+    Sync {
+        /// Data-points uploaded in one request
+        #[arg(long, default_value_t = 5000)]
+        chunk: usize,
+
+        /// Forget what was uploaded and start from the oldest data-point
+        #[arg(long)]
+        restart: bool,
+    },
+
+    /// Print the metadata of the devices kept in the device inventory
+    #[cfg(feature = "sqlite")]
+    Devices {
+        /// Also print the snapshot of the device settings
+        #[arg(long)]
+        settings: bool,
+    },
+
+    /// Import a line-protocol file (with timestamps in seconds) into the configured sinks
+    Import {
+        /// Line-protocol file to read
+        file: PathBuf,
+    },
+
+    /// Switch the relay of a device, recording it in the audit log
+    Relay {
+        /// Name of the device in the config
+        device: String,
+
+        state: RelayState,
+    },
+
+    /// Print the audit log of relay switches, checking that it was not tampered with
+    Audit,
+
+    /// Upgrade config.json to the current version of its schema
+    Migrate {
+        /// Only print the changes, without writing them
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Reports of the consumption from the local store
+    #[cfg(feature = "sqlite")]
+    Report {
+        #[command(subcommand)]
+        command: ReportCommand,
+    },
+}
+
+/// Reports of the consumption
+#[cfg(feature = "sqlite")]
+#[derive(Subcommand, Debug)]
+pub enum ReportCommand {
+
+    /// Export the daily consumption (and cost) of the devices in a month as CSV
+    Export {
+        /// Month of the report (YYYY-MM)
+        #[arg(long, value_parser = parse_month)]
+        month: NaiveDate,
+
+        /// CSV file to create; printed if not set
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Write the HTML report of a day, week or month by the configured template
+    #[cfg(feature = "reports")]
+    Html {
+        #[arg(long, value_enum, default_value = "month")]
+        period: crate::report::Period,
+
+        /// Day in the period (YYYY-MM-DD)
+        #[arg(long)]
+        day: NaiveDate,
+
+        /// HTML file to create; printed if not set
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+}
+
+/// State of a relay
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RelayState {
+    On,
+    Off,
+}
+
+/// Selection of stored data-points
+#[cfg(feature = "sqlite")]
+#[derive(clap::Args, Debug)]
+pub struct FilterArgs {
+
+    /// Only data-points of this device
+    #[arg(long)]
+    pub device: Option<String>,
+
+    /// Only data-points of this measurement, e.g. "instantaneous_consumption_in_w"
+    #[arg(long)]
+    pub measurement: Option<String>,
+
+    /// Only data-points measured at or after this time (RFC 3339 or YYYY-MM-DD)
+    #[arg(long, value_parser = parse_time)]
+    pub since: Option<DateTime<Utc>>,
+
+    /// Only data-points measured before this time (RFC 3339 or YYYY-MM-DD)
+    #[arg(long, value_parser = parse_time)]
+    pub until: Option<DateTime<Utc>>,
+}
+
+#[cfg(feature = "sqlite")]
+impl From<FilterArgs> for crate::store::Filter {
+    fn from(args: FilterArgs) -> crate::store::Filter {
+        crate::store::Filter {
+            device_name: args.device,
+            measurement: args.measurement,
+            since: args.since,
+            until: args.until,
+        }
+    }
+}
+
+/// Parse a time given as RFC 3339 or as a date (midnight UTC)
+#[cfg(feature = "sqlite")]
+pub fn parse_time(text: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(text) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(text, "%Y-%m-%d")
+        .map(|date| DateTime::<Utc>::from_utc(
+            date.and_hms_opt(0, 0, 0).expect("midnight exists"), Utc))
+        .map_err(|_| format!("'{}' is neither RFC 3339 time nor YYYY-MM-DD date", text))
+}
+
+/// Parse a month given as YYYY-MM into its first day
+#[cfg(feature = "sqlite")]
+pub fn parse_month(text: &str) -> Result<NaiveDate, String> {
+    NaiveDate::parse_from_str(&format!("{}-01", text), "%Y-%m-%d")
+        .map_err(|_| format!("'{}' is not a YYYY-MM month", text))
+}
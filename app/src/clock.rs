@@ -0,0 +1,155 @@
+use crate::point::{Datum, Measurement};
+use chrono::{DateTime, TimeZone, Utc};
+use log::{info, warn};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Earliest plausible time (2024-01-01); an earlier clock was not set yet,
+/// e.g. on a board without a real-time clock before NTP synchronized it
+const MIN_PLAUSIBLE_TIMESTAMP: i64 = 1_704_067_200;
+
+/// Most data-points held back while the clock is not plausible; they are
+/// held in memory only, so those still held when the logger stops are lost
+const MAX_HELD: usize = 100_000;
+
+/// Whether the time can be right
+pub fn is_plausible(time: DateTime<Utc>) -> bool {
+    time.timestamp() >= MIN_PLAUSIBLE_TIMESTAMP
+}
+
+/// Holds back the data-points while the system clock is not plausible,
+/// and corrects their time once it is set
+pub struct ClockGate {
+    /// Data-points held back, oldest first
+    held: VecDeque<Datum>,
+    /// Wall and monotonic clock when the first data-point was held back
+    reference: Option<(DateTime<Utc>, Instant)>,
+}
+
+impl ClockGate {
+
+    pub fn new() -> ClockGate {
+        ClockGate { held: VecDeque::new(), reference: None }
+    }
+
+    /// Data-points which can be written now: none while the clock is not
+    /// plausible, all held ones (with corrected times) once it is
+    pub fn pass(&mut self, datum: Datum) -> Vec<Datum> {
+        let now = Utc::now();
+        if !is_plausible(now) {
+            if self.reference.is_none() {
+                warn!("the system clock shows {}, which can not be right (not synchronized \
+                    yet?), data-points are held back until it is set", now.to_rfc3339());
+                self.reference = Some((now, Instant::now()));
+            }
+            if self.held.len() >= MAX_HELD {
+                self.held.pop_front();
+            }
+            self.held.push_back(datum);
+            return vec![];
+        }
+        let (reference_time, reference_instant) = match self.reference.take() {
+            Some(reference) => reference,
+            None => return vec![datum],
+        };
+
+        // How far the clock was set, as it advanced the same as the monotonic one before
+        let elapsed = chrono::Duration::from_std(reference_instant.elapsed())
+            .unwrap_or_else(|_| chrono::Duration::zero());
+        let correction = now - (reference_time + elapsed);
+        info!("the system clock was set ({}s forward), {} data-points held back are written",
+            correction.num_seconds(), self.held.len());
+        let mut passed: Vec<Datum> = self.held.drain(..).collect();
+        for held in &mut passed {
+            // Data-points timestamped by the device clock may be right already
+            if !is_plausible(held.measured_on) {
+                held.measured_on = corrected(held, correction);
+            }
+        }
+        passed.push(datum);
+        passed
+    }
+}
+
+impl Drop for ClockGate {
+    fn drop(&mut self) {
+        if !self.held.is_empty() {
+            warn!("{} data-points held back until the system clock is set are lost",
+                self.held.len());
+        }
+    }
+}
+
+/// Time of the data-point measured by the wrong clock; the minute counters
+/// stay at round minutes, all other data-points keep their precise time
+fn corrected(datum: &Datum, correction: chrono::Duration) -> DateTime<Utc> {
+    let time = datum.measured_on + correction;
+    match datum.measurement {
+        Measurement::last_minute_consumption_in_wh | Measurement::last_minute_cost
+        | Measurement::consumption_since_reboot_in_wh | Measurement::consumption_today_in_wh => {
+            let minute_s = (time + chrono::Duration::seconds(30)).timestamp().div_euclid(60) * 60;
+            Utc.timestamp_opt(minute_s, 0).single().unwrap_or(time)
+        },
+        _ => time,
+    }
+}
+
+/// Smallest difference between the clocks taken as a suspend
+const MIN_SUSPEND: Duration = Duration::from_secs(30);
+
+/// Detects that the system was suspended, by comparing how far the wall clock
+/// and the monotonic clock (which stops while the system sleeps) advanced
+pub struct SuspendDetector {
+    last_instant: Instant,
+    last_time: SystemTime,
+}
+
+impl SuspendDetector {
+
+    pub fn new() -> SuspendDetector {
+        SuspendDetector { last_instant: Instant::now(), last_time: SystemTime::now() }
+    }
+
+    /// Duration of the suspend since the last check, if there was one;
+    /// a wall clock set forward looks the same
+    pub fn check(&mut self) -> Option<Duration> {
+        let (instant, time) = (Instant::now(), SystemTime::now());
+        let monotonic = instant.duration_since(self.last_instant);
+        let wall = time.duration_since(self.last_time).unwrap_or_default();
+        self.last_instant = instant;
+        self.last_time = time;
+        wall.checked_sub(monotonic).filter(|suspended| *suspended >= MIN_SUSPEND)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datum(measurement: Measurement, measured_on: &str) -> Datum {
+        Datum { measured_on: measured_on.parse().unwrap(), measurement,
+            device_name: "fridge".into(), device_host: "192.0.2.1".into(), value: 1.0,
+            valid: true }
+    }
+
+    #[test]
+    fn corrected_times_keep_their_fraction_of_a_second() {
+        let correction = chrono::Duration::seconds(1_700_000_000);
+        let power = datum(Measurement::instantaneous_consumption_in_w,
+            "1970-01-01T00:01:05.250Z");
+        assert_eq!(corrected(&power, correction).to_rfc3339(),
+            "2023-11-14T22:14:25.250+00:00");
+        let delta = datum(Measurement::power_delta_w_per_s, "1970-01-01T00:01:05.250Z");
+        assert_eq!(corrected(&delta, correction).to_rfc3339(),
+            "2023-11-14T22:14:25.250+00:00");
+    }
+
+    #[test]
+    fn corrected_counters_stay_at_round_minutes() {
+        let correction = chrono::Duration::seconds(1_700_000_000);
+        let minute = datum(Measurement::last_minute_consumption_in_wh, "1970-01-01T00:01:05.250Z");
+        assert_eq!(corrected(&minute, correction).to_rfc3339(), "2023-11-14T22:14:00+00:00");
+        let minute = datum(Measurement::last_minute_consumption_in_wh, "1970-01-01T00:01:34.750Z");
+        assert_eq!(corrected(&minute, correction).to_rfc3339(), "2023-11-14T22:15:00+00:00");
+    }
+}
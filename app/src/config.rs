@@ -1,34 +1,526 @@
+use crate::archive;
+use crate::audit;
+use crate::calendar;
+use crate::discovery;
+use crate::domoticz;
+use crate::emoncms;
+use crate::exec;
+use crate::files;
+use crate::evcc;
+use crate::grafana;
+use crate::ha;
+use crate::health;
+use crate::icinga;
 use crate::influx;
+use crate::log_limit::FailureLog;
+use crate::inventory;
+use crate::migrate;
+use crate::mqtt;
+use crate::mqtt_source;
+use crate::network;
+use crate::openhab;
 use crate::plug;
+use crate::prometheus;
+use crate::report;
+use crate::retry;
+use crate::sandbox;
+use crate::sink;
+use crate::store;
+use crate::tariff;
+use crate::zabbix;
+use log::{debug, warn};
 use serde::Deserialize;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 use std::time::Duration;
 
+/// File with the config, in the working directory
+pub const DEFAULT_FILE: &str = "config.json";
+
+/// Prefix of the environment variables overriding the settings of the config
+/// file, e.g. "SHELLY_LOGGER__DATABASE__TOKEN" sets `database.token`
+pub const ENV_PREFIX: &str = "SHELLY_LOGGER__";
+
+/// Config file given on the command line, if any
+static PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Use the config file instead of the default one, before it is read
+pub fn use_path(path: PathBuf) {
+    let _ = PATH.set(path);
+}
+
+/// Config file in use
+pub fn path() -> &'static Path {
+    PATH.get().map(PathBuf::as_path).unwrap_or(Path::new(DEFAULT_FILE))
+}
+
 /// Configuration of this application
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct Config {
 
+    /// Version of the config schema; 0 for configs from before it was versioned
+    #[serde(default)]
+    pub version: u64,
+
     // Network timeout in milliseconds
     network_timeout_ms: u64,
 
+    /// Number of threads of the runtime, which polls the devices
+    #[serde(default = "Config::default_worker_threads")]
+    pub worker_threads: usize,
+
+    /// Time allowed for probing all devices at startup, in milliseconds
+    #[serde(default = "Config::default_startup_probe_budget_ms")]
+    startup_probe_budget_ms: u64,
+
+    /// How long must a device be failing for its failures to be logged as errors, in seconds
+    #[serde(default = "Config::default_error_after_failing_s")]
+    error_after_failing_s: u64,
+
+    /// Delays of the retries when a device fails, unless it has its own
+    #[serde(default)]
+    pub retry: retry::Policy,
+
+    /// Interval between the checks whether the config file changed, in seconds;
+    /// the devices are then reloaded, never if 0
+    #[serde(default = "Config::default_config_reload_interval_s")]
+    config_reload_interval_s: u64,
+
     /// Configurations of Shelly Plug (S) devices
+    #[serde(default)]
     pub shelly_plugs: Vec<plug::Config>,
 
-    pub influxdb2: influx::Config,
+    /// Discovery of the devices not listed in `shelly_plugs`, if any
+    pub discovery: Option<discovery::Config>,
+
+    /// What to do with devices sharing the name or the host of a previous one
+    #[serde(default)]
+    duplicate_plugs: DuplicatePlugs,
+
+    /// Networks which the devices may be in, e.g. `192.168.1.0/24`; any if not set
+    pub allowed_networks: Option<Vec<network::Network>>,
+
+    /// Broker with the telemetry of the devices fed by MQTT, if any
+    pub mqtt_source: Option<mqtt_source::Config>,
+
+    /// File keeping the state of devices across restarts, if any
+    pub state_file: Option<PathBuf>,
+
+    /// Days by which the consumption is totalled; UTC days if not set
+    #[serde(default)]
+    pub calendar: calendar::Calendar,
+
+    /// Price of the energy, by which the cost of each minute is computed, if any
+    pub tariff: Option<tariff::Config>,
+
+    /// Archive of raw device responses, if any
+    pub response_archive: Option<archive::Config>,
+
+    /// Sections of the sinks written to, e.g. `["database", "files"]`; all
+    /// configured ones if not set
+    pub sinks: Option<Vec<String>>,
+
+    /// Database sink (InfluxDB or PostgreSQL, by its `backend`), if any;
+    /// named `influxdb2` before version 2 of the config
+    #[serde(alias = "influxdb2")]
+    pub database: Option<influx::Config>,
+
+    /// Local storage of data-points, if any
+    pub local_store: Option<store::Config>,
+
+    /// MQTT sink (e.g. for Home Assistant), if any
+    pub mqtt: Option<mqtt::Config>,
+
+    /// Domoticz sink, if any
+    pub domoticz: Option<domoticz::Config>,
+
+    /// openHAB sink, if any
+    pub openhab: Option<openhab::Config>,
+
+    /// Zabbix sink, if any
+    pub zabbix: Option<zabbix::Config>,
+
+    /// Icinga2 passive check results, if any
+    pub icinga: Option<icinga::Config>,
+
+    /// HTTP endpoint with meters for EVCC, if any
+    pub evcc: Option<evcc::Config>,
+
+    /// HTTP endpoint scraped by Prometheus, if any
+    pub prometheus: Option<prometheus::Config>,
+
+    /// Grafana Live streaming, if any
+    pub grafana_live: Option<grafana::Config>,
+
+    /// emoncms sink, if any
+    pub emoncms: Option<emoncms::Config>,
+
+    /// External program sink, if any
+    pub exec: Option<exec::Config>,
+
+    /// Files of data-points, each in its directory and format
+    #[serde(default)]
+    pub files: Vec<files::Config>,
+
+    /// Local database of device metadata, if any
+    pub device_inventory: Option<inventory::Config>,
+
+    /// Log of the relay switches, if any
+    pub audit_log: Option<audit::Config>,
+
+    /// Hardening of the process, if any
+    pub sandbox: Option<sandbox::Config>,
+
+    /// Role in a pair of instances of which only one writes, if any
+    pub high_availability: Option<ha::Config>,
+
+    /// HTTP endpoint with the health of the logger, if any
+    pub health: Option<health::Config>,
+
+    /// Consumption reports
+    #[serde(default)]
+    pub report: report::Config,
+}
+
+/// What to do with devices sharing the name or the host of a previous one,
+/// which would be polled and written twice
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicatePlugs {
+    /// Refuse to start
+    #[default]
+    Refuse,
+    /// Skip them with a warning
+    Skip,
 }
 
 impl Config {
 
-    // Read the config file from 'config.json'
-    pub fn read_from_deafult_file() -> Config {
-        let config_as_string: String = std::fs::read_to_string("config.json")
-            .expect("config file can not be read from 'config.json'");
-        let config: Config = serde_json::from_str(&config_as_string)
-            .expect("config file could not be parsed as JSON");    
-        config
+    fn default_worker_threads() -> usize { 4 }
+
+    fn default_startup_probe_budget_ms() -> u64 { 5000 }
+
+    fn default_error_after_failing_s() -> u64 { 3600 }
+
+    fn default_config_reload_interval_s() -> u64 { 10 }
+
+    /// Read the config file in use, warning if it is of another version
+    pub fn load() -> Result<Config, String> {
+        let config = Config::read(path())?;
+        if config.version < migrate::CONFIG_VERSION {
+            warn!("{} is of version {}, 'shelly-logger migrate' upgrades it \
+                to version {}", path().display(), config.version, migrate::CONFIG_VERSION);
+        } else if config.version > migrate::CONFIG_VERSION {
+            warn!("{} is of version {}, newer than version {} of this logger, \
+                so some of its options may be ignored", path().display(), config.version,
+                migrate::CONFIG_VERSION);
+        }
+        Ok(config)
+    }
+
+    /// Read the config file, with the settings overridden by the environment
+    pub fn read(path: &Path) -> Result<Config, String> {
+        let mut settings = read_settings(path)?;
+        override_settings(&mut settings, std::env::vars())?;
+        serde_json::from_value(settings)
+            .map(Config::with_report_price)
+            .map_err(|err| format!("config file {} is not valid: {}", path.display(), err))
+    }
+
+    /// Take the deprecated price of the reports as the `tariff`, unless there is one
+    fn with_report_price(mut self) -> Config {
+        let price_per_kwh = self.report.price_per_kwh.take();
+        let currency = self.report.currency.take();
+        if price_per_kwh.is_none() && currency.is_none() {
+            return self;
+        }
+        match (&self.tariff, price_per_kwh) {
+            (Some(_), _) => warn!("'report.price_per_kwh' and 'report.currency' are ignored, \
+                the reports are priced by the 'tariff'"),
+            (None, Some(price_per_kwh)) => {
+                warn!("'report.price_per_kwh' and 'report.currency' are deprecated, \
+                    move them into the 'tariff'");
+                self.tariff = Some(tariff::Config { price_per_kwh, currency, schedule: vec![] });
+            },
+            (None, None) => warn!("'report.currency' is ignored without a price, \
+                set the 'tariff' to price the reports"),
+        }
+        self
+    }
+
+    /// Check that no two devices share a name or a channel of a host, refusing
+    /// the config or skipping the later ones as configured
+    pub fn with_unique_plugs(mut self) -> Result<Config, String> {
+        let mut conflicts = vec![];
+        let mut unique_plugs: Vec<plug::Config> = vec![];
+        for shelly_plug_config in std::mem::take(&mut self.shelly_plugs) {
+            let host = normalized_host(&shelly_plug_config.host);
+            let conflict = unique_plugs.iter().find_map(|unique| {
+                if unique.name == shelly_plug_config.name {
+                    Some(format!("'{}' is the name of two devices", unique.name))
+                } else if normalized_host(&unique.host) == host
+                    && unique.channel == shelly_plug_config.channel {
+                    Some(format!("'{}' and '{}' are the same channel {} of the device at {}",
+                        unique.name, shelly_plug_config.name, shelly_plug_config.channel,
+                        shelly_plug_config.host))
+                } else {
+                    None
+                }
+            });
+            match conflict {
+                Some(conflict) => conflicts.push(conflict),
+                None => unique_plugs.push(shelly_plug_config),
+            }
+        }
+        self.shelly_plugs = unique_plugs;
+
+        if conflicts.is_empty() {
+            return Ok(self);
+        }
+        match self.duplicate_plugs {
+            DuplicatePlugs::Refuse => Err(format!("conflicting devices in the config: {} \
+                (set \"duplicate_plugs\": \"skip\" to skip the later ones)", conflicts.join("; "))),
+            DuplicatePlugs::Skip => {
+                for conflict in conflicts {
+                    warn!("{}, the later one is skipped", conflict);
+                }
+                Ok(self)
+            },
+        }
+    }
+
+    /// Whether the sink of the section is written to, if it is configured
+    pub fn is_sink_selected(&self, section: &str) -> bool {
+        self.sinks.as_ref().is_none_or(|sinks| sinks.iter()
+            .any(|sink| sink::current_section(sink) == section))
+    }
+
+    /// Devices polled over HTTP, i.e. not fed by the `mqtt_source`
+    pub fn polled_plugs(&self) -> Vec<plug::Config> {
+        self.shelly_plugs.iter()
+            .filter(|shelly_plug_config| shelly_plug_config.mode() == plug::Mode::Poll)
+            .cloned()
+            .collect()
+    }
+
+    /// Directories where the logger writes its data
+    pub fn data_paths(&self) -> Vec<PathBuf> {
+        let directory_of = |path: &Path| match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let mut paths = vec![];
+        paths.extend(self.state_file.as_deref().map(directory_of));
+        paths.extend(self.database.as_ref()
+            .and_then(|database_config| database_config.spill_file.as_deref())
+            .map(directory_of));
+        paths.extend(self.local_store.as_ref().map(|store_config| directory_of(&store_config.path)));
+        paths.extend(self.device_inventory.as_ref()
+            .map(|inventory_config| directory_of(&inventory_config.path)));
+        paths.extend(self.response_archive.as_ref()
+            .map(|archive_config| archive_config.directory.clone()));
+        paths.extend(self.audit_log.as_ref().map(|audit_config| directory_of(&audit_config.path)));
+        paths.extend(self.report.html.as_ref().map(|html_config| html_config.directory.clone()));
+        paths.extend(self.files.iter().map(|files_config| files_config.directory.clone()));
+        paths
+    }
+
+    /// Programs which the logger executes
+    pub fn executables(&self) -> Vec<PathBuf> {
+        self.exec.as_ref()
+            .and_then(exec::Config::program)
+            .map(PathBuf::from)
+            .into_iter()
+            .collect()
+    }
+
+    /// HTTP client for the devices
+    pub fn device_client(&self) -> network::DeviceClient {
+        network::DeviceClient::new(self.network_timeout(), self.allowed_networks.clone())
+    }
+
+    /// Log of the failures of the device, with its retry policy
+    pub fn failure_log(&self, shelly_plug_config: &plug::Config) -> FailureLog {
+        FailureLog::new(self.error_after_failing(), shelly_plug_config.retry_policy(&self.retry))
     }
 
     /// Network connection timeout
     pub fn network_timeout(&self) -> Duration {
         Duration::from_millis(self.network_timeout_ms)
     }
+
+    /// Interval between the checks whether the config file changed, if checked
+    pub fn config_reload_interval(&self) -> Option<Duration> {
+        Some(Duration::from_secs(self.config_reload_interval_s))
+            .filter(|interval| !interval.is_zero())
+    }
+
+    /// Time allowed for probing all devices at startup
+    pub fn startup_probe_budget(&self) -> Duration {
+        Duration::from_millis(self.startup_probe_budget_ms)
+    }
+
+    /// How long must a device be failing for its failures to be logged as errors
+    pub fn error_after_failing(&self) -> Duration {
+        Duration::from_secs(self.error_after_failing_s)
+    }
+}
+
+/// Format of a config file, by its extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl Format {
+
+    /// Format of the file, JSON unless it is ".toml", ".yaml" or ".yml"
+    pub fn of(path: &Path) -> Format {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => Format::Toml,
+            Some("yaml" | "yml") => Format::Yaml,
+            _ => Format::Json,
+        }
+    }
+}
+
+/// Settings of the config file as a JSON value, whatever its format
+pub fn read_settings(path: &Path) -> Result<Value, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|err| format!("config file can not be read from '{}': {}",
+            path.display(), err))?;
+    let settings: Result<Value, String> = match Format::of(path) {
+        Format::Json => serde_json::from_str(&text)
+            .map_err(|err| format!("is not valid JSON: {}", err)),
+        #[cfg(feature = "toml")]
+        Format::Toml => toml::from_str(&text)
+            .map_err(|err| format!("is not valid TOML: {}", err)),
+        #[cfg(not(feature = "toml"))]
+        Format::Toml => Err("needs the 'toml' feature".to_string()),
+        #[cfg(feature = "yaml")]
+        Format::Yaml => serde_yaml::from_str(&text)
+            .map_err(|err| format!("is not valid YAML: {}", err)),
+        #[cfg(not(feature = "yaml"))]
+        Format::Yaml => Err("needs the 'yaml' feature".to_string()),
+    };
+    settings.map_err(|err| format!("config file {} {}", path.display(), err))
+}
+
+/// Override the settings by the environment variables starting with `ENV_PREFIX`,
+/// with the path to the setting in lower case, its levels separated by "__"
+/// and the items of lists by their index (e.g. "SHELLY_LOGGER__SHELLY_PLUGS__0__PASSWORD");
+/// the values of settings which are not strings (e.g. numbers) are parsed as JSON, and
+/// those of settings missing in the file as numbers or booleans, if they are ones
+fn override_settings(settings: &mut Value, vars: impl Iterator<Item=(String, String)>)
+-> Result<(), String>
+{
+    for (name, value) in vars {
+        let path = match name.strip_prefix(ENV_PREFIX) {
+            Some(path) => path.to_ascii_lowercase(),
+            None => continue,
+        };
+        let mut setting = &mut *settings;
+        for level in path.split("__") {
+            setting = match setting {
+                Value::Array(items) => level.parse::<usize>().ok()
+                    .and_then(|index| items.get_mut(index))
+                    .ok_or_else(|| format!("{} overrides a list item which is not \
+                        in the config", name))?,
+                Value::Object(fields) => fields.entry(level).or_insert(Value::Null),
+                Value::Null => {
+                    *setting = Value::Object(Default::default());
+                    setting.as_object_mut().expect("object was just set")
+                        .entry(level).or_insert(Value::Null)
+                },
+                _ => return Err(format!("{} overrides a part of a setting which \
+                    has no parts", name)),
+            };
+        }
+        *setting = match setting {
+            Value::String(_) => Value::String(value),
+            Value::Null => match serde_json::from_str(&value) {
+                Ok(scalar @ (Value::Number(_) | Value::Bool(_))) => scalar,
+                _ => Value::String(value),
+            },
+            _ => serde_json::from_str(&value).unwrap_or(Value::String(value)),
+        };
+        debug!("{} overrides '{}' of the config", name, path.replace("__", "."));
+    }
+    Ok(())
+}
+
+/// Host in the form compared for duplicates, e.g. "Plug.local:80" is "plug.local"
+fn normalized_host(host: &str) -> String {
+    host.strip_suffix(":80").unwrap_or(host).to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Settings of the JSON, overridden by the variables
+    fn overridden(json: &str, vars: &[(&str, &str)]) -> Value {
+        let mut settings = serde_json::from_str(json).unwrap();
+        override_settings(&mut settings, vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))).unwrap();
+        settings
+    }
+
+    #[test]
+    fn missing_settings_are_overridden_by_numbers_and_booleans() {
+        let settings = overridden(r#"{"network_timeout_ms": 1000, "shelly_plugs": [],
+            "device_inventory": {"path": "devices.db"}}"#, &[
+            ("SHELLY_LOGGER__DEVICE_INVENTORY__REFRESH_INTERVAL_S", "60"),
+            ("SHELLY_LOGGER__WORKER_THREADS", "8"),
+            ("SHELLY_LOGGER__DISCOVERY__ENABLED", "true"),
+        ]);
+        assert_eq!(settings["device_inventory"]["refresh_interval_s"], 60);
+        assert_eq!(settings["discovery"]["enabled"], true);
+        let app_config: Config = serde_json::from_value(settings).unwrap();
+        assert_eq!(app_config.device_inventory.unwrap().refresh_interval_s, 60);
+        assert_eq!(app_config.worker_threads, 8);
+    }
+
+    #[test]
+    fn strings_stay_strings() {
+        let settings = overridden(r#"{"database": {"token": "abc"},
+            "shelly_plugs": [{"name": "fridge", "host": "192.0.2.1"}]}"#, &[
+            ("SHELLY_LOGGER__DATABASE__TOKEN", "12345"),
+            ("SHELLY_LOGGER__DATABASE__ORG", "home"),
+            ("SHELLY_LOGGER__SHELLY_PLUGS__0__HOST", "192.0.2.2"),
+            ("OTHER__WORKER_THREADS", "8"),
+        ]);
+        assert_eq!(settings["database"]["token"], "12345");
+        assert_eq!(settings["database"]["org"], "home");
+        assert_eq!(settings["shelly_plugs"][0]["host"], "192.0.2.2");
+        assert!(settings.get("worker_threads").is_none());
+    }
+
+    #[test]
+    fn numbers_of_missing_secrets_are_secrets() {
+        let settings = overridden(r#"{"shelly_plugs": [{"name": "fridge", "host": "192.0.2.1",
+            "instantaneous_meter_interval_in_s": 10}]}"#, &[("SHELLY_LOGGER__SHELLY_PLUGS__0__PASSWORD", "1234")]);
+        let shelly_plug_config: plug::Config =
+            serde_json::from_value(settings["shelly_plugs"][0].clone()).unwrap();
+        assert_eq!(shelly_plug_config.password.unwrap().expose(), "1234");
+    }
+
+    #[test]
+    fn overrides_of_missing_list_items_are_refused() {
+        let mut settings = serde_json::from_str(r#"{"shelly_plugs": []}"#).unwrap();
+        assert!(override_settings(&mut settings, std::iter::once((
+            "SHELLY_LOGGER__SHELLY_PLUGS__0__HOST".to_string(), "192.0.2.1".to_string()))).is_err());
+    }
+
+    #[test]
+    fn database_is_read_by_its_former_name() {
+        let app_config: Config = serde_json::from_str(r#"{"network_timeout_ms": 1000,
+            "shelly_plugs": [], "sinks": ["influxdb2"],
+            "influxdb2": {"host": "localhost", "port": 8086}}"#).unwrap();
+        assert!(app_config.database.is_some());
+        assert!(app_config.is_sink_selected("database"));
+    }
 }
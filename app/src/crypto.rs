@@ -0,0 +1,126 @@
+use crate::secret::{KeyringEntry, Secret};
+use serde::Deserialize;
+use std::path::PathBuf;
+
+#[cfg(feature = "encryption")]
+use chacha20poly1305::{aead::{Aead, AeadCore, KeyInit, OsRng}, ChaCha20Poly1305, Key, Nonce};
+
+/// Prefix of encrypted data, followed by the nonce and the ciphertext
+const MAGIC: &[u8] = b"SLENC1";
+
+/// Length of the ChaCha20-Poly1305 nonce
+#[cfg(feature = "encryption")]
+const NONCE_BYTES: usize = 12;
+
+/// Source of the 256-bit encryption key, given as 64 hexadecimal digits;
+/// exactly one of the fields must be set
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "encryption"), allow(dead_code))]
+pub struct Config {
+
+    /// The key itself
+    pub key: Option<Secret>,
+
+    /// File containing the key
+    pub key_file: Option<PathBuf>,
+
+    /// Entry of the operating system keyring containing the key
+    pub keyring: Option<KeyringEntry>,
+}
+
+impl Config {
+
+    /// Read the key from its source
+    #[cfg(feature = "encryption")]
+    fn read_key(&self) -> Result<[u8; 32], String> {
+        let text = match (&self.key, &self.key_file, &self.keyring) {
+            (Some(key), None, None) => key.expose().to_string(),
+            (None, Some(path), None) => std::fs::read_to_string(path)
+                .map_err(|err| format!("{} can not be read: {}", path.display(), err))?,
+            (None, None, Some(entry)) => crate::secret::read_keyring(entry)?,
+            _ => return Err("exactly one of 'key', 'key_file' \
+                and 'keyring' must be set".to_string()),
+        };
+        parse_hex_key(text.trim())
+    }
+}
+
+/// Decode 64 hexadecimal digits
+#[cfg(feature = "encryption")]
+fn parse_hex_key(text: &str) -> Result<[u8; 32], String> {
+    let digits = text.as_bytes();
+    if digits.len() != 64 {
+        return Err(format!("key must be 64 hexadecimal digits, not {}", digits.len()));
+    }
+    let mut key = [0u8; 32];
+    for (byte, pair) in key.iter_mut().zip(digits.chunks(2)) {
+        let pair = std::str::from_utf8(pair).map_err(|_| "key is not hexadecimal")?;
+        *byte = u8::from_str_radix(pair, 16).map_err(|_| "key is not hexadecimal")?;
+    }
+    Ok(key)
+}
+
+/// Whether the data was produced by `Cipher::encrypt`
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Authenticated encryption of local files
+#[cfg(feature = "encryption")]
+pub struct Cipher {
+    cipher: ChaCha20Poly1305,
+}
+
+#[cfg(feature = "encryption")]
+impl Cipher {
+
+    pub fn new(encryption_config: &Config) -> Result<Cipher, String> {
+        let key = encryption_config.read_key()?;
+        Ok(Cipher { cipher: ChaCha20Poly1305::new(Key::from_slice(&key)) })
+    }
+
+    /// Encrypt the data with a fresh random nonce
+    pub fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self.cipher.encrypt(&nonce, plaintext)
+            .expect("encryption into a Vec can not fail");
+        let mut data = Vec::with_capacity(MAGIC.len() + NONCE_BYTES + ciphertext.len());
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&nonce);
+        data.extend_from_slice(&ciphertext);
+        data
+    }
+
+    /// Decrypt the data, checking that it was not tampered with
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, String> {
+        let data = data.strip_prefix(MAGIC).ok_or("data is not encrypted")?;
+        if data.len() < NONCE_BYTES {
+            return Err("encrypted data is truncated".to_string());
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_BYTES);
+        self.cipher.decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| "data can not be decrypted, the key is wrong \
+                or the data is corrupt".to_string())
+    }
+}
+
+/// Authenticated encryption of local files, which is not compiled in
+#[cfg(not(feature = "encryption"))]
+pub enum Cipher {}
+
+#[cfg(not(feature = "encryption"))]
+impl Cipher {
+
+    pub fn new(_encryption_config: &Config) -> Result<Cipher, String> {
+        Err("encryption of local files needs the 'encryption' feature".to_string())
+    }
+
+    pub fn encrypt(&self, _plaintext: &[u8]) -> Vec<u8> {
+        match *self {}
+    }
+
+    pub fn decrypt(&self, _data: &[u8]) -> Result<Vec<u8>, String> {
+        match *self {}
+    }
+}
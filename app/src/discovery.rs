@@ -0,0 +1,137 @@
+use crate::mdns;
+use crate::network::DeviceClient;
+use crate::plug;
+use crate::probe;
+use crate::scheduler::{Intake, Poll, Task};
+use log::{debug, info, warn};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::time::Duration;
+
+/// Discovery of devices on the local network, which are not in the config
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Config {
+
+    /// Whether the devices are discovered
+    #[serde(default = "Config::default_enabled")]
+    pub enabled: bool,
+
+    /// Interval between the searches for new devices, in seconds
+    #[serde(default = "Config::default_interval_s")]
+    interval_s: u64,
+
+    /// Interval between measurements of instantaneous power of the
+    /// discovered devices, in seconds; not measured if negative
+    #[serde(default = "Config::default_instantaneous_meter_interval_in_s")]
+    instantaneous_meter_interval_in_s: f64,
+}
+
+impl Config {
+
+    fn default_enabled() -> bool { true }
+
+    fn default_interval_s() -> u64 { 300 }
+
+    fn default_instantaneous_meter_interval_in_s() -> f64 { 10.0 }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_s.max(1))
+    }
+}
+
+/// How long to wait for the answers to an mDNS query
+const MDNS_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Meter polling a discovered device
+pub type MeterFactory = Box<dyn Fn(&plug::Config) -> Box<dyn Task> + Send>;
+
+/// Searches the local network (by mDNS) for Shelly devices, and adds a meter
+/// of each one not metered yet to the scheduler
+///
+/// Devices are recognized by their MAC address, so a device which changed
+/// its IP is not metered twice.
+pub struct Discovery {
+    discovery_config: Config,
+    client: DeviceClient,
+    /// Hosts of the devices in the config or discovered
+    known_hosts: HashSet<String>,
+    /// MAC addresses of the devices in the config or discovered
+    known_macs: HashSet<String>,
+    meter: MeterFactory,
+    intake: Intake,
+}
+
+impl Discovery {
+
+    /// Discovery of the devices not in `shelly_plug_configs`, nor among
+    /// the `known_macs` (e.g. found by the startup probe)
+    pub fn new(discovery_config: Config, client: DeviceClient,
+        shelly_plug_configs: &[plug::Config], known_macs: impl IntoIterator<Item=String>,
+        meter: MeterFactory, intake: Intake) -> Discovery
+    {
+        let known_hosts = shelly_plug_configs.iter()
+            .map(|shelly_plug_config| shelly_plug_config.host.to_string())
+            .collect();
+        let known_macs = shelly_plug_configs.iter()
+            .filter_map(|shelly_plug_config| shelly_plug_config.mac.as_deref())
+            .map(probe::normalized_mac)
+            .chain(known_macs)
+            .collect();
+        info!("Discovering Shelly devices on the local network every {}s",
+            discovery_config.interval().as_secs());
+        Discovery { discovery_config, client, known_hosts, known_macs, meter, intake }
+    }
+
+    /// Config of a discovered device, named by its ID (Gen2+) or MAC address
+    fn plug_config(&self, host: &str, mac: &str, device_info: &probe::DeviceInfo) -> plug::Config {
+        let name = device_info.id.clone()
+            .unwrap_or_else(|| format!("shelly-{}", mac.to_ascii_lowercase()));
+        plug::Config {
+            instantaneous_meter_interval_in_s:
+                self.discovery_config.instantaneous_meter_interval_in_s,
+            mac: Some(mac.to_string()),
+            ..plug::Config::new(&name, host)
+        }
+    }
+}
+
+impl Task for Discovery {
+
+    fn poll(&mut self) -> Poll<'_> {
+        Box::pin(async move {
+            let addresses = match mdns::responders(&mdns::SHELLY_SERVICES, MDNS_TIMEOUT).await {
+                Ok(addresses) => addresses,
+                Err(err) => {
+                    warn!("devices can not be discovered: {}", err);
+                    return Ok(Some(self.discovery_config.interval()));
+                }
+            };
+            for address in addresses {
+                let host = address.to_string();
+                if self.known_hosts.contains(&host) {
+                    continue;
+                }
+                let device_info = match probe::probe_host(&host, &self.client).await {
+                    Ok(device_info) => device_info,
+                    Err(err) => {
+                        debug!("{} answered mDNS, but could not be probed: {}", host, err);
+                        continue;
+                    }
+                };
+                // Known at another address
+                let mac = probe::normalized_mac(&device_info.mac);
+                if !self.known_macs.insert(mac.clone()) {
+                    continue;
+                }
+                self.known_hosts.insert(host.clone());
+                let shelly_plug_config = self.plug_config(&host, &mac, &device_info);
+                info!("{} discovered at {} ({}, MAC {}), metering it", shelly_plug_config.name,
+                    host, device_info.model.as_deref().unwrap_or("unknown model"), mac);
+                if !self.intake.add((self.meter)(&shelly_plug_config)) {
+                    return Ok(None);
+                }
+            }
+            Ok(Some(self.discovery_config.interval()))
+        })
+    }
+}
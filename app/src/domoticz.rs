@@ -0,0 +1,134 @@
+use crate::point::Datum;
+use crate::secret::Secret;
+use crate::sink::{self, Sink};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::mpsc::Receiver;
+
+#[cfg(feature = "domoticz")]
+use {
+    crate::point::Measurement,
+    base64::Engine,
+    log::{debug, info, warn},
+    std::sync::Arc,
+    std::thread::JoinHandle,
+    std::time::Duration,
+};
+
+/// Domoticz sink configuration
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(not(feature = "domoticz"), allow(dead_code))]
+pub struct Config {
+
+    /// Base URL of Domoticz, e.g. "http://domoticz.local:8080"
+    pub url: String,
+
+    pub username: Option<String>,
+
+    pub password: Option<Secret>,
+
+    /// IDX of the "Electric (Instant+Counter)" device of each plug, by plug name;
+    /// plugs without an IDX are not pushed
+    pub idx: HashMap<String, u32>,
+}
+
+/// Response of the Domoticz JSON API
+#[cfg(feature = "domoticz")]
+#[derive(Deserialize)]
+struct Response {
+    status: String,
+}
+
+impl Sink for Config {
+    #[cfg_attr(not(feature = "domoticz"), allow(unused_variables))]
+    fn spawn(self: Box<Self>, _context: &sink::Context, data_receiver: Receiver<Datum>)
+    -> Result<sink::Handle, String>
+    {
+        #[cfg(feature = "domoticz")]
+        return Ok(Pusher::spawn(*self, data_receiver));
+        #[cfg(not(feature = "domoticz"))]
+        return Err(format!("Domoticz at {} needs the 'domoticz' feature", self.url));
+    }
+}
+
+/// Pushes power and energy of the plugs to Domoticz devices
+#[cfg(feature = "domoticz")]
+pub struct Pusher {
+    domoticz_config: Config,
+    agent: ureq::Agent,
+    authorization: Option<String>,
+    /// Latest power of each plug, in Watts
+    power: HashMap<Arc<str>, f32>,
+}
+
+#[cfg(feature = "domoticz")]
+impl Pusher {
+
+    pub fn spawn(domoticz_config: Config, data_receiver: Receiver<Datum>)
+    -> JoinHandle<Result<(),String>>
+    {
+        let authorization = domoticz_config.username.as_ref().map(|username| {
+            let credentials = format!("{}:{}", username,
+                domoticz_config.password.as_ref().map(Secret::expose).unwrap_or_default());
+            format!("Basic {}", base64::engine::general_purpose::STANDARD.encode(credentials))
+        });
+        let mut pusher = Pusher {
+            agent: ureq::AgentBuilder::new().timeout(Duration::from_secs(10)).build(),
+            domoticz_config,
+            authorization,
+            power: HashMap::new(),
+        };
+        std::thread::spawn(move || {
+            info!("Pushing data-points to Domoticz at {}", pusher.domoticz_config.url);
+            for datum in data_receiver {
+                pusher.accept(&datum);
+            }
+            debug!("all meters stopped, stopping");
+            Ok(())
+        })
+    }
+
+    /// Remember the power; push it with the energy counter once per minute
+    fn accept(&mut self, datum: &Datum) {
+        match datum.measurement {
+            Measurement::instantaneous_consumption_in_w => {
+                self.power.insert(datum.device_name.clone(), datum.value);
+            },
+            // Average power of the last minute, if the instantaneous one is not measured
+            Measurement::last_minute_consumption_in_wh => {
+                self.power.entry(datum.device_name.clone()).or_insert(datum.value * 60.0);
+            },
+            Measurement::consumption_since_reboot_in_wh => {
+                let power = self.power.get(&datum.device_name).copied().unwrap_or_default();
+                self.push(&datum.device_name, power, datum.value);
+            },
+            _ => (),
+        }
+    }
+
+    /// Update the Domoticz device of the plug, if it has one
+    fn push(&self, device_name: &str, power_w: f32, energy_wh: f32) {
+        let idx = match self.domoticz_config.idx.get(device_name) {
+            Some(idx) => idx,
+            None => return,
+        };
+        let url = format!("{}/json.htm", self.domoticz_config.url.trim_end_matches('/'));
+        let mut request = self.agent.get(&url)
+            .query("type", "command")
+            .query("param", "udevice")
+            .query("idx", &idx.to_string())
+            .query("nvalue", "0")
+            .query("svalue", &format!("{:.1};{:.1}", power_w, energy_wh));
+        if let Some(authorization) = &self.authorization {
+            request = request.set("Authorization", authorization);
+        }
+        match request.call().map_err(|err| err.to_string())
+            .and_then(|response| response.into_json::<Response>().map_err(|err| err.to_string())) {
+            Ok(response) if response.status == "OK" => (),
+            Ok(response) => warn!("Domoticz rejected the update of {} (IDX {}): {}",
+                device_name, idx, response.status),
+            Err(err) => warn!("Domoticz device of {} (IDX {}) could not be updated: {}",
+                device_name, idx, err),
+        }
+    }
+}
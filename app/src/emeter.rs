@@ -0,0 +1,143 @@
+use crate::log_limit::FailureLog;
+use crate::network::DeviceClient;
+use crate::plug;
+use crate::point::Datum;
+use crate::point::Measurement::*;
+use crate::scheduler::{Poll, Task};
+use log::{debug, info, warn};
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Response from the Gen1 "/emeter/{channel}" endpoint; the Shelly EM
+/// reports neither the current nor (on older firmware) the power factor
+#[derive(Deserialize)]
+struct EmeterStatus {
+    /// Active power, in Watts; negative when returning to the grid
+    power: Option<f32>,
+    voltage: Option<f32>,
+    current: Option<f32>,
+    pf: Option<f32>,
+    /// Whether the metering of the phase self-checks OK
+    is_valid: Option<bool>,
+    /// Energy consumed, in Watt-hours
+    total: Option<f32>,
+    /// Energy returned to the grid, in Watt-hours
+    total_returned: Option<f32>,
+}
+
+/// Interval between the measurements of an energy meter without the
+/// instantaneous consumption measured
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Polls all phases of an energy meter (e.g. Shelly 3EM) in turn, each
+/// reported as a device of its own, named after the phase
+pub struct EnergyMeter {
+    config: plug::Config,
+    client: DeviceClient,
+    interval: Duration,
+    /// Config of each phase: its name and channel
+    phases: Vec<plug::Config>,
+    /// Whether the last measurement of each phase was invalid, to warn only once
+    invalid: Vec<bool>,
+    /// Failures since the last successful poll
+    failures: FailureLog,
+    data_sender: UnboundedSender<Datum>,
+}
+
+impl EnergyMeter {
+
+    pub fn new(
+        shelly_plug_config: &plug::Config,
+        client: DeviceClient,
+        failures: FailureLog,
+        data_sender: UnboundedSender<Datum>)
+    -> EnergyMeter
+    {
+        let phases: Vec<plug::Config> = shelly_plug_config.phases.iter().enumerate()
+            .map(|(channel, phase)| plug::Config {
+                name: format!("{}-{}", shelly_plug_config.name, phase).into(),
+                channel: channel as u32,
+                ..shelly_plug_config.clone()
+            })
+            .collect();
+        info!("{} will measure the phases {}", shelly_plug_config.host, phases.iter()
+            .map(|phase| phase.name.to_string()).collect::<Vec<_>>().join(", "));
+        EnergyMeter {
+            config: shelly_plug_config.clone(),
+            client: client.for_device(shelly_plug_config),
+            interval: shelly_plug_config.instantaneous_meter_interval().unwrap_or(DEFAULT_INTERVAL),
+            invalid: vec![false; phases.len()],
+            phases,
+            failures,
+            data_sender,
+        }
+    }
+
+    /// Status of the channel of the phase
+    async fn measure(&self, phase: &plug::Config) -> Result<EmeterStatus, String> {
+        let url = self.client.url(&self.config.host, &format!("/emeter/{}", phase.channel));
+        self.client.get(&url).call().await
+            .map_err(|err| err.to_string())?
+            .json().await
+            .map_err(|err| format!("{} returned unexpected data: {}", url, err))
+    }
+
+    /// Data-points of the phase derived from its status; the invalid ones
+    /// are skipped, unless they are to be flagged
+    fn datums(&mut self, index: usize, status: &EmeterStatus) -> Vec<Datum> {
+        let phase = &self.phases[index];
+        let valid = status.is_valid.unwrap_or(true);
+        if valid && self.invalid[index] {
+            info!("{} measurements are valid again", phase.name);
+        } else if !valid && !self.invalid[index] {
+            warn!("{} last measurement was invalid; {} invalid measurements", phase.name,
+                if phase.invalid_samples == plug::InvalidSamples::Flag { "flagging" } else { "skipping" });
+        }
+        self.invalid[index] = !valid;
+        if !valid && phase.invalid_samples != plug::InvalidSamples::Flag {
+            return vec![];
+        }
+        [
+            (instantaneous_consumption_in_w, status.power),
+            (voltage_in_v, status.voltage),
+            (current_in_a, status.current),
+            (power_factor, status.pf),
+            (consumption_since_reboot_in_wh, status.total),
+            (returned_since_reboot_in_wh, status.total_returned),
+        ].into_iter()
+            .filter_map(|(measurement, value)| {
+                let mut datum = phase.datum(measurement, value?);
+                datum.valid = valid;
+                Some(datum)
+            })
+            .collect()
+    }
+}
+
+impl Task for EnergyMeter {
+
+    fn poll(&mut self) -> Poll<'_> {
+        Box::pin(async move {
+            let mut datums = vec![];
+            for index in 0..self.phases.len() {
+                let status = match self.measure(&self.phases[index]).await {
+                    Ok(status) => status,
+                    Err(err) => {
+                        return Ok(Some(self.failures.failed(&self.config.host, &format!(
+                            "{} could not be measured ({})", self.phases[index].name, err))));
+                    },
+                };
+                datums.extend(self.datums(index, &status));
+            }
+            self.failures.succeeded(&self.config.host);
+            for datum in datums {
+                if self.data_sender.send(datum).is_err() {
+                    debug!("channel to the DB thread closed, stopping");
+                    return Ok(None);
+                }
+            }
+            Ok(Some(self.interval))
+        })
+    }
+}
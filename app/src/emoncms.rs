@@ -0,0 +1,166 @@
+use crate::point::Datum;
+use crate::secret::Secret;
+use crate::sink::{self, Sink};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::mpsc::Receiver;
+
+#[cfg(feature = "emoncms")]
+use {
+    log::{debug, info, warn},
+    std::thread::JoinHandle,
+    std::time::Duration,
+};
+
+/// emoncms sink configuration
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(not(feature = "emoncms"), allow(dead_code))]
+pub struct Config {
+
+    /// Base URL of emoncms, e.g. "https://emoncms.org" or "http://emonpi.local/emoncms"
+    pub url: String,
+
+    /// Read & Write API key
+    pub apikey: Secret,
+
+    /// Node of each plug, by plug name; the plug name if not listed
+    #[serde(default)]
+    pub nodes: HashMap<String, String>,
+}
+
+/// Most data-points posted in one batch
+#[cfg(feature = "emoncms")]
+const MAX_POINTS_PER_BATCH: usize = 1000;
+
+/// Inputs of a node measured at the same time
+#[cfg(feature = "emoncms")]
+type Inputs = serde_json::Map<String, serde_json::Value>;
+
+/// Data of the bulk API: the time of the first data-point, and a
+/// `[seconds after it, node, {input: value, ...}]` per node and time
+#[cfg(feature = "emoncms")]
+fn bulk<'a>(datums: &'a [Datum], node: impl Fn(&'a Datum) -> &'a str)
+-> Option<(i64, serde_json::Value)>
+{
+    let mut inputs: Vec<((&str, i64), Inputs)> = vec![];
+    // emoncms has no quality of its inputs, so invalid data-points are not posted
+    for datum in datums.iter().filter(|datum| datum.valid) {
+        let key = (node(datum), datum.measured_on.timestamp());
+        let index = match inputs.iter().position(|(existing, _)| *existing == key) {
+            Some(index) => index,
+            None => {
+                inputs.push((key, Inputs::new()));
+                inputs.len() - 1
+            }
+        };
+        inputs[index].1.insert(datum.measurement.to_string(), datum.value.into());
+    }
+    let reference = inputs.first()?.0.1;
+    let data = inputs.into_iter()
+        .map(|((node, time), values)| serde_json::json!([time - reference, node, values]))
+        .collect();
+    Some((reference, serde_json::Value::Array(data)))
+}
+
+impl Sink for Config {
+    #[cfg_attr(not(feature = "emoncms"), allow(unused_variables))]
+    fn spawn(self: Box<Self>, _context: &sink::Context, data_receiver: Receiver<Datum>)
+    -> Result<sink::Handle, String>
+    {
+        #[cfg(feature = "emoncms")]
+        return Ok(Poster::spawn(*self, data_receiver));
+        #[cfg(not(feature = "emoncms"))]
+        return Err(format!("emoncms at {} needs the 'emoncms' feature", self.url));
+    }
+}
+
+/// Posts the data-points as inputs of emoncms nodes
+#[cfg(feature = "emoncms")]
+pub struct Poster {
+    emoncms_config: Config,
+    agent: ureq::Agent,
+}
+
+#[cfg(feature = "emoncms")]
+impl Poster {
+
+    pub fn spawn(emoncms_config: Config, data_receiver: Receiver<Datum>)
+    -> JoinHandle<Result<(),String>>
+    {
+        let poster = Poster {
+            agent: ureq::AgentBuilder::new().timeout(Duration::from_secs(10)).build(),
+            emoncms_config,
+        };
+        std::thread::spawn(move || {
+            info!("Posting data-points to emoncms at {}", poster.emoncms_config.url);
+            loop {
+                let mut datums = match data_receiver.recv() {
+                    Ok(datum) => vec![datum],
+                    Err(_) => {
+                        debug!("all meters stopped, stopping");
+                        return Ok(());
+                    }
+                };
+                datums.extend(data_receiver.try_iter().take(MAX_POINTS_PER_BATCH - 1));
+                poster.post_all(&datums);
+            }
+        })
+    }
+
+    /// Node of the plug
+    fn node<'a>(&'a self, datum: &'a Datum) -> &'a str {
+        self.emoncms_config.nodes.get(datum.device_name.as_ref())
+            .map(String::as_str)
+            .unwrap_or(&datum.device_name)
+    }
+
+    /// Post the data-points in one request of the bulk API
+    fn post_all(&self, datums: &[Datum]) {
+        let Some((time, data)) = bulk(datums, |datum| self.node(datum)) else {
+            return;
+        };
+        let url = format!("{}/input/bulk", self.emoncms_config.url.trim_end_matches('/'));
+        let result = self.agent.post(&url)
+            .query("time", &time.to_string())
+            // Not in the query, which ends up in logs and error messages
+            .set("Authorization", &format!("Bearer {}", self.emoncms_config.apikey.expose()))
+            .send_form(&[("data", &data.to_string())])
+            .map_err(|err| err.to_string())
+            .and_then(|response| response.into_string().map_err(|err| err.to_string()));
+        match result {
+            // emoncms answers "ok" or a JSON object with "success"
+            Ok(body) if body.trim() == "ok" || body.contains("\"success\":true") => (),
+            Ok(body) => warn!("emoncms rejected {} data-points: {}", datums.len(), body.trim()),
+            Err(err) => warn!("{} data-points could not be posted to emoncms: {}", datums.len(), err),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "emoncms"))]
+mod tests {
+    use super::*;
+    use crate::point::Measurement;
+    use chrono::{TimeZone, Utc};
+
+    fn datum(second: i64, device_name: &str, measurement: Measurement, valid: bool) -> Datum {
+        Datum { measured_on: Utc.timestamp_opt(second, 0).unwrap(), measurement,
+            device_name: device_name.into(), device_host: "192.0.2.1".into(), value: 1.0, valid }
+    }
+
+    #[test]
+    fn bulk_has_the_valid_inputs_by_node_and_time() {
+        let datums = [
+            datum(100, "fridge", Measurement::instantaneous_consumption_in_w, true),
+            datum(100, "fridge", Measurement::last_minute_consumption_in_wh, true),
+            datum(100, "oven", Measurement::instantaneous_consumption_in_w, false),
+            datum(160, "fridge", Measurement::instantaneous_consumption_in_w, true),
+        ];
+        let (time, data) = bulk(&datums, |datum| &datum.device_name).unwrap();
+        assert_eq!(time, 100);
+        assert_eq!(data, serde_json::json!([
+            [0, "fridge", {"instantaneous_consumption_in_w": 1.0, "last_minute_consumption_in_wh": 1.0}],
+            [60, "fridge", {"instantaneous_consumption_in_w": 1.0}],
+        ]));
+        assert!(bulk(&datums[2..3], |datum| &datum.device_name).is_none());
+    }
+}
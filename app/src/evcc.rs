@@ -0,0 +1,134 @@
+use crate::point::Datum;
+use crate::sink::{self, Sink};
+use crate::tls;
+use serde::Deserialize;
+use std::sync::mpsc::Receiver;
+
+#[cfg(feature = "evcc")]
+use {
+    crate::httpd,
+    crate::point::Measurement,
+    chrono::{DateTime, Utc},
+    log::debug,
+    std::collections::HashMap,
+    std::sync::{Arc, Mutex},
+    std::thread::JoinHandle,
+};
+
+/// EVCC endpoint configuration
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(not(feature = "evcc"), allow(dead_code))]
+pub struct Config {
+
+    /// Address of the HTTP endpoint, e.g. "0.0.0.0:7070"
+    pub listen: String,
+
+    /// TLS of the endpoint, if any
+    pub tls: Option<tls::ServerTls>,
+
+    /// Names of the plugs exposed as meters; all plugs if empty
+    #[serde(default)]
+    pub devices: Vec<String>,
+}
+
+/// Latest values of a plug, in the units of EVCC
+#[cfg(feature = "evcc")]
+#[derive(Default, Clone)]
+struct Meter {
+    /// Power in Watts
+    power: Option<f32>,
+    /// Whether the power is the instantaneous one (rather than a minute average)
+    instantaneous: bool,
+    /// Energy counter of the plug in kWh
+    energy: Option<f64>,
+    updated_on: Option<DateTime<Utc>>,
+}
+
+#[cfg(feature = "evcc")]
+impl Meter {
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "power": self.power,
+            "energy": self.energy,
+            "updated": self.updated_on.map(|updated_on| updated_on.to_rfc3339()),
+        })
+    }
+}
+
+/// Latest meters, by plug name
+#[cfg(feature = "evcc")]
+type Meters = Arc<Mutex<HashMap<Arc<str>, Meter>>>;
+
+impl Sink for Config {
+    #[cfg_attr(not(feature = "evcc"), allow(unused_variables))]
+    fn spawn(self: Box<Self>, _context: &sink::Context, data_receiver: Receiver<Datum>)
+    -> Result<sink::Handle, String>
+    {
+        #[cfg(feature = "evcc")]
+        return Endpoint::spawn(*self, data_receiver);
+        #[cfg(not(feature = "evcc"))]
+        return Err(format!("EVCC endpoint on {} needs the 'evcc' feature", self.listen));
+    }
+}
+
+/// Keeps the latest power and energy of the plugs for EVCC
+#[cfg(feature = "evcc")]
+pub struct Endpoint;
+
+#[cfg(feature = "evcc")]
+impl Endpoint {
+
+    pub fn spawn(evcc_config: Config, data_receiver: Receiver<Datum>)
+    -> Result<JoinHandle<Result<(),String>>, String>
+    {
+        let meters: Meters = Arc::default();
+        let served = meters.clone();
+        httpd::spawn(&evcc_config.listen, "EVCC endpoint", evcc_config.tls.as_ref(),
+            move |request| Endpoint::respond(&served, request))?;
+
+        Ok(std::thread::spawn(move || {
+            for datum in data_receiver {
+                if !evcc_config.devices.is_empty()
+                    && !evcc_config.devices.iter().any(|name| **name == *datum.device_name) {
+                    continue;
+                }
+                let mut meters = meters.lock().expect("internal error, EVCC lock poisoned");
+                let meter = meters.entry(datum.device_name.clone()).or_default();
+                meter.updated_on = Some(datum.measured_on.max(meter.updated_on.unwrap_or_default()));
+                match datum.measurement {
+                    Measurement::instantaneous_consumption_in_w => {
+                        meter.power = Some(datum.value);
+                        meter.instantaneous = true;
+                    },
+                    // Average power of the last minute, if the instantaneous one is not measured
+                    Measurement::last_minute_consumption_in_wh if !meter.instantaneous =>
+                        meter.power = Some(datum.value * 60.0),
+                    Measurement::consumption_since_reboot_in_wh =>
+                        meter.energy = Some(datum.value as f64 / 1000.0),
+                    _ => (),
+                }
+            }
+            debug!("all meters stopped, stopping");
+            Ok(())
+        }))
+    }
+
+    /// "/evcc" lists all meters, "/evcc/{device}" is the meter of one plug
+    fn respond(meters: &Meters, request: &httpd::Request) -> httpd::Response {
+        if request.method != "GET" {
+            return httpd::Response::text(405, "only GET is supported\n");
+        }
+        let meters = meters.lock().expect("internal error, EVCC lock poisoned");
+        match request.path.trim_end_matches('/').strip_prefix("/evcc") {
+            Some("") => httpd::Response::json(&serde_json::Value::Object(meters.iter()
+                .map(|(name, meter)| (name.to_string(), meter.to_json()))
+                .collect())),
+            Some(device) => match device.strip_prefix('/').and_then(|name| meters.get(name)) {
+                Some(meter) => httpd::Response::json(&meter.to_json()),
+                None => httpd::Response::not_found(),
+            },
+            None => httpd::Response::not_found(),
+        }
+    }
+}
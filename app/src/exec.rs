@@ -0,0 +1,156 @@
+use crate::point::Datum;
+use crate::sink::{self, Sink};
+use serde::Deserialize;
+use std::sync::mpsc::Receiver;
+
+#[cfg(feature = "exec")]
+use {
+    crate::signals,
+    log::{debug, info, warn},
+    std::io::{BufWriter, Write},
+    std::process::{Child, ChildStdin, Command, Stdio},
+    std::thread::JoinHandle,
+    std::time::Duration,
+};
+
+/// External program sink configuration
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(not(feature = "exec"), allow(dead_code))]
+pub struct Config {
+
+    /// Program and its arguments, e.g. ["/usr/local/bin/forward", "--site", "cabin"]
+    pub command: Vec<String>,
+
+    /// Delay before starting the program again after it exited, in seconds
+    #[serde(default = "Config::default_restart_delay_s")]
+    restart_delay_s: u64,
+}
+
+impl Config {
+
+    fn default_restart_delay_s() -> u64 { 5 }
+
+    /// Path of the program
+    pub fn program(&self) -> Option<&str> {
+        self.command.first().map(String::as_str)
+    }
+}
+
+/// Most data-points written before the pipe is flushed
+#[cfg(feature = "exec")]
+const MAX_POINTS_PER_FLUSH: usize = 1000;
+
+/// Running program, with the pipe to its standard input
+#[cfg(feature = "exec")]
+struct Running {
+    child: Child,
+    stdin: BufWriter<ChildStdin>,
+}
+
+impl Sink for Config {
+    #[cfg_attr(not(feature = "exec"), allow(unused_variables))]
+    fn spawn(self: Box<Self>, _context: &sink::Context, data_receiver: Receiver<Datum>)
+    -> Result<sink::Handle, String>
+    {
+        #[cfg(feature = "exec")]
+        return Streamer::spawn(*self, data_receiver);
+        #[cfg(not(feature = "exec"))]
+        return Err(format!("program {} needs the 'exec' feature", self.program().unwrap_or_default()));
+    }
+}
+
+/// Streams the data-points to an external program as JSON lines on its
+/// standard input, starting it again whenever it exits
+///
+/// A program which reads slowly blocks the writes, and the data-points wait
+/// in memory meanwhile (as they do while it is being started again).
+#[cfg(feature = "exec")]
+pub struct Streamer {
+    exec_config: Config,
+    running: Option<Running>,
+}
+
+#[cfg(feature = "exec")]
+impl Streamer {
+
+    pub fn spawn(exec_config: Config, data_receiver: Receiver<Datum>)
+    -> Result<JoinHandle<Result<(),String>>, String>
+    {
+        if exec_config.command.is_empty() {
+            return Err("'exec.command' is empty".to_string());
+        }
+        let mut streamer = Streamer { exec_config, running: None };
+        Ok(std::thread::spawn(move || {
+            info!("Streaming data-points to {}", streamer.program());
+            let mut lines = String::new();
+            loop {
+                let mut datums = match data_receiver.recv() {
+                    Ok(datum) => vec![datum],
+                    Err(_) => {
+                        debug!("all meters stopped, stopping");
+                        streamer.stop();
+                        return Ok(());
+                    }
+                };
+                datums.extend(data_receiver.try_iter().take(MAX_POINTS_PER_FLUSH - 1));
+                lines.clear();
+                for datum in &datums {
+                    lines.push_str(&datum.to_json().to_string());
+                    lines.push('\n');
+                }
+                // Written again to the restarted program, if it exited meanwhile
+                while let Err(err) = streamer.write(&lines) {
+                    warn!("{} stopped ({}), starting it again in {}s", streamer.program(),
+                        err, streamer.exec_config.restart_delay_s);
+                    streamer.stop();
+                    if !signals::sleep(Duration::from_secs(streamer.exec_config.restart_delay_s)) {
+                        warn!("terminating, {} data-points were not written to {}",
+                            datums.len(), streamer.program());
+                        return Ok(());
+                    }
+                }
+            }
+        }))
+    }
+
+    fn program(&self) -> &str {
+        self.exec_config.program().unwrap_or_default()
+    }
+
+    /// Write the lines to the program, starting it if it is not running
+    fn write(&mut self, lines: &str) -> Result<(), String> {
+        let running = match &mut self.running {
+            Some(running) => running,
+            None => self.running.insert(self.start()?),
+        };
+        if let Ok(Some(status)) = running.child.try_wait() {
+            return Err(status.to_string());
+        }
+        running.stdin.write_all(lines.as_bytes())
+            .and_then(|_| running.stdin.flush())
+            .map_err(|err| err.to_string())
+    }
+
+    fn start(&self) -> Result<Running, String> {
+        let mut child = Command::new(self.program())
+            .args(&self.exec_config.command[1..])
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|err| format!("it can not be started: {}", err))?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        debug!("{} started as process {}", self.program(), child.id());
+        Ok(Running { child, stdin: BufWriter::new(stdin) })
+    }
+
+    /// Close the standard input of the program and wait for it to exit
+    fn stop(&mut self) {
+        if let Some(running) = self.running.take() {
+            let Running { mut child, stdin } = running;
+            drop(stdin);
+            match child.wait() {
+                Ok(status) => debug!("{} exited: {}", self.program(), status),
+                Err(err) => warn!("{} could not be waited for: {}", self.program(), err),
+            }
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use crate::point::Datum;
+use crate::retention;
+use crate::sink::{self, Sink};
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::sync::mpsc::Receiver;
+
+#[cfg(feature = "files")]
+use {
+    crate::calendar::Calendar,
+    crate::report::push_row,
+    chrono::NaiveDate,
+    log::{debug, info, warn},
+    std::fs::{File, OpenOptions},
+    std::io::{BufWriter, Write},
+    std::path::Path,
+    std::sync::{Arc, Mutex},
+    std::thread::JoinHandle,
+};
+
+/// File sink configuration
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(not(feature = "files"), allow(dead_code))]
+pub struct Config {
+
+    /// Directory with a file per day, e.g. "2024-07-01.csv"
+    pub directory: PathBuf,
+
+    /// Format of the files
+    #[serde(default)]
+    pub format: Format,
+
+    /// Limits of the directory
+    #[serde(flatten)]
+    pub retention: retention::Policy,
+}
+
+/// Format of the data-points in the files
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[cfg_attr(not(feature = "files"), allow(dead_code))]
+pub enum Format {
+    /// Comma-separated values, with a header
+    #[default]
+    Csv,
+    /// A JSON object per line
+    JsonLines,
+}
+
+#[cfg(feature = "files")]
+impl Format {
+
+    fn extension(&self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::JsonLines => "jsonl",
+        }
+    }
+}
+
+/// Whether the file is a file of a day written in the format, e.g. "2024-07-01.csv",
+/// so that the retention policy deletes nothing else
+#[cfg(feature = "files")]
+fn is_day_file(path: &Path, format: Format) -> bool {
+    path.extension().is_some_and(|extension| extension == format.extension())
+        && path.file_stem().and_then(|stem| stem.to_str())
+            .is_some_and(|stem| NaiveDate::parse_from_str(stem, "%Y-%m-%d").is_ok())
+}
+
+/// Most data-points written before the file is flushed
+#[cfg(feature = "files")]
+const MAX_POINTS_PER_FLUSH: usize = 1000;
+
+impl Sink for Config {
+    #[cfg_attr(not(feature = "files"), allow(unused_variables))]
+    fn spawn(self: Box<Self>, context: &sink::Context, data_receiver: Receiver<Datum>)
+    -> Result<sink::Handle, String>
+    {
+        #[cfg(feature = "files")]
+        return Writer::spawn(*self, context.app_config.calendar, data_receiver);
+        #[cfg(not(feature = "files"))]
+        return Err(format!("files in {} need the 'files' feature", self.directory.display()));
+    }
+}
+
+/// Writes the data-points into a file per day of the calendar, which
+/// is appended to if it exists (e.g. after a restart)
+#[cfg(feature = "files")]
+pub struct Writer {
+    files_config: Config,
+    calendar: Calendar,
+    /// File of the day being written
+    file: Option<(NaiveDate, BufWriter<File>)>,
+    /// Path of the file being written, which the retention policy keeps
+    writing: Arc<Mutex<Option<PathBuf>>>,
+}
+
+#[cfg(feature = "files")]
+impl Writer {
+
+    pub fn spawn(files_config: Config, calendar: Calendar, data_receiver: Receiver<Datum>)
+    -> Result<JoinHandle<Result<(),String>>, String>
+    {
+        std::fs::create_dir_all(&files_config.directory).map_err(|err| format!(
+            "{} can not be created: {}", files_config.directory.display(), err))?;
+        let writing = Arc::new(Mutex::new(None));
+        retention::spawn_pruner(vec![retention::Target {
+            directory: files_config.directory.clone(),
+            policy: files_config.retention.clone(),
+            prunable: {
+                let format = files_config.format;
+                let writing = writing.clone();
+                Box::new(move |path| is_day_file(path, format)
+                    && writing.lock().is_ok_and(|writing| writing.as_deref() != Some(path)))
+            },
+        }]);
+        let mut writer = Writer { files_config, calendar, file: None, writing };
+        Ok(std::thread::spawn(move || {
+            info!("Writing data-points into {}", writer.files_config.directory.display());
+            loop {
+                let mut datums = match data_receiver.recv() {
+                    Ok(datum) => vec![datum],
+                    Err(_) => {
+                        debug!("all meters stopped, stopping");
+                        return Ok(());
+                    }
+                };
+                datums.extend(data_receiver.try_iter().take(MAX_POINTS_PER_FLUSH - 1));
+                if let Err(err) = writer.write(&datums) {
+                    warn!("{} data-points could not be written into {}: {}", datums.len(),
+                        writer.files_config.directory.display(), err);
+                    writer.file = None;
+                }
+            }
+        }))
+    }
+
+    fn write(&mut self, datums: &[Datum]) -> Result<(), String> {
+        for datum in datums {
+            let day = self.calendar.day_of(datum.measured_on);
+            if !matches!(&self.file, Some((open_day, _)) if *open_day == day) {
+                let file = self.open(day)?;
+                self.file = Some((day, file));
+            }
+            let (_, file) = self.file.as_mut().expect("the file of the day is open");
+            let line = match self.files_config.format {
+                Format::Csv => {
+                    let mut row = String::new();
+                    push_row(&mut row, &fields(datum));
+                    row
+                },
+                Format::JsonLines => datum.to_json().to_string() + "\n",
+            };
+            file.write_all(line.as_bytes()).map_err(|err| err.to_string())?;
+        }
+        match &mut self.file {
+            Some((_, file)) => file.flush().map_err(|err| err.to_string()),
+            None => Ok(()),
+        }
+    }
+
+    /// Open the file of the day, writing the header into a new CSV file
+    fn open(&mut self, day: NaiveDate) -> Result<BufWriter<File>, String> {
+        // The previous day is complete
+        if let Some((_, mut file)) = self.file.take() {
+            file.flush().map_err(|err| err.to_string())?;
+        }
+        let path = self.files_config.directory.join(format!("{}.{}",
+            day.format("%Y-%m-%d"), self.files_config.format.extension()));
+        let file = OpenOptions::new().create(true).append(true).open(&path)
+            .map_err(|err| format!("{} can not be opened: {}", path.display(), err))?;
+        if let Ok(mut writing) = self.writing.lock() {
+            *writing = Some(path.clone());
+        }
+        let is_new = file.metadata().map(|metadata| metadata.len() == 0).unwrap_or(false);
+        let mut file = BufWriter::new(file);
+        if is_new && self.files_config.format == Format::Csv {
+            let mut header = String::new();
+            push_row(&mut header, &HEADER.map(String::from));
+            file.write_all(header.as_bytes())
+                .map_err(|err| format!("{} can not be written: {}", path.display(), err))?;
+        }
+        debug!("Writing data-points into {}", path.display());
+        Ok(file)
+    }
+}
+
+/// Columns of the CSV files
+#[cfg(feature = "files")]
+const HEADER: [&str; 6] = ["measured_on", "measurement", "device", "host", "value", "valid"];
+
+/// CSV fields of the data-point, in the order of the `HEADER`
+#[cfg(feature = "files")]
+fn fields(datum: &Datum) -> [String; 6] {
+    [
+        datum.measured_on.to_rfc3339(),
+        datum.measurement.to_string(),
+        datum.device_name.to_string(),
+        datum.device_host.to_string(),
+        datum.value.to_string(),
+        datum.valid.to_string(),
+    ]
+}
@@ -0,0 +1,94 @@
+use crate::point::Datum;
+use crate::secret::Secret;
+use crate::sink::{self, Sink};
+use serde::Deserialize;
+use std::sync::mpsc::Receiver;
+
+#[cfg(feature = "grafana-live")]
+use {
+    crate::line_protocol::{Encoder, Precision},
+    log::{debug, info, warn},
+    std::thread::JoinHandle,
+    std::time::Duration,
+};
+
+/// Grafana Live sink configuration
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(not(feature = "grafana-live"), allow(dead_code))]
+pub struct Config {
+
+    /// Base URL of Grafana, e.g. "http://grafana.local:3000"
+    pub url: String,
+
+    /// Token of a service account with the "Editor" role
+    pub token: Secret,
+
+    /// Data-points appear in the channels "stream/{stream_id}/{measurement}"
+    #[serde(default = "Config::default_stream_id")]
+    pub stream_id: String,
+}
+
+impl Config {
+
+    fn default_stream_id() -> String { "shelly".to_string() }
+}
+
+/// Most data-points pushed at once
+#[cfg(feature = "grafana-live")]
+const MAX_POINTS_PER_PUSH: usize = 1000;
+
+impl Sink for Config {
+    #[cfg_attr(not(feature = "grafana-live"), allow(unused_variables))]
+    fn spawn(self: Box<Self>, _context: &sink::Context, data_receiver: Receiver<Datum>)
+    -> Result<sink::Handle, String>
+    {
+        #[cfg(feature = "grafana-live")]
+        return Ok(LivePusher::spawn(*self, data_receiver));
+        #[cfg(not(feature = "grafana-live"))]
+        return Err(format!("Grafana Live at {} needs the 'grafana-live' feature", self.url));
+    }
+}
+
+/// Pushes data-points to Grafana Live as soon as they are measured,
+/// independently of the other sinks
+#[cfg(feature = "grafana-live")]
+pub struct LivePusher;
+
+#[cfg(feature = "grafana-live")]
+impl LivePusher {
+
+    pub fn spawn(grafana_config: Config, data_receiver: Receiver<Datum>)
+    -> JoinHandle<Result<(),String>>
+    {
+        std::thread::spawn(move || {
+            let agent = ureq::AgentBuilder::new().timeout(Duration::from_secs(10)).build();
+            let url = format!("{}/api/live/push/{}",
+                grafana_config.url.trim_end_matches('/'), grafana_config.stream_id);
+            let authorization = format!("Bearer {}", grafana_config.token.expose());
+            let mut encoder = Encoder::with_precision(Precision::Nanoseconds);
+            info!("Pushing data-points to Grafana Live at {}", url);
+
+            let mut body = String::new();
+            loop {
+                body.clear();
+                match data_receiver.recv() {
+                    Ok(datum) => encoder.encode(&datum, &mut body),
+                    Err(_) => {
+                        debug!("all meters stopped, stopping");
+                        return Ok(());
+                    }
+                }
+                // Push everything that is waiting at once
+                for datum in data_receiver.try_iter().take(MAX_POINTS_PER_PUSH - 1) {
+                    encoder.encode(&datum, &mut body);
+                }
+                // Late data is useless for live dashboards, so it is not retried
+                if let Err(err) = agent.post(&url)
+                    .set("Authorization", &authorization)
+                    .send_string(&body) {
+                    warn!("data-points could not be pushed to Grafana Live: {}", err);
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,245 @@
+use crate::health::{Health as LoggerHealth, SharedHealth};
+use crate::point::Datum;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Greeting of the primary to the health checks, followed by its `Activity`
+const GREETING: &[u8] = b"shelly-logger primary\n";
+
+/// Longest answer of the primary to a health check
+const MAX_ANSWER: u64 = 4096;
+
+/// Most data-points held back by the standby
+const MAX_HELD: usize = 100_000;
+
+/// High-availability configuration, for a pair of instances of which only one writes
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Config {
+
+    /// Role of this instance
+    pub role: Role,
+
+    /// Address on which the primary answers the health checks, e.g. "0.0.0.0:7080"
+    pub listen: Option<String>,
+
+    /// Address of the primary checked by the standby, e.g. "logger1:7080"
+    pub primary: Option<String>,
+
+    /// Interval between the health checks of the primary, in seconds
+    #[serde(default = "Config::default_check_interval_s")]
+    check_interval_s: u64,
+
+    /// How long must the primary fail the health checks for the standby to take over, in seconds
+    #[serde(default = "Config::default_takeover_after_s")]
+    takeover_after_s: u64,
+
+    /// How long may the primary poll no device successfully, or write nothing into
+    /// its database, and still pass the health checks, in seconds
+    #[serde(default = "Config::default_stale_after_s")]
+    stale_after_s: u64,
+}
+
+impl Config {
+    fn default_check_interval_s() -> u64 { 10 }
+    fn default_takeover_after_s() -> u64 { 30 }
+    fn default_stale_after_s() -> u64 { 300 }
+}
+
+/// Role of an instance in the pair
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Always writes, and answers the health checks of the standby
+    Primary,
+    /// Polls the devices too, but only writes while the primary is not healthy
+    Standby,
+}
+
+/// Activity of the primary, which it reports to the health checks
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Activity {
+    /// Seconds since a device was last polled successfully, if any is polled
+    polled_s_ago: Option<i64>,
+    /// Seconds since data-points were last written into the database, if there is one
+    written_s_ago: Option<i64>,
+}
+
+impl Activity {
+
+    fn of(health: &LoggerHealth) -> Activity {
+        let (polled_ago, written_ago) = health.idle_for();
+        Activity {
+            polled_s_ago: polled_ago.map(|ago| ago.num_seconds()),
+            written_s_ago: written_ago.map(|ago| ago.num_seconds()),
+        }
+    }
+
+    /// Why the primary is not healthy, if it is not
+    fn problem(&self, stale_after: Duration) -> Option<String> {
+        let stale_after_s = stale_after.as_secs() as i64;
+        match (self.polled_s_ago, self.written_s_ago) {
+            (Some(polled_s_ago), _) if polled_s_ago > stale_after_s =>
+                Some(format!("it polled no device for {}s", polled_s_ago)),
+            (_, Some(written_s_ago)) if written_s_ago > stale_after_s =>
+                Some(format!("it wrote nothing into its database for {}s", written_s_ago)),
+            _ => None,
+        }
+    }
+}
+
+/// What the standby knows about the primary
+struct Health {
+    /// Whether the standby took over
+    active: bool,
+    /// When was the primary last healthy
+    primary_seen: Instant,
+}
+
+/// Passes the data-points of the standby only while it is active
+///
+/// Data-points received since the primary was last healthy are held back, so
+/// that on a takeover the standby writes those which the primary may have missed.
+pub struct StandbyGate {
+    health: Arc<Mutex<Health>>,
+    /// Data-points held back, with the time they were received, oldest first
+    held: VecDeque<(Instant, Datum)>,
+}
+
+impl StandbyGate {
+
+    /// Data-points which can be written now
+    pub fn pass(&mut self, datum: Datum) -> Vec<Datum> {
+        let health = self.health.lock().expect("internal error, health lock poisoned");
+        if health.active {
+            let mut passed: Vec<Datum> = self.held.drain(..).map(|(_, datum)| datum).collect();
+            passed.push(datum);
+            return passed;
+        }
+        // The primary wrote what it received before it was last seen healthy
+        while self.held.front().is_some_and(|(received, _)| *received < health.primary_seen) {
+            self.held.pop_front();
+        }
+        if self.held.len() >= MAX_HELD {
+            self.held.pop_front();
+        }
+        self.held.push_back((Instant::now(), datum));
+        vec![]
+    }
+}
+
+/// Start the role of this instance, the primary reporting its `health`;
+/// returns the gate of the data-points of a standby
+pub fn start(ha_config: &Config, health: &SharedHealth) -> Result<Option<StandbyGate>, String> {
+    match ha_config.role {
+        Role::Primary => {
+            let listen = ha_config.listen.as_ref()
+                .ok_or("the primary needs 'high_availability.listen'")?;
+            let listener = TcpListener::bind(listen)
+                .map_err(|err| format!("health checks can not listen on {}: {}", listen, err))?;
+            info!("Primary instance, answering the health checks on {}", listen);
+            let health = health.clone();
+            std::thread::spawn(move || {
+                for mut stream in listener.incoming().flatten() {
+                    let activity = Activity::of(&health.lock()
+                        .expect("internal error, health lock poisoned"));
+                    let mut answer = GREETING.to_vec();
+                    serde_json::to_writer(&mut answer, &activity)
+                        .expect("activity is always serializable");
+                    answer.push(b'\n');
+                    let _ = stream.write_all(&answer);
+                }
+            });
+            Ok(None)
+        },
+        Role::Standby => {
+            let primary = ha_config.primary.clone()
+                .ok_or("the standby needs 'high_availability.primary'")?;
+            let check_interval = Duration::from_secs(ha_config.check_interval_s.max(1));
+            let takeover_after = Duration::from_secs(ha_config.takeover_after_s);
+            let stale_after = Duration::from_secs(ha_config.stale_after_s);
+            info!("Standby instance, writing only if the primary {} is not healthy \
+                for {}s", primary, takeover_after.as_secs());
+            // The primary gets the takeover period to start as well
+            let health = Arc::new(Mutex::new(Health { active: false, primary_seen: Instant::now() }));
+            let monitored = health.clone();
+            std::thread::spawn(move || loop {
+                let checked = check(&primary, check_interval, stale_after);
+                let mut health = monitored.lock().expect("internal error, health lock poisoned");
+                match checked {
+                    Ok(()) => {
+                        if health.active {
+                            info!("primary {} is healthy again, standing by", primary);
+                            health.active = false;
+                        }
+                        health.primary_seen = Instant::now();
+                    },
+                    Err(err) if !health.active
+                        && health.primary_seen.elapsed() >= takeover_after => {
+                        warn!("primary {} is not healthy for {}s ({}), taking over",
+                            primary, health.primary_seen.elapsed().as_secs(), err);
+                        health.active = true;
+                    },
+                    Err(_) => (),
+                }
+                drop(health);
+                std::thread::sleep(check_interval);
+            });
+            Ok(Some(StandbyGate { health, held: VecDeque::new() }))
+        },
+    }
+}
+
+/// Whether the primary greets within the timeout, and polled and wrote recently
+fn check(primary: &str, timeout: Duration, stale_after: Duration) -> Result<(), String> {
+    let address = primary.to_socket_addrs()
+        .map_err(|err| err.to_string())?
+        .next()
+        .ok_or("it does not resolve")?;
+    let stream = TcpStream::connect_timeout(&address, timeout)
+        .map_err(|err| err.to_string())?;
+    stream.set_read_timeout(Some(timeout)).map_err(|err| err.to_string())?;
+    let mut answer = Vec::new();
+    stream.take(MAX_ANSWER).read_to_end(&mut answer)
+        .map_err(|err| err.to_string())?;
+    checked_answer(&answer, stale_after)
+}
+
+/// Whether the answer is the greeting of a healthy primary
+fn checked_answer(answer: &[u8], stale_after: Duration) -> Result<(), String> {
+    let activity = answer.strip_prefix(GREETING)
+        .ok_or("it is not a shelly-logger primary")?;
+    let activity: Activity = serde_json::from_slice(activity)
+        .map_err(|err| format!("it reports no activity ({}), is it up to date?", err))?;
+    match activity.problem(stale_after) {
+        Some(problem) => Err(problem),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn answer(polled_s_ago: Option<i64>, written_s_ago: Option<i64>) -> Vec<u8> {
+        let mut answer = GREETING.to_vec();
+        serde_json::to_writer(&mut answer, &Activity { polled_s_ago, written_s_ago }).unwrap();
+        answer.push(b'\n');
+        answer
+    }
+
+    #[test]
+    fn primaries_which_stopped_polling_or_writing_are_not_healthy() {
+        let stale_after = Duration::from_secs(300);
+        assert!(checked_answer(&answer(Some(10), Some(20)), stale_after).is_ok());
+        assert!(checked_answer(&answer(None, None), stale_after).is_ok());
+        assert!(checked_answer(&answer(Some(301), Some(20)), stale_after).is_err());
+        assert!(checked_answer(&answer(Some(10), Some(301)), stale_after).is_err());
+        assert!(checked_answer(GREETING, stale_after).is_err());
+        assert!(checked_answer(b"SSH-2.0-OpenSSH\n", stale_after).is_err());
+    }
+}
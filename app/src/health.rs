@@ -0,0 +1,283 @@
+use crate::tls;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[cfg(feature = "health")]
+use {
+    crate::httpd,
+    crate::signals,
+    serde_json::json,
+};
+
+/// Health endpoint configuration
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(not(feature = "health"), allow(dead_code))]
+pub struct Config {
+
+    /// Address of the HTTP endpoint, e.g. "127.0.0.1:9809"
+    pub listen_address: String,
+
+    /// TLS of the endpoint, if any
+    pub tls: Option<tls::ServerTls>,
+
+    /// The logger is unhealthy once no device was polled successfully for
+    /// this long, in seconds
+    #[serde(default = "Config::default_stale_after_s")]
+    stale_after_s: i64,
+}
+
+impl Config {
+
+    fn default_stale_after_s() -> i64 { 600 }
+}
+
+/// State of the connection to the database
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Connection {
+    /// Nothing was written yet
+    Connecting,
+    Connected,
+    /// The writes fail, so the data-points are journaled into the spill file
+    Journaling,
+    /// The writes fail, so they wait for the server
+    Unavailable,
+}
+
+#[cfg(feature = "health")]
+impl Connection {
+
+    fn name(self) -> &'static str {
+        match self {
+            Connection::Connecting => "connecting",
+            Connection::Connected => "connected",
+            Connection::Journaling => "journaling",
+            Connection::Unavailable => "unavailable",
+        }
+    }
+}
+
+/// Polls of a device
+#[cfg_attr(not(feature = "health"), allow(dead_code))]
+struct Device {
+    host: Arc<str>,
+    /// Meter reporting them, so that a meter stopped after the device was
+    /// metered anew does not remove it
+    reporter: u64,
+    last_success: Option<DateTime<Utc>>,
+    consecutive_failures: u64,
+    last_error: Option<String>,
+}
+
+/// Writes into the database
+#[cfg_attr(not(feature = "health"), allow(dead_code))]
+struct Database {
+    /// Name of the backend, e.g. "PostgreSQL"
+    backend: String,
+    connection: Connection,
+    written: u64,
+    journaled: u64,
+    /// When were data-points last written
+    last_written: Option<DateTime<Utc>>,
+}
+
+/// Health of the logger: the polls of the devices and the writes
+/// of the data-points, as served by the health endpoint
+#[cfg_attr(not(feature = "health"), allow(dead_code))]
+pub struct Health {
+    started_on: DateTime<Utc>,
+    devices: BTreeMap<Arc<str>, Device>,
+    /// Data-points passed to the sinks
+    measured: u64,
+    /// Writes into the database, if configured
+    database: Option<Database>,
+}
+
+/// Health shared by the meters and sinks
+pub type SharedHealth = Arc<Mutex<Health>>;
+
+/// Identifiers of the reporters, unique within the process
+static REPORTERS: AtomicU64 = AtomicU64::new(0);
+
+impl Health {
+
+    /// Health of the logger writing into the database of the backend, if any
+    pub fn new(backend: Option<String>) -> Health {
+        Health {
+            started_on: Utc::now(),
+            devices: BTreeMap::new(),
+            measured: 0,
+            database: backend.map(|backend| Database {
+                backend, connection: Connection::Connecting, written: 0, journaled: 0,
+                last_written: None }),
+        }
+    }
+
+    /// Wrap the health for sharing between meters and sinks
+    pub fn shared(self) -> SharedHealth {
+        Arc::new(Mutex::new(self))
+    }
+
+    /// Record data-points passed to the sinks
+    pub fn record_measured(&mut self, count: usize) {
+        self.measured += count as u64;
+    }
+
+    /// Record the state of the connection to the database and the data-points
+    /// written into it, or journaled
+    pub fn record_database(&mut self, connection: Connection, written: usize, journaled: usize) {
+        if let Some(database) = &mut self.database {
+            database.connection = connection;
+            database.written += written as u64;
+            database.journaled += journaled as u64;
+            if written > 0 {
+                database.last_written = Some(Utc::now());
+            }
+        }
+    }
+
+    /// How long ago was a device last polled successfully (if any is polled), and
+    /// data-points last written into the database (if there is one); since the
+    /// start if not yet
+    pub fn idle_for(&self) -> (Option<chrono::Duration>, Option<chrono::Duration>) {
+        let now = Utc::now();
+        let last_success = self.devices.values()
+            .filter_map(|device| device.last_success)
+            .max()
+            .unwrap_or(self.started_on);
+        (Some(now - last_success).filter(|_| !self.devices.is_empty()),
+            self.database.as_ref()
+                .map(|database| now - database.last_written.unwrap_or(self.started_on)))
+    }
+
+    /// Reasons why the logger is not healthy; none if it is
+    #[cfg(feature = "health")]
+    fn problems(&self, health_config: &Config) -> Vec<String> {
+        let mut problems = vec![];
+        if signals::terminating() {
+            problems.push("the logger is terminating".to_string());
+        }
+        if let Some(database) = &self.database {
+            if matches!(database.connection, Connection::Journaling | Connection::Unavailable) {
+                problems.push(format!("{} is {}", database.backend, database.connection.name()));
+            }
+        }
+        let stale_on = Utc::now() - chrono::Duration::seconds(health_config.stale_after_s);
+        let last_success = self.devices.values()
+            .filter_map(|device| device.last_success)
+            .max()
+            .unwrap_or(self.started_on);
+        if !self.devices.is_empty() && last_success < stale_on {
+            problems.push(format!("no device was polled successfully since {}",
+                last_success.to_rfc3339()));
+        }
+        problems
+    }
+
+    /// Status of the logger, as served on "/status"
+    #[cfg(feature = "health")]
+    fn status(&self, health_config: &Config) -> serde_json::Value {
+        let problems = self.problems(health_config);
+        let devices: Vec<serde_json::Value> = self.devices.iter()
+            .map(|(name, device)| json!({
+                "name": name.as_ref(),
+                "host": device.host.as_ref(),
+                "last_success": device.last_success.map(|time| time.to_rfc3339()),
+                "consecutive_failures": device.consecutive_failures,
+                "last_error": device.last_error,
+            }))
+            .collect();
+        let mut status = json!({
+            "healthy": problems.is_empty(),
+            "problems": problems,
+            "started_on": self.started_on.to_rfc3339(),
+            "data_points_measured": self.measured,
+            "devices": devices,
+        });
+        if let Some(database) = &self.database {
+            status["database"] = json!({
+                "backend": database.backend,
+                "connection": database.connection.name(),
+                "data_points_written": database.written,
+                "data_points_journaled": database.journaled,
+            });
+        }
+        status
+    }
+}
+
+/// Reports the polls of a device into the health, until dropped
+pub struct Reporter {
+    health: SharedHealth,
+    name: Arc<str>,
+    id: u64,
+}
+
+impl Reporter {
+
+    pub fn new(health: &SharedHealth, name: Arc<str>, host: Arc<str>) -> Reporter {
+        let id = REPORTERS.fetch_add(1, Ordering::Relaxed);
+        health.lock().expect("internal error, health lock poisoned")
+            .devices.insert(name.clone(), Device { host, reporter: id, last_success: None,
+                consecutive_failures: 0, last_error: None });
+        Reporter { health: health.clone(), name, id }
+    }
+
+    /// Update the device, unless it is reported by another meter now
+    fn update(&self, update: impl FnOnce(&mut Device)) {
+        let mut health = self.health.lock().expect("internal error, health lock poisoned");
+        if let Some(device) = health.devices.get_mut(&self.name)
+            .filter(|device| device.reporter == self.id) {
+            update(device);
+        }
+    }
+
+    pub fn succeeded(&self) {
+        self.update(|device| {
+            device.last_success = Some(Utc::now());
+            device.consecutive_failures = 0;
+        });
+    }
+
+    pub fn failed(&self, consecutive_failures: u64, error: &str) {
+        self.update(|device| {
+            device.consecutive_failures = consecutive_failures;
+            device.last_error = Some(error.to_string());
+        });
+    }
+}
+
+/// The device is no longer metered, e.g. as it was removed from the config
+impl Drop for Reporter {
+    fn drop(&mut self) {
+        if let Ok(mut health) = self.health.lock() {
+            if health.devices.get(&self.name).is_some_and(|device| device.reporter == self.id) {
+                health.devices.remove(&self.name);
+            }
+        }
+    }
+}
+
+/// Serve "/healthz" (200 if healthy, 503 with the problems otherwise) and
+/// "/status" (JSON with the polls of the devices and the writes)
+#[cfg(feature = "health")]
+pub fn spawn(health_config: Config, health: SharedHealth) -> Result<(), String> {
+    let listen_address = health_config.listen_address.clone();
+    let server_tls = health_config.tls.clone();
+    httpd::spawn(&listen_address, "Health endpoint", server_tls.as_ref(), move |request| {
+        if request.method != "GET" {
+            return httpd::Response::text(405, "only GET is supported\n");
+        }
+        let health = health.lock().expect("internal error, health lock poisoned");
+        match request.path.as_str() {
+            "/healthz" => match health.problems(&health_config).as_slice() {
+                [] => httpd::Response::text(200, "ok\n"),
+                problems => httpd::Response::text(503, &format!("{}\n", problems.join("\n"))),
+            },
+            "/status" => httpd::Response::json(&health.status(&health_config)),
+            _ => httpd::Response::not_found(),
+        }
+    })
+}
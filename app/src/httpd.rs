@@ -0,0 +1,167 @@
+use crate::tls;
+use log::{debug, info, warn};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Largest accepted request head (request line and headers)
+const MAX_HEAD_BYTES: u64 = 16 * 1024;
+
+/// Time allowed for reading the request and writing the response
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Request of a client
+pub struct Request {
+    pub method: String,
+    /// Path without the query, percent-decoded
+    pub path: String,
+}
+
+/// Response to a request
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+
+    #[cfg_attr(not(any(feature = "evcc", feature = "health")), allow(dead_code))]
+    pub fn json(value: &serde_json::Value) -> Response {
+        Response { status: 200, content_type: "application/json", body: value.to_string().into_bytes() }
+    }
+
+    pub fn text(status: u16, text: &str) -> Response {
+        Response { status, content_type: "text/plain; charset=utf-8", body: text.as_bytes().to_vec() }
+    }
+
+    pub fn not_found() -> Response {
+        Response::text(404, "not found\n")
+    }
+}
+
+/// Reason phrase of the status code
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+/// Decode %XX escapes (and '+' as a space)
+pub fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        match bytes[index] {
+            b'%' if index + 2 < bytes.len() => {
+                match std::str::from_utf8(&bytes[index + 1..index + 3]).ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        index += 3;
+                        continue;
+                    },
+                    None => decoded.push(b'%'),
+                }
+            },
+            b'+' => decoded.push(b' '),
+            byte => decoded.push(byte),
+        }
+        index += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Serve requests on the address in a background thread, one thread per connection;
+/// over TLS if configured
+pub fn spawn<H>(listen: &str, name: &str, server_tls: Option<&tls::ServerTls>, handler: H)
+-> Result<(), String>
+where H: Fn(&Request) -> Response + Send + Sync + 'static
+{
+    let acceptor = server_tls.map(tls::Acceptor::new).transpose()
+        .map_err(|err| format!("{} can not use TLS: {}", name, err))?
+        .map(Arc::new);
+    let listener = TcpListener::bind(listen)
+        .map_err(|err| format!("{} can not listen on {}: {}", name, listen, err))?;
+    info!("{} listens on {}{}", name, listen, if acceptor.is_some() { " with TLS" } else { "" });
+    let handler = Arc::new(handler);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let handler = handler.clone();
+                    let acceptor = acceptor.clone();
+                    std::thread::spawn(move || {
+                        if let Err(err) = connection(stream, acceptor.as_deref(), handler.as_ref()) {
+                            debug!("HTTP connection failed: {}", err);
+                        }
+                    });
+                },
+                Err(err) => warn!("HTTP connection could not be accepted: {}", err),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Serve one request of the connection
+fn connection<H>(mut stream: TcpStream, acceptor: Option<&tls::Acceptor>, handler: &H)
+-> Result<(), String>
+where H: Fn(&Request) -> Response
+{
+    stream.set_read_timeout(Some(CONNECTION_TIMEOUT)).map_err(|err| err.to_string())?;
+    stream.set_write_timeout(Some(CONNECTION_TIMEOUT)).map_err(|err| err.to_string())?;
+    match acceptor {
+        Some(acceptor) => acceptor.session(stream, |session| serve(session, handler)),
+        None => serve(&mut stream, handler),
+    }
+}
+
+/// Serve one request of the plain or TLS stream
+fn serve<S, H>(stream: &mut S, handler: &H) -> Result<(), String>
+where S: Read + Write, H: Fn(&Request) -> Response
+{
+    let response = match read_request(stream) {
+        Ok(request) => handler(&request),
+        Err(response) => response,
+    };
+    write!(stream, "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+        Connection: close\r\n\r\n", response.status, reason(response.status),
+        response.content_type, response.body.len())
+        .and_then(|_| stream.write_all(&response.body))
+        .map_err(|err| err.to_string())
+}
+
+/// Read and parse the request head; the error is the response to send
+fn read_request<S: Read>(stream: &mut S) -> Result<Request, Response> {
+    let mut reader = BufReader::new(stream.by_ref().take(MAX_HEAD_BYTES));
+    let mut line = String::new();
+    let bad_request = || Response::text(400, "bad request\n");
+
+    reader.read_line(&mut line).map_err(|_| bad_request())?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().ok_or_else(bad_request)?.to_string();
+    let target = parts.next().ok_or_else(bad_request)?;
+    let path = percent_decode(target.split_once('?').map_or(target, |(path, _)| path));
+
+    // Headers are not used
+    loop {
+        line.clear();
+        reader.read_line(&mut line).map_err(|_| bad_request())?;
+        if line.trim_end().is_empty() {
+            break;
+        }
+    }
+    Ok(Request { method, path })
+}
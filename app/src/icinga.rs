@@ -0,0 +1,234 @@
+use crate::point::Datum;
+use crate::secret::Secret;
+use crate::sink::{self, Sink};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::mpsc::Receiver;
+
+#[cfg(feature = "icinga")]
+use {
+    crate::plug,
+    crate::point::Measurement,
+    base64::Engine,
+    chrono::{DateTime, Utc},
+    log::{debug, info, warn},
+    std::sync::Arc,
+    std::sync::mpsc::RecvTimeoutError,
+    std::thread::JoinHandle,
+    std::time::{Duration, Instant},
+};
+
+/// Icinga2 sink configuration
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(not(feature = "icinga"), allow(dead_code))]
+pub struct Config {
+
+    /// Base URL of the Icinga2 API, e.g. "https://icinga.local:5665"
+    pub url: String,
+
+    /// API user with the "actions/process-check-result" permission
+    pub username: String,
+
+    pub password: Secret,
+
+    /// Icinga2 host of each plug, by plug name; the plug name if not listed
+    #[serde(default)]
+    pub hosts: HashMap<String, String>,
+
+    /// Passive service reporting whether the plug delivers data
+    #[serde(default = "Config::default_reachability_service")]
+    pub reachability_service: String,
+
+    /// Passive service reporting the power against the thresholds
+    #[serde(default = "Config::default_power_service")]
+    pub power_service: String,
+
+    /// Power above which the power service is WARNING, in Watts
+    pub power_warning_w: Option<f32>,
+
+    /// Power above which the power service is CRITICAL, in Watts
+    pub power_critical_w: Option<f32>,
+
+    /// Interval between submissions of the check results, in seconds
+    #[serde(default = "Config::default_interval_s")]
+    pub interval_s: u64,
+
+    /// Plugs without data for this many seconds are unreachable
+    #[serde(default = "Config::default_stale_after_s")]
+    pub stale_after_s: u64,
+}
+
+impl Config {
+
+    fn default_reachability_service() -> String { "shelly-reachability".to_string() }
+
+    fn default_power_service() -> String { "shelly-power".to_string() }
+
+    fn default_interval_s() -> u64 { 60 }
+
+    fn default_stale_after_s() -> u64 { 300 }
+}
+
+/// Exit status of a check
+#[cfg(feature = "icinga")]
+#[derive(Debug, Clone, Copy)]
+enum Status {
+    Ok = 0,
+    Warning = 1,
+    Critical = 2,
+}
+
+/// Latest data of a plug
+#[cfg(feature = "icinga")]
+#[derive(Default)]
+struct Latest {
+    received_on: Option<DateTime<Utc>>,
+    power_w: Option<f32>,
+}
+
+impl Sink for Config {
+    #[cfg_attr(not(feature = "icinga"), allow(unused_variables))]
+    fn spawn(self: Box<Self>, context: &sink::Context, data_receiver: Receiver<Datum>)
+    -> Result<sink::Handle, String>
+    {
+        #[cfg(feature = "icinga")]
+        return Ok(Reporter::spawn(*self, &context.app_config.shelly_plugs, data_receiver));
+        #[cfg(not(feature = "icinga"))]
+        return Err(format!("Icinga2 at {} needs the 'icinga' feature", self.url));
+    }
+}
+
+/// Submits passive check results of the plugs to Icinga2
+#[cfg(feature = "icinga")]
+pub struct Reporter {
+    icinga_config: Config,
+    agent: ureq::Agent,
+    authorization: String,
+    /// Latest data of each configured plug, by name
+    latest: HashMap<Arc<str>, Latest>,
+}
+
+#[cfg(feature = "icinga")]
+impl Reporter {
+
+    pub fn spawn(icinga_config: Config, shelly_plug_configs: &[plug::Config],
+        data_receiver: Receiver<Datum>)
+    -> JoinHandle<Result<(),String>>
+    {
+        let credentials = format!("{}:{}", icinga_config.username, icinga_config.password.expose());
+        let mut reporter = Reporter {
+            agent: ureq::AgentBuilder::new().timeout(Duration::from_secs(10)).build(),
+            authorization: format!("Basic {}",
+                base64::engine::general_purpose::STANDARD.encode(credentials)),
+            latest: shelly_plug_configs.iter()
+                .map(|shelly_plug_config| (shelly_plug_config.name.clone(), Latest::default()))
+                .collect(),
+            icinga_config,
+        };
+        let interval = Duration::from_secs(reporter.icinga_config.interval_s.max(1));
+        std::thread::spawn(move || {
+            info!("Submitting check results to Icinga2 at {}", reporter.icinga_config.url);
+            let started = Instant::now();
+            let mut next_report = started + interval;
+            loop {
+                let timeout = next_report.saturating_duration_since(Instant::now());
+                match data_receiver.recv_timeout(timeout) {
+                    Ok(datum) => reporter.accept(&datum),
+                    Err(RecvTimeoutError::Timeout) => {
+                        reporter.report(started.elapsed());
+                        next_report += interval;
+                    },
+                    Err(RecvTimeoutError::Disconnected) => {
+                        debug!("all meters stopped, stopping");
+                        return Ok(());
+                    }
+                }
+            }
+        })
+    }
+
+    /// Remember the latest data of the plug
+    fn accept(&mut self, datum: &Datum) {
+        let latest = self.latest.entry(datum.device_name.clone()).or_default();
+        latest.received_on = Some(Utc::now());
+        match datum.measurement {
+            Measurement::instantaneous_consumption_in_w => latest.power_w = Some(datum.value),
+            // Average power of the last minute, if the instantaneous one is not measured
+            Measurement::last_minute_consumption_in_wh if latest.power_w.is_none() =>
+                latest.power_w = Some(datum.value * 60.0),
+            _ => (),
+        }
+    }
+
+    /// Submit the results of all plugs; `uptime` avoids reporting plugs
+    /// as unreachable before they had a chance to deliver data
+    fn report(&self, uptime: Duration) {
+        let stale_after = chrono::Duration::seconds(self.icinga_config.stale_after_s as i64);
+        let now = Utc::now();
+        for (device_name, latest) in &self.latest {
+            let (status, output) = match latest.received_on {
+                Some(received_on) if now - received_on <= stale_after =>
+                    (Status::Ok, format!("{} delivers data", device_name)),
+                Some(received_on) => (Status::Critical, format!(
+                    "{} delivered no data since {}", device_name, received_on.to_rfc3339())),
+                None if uptime.as_secs() < self.icinga_config.stale_after_s => continue,
+                None => (Status::Critical, format!("{} delivered no data yet", device_name)),
+            };
+            self.submit(device_name, &self.icinga_config.reachability_service,
+                status, &output, None);
+
+            if let Some(power_w) = latest.power_w {
+                let status = self.power_status(power_w);
+                let performance_data = format!("power={}W;{};{};0", power_w,
+                    threshold(self.icinga_config.power_warning_w),
+                    threshold(self.icinga_config.power_critical_w));
+                self.submit(device_name, &self.icinga_config.power_service, status,
+                    &format!("{} draws {}W", device_name, power_w), Some(performance_data));
+            }
+        }
+    }
+
+    /// Status of the power against the thresholds
+    fn power_status(&self, power_w: f32) -> Status {
+        if self.icinga_config.power_critical_w.is_some_and(|critical| power_w > critical) {
+            Status::Critical
+        } else if self.icinga_config.power_warning_w.is_some_and(|warning| power_w > warning) {
+            Status::Warning
+        } else {
+            Status::Ok
+        }
+    }
+
+    /// Submit one passive check result
+    fn submit(&self, device_name: &str, service: &str, status: Status, output: &str,
+        performance_data: Option<String>)
+    {
+        let host = self.icinga_config.hosts.get(device_name)
+            .map(String::as_str)
+            .unwrap_or(device_name);
+        let url = format!("{}/v1/actions/process-check-result",
+            self.icinga_config.url.trim_end_matches('/'));
+        let body = serde_json::json!({
+            "type": "Service",
+            "filter": "host.name == h && service.name == s",
+            "filter_vars": { "h": host, "s": service },
+            "exit_status": status as u8,
+            "plugin_output": output,
+            "performance_data": performance_data.into_iter().collect::<Vec<_>>(),
+            "check_source": "shelly-logger",
+        });
+        let result = self.agent.post(&url)
+            .set("Authorization", &self.authorization)
+            .set("Accept", "application/json")
+            .send_json(body);
+        if let Err(err) = result {
+            warn!("Icinga2 service {}!{} could not be updated: {}", host, service, err);
+        }
+    }
+}
+
+/// Threshold in the performance data, empty if not set
+#[cfg(feature = "icinga")]
+fn threshold(value: Option<f32>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
@@ -1,107 +1,658 @@
+use crate::crypto::{self, Cipher};
+use crate::health::{self, SharedHealth};
+use crate::line_protocol::{parse_line_in, spawn_encoders, Precision};
 use crate::point::Datum;
+#[cfg(feature = "postgres")]
+use crate::postgresql;
+#[cfg(feature = "influxdb2")]
+use crate::runtime;
+use crate::secret::Secret;
+use crate::signals;
+use crate::sink::{self, Sink};
+use crate::spill::Spill;
+use crate::state::SharedState;
+use crate::tls;
 
+use base64::Engine;
 use core::time::Duration;
-use influxdb2::Client;
-use influxdb2::api::write::TimestampPrecision;
-use influxdb2::models::DataPoint;
-use log::{debug, info, warn, error};
-use std::sync::mpsc::Receiver;
-use std::thread;
+use log::{debug, info, warn};
+use std::path::PathBuf;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::Instant;
 use serde::Deserialize;
 use std::thread::JoinHandle;
 
 
-/// InfluxDB2 data-sink configuration
-#[derive(Deserialize, Debug, Clone)]
+/// Precision of the timestamps written, fine enough for sub-second polling
+pub const PRECISION: Precision = Precision::Milliseconds;
+
+/// Database written into by the sink
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    /// InfluxDB 2.x, by its token, organization and bucket
+    #[default]
+    Influxdb2,
+    /// InfluxDB 1.x, by its username, password, database and retention policy
+    Influxdb1,
+    /// PostgreSQL (or TimescaleDB), by its username, password, database and table
+    Postgres,
+}
+
+impl std::fmt::Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Backend::Influxdb2 => write!(f, "InfluxDB2"),
+            Backend::Influxdb1 => write!(f, "InfluxDB1"),
+            Backend::Postgres => write!(f, "PostgreSQL"),
+        }
+    }
+}
+
+/// Database data-sink configuration, of InfluxDB2 unless another `backend` is set
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct Config {
+    #[serde(default)]
+    pub backend: Backend,
+    #[serde(default)]
     https: bool,
     host: String,
     port: u32,
-    token: String,
-    org: String,
-    pub bucket: String,
+
+    /// Token, organization and bucket of InfluxDB2
+    token: Option<Secret>,
+    org: Option<String>,
+    bucket: Option<String>,
+
+    /// Login of InfluxDB1 (if it has authentication enabled) and PostgreSQL
+    username: Option<String>,
+    password: Option<Secret>,
+
+    /// Database of InfluxDB1 and PostgreSQL
+    database: Option<String>,
+
+    /// Retention policy of InfluxDB1; the default one of the database if not set
+    retention_policy: Option<String>,
+
+    /// Table of PostgreSQL, created if it does not exist
+    #[serde(default = "Config::default_table")]
+    #[cfg_attr(not(any(feature = "postgres", feature = "sqlite")), allow(dead_code))]
+    table: String,
+
+    /// Number of threads encoding data-points into the line protocol
+    #[serde(default = "Config::default_encoder_threads")]
+    encoder_threads: usize,
+
+    /// Certificate presented to the server (e.g. to a proxy requiring mutual TLS), if any
+    client_certificate: Option<tls::ClientCertificate>,
+
+    /// File journaling the data-points while the server is unavailable, which
+    /// are written once it is available again; if not set, the writes wait
+    /// for the server meanwhile, and the data-points wait in memory
+    pub spill_file: Option<PathBuf>,
+
+    /// Encrypt the `spill_file` with this key, since the data-points reveal
+    /// when the appliances are used
+    spill_encryption: Option<crypto::Config>,
+
+    /// Largest size of the `spill_file`, in megabytes
+    #[serde(default = "Config::default_spill_max_mb")]
+    spill_max_mb: u64,
+
+    /// Most data-points written in one request
+    #[serde(default = "Config::default_batch_size")]
+    batch_size: usize,
+
+    /// Longest time a data-point waits for others to be written with it, in milliseconds
+    #[serde(default = "Config::default_flush_interval_ms")]
+    flush_interval_ms: u64,
 }
 
 impl Config {
+
+    fn default_encoder_threads() -> usize { 1 }
+
+    fn default_spill_max_mb() -> u64 { 100 }
+
+    fn default_batch_size() -> usize { 5000 }
+
+    fn default_flush_interval_ms() -> u64 { 1000 }
+
+    fn default_table() -> String { "datum".to_string() }
+
+    /// Setting needed by the backend
+    fn required<'a, T>(&self, value: &'a Option<T>, name: &str) -> Result<&'a T, String> {
+        value.as_ref().ok_or_else(|| format!("'database.{}' is needed by the {} backend",
+            name, self.backend))
+    }
+
+    fn flush_interval(&self) -> Duration {
+        Duration::from_millis(self.flush_interval_ms)
+    }
+
     fn url(&self) -> String {
         let protocol = if self.https { "https" } else { "http" };
         format!("{}://{}:{}", protocol, self.host, self.port)
     }
+
+    /// Identification of the bucket (or database), e.g. for remembering
+    /// what was uploaded to it
+    pub fn target(&self) -> String {
+        let name = |value: &Option<String>| value.clone().unwrap_or_default();
+        match self.backend {
+            Backend::Influxdb2 => format!("{}/{}/{}", self.url(), name(&self.org), name(&self.bucket)),
+            Backend::Influxdb1 => format!("{}/{}/{}", self.url(), name(&self.database),
+                name(&self.retention_policy)),
+            Backend::Postgres => format!("postgres://{}:{}/{}/{}", self.host, self.port,
+                name(&self.database), self.table),
+        }
+    }
+
+    /// Secret by which the backend logs in, if any
+    fn secret_mut(&mut self) -> &mut Option<Secret> {
+        match self.backend {
+            Backend::Influxdb2 => &mut self.token,
+            Backend::Influxdb1 | Backend::Postgres => &mut self.password,
+        }
+    }
 }
 
-/// Connection to the InfluxDB2 server
-struct Connection {
-    client: Client,
-    bucket: String,
+/// Connection to the database server
+pub enum Connection {
+    #[cfg(feature = "influxdb2")]
+    Client(ClientConnection),
+    Direct(DirectConnection),
+    #[cfg(feature = "postgres")]
+    Postgres(Box<postgresql::Connection>),
 }
 
 impl Connection {
 
-    fn new(influxdb2_config: &Config) -> Connection {
-        Connection{
-            client: Client::new(
-                influxdb2_config.url(),
-                influxdb2_config.org.clone(),
-                influxdb2_config.token.clone()),
-            bucket: influxdb2_config.bucket.clone()}
+    /// Connection to the database of the backend; to InfluxDB2 by the client library
+    /// if compiled in, the client library can however not present a client certificate
+    pub fn new(database_config: &Config) -> Result<Connection, String> {
+        match database_config.backend {
+            Backend::Influxdb2 => {
+                #[cfg(feature = "influxdb2")]
+                if database_config.client_certificate.is_none() {
+                    return ClientConnection::new(database_config).map(Connection::Client);
+                }
+                DirectConnection::new(database_config).map(Connection::Direct)
+            },
+            Backend::Influxdb1 => DirectConnection::new(database_config).map(Connection::Direct),
+            Backend::Postgres => {
+                if database_config.https || database_config.client_certificate.is_some() {
+                    return Err("TLS to PostgreSQL is not supported, connect to it \
+                        locally or over a trusted network".to_string());
+                }
+                #[cfg(feature = "postgres")]
+                return postgresql::Connection::new(
+                    &database_config.host,
+                    database_config.port,
+                    database_config.required(&database_config.username, "username")?,
+                    database_config.password.as_ref(),
+                    database_config.required(&database_config.database, "database")?,
+                    &database_config.table)
+                    .map(|connection| Connection::Postgres(Box::new(connection)));
+                #[cfg(not(feature = "postgres"))]
+                return Err(format!("PostgreSQL at {} needs the 'postgres' feature",
+                    database_config.host));
+            },
+        }
     }
 
-    #[tokio::main]
-    async fn write_one_datapoint(&self, datum: Datum)
+    /// Write the data-points, given also as lines of the line protocol
+    /// (with timestamps in `PRECISION`)
+    #[cfg_attr(not(feature = "postgres"), allow(unused_variables))]
+    pub fn write(&mut self, datums: &[Datum], lines: &str)
     -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            #[cfg(feature = "influxdb2")]
+            Connection::Client(connection) => connection.write_lines(lines),
+            Connection::Direct(connection) => connection.write_lines(lines),
+            #[cfg(feature = "postgres")]
+            Connection::Postgres(connection) => Ok(connection.write(datums)?),
+        }
+    }
 
-        let points = vec![
-            DataPoint::builder(datum.measurement.to_string())
-                .tag("device_name", datum.device_name)
-                .tag("device_host", datum.device_host)
-                .field("value", datum.value as f64)
-                .timestamp(datum.measured_on.timestamp())
-                .build()?
-        ];
-  
-        self.client.write_with_precision(&self.bucket,
-            futures::prelude::stream::iter(points),
-            TimestampPrecision::Seconds).await?;
+    /// Check whether the server is ready to accept writes
+    pub fn is_ready(&mut self) -> bool {
+        match self {
+            #[cfg(feature = "influxdb2")]
+            Connection::Client(connection) => connection.is_ready(),
+            Connection::Direct(connection) => connection.is_ready(),
+            #[cfg(feature = "postgres")]
+            Connection::Postgres(connection) => connection.is_ready(),
+        }
+    }
+
+    /// Whether the write failed because the server refused the token (or password)
+    fn is_unauthorized(err: &(dyn std::error::Error + 'static)) -> bool {
+        #[cfg(feature = "influxdb2")]
+        if let Some(influxdb2::RequestError::Http { status, .. }) = err.downcast_ref() {
+            return matches!(status.as_u16(), 401 | 403);
+        }
+        #[cfg(feature = "postgres")]
+        if postgresql::is_unauthorized(err) {
+            return true;
+        }
+        matches!(err.downcast_ref(), Some(ureq::Error::Status(401 | 403, _)))
+    }
+}
+
+/// Connection to the InfluxDB2 server by the client library
+#[cfg(feature = "influxdb2")]
+pub struct ClientConnection {
+    client: influxdb2::Client,
+    org: String,
+    bucket: String,
+}
+
+#[cfg(feature = "influxdb2")]
+impl ClientConnection {
+
+    fn new(database_config: &Config) -> Result<ClientConnection, String> {
+        let token = database_config.required(&database_config.token, "token")?;
+        let org = database_config.required(&database_config.org, "org")?;
+        let bucket = database_config.required(&database_config.bucket, "bucket")?;
+        Ok(ClientConnection{
+            client: influxdb2::Client::new(database_config.url(), org.clone(), token.expose()),
+            org: org.clone(),
+            bucket: bucket.clone()})
+    }
+
+    /// Write lines of the line protocol (with timestamps in `PRECISION`)
+    fn write_lines(&self, body: &str)
+    -> Result<(), Box<dyn std::error::Error>> {
+
+        // The requests are made one at a time, by the thread writing them,
+        // on the runtime shared with the meters
+        runtime::block_on(self.client.write_line_protocol_with_precision(
+            &self.org, &self.bucket, body.to_owned(),
+            influxdb2::api::write::TimestampPrecision::Milliseconds))?;
 
         Ok(())
     }
+
+    /// Check whether the server is ready to accept writes
+    fn is_ready(&self) -> bool {
+        matches!(runtime::block_on(self.client.ready()), Ok(true))
+    }
+}
+
+/// Connection to the InfluxDB2 (or InfluxDB1) server, which POSTs the line
+/// protocol directly
+pub struct DirectConnection {
+    agent: ureq::Agent,
+    write_url: String,
+    ready_url: String,
+    /// Bucket (or database) written into and the precision of the timestamps
+    query: Vec<(&'static str, String)>,
+    authorization: Option<String>,
 }
 
+impl DirectConnection {
+
+    fn new(database_config: &Config) -> Result<DirectConnection, String> {
+        let mut agent_builder = ureq::AgentBuilder::new().timeout(Duration::from_secs(30));
+        if let Some(client_certificate) = &database_config.client_certificate {
+            agent_builder = client_certificate.apply(agent_builder)?;
+        }
+        let url = database_config.url();
+        let precision = ("precision", "ms".to_string());
+        let connection = match database_config.backend {
+            Backend::Influxdb1 => DirectConnection {
+                agent: agent_builder.build(),
+                write_url: format!("{}/write", url),
+                ready_url: format!("{}/ping", url),
+                query: [
+                    Some(("db", database_config.required(&database_config.database, "database")?
+                        .clone())),
+                    database_config.retention_policy.clone().map(|policy| ("rp", policy)),
+                    Some(precision),
+                ].into_iter().flatten().collect(),
+                authorization: database_config.username.as_ref().map(|username| {
+                    let password = database_config.password.as_ref()
+                        .map(Secret::expose).unwrap_or_default();
+                    format!("Basic {}", base64::engine::general_purpose::STANDARD
+                        .encode(format!("{}:{}", username, password)))
+                }),
+            },
+            Backend::Postgres => return Err("PostgreSQL is not written by the line protocol, \
+                but by its own connection".to_string()),
+            Backend::Influxdb2 => DirectConnection {
+                agent: agent_builder.build(),
+                write_url: format!("{}/api/v2/write", url),
+                ready_url: format!("{}/ready", url),
+                query: vec![
+                    ("org", database_config.required(&database_config.org, "org")?.clone()),
+                    ("bucket", database_config.required(&database_config.bucket, "bucket")?.clone()),
+                    precision,
+                ],
+                authorization: Some(format!("Token {}",
+                    database_config.required(&database_config.token, "token")?.expose())),
+            },
+        };
+        Ok(connection)
+    }
+
+    /// Write lines of the line protocol (with timestamps in `PRECISION`)
+    fn write_lines(&self, body: &str)
+    -> Result<(), Box<dyn std::error::Error>> {
+
+        let mut request = self.agent.post(&self.write_url)
+            .set("Content-Type", "text/plain; charset=utf-8");
+        for (name, value) in &self.query {
+            request = request.query(name, value);
+        }
+        if let Some(authorization) = &self.authorization {
+            request = request.set("Authorization", authorization);
+        }
+        request.send_string(body)?;
+
+        Ok(())
+    }
+
+    /// Check whether the server is ready to accept writes
+    fn is_ready(&self) -> bool {
+        self.agent.get(&self.ready_url).call().is_ok()
+    }
+}
+
+/// First delay between readiness checks of an unavailable server
+const FIRST_READY_CHECK_DELAY: Duration = Duration::from_secs(5);
+
+/// Longest delay between readiness checks of an unavailable server
+const MAX_READY_CHECK_DELAY: Duration = Duration::from_secs(300);
+
+/// Lines of the spill file written in one request
+const REPLAY_CHUNK: usize = 5000;
+
 pub struct Pump;
 
+impl Sink for Config {
+    fn spawn(self: Box<Self>, context: &sink::Context, data_receiver: Receiver<Datum>)
+    -> Result<sink::Handle, String>
+    {
+        Pump::spawn(*self, data_receiver, context.state.clone(), context.health.clone())
+    }
+}
+
 impl Pump {
 
-    pub fn spawn(influxdb2_config: Config,
-        data_receiver: Receiver<Datum>)
-    -> JoinHandle<Result<(),String>>
+    pub fn spawn(mut database_config: Config,
+        data_receiver: Receiver<Datum>,
+        state: SharedState,
+        health: SharedHealth)
+    -> Result<JoinHandle<Result<(),String>>, String>
     {
-        std::thread::spawn(move || {
+        let mut connection = Connection::new(&database_config)?;
+        let mut spill = match &database_config.spill_file {
+            Some(path) => {
+                let cipher = database_config.spill_encryption.as_ref().map(Cipher::new).transpose()
+                    .map_err(|err| format!("{} can not be encrypted: {}", path.display(), err))?;
+                Some(Spill::open(path, database_config.spill_max_mb * 1024 * 1024, cipher)?)
+            },
+            None => None,
+        };
+        let mut hangups = signals::hangups();
+        // Name of the sink in the write checkpoints
+        let sink = database_config.target();
+        Ok(std::thread::spawn(move || {
+
+            let line_receiver = spawn_encoders(
+                data_receiver, database_config.encoder_threads, PRECISION);
 
-            let mut connection = Connection::new(&influxdb2_config);
             let mut successful_connection_confirmed = false;
+            // After reconnecting failed (e.g. while the client certificate is
+            // being replaced), when to try it again; the old connection is kept meanwhile
+            let mut reconnect_delay = FIRST_READY_CHECK_DELAY;
+            let mut reconnect_on = None;
+            // While data-points are journaled, when to try writing them again;
+            // those journaled by the previous run right away
+            let mut replay_delay = FIRST_READY_CHECK_DELAY;
+            let mut replay_on = spill.as_ref()
+                .filter(|spill| !spill.is_empty())
+                .map(|_| Instant::now());
+            // The last batch was cut short, as the meters stopped
+            let mut stopping = false;
             loop {
-                let datum = data_receiver.recv()
-                    .expect("internal error, \
-                    data not sent between threads");
-
-                match connection.write_one_datapoint(datum) {
-                    
-                    Ok(_) => {
-                        if !successful_connection_confirmed {
-                            info!("Connection to InfluxDB2 established.");
-                            successful_connection_confirmed = true;
+                if stopping {
+                    debug!("all meters stopped, stopping");
+                    return Ok(());
+                }
+                let received = match replay_on {
+                    Some(replay_on) => match line_receiver.recv_timeout(
+                        replay_on.saturating_duration_since(Instant::now())) {
+                        Ok(encoded) => Some(encoded),
+                        Err(RecvTimeoutError::Timeout) => None,
+                        Err(RecvTimeoutError::Disconnected) => {
+                            debug!("all meters stopped, stopping with data-points journaled");
+                            return Ok(());
+                        },
+                    },
+                    None => match line_receiver.recv() {
+                        Ok(encoded) => Some(encoded),
+                        Err(_) => {
+                            debug!("all meters stopped, stopping");
+                            return Ok(());
                         }
                     },
+                };
+
+                // Written together with those following within the flush interval
+                let mut datums = vec![];
+                let mut lines = String::new();
+                if let Some((datum, line)) = received {
+                    datums.push(datum);
+                    lines.push_str(&line);
+                    let flush_on = Instant::now() + database_config.flush_interval();
+                    while datums.len() < database_config.batch_size {
+                        match line_receiver.recv_timeout(flush_on.saturating_duration_since(Instant::now())) {
+                            Ok((datum, line)) => {
+                                datums.push(datum);
+                                lines.push_str(&line);
+                            },
+                            Err(RecvTimeoutError::Timeout) => break,
+                            Err(RecvTimeoutError::Disconnected) => {
+                                stopping = true;
+                                break;
+                            },
+                        }
+                    }
+                }
 
-                    Err(err) => {
-                        warn!("We will have to reconnect in \
-                            5 seconds, because: {}",  err.to_string());
-                        thread::sleep(Duration::from_secs(5));
-                        connection = Connection::new(&influxdb2_config);
+                if signals::hangups() != hangups {
+                    hangups = signals::hangups();
+                    Pump::reread_secret(&mut database_config, &mut connection);
+                }
+                if reconnect_on.is_some_and(|due| due <= Instant::now()) {
+                    reconnect_on = Pump::reconnect(&database_config, &mut connection, &mut reconnect_delay);
+                }
+
+                // Journaled after the older ones, so that all are written in order
+                if let (Some(spill), Some(due)) = (&mut spill, replay_on) {
+                    if !lines.is_empty() {
+                        spill.append(&lines);
+                        Pump::record_health(&health, health::Connection::Journaling, 0, datums.len());
+                    }
+                    if Instant::now() < due {
+                        continue;
+                    }
+                    match Pump::replay(&mut database_config, &mut connection, spill, &sink, &state, &health) {
+                        Ok(()) => {
+                            info!("Journaled data-points written to {}.", database_config.backend);
+                            successful_connection_confirmed = true;
+                            replay_on = None;
+                            replay_delay = FIRST_READY_CHECK_DELAY;
+                        },
+                        Err(err) => {
+                            replay_delay = (replay_delay * 2).min(MAX_READY_CHECK_DELAY);
+                            debug!("Journaled data-points not written, trying again \
+                                in {}s: {}", replay_delay.as_secs(), err);
+                            replay_on = Some(Instant::now() + replay_delay);
+                        },
+                    }
+                    continue;
+                }
+                if datums.is_empty() {
+                    continue;
+                }
+
+                if let Err(err) = Pump::write(&mut database_config, &mut connection, &datums, &lines) {
+                    if let Some(spill) = &mut spill {
+                        warn!("Writing to {} failed, journaling the data-points into {} \
+                            until the server is available: {}", database_config.backend,
+                            spill.path().display(), err);
                         successful_connection_confirmed = false;
+                        spill.append(&lines);
+                        Pump::record_health(&health, health::Connection::Journaling, 0, datums.len());
+                        replay_on = Some(Instant::now() + replay_delay);
+                        continue;
+                    }
+                    warn!("Writing to {} failed, waiting for the server to be ready: {}",
+                        database_config.backend, err);
+                    successful_connection_confirmed = false;
+                    Pump::record_health(&health, health::Connection::Unavailable, 0, 0);
+                    Pump::wait_until_ready(database_config.backend, &mut connection);
+
+                    // The server is fine, so the client state may be broken
+                    if let Err(err) = Pump::write(&mut database_config, &mut connection, &datums, &lines) {
+                        warn!("Writing to {} failed again, dropping the data and reconnecting: {}",
+                            database_config.backend, err);
+                        reconnect_on = Pump::reconnect(&database_config, &mut connection, &mut reconnect_delay);
+                        continue;
+                    }
+                }
+
+                if !successful_connection_confirmed {
+                    info!("Connection to {} established.", database_config.backend);
+                    successful_connection_confirmed = true;
+                }
+                state.lock().expect("internal error, state lock poisoned")
+                    .record_written(&sink, &datums);
+                Pump::record_health(&health, health::Connection::Connected, datums.len(), 0);
+            }
+        }))
+    }
+
+    /// Replace the connection by a new one; if it can not be made, the old one
+    /// is kept, and when to try again is returned
+    fn reconnect(database_config: &Config, connection: &mut Connection, delay: &mut Duration)
+    -> Option<Instant> {
+        match Connection::new(database_config) {
+            Ok(new_connection) => {
+                *connection = new_connection;
+                *delay = FIRST_READY_CHECK_DELAY;
+                None
+            },
+            Err(err) => {
+                warn!("{} could not be reconnected, keeping the old connection \
+                    and trying again in {}s: {}", database_config.backend, delay.as_secs(), err);
+                let due = Instant::now() + *delay;
+                *delay = (*delay * 2).min(MAX_READY_CHECK_DELAY);
+                Some(due)
+            },
+        }
+    }
+
+    /// Record the connection state and the data-points written or journaled
+    fn record_health(health: &SharedHealth, connection: health::Connection,
+        written: usize, journaled: usize) {
+        health.lock().expect("internal error, health lock poisoned")
+            .record_database(connection, written, journaled);
+    }
+
+    /// Write the journaled lines, if the server is ready; those confirmed
+    /// written before (e.g. just before a crash) are skipped
+    fn replay(database_config: &mut Config, connection: &mut Connection, spill: &mut Spill,
+        sink: &str, state: &SharedState, health: &SharedHealth) -> Result<(), String> {
+        if !connection.is_ready() {
+            return Err("the server is not ready".to_string());
+        }
+        spill.replay(REPLAY_CHUNK, |chunk| {
+            let mut datums = vec![];
+            let mut lines = String::new();
+            {
+                let state = state.lock().expect("internal error, state lock poisoned");
+                for line in chunk.lines() {
+                    match parse_line_in(line, PRECISION) {
+                        Ok(datum) if state.is_written(sink, &datum) => {},
+                        Ok(datum) => {
+                            datums.push(datum);
+                            lines.push_str(line);
+                            lines.push('\n');
+                        },
+                        Err(err) => warn!("journaled data-point skipped: {}", err),
                     }
                 }
             }
+            if datums.is_empty() {
+                return Ok(());
+            }
+            Pump::write(database_config, connection, &datums, &lines).map_err(|err| err.to_string())?;
+            state.lock().expect("internal error, state lock poisoned")
+                .record_written(sink, &datums);
+            Pump::record_health(health, health::Connection::Connected, datums.len(), 0);
+            Ok(())
         })
     }
+
+    /// Write the data-points; if the token (or password) is refused, it may have
+    /// been rotated (e.g. in the file it is read from), so it is read again for a retry
+    fn write(database_config: &mut Config, connection: &mut Connection,
+        datums: &[Datum], lines: &str)
+    -> Result<(), Box<dyn std::error::Error>> {
+        match connection.write(datums, lines) {
+            Err(err) if Connection::is_unauthorized(err.as_ref())
+                && Pump::reread_secret(database_config, connection) =>
+                connection.write(datums, lines),
+            result => result,
+        }
+    }
+
+    /// Read the token (or password) again from its source and reconnect
+    /// with it; whether there is a new one
+    fn reread_secret(database_config: &mut Config, connection: &mut Connection) -> bool {
+        let backend = database_config.backend;
+        let secret = match database_config.secret_mut().as_ref().map(Secret::reread) {
+            Some(Ok(Some(secret))) => secret,
+            None | Some(Ok(None)) => {
+                debug!("{} secret did not change", backend);
+                return false;
+            },
+            Some(Err(err)) => {
+                warn!("{} secret could not be read again: {}", backend, err);
+                return false;
+            },
+        };
+        *database_config.secret_mut() = Some(secret);
+        match Connection::new(database_config) {
+            Ok(new_connection) => {
+                info!("{} secret was read again", backend);
+                *connection = new_connection;
+                true
+            },
+            Err(err) => {
+                warn!("{} could not be reconnected with the new secret: {}", backend, err);
+                false
+            },
+        }
+    }
+
+    /// Block until the server is ready, checking it with an exponential backoff
+    fn wait_until_ready(backend: Backend, connection: &mut Connection) {
+        let mut delay = FIRST_READY_CHECK_DELAY;
+        loop {
+            // The write is tried once more, then dropped
+            if !signals::sleep(delay) {
+                return;
+            }
+            if connection.is_ready() {
+                return;
+            }
+            delay = (delay * 2).min(MAX_READY_CHECK_DELAY);
+            debug!("{} is not ready, checking again in {}s", backend, delay.as_secs());
+        }
+    }
 }
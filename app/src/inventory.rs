@@ -0,0 +1,264 @@
+use crate::crypto;
+use serde::Deserialize;
+use std::path::PathBuf;
+
+#[cfg(feature = "sqlite")]
+use {
+    crate::network::DeviceClient,
+    crate::plug,
+    crate::probe,
+    crate::probe::DeviceInfo,
+    crate::runtime,
+    chrono::{DateTime, TimeZone, Utc},
+    log::{debug, info, warn},
+    rusqlite::types::Value,
+    std::collections::HashMap,
+    std::net::ToSocketAddrs,
+    std::sync::Arc,
+    std::time::Duration,
+};
+
+/// Device inventory configuration
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+pub struct Config {
+
+    /// Path of the SQLite database file
+    pub path: PathBuf,
+
+    /// Interval between refreshes of the metadata, in seconds
+    #[serde(default = "Config::default_refresh_interval_s")]
+    pub refresh_interval_s: u64,
+
+    /// Encryption of the settings snapshots, which may contain credentials, if any
+    pub encryption: Option<crypto::Config>,
+}
+
+impl Config {
+
+    fn default_refresh_interval_s() -> u64 { 3600 }
+}
+
+/// Metadata of one device
+#[cfg(feature = "sqlite")]
+pub struct Device {
+    pub name: String,
+    pub host: String,
+    pub ip: Option<String>,
+    pub mac: String,
+    pub model: Option<String>,
+    pub firmware: Option<String>,
+    pub generation: String,
+    /// Snapshot of the device settings, as JSON
+    pub settings: Option<String>,
+    pub updated_on: DateTime<Utc>,
+}
+
+/// Database of device metadata
+#[cfg(feature = "sqlite")]
+pub struct Inventory {
+    connection: rusqlite::Connection,
+    cipher: Option<crypto::Cipher>,
+}
+
+#[cfg(feature = "sqlite")]
+impl Inventory {
+
+    /// Open (or create) the database file
+    pub fn open(inventory_config: &Config) -> Result<Inventory, String> {
+        let path = &inventory_config.path;
+        let cipher = inventory_config.encryption.as_ref()
+            .map(crypto::Cipher::new)
+            .transpose()
+            .map_err(|err| format!("{} can not be encrypted: {}", path.display(), err))?;
+        let connection = rusqlite::Connection::open(path)
+            .map_err(|err| format!("{} can not be opened: {}", path.display(), err))?;
+        connection.execute_batch("
+            CREATE TABLE IF NOT EXISTS device (
+                name TEXT PRIMARY KEY,
+                host TEXT NOT NULL,
+                ip TEXT,
+                mac TEXT NOT NULL,
+                model TEXT,
+                firmware TEXT,
+                generation TEXT NOT NULL,
+                settings TEXT,
+                updated_on INTEGER NOT NULL
+            );
+        ").map_err(|err| format!("{} can not be initialized: {}", path.display(), err))?;
+        Ok(Inventory { connection, cipher })
+    }
+
+    /// Settings snapshot as stored, encrypted if there is a key
+    fn seal(&self, settings: &Option<String>) -> Value {
+        match (settings, &self.cipher) {
+            (None, _) => Value::Null,
+            (Some(settings), None) => Value::Text(settings.clone()),
+            (Some(settings), Some(cipher)) => Value::Blob(cipher.encrypt(settings.as_bytes())),
+        }
+    }
+
+    /// Settings snapshot as stored, decrypted; snapshots stored before
+    /// the encryption was configured are plain text
+    fn open_sealed(&self, name: &str, settings: Value) -> Result<Option<String>, String> {
+        match settings {
+            Value::Null => Ok(None),
+            Value::Text(settings) => Ok(Some(settings)),
+            Value::Blob(data) if crypto::is_encrypted(&data) => {
+                let cipher = self.cipher.as_ref()
+                    .ok_or_else(|| format!("settings of {} are encrypted, \
+                        but there is no 'device_inventory.encryption'", name))?;
+                let settings = cipher.decrypt(&data)
+                    .map_err(|err| format!("settings of {}: {}", name, err))?;
+                String::from_utf8(settings)
+                    .map(Some)
+                    .map_err(|_| format!("settings of {} are not UTF-8 text", name))
+            },
+            _ => Err(format!("settings of {} are not text", name)),
+        }
+    }
+
+    /// Insert or replace the metadata of the device
+    pub fn update(&self, device: &Device) -> Result<(), rusqlite::Error> {
+        self.connection.execute("INSERT OR REPLACE INTO device (name, host, ip, mac, \
+            model, firmware, generation, settings, updated_on) \
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)", (
+                &device.name, &device.host, &device.ip, &device.mac,
+                &device.model, &device.firmware, &device.generation,
+                self.seal(&device.settings), device.updated_on.timestamp(),
+            ))?;
+        Ok(())
+    }
+
+    /// Metadata of all devices, by name
+    pub fn devices(&self) -> Result<Vec<Device>, String> {
+        let mut statement = self.connection.prepare("SELECT name, host, ip, mac, model, \
+            firmware, generation, settings, updated_on FROM device ORDER BY name")
+            .map_err(|err| err.to_string())?;
+        let rows = statement.query_map([], |row| Ok((Device {
+                name: row.get(0)?,
+                host: row.get(1)?,
+                ip: row.get(2)?,
+                mac: row.get(3)?,
+                model: row.get(4)?,
+                firmware: row.get(5)?,
+                generation: row.get(6)?,
+                settings: None,
+                updated_on: Utc.timestamp_opt(row.get(8)?, 0).single().unwrap_or_default(),
+            }, row.get::<_, Value>(7)?)))
+            .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+            .map_err(|err| err.to_string())?;
+        rows.into_iter()
+            .map(|(mut device, settings)| {
+                device.settings = self.open_sealed(&device.name, settings)?;
+                Ok(device)
+            })
+            .collect()
+    }
+
+    /// Record the probed device with a snapshot of its settings,
+    /// warning if a known device (by MAC) changed its address
+    fn record(&self, known: &[Device], shelly_plug_config: &plug::Config,
+        device_info: &DeviceInfo, settings: Option<String>)
+    {
+        let ip = resolve(&shelly_plug_config.host);
+        for device in known.iter().filter(|device| device.mac == device_info.mac) {
+            if device.name != *shelly_plug_config.name {
+                warn!("{} has the MAC {} of the known device {}",
+                    shelly_plug_config.name, device_info.mac, device.name);
+            } else if device.ip.is_some() && device.ip != ip {
+                info!("{} moved from {} to {}", device.name,
+                    device.ip.as_deref().unwrap_or_default(),
+                    ip.as_deref().unwrap_or("an unknown address"));
+            }
+        }
+        let device = Device {
+            name: shelly_plug_config.name.to_string(),
+            host: shelly_plug_config.host.to_string(),
+            ip,
+            mac: device_info.mac.clone(),
+            model: device_info.model.clone(),
+            firmware: device_info.fw.clone(),
+            generation: device_info.generation().to_string(),
+            settings,
+            updated_on: Utc::now(),
+        };
+        if let Err(err) = self.update(&device) {
+            warn!("{} metadata could not be stored: {}", device.name, err);
+        }
+    }
+}
+
+/// IP address of the host (which may include a port)
+#[cfg(feature = "sqlite")]
+fn resolve(host: &str) -> Option<String> {
+    host.to_socket_addrs()
+        .or_else(|_| (host, 80).to_socket_addrs())
+        .ok()?
+        .next()
+        .map(|address| address.ip().to_string())
+}
+
+/// Refresh the metadata of all devices periodically, in a background thread;
+/// devices found by the startup probe are recorded right away
+#[cfg(feature = "sqlite")]
+pub fn spawn_refresher(inventory_config: Config, shelly_plug_configs: Vec<plug::Config>,
+    client: DeviceClient, found: HashMap<Arc<str>, DeviceInfo>)
+-> Result<(), String>
+{
+    let inventory = Inventory::open(&inventory_config)?;
+    let interval = Duration::from_secs(inventory_config.refresh_interval_s.max(60));
+    std::thread::spawn(move || {
+        let mut found = found;
+        loop {
+            let known = inventory.devices().unwrap_or_else(|err| {
+                warn!("device metadata could not be read: {}", err);
+                vec![]
+            });
+            for shelly_plug_config in &shelly_plug_configs {
+                // Channels of a device share its information
+                let device_info = match found.get(&shelly_plug_config.host) {
+                    Some(device_info) => device_info.clone(),
+                    None => match runtime::block_on(probe::probe(shelly_plug_config, &client)) {
+                        Ok(device_info) => device_info,
+                        Err(err) => {
+                            debug!("{} metadata not refreshed: {}", shelly_plug_config.host, err);
+                            continue;
+                        }
+                    },
+                };
+                let settings = runtime::block_on(probe::settings(shelly_plug_config,
+                    device_info.generation(), &client))
+                    .map_err(|err| debug!("{} settings not refreshed: {}",
+                        shelly_plug_config.host, err))
+                    .ok();
+                inventory.record(&known, shelly_plug_config, &device_info, settings);
+            }
+            // Probed again on the next refresh
+            found.clear();
+            std::thread::sleep(interval);
+        }
+    });
+    Ok(())
+}
+
+/// Print the metadata of all known devices
+#[cfg(feature = "sqlite")]
+pub fn print(inventory_config: &Config, with_settings: bool) -> Result<(), String> {
+    let inventory = Inventory::open(inventory_config)?;
+    let devices = inventory.devices()
+        .map_err(|err| format!("{} can not be queried: {}",
+            inventory_config.path.display(), err))?;
+    for device in devices {
+        println!("{} host={} ip={} mac={} model={} firmware={} generation={} updated={}",
+            device.name, device.host,
+            device.ip.as_deref().unwrap_or("?"), device.mac,
+            device.model.as_deref().unwrap_or("?"),
+            device.firmware.as_deref().unwrap_or("?"),
+            device.generation, device.updated_on.to_rfc3339());
+        if with_settings {
+            println!("{}", device.settings.as_deref().unwrap_or("{}"));
+        }
+    }
+    Ok(())
+}
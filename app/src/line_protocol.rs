@@ -0,0 +1,246 @@
+use crate::point::{Datum, Measurement};
+use chrono::{TimeZone, Utc};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex};
+
+/// Escape a tag key or value of the InfluxDB line protocol; line breaks,
+/// which the protocol can not escape, are replaced by spaces
+pub fn escape_tag(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        let c = if matches!(c, '\n' | '\r') { ' ' } else { c };
+        if matches!(c, ',' | '=' | ' ' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Split the text at separators which are not escaped by a backslash
+fn split_unescaped(text: &str, separator: char) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut start = 0;
+    let mut escaped = false;
+    for (index, c) in text.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == separator {
+            parts.push(&text[start..index]);
+            start = index + c.len_utf8();
+        }
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+/// Remove backslash escapes
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut escaped = false;
+    for c in text.chars() {
+        if c == '\\' && !escaped {
+            escaped = true;
+        } else {
+            unescaped.push(c);
+            escaped = false;
+        }
+    }
+    unescaped
+}
+
+/// Parse a line in the format written by the `Encoder`
+pub fn parse_line(line: &str) -> Result<Datum, String> {
+    parse_line_in(line, Precision::Seconds)
+}
+
+/// Parse one line of the line protocol with the timestamp in the precision
+pub fn parse_line_in(line: &str, precision: Precision) -> Result<Datum, String> {
+    let parts = split_unescaped(line.trim_end(), ' ');
+    let (key, fields, timestamp) = match parts.as_slice() {
+        [key, fields, timestamp] => (key, fields, timestamp),
+        _ => return Err("expected measurement with tags, fields and timestamp".to_string()),
+    };
+
+    let mut key = split_unescaped(key, ',').into_iter();
+    let measurement: Measurement = unescape(key.next().unwrap_or_default()).parse()?;
+    let mut device_name = None;
+    let mut device_host = None;
+    let mut valid = true;
+    for tag in key {
+        match split_unescaped(tag, '=').as_slice() {
+            ["device_name", value] => device_name = Some(unescape(value)),
+            ["device_host", value] => device_host = Some(unescape(value)),
+            ["quality", value] => valid = unescape(value) != "invalid",
+            _ => (), // other tags are ignored
+        }
+    }
+
+    let value = split_unescaped(fields, ',').into_iter()
+        .find_map(|field| field.strip_prefix("value="))
+        .ok_or("field 'value' is missing")?;
+    let value: f32 = value.trim_end_matches('i').parse()
+        .map_err(|_| format!("'{}' is not a number", value))?;
+
+    let timestamp: i64 = timestamp.parse()
+        .map_err(|_| format!("'{}' is not a timestamp", timestamp))?;
+    let measured_on = match precision {
+        Precision::Seconds => Utc.timestamp_opt(timestamp, 0).single(),
+        Precision::Milliseconds => Utc.timestamp_millis_opt(timestamp).single(),
+        Precision::Nanoseconds => Some(Utc.timestamp_nanos(timestamp)),
+    };
+
+    Ok(Datum {
+        measured_on: measured_on.ok_or_else(|| format!("'{}' is out of range", timestamp))?,
+        measurement,
+        device_name: device_name.ok_or("tag 'device_name' is missing")?.into(),
+        device_host: device_host.ok_or("tag 'device_host' is missing")?.into(),
+        value,
+        valid,
+    })
+}
+
+/// Precision of the timestamps in the line protocol
+#[derive(Default, Clone, Copy)]
+pub enum Precision {
+    #[default]
+    Seconds,
+    Milliseconds,
+    #[cfg_attr(not(feature = "grafana-live"), allow(dead_code))]
+    Nanoseconds,
+}
+
+/// Encodes data-points as InfluxDB line protocol
+///
+/// The escaped tag set of each device is computed once and reused for all
+/// its data-points.
+#[derive(Default)]
+pub struct Encoder {
+    tag_sets: HashMap<(Arc<str>, Arc<str>), String>,
+    precision: Precision,
+}
+
+impl Encoder {
+
+    /// Encoder writing timestamps in the given precision
+    pub fn with_precision(precision: Precision) -> Encoder {
+        Encoder { tag_sets: HashMap::new(), precision }
+    }
+
+    /// Escaped tag set of the device, starting with a comma
+    fn tag_set(&mut self, datum: &Datum) -> &str {
+        self.tag_sets
+            .entry((datum.device_name.clone(), datum.device_host.clone()))
+            // Tags sorted by key, as recommended by InfluxDB
+            .or_insert_with(|| format!(",device_host={},device_name={}",
+                escape_tag(&datum.device_host), escape_tag(&datum.device_name)))
+    }
+
+    /// Append one line (with timestamp in the precision of the encoder) to the body
+    pub fn encode(&mut self, datum: &Datum, body: &mut String) {
+        let measurement = datum.measurement;
+        let timestamp = match self.precision {
+            Precision::Seconds => datum.measured_on.timestamp(),
+            Precision::Milliseconds => datum.measured_on.timestamp_millis(),
+            Precision::Nanoseconds => datum.measured_on.timestamp_nanos(),
+        };
+        let value = datum.value as f64;
+        let tag_set = self.tag_set(datum);
+        let quality = if datum.valid { "" } else { ",quality=invalid" };
+        writeln!(body, "{}{}{} value={} {}", measurement, tag_set, quality, value, timestamp)
+            .expect("writing to a String can not fail");
+    }
+}
+
+/// Encode data-points on a pool of threads, so that encoding overlaps
+/// with writing; returns the receiver of the data-points with their lines,
+/// in the order the data-points were received
+///
+/// The order matters to the write checkpoints: a data-point journaled after
+/// a newer one of its device was written would be skipped on replay.
+pub fn spawn_encoders(data_receiver: Receiver<Datum>, thread_count: usize, precision: Precision)
+-> Receiver<(Datum, String)>
+{
+    // Data-points are numbered as they are received, so that their lines are reordered
+    let data_receiver = Arc::new(Mutex::new((data_receiver, 0u64)));
+    let (encoded_sender, encoded_receiver) = channel::<(u64, Datum, String)>();
+    for _ in 0..thread_count.max(1) {
+        let data_receiver = data_receiver.clone();
+        let encoded_sender = encoded_sender.clone();
+        std::thread::spawn(move || {
+            let mut encoder = Encoder::with_precision(precision);
+            loop {
+                let next = {
+                    let mut data_receiver = data_receiver.lock()
+                        .expect("internal error, encoder lock poisoned");
+                    let (receiver, received) = &mut *data_receiver;
+                    receiver.recv().map(|datum| {
+                        *received += 1;
+                        (*received - 1, datum)
+                    })
+                };
+                let (number, datum) = match next {
+                    Ok(next) => next,
+                    Err(_) => return, // all meters stopped
+                };
+                let mut line = String::new();
+                encoder.encode(&datum, &mut line);
+                if encoded_sender.send((number, datum, line)).is_err() {
+                    return; // writer stopped
+                }
+            }
+        });
+    }
+    let (line_sender, line_receiver) = channel::<(Datum, String)>();
+    std::thread::spawn(move || {
+        let mut pending = BTreeMap::new();
+        let mut next = 0;
+        for (number, datum, line) in encoded_receiver {
+            pending.insert(number, (datum, line));
+            while let Some(encoded) = pending.remove(&next) {
+                if line_sender.send(encoded).is_err() {
+                    return; // writer stopped
+                }
+                next += 1;
+            }
+        }
+    });
+    line_receiver
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escaped_tags_are_parsed_back() {
+        assert_eq!(escape_tag("a b,c=d\\"), "a\\ b\\,c\\=d\\\\");
+        assert_eq!(escape_tag("two\r\nlines"), "two\\ \\ lines");
+        let line = format!("instantaneous_consumption_in_w,device_host=192.0.2.1,device_name={} \
+            value=1 60", escape_tag("C:\\ fridge\nkitchen\\"));
+        let datum = parse_line(&line).unwrap();
+        assert_eq!(datum.device_name.as_ref(), "C:\\ fridge kitchen\\");
+        assert_eq!(datum.device_host.as_ref(), "192.0.2.1");
+    }
+
+    #[test]
+    fn encoded_lines_keep_the_order_of_the_data_points() {
+        let (data_sender, data_receiver) = channel();
+        let line_receiver = spawn_encoders(data_receiver, 4, Precision::Seconds);
+        for second in 0..1000 {
+            data_sender.send(Datum { measured_on: Utc.timestamp_opt(second, 0).unwrap(),
+                measurement: Measurement::instantaneous_consumption_in_w,
+                device_name: "fridge".into(), device_host: "192.0.2.1".into(), value: 1.0,
+                valid: true }).unwrap();
+        }
+        drop(data_sender);
+        let seconds: Vec<i64> = line_receiver.iter()
+            .map(|(datum, _)| datum.measured_on.timestamp())
+            .collect();
+        assert_eq!(seconds, (0..1000).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,94 @@
+use crate::health;
+use crate::retry;
+use log::{error, info, log, warn, Level};
+use std::time::{Duration, Instant};
+
+/// Interval between the summaries of a lasting failure
+const SUMMARY_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Failures of a device, since its last success
+struct Streak {
+    since: Instant,
+    count: u64,
+    /// Failures since the last message
+    unreported: u64,
+    last_reported: Instant,
+    escalated: bool,
+}
+
+/// Logs the recurring failures of a device without repeating the same warning
+/// on every retry: the first failure, then hourly summaries with the count of
+/// failures, at the error level once the device is failing for `error_after`
+///
+/// The retries are delayed by the retry policy, and the device is marked down
+/// after as many consecutive failures as the policy allows.
+pub struct FailureLog {
+    error_after: Duration,
+    retry_policy: retry::Policy,
+    streak: Option<Streak>,
+    /// Reports the failures and successes into the health, if they are the device's
+    reporter: Option<health::Reporter>,
+}
+
+impl FailureLog {
+
+    pub fn new(error_after: Duration, retry_policy: retry::Policy) -> FailureLog {
+        FailureLog { error_after, retry_policy, streak: None, reporter: None }
+    }
+
+    /// Also report the failures and successes into the health
+    pub fn reporting_to(self, reporter: health::Reporter) -> FailureLog {
+        FailureLog { reporter: Some(reporter), ..self }
+    }
+
+    /// Record a failure of the device; returns the delay of the retry
+    pub fn failed(&mut self, host: &str, message: &str) -> Duration {
+        let count = self.streak.as_ref().map_or(1, |streak| streak.count + 1);
+        if let Some(reporter) = &self.reporter {
+            reporter.failed(count, message);
+        }
+        let delay = self.retry_policy.delay(count);
+        let message = format!("{}; retrying in {}s", message, delay.as_secs_f64());
+        if self.retry_policy.down_after_failures == Some(count) {
+            error!("{} is down after {} consecutive failures, last: {}", host, count, message);
+        }
+        let streak = match &mut self.streak {
+            Some(streak) => streak,
+            None => {
+                warn!("{} {}", host, message);
+                let now = Instant::now();
+                self.streak = Some(Streak {
+                    since: now, count: 1, unreported: 0, last_reported: now, escalated: false });
+                return delay;
+            }
+        };
+        streak.count = count;
+        streak.unreported += 1;
+        let failing_for = streak.since.elapsed();
+        let escalate = !streak.escalated && failing_for >= self.error_after;
+        if escalate || streak.last_reported.elapsed() >= SUMMARY_INTERVAL {
+            streak.escalated |= escalate;
+            let level = if streak.escalated { Level::Error } else { Level::Warn };
+            log!(level, "{} is failing for {} minutes ({} failures, {} since the last \
+                message), last: {}", host, failing_for.as_secs() / 60,
+                streak.count, streak.unreported, message);
+            streak.unreported = 0;
+            streak.last_reported = Instant::now();
+        }
+        delay
+    }
+
+    /// Record a success of the device, ending its failures
+    pub fn succeeded(&mut self, host: &str) {
+        if let Some(reporter) = &self.reporter {
+            reporter.succeeded();
+        }
+        if let Some(streak) = self.streak.take() {
+            let down = self.retry_policy.down_after_failures
+                .is_some_and(|down_after_failures| streak.count >= down_after_failures);
+            info!("{} {} again after {} failures in {} minutes", host,
+                if down { "is up" } else { "works" }, streak.count,
+                streak.since.elapsed().as_secs() / 60);
+        }
+    }
+}
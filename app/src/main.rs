@@ -1,41 +1,349 @@
+mod archive;
+mod audit;
+mod bench;
+mod calendar;
+mod cli;
+mod clock;
 mod config;
+mod crypto;
+mod discovery;
+mod domoticz;
+mod emeter;
+mod emoncms;
+mod exec;
+mod evcc;
+mod files;
+mod grafana;
+mod ha;
+mod health;
+#[cfg(any(feature = "evcc", feature = "prometheus", feature = "health"))]
+mod httpd;
+mod icinga;
 mod influx;
+mod inventory;
+mod line_protocol;
+mod log_limit;
+mod mdns;
+mod migrate;
+mod mqtt;
+mod mqtt_source;
+mod network;
+mod openhab;
 mod plug;
 mod point;
+#[cfg(feature = "postgres")]
+mod postgresql;
+mod probe;
+mod prometheus;
+mod relay;
+mod reload;
+mod report;
+mod retention;
+mod retry;
+mod runtime;
+mod sandbox;
+mod schedule;
+mod scheduler;
+mod secret;
+mod signals;
+mod sink;
+mod spill;
+mod state;
+mod status;
+mod tariff;
+mod store;
+mod tls;
+mod transfer;
+mod triage;
+mod zabbix;
 
-use log::{debug, warn, error};
+use log::{debug, info, warn, error};
 use std::thread::JoinHandle;
-use std::sync::mpsc::channel;
+use std::sync::mpsc::{channel, Sender};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
 
 fn main() {
-    env_logger::init();
-    let app_config = config::Config::read_from_deafult_file();
+    let args = cli::Args::parse_with_features();
+    let mut logger = env_logger::Builder::from_default_env();
+    if let Some(log_level) = &args.log_level {
+        logger.parse_filters(log_level);
+    }
+    logger.init();
+    config::use_path(args.config.clone());
 
-    //
-    let (tx, rx) = channel::<point::Datum>();
+    let result = match args.command {
+        None if args.bench_sink => config::Config::load().and_then(|app_config| bench::run(
+            &app_config,
+            &bench::Load {
+                devices: args.bench_devices,
+                rate: args.bench_rate,
+                duration: std::time::Duration::from_secs(args.bench_duration_s),
+            })),
+        None if args.dry_run => check(),
+        None => run(),
+        Some(cli::Command::Parse { file, name, host }) => triage::parse(&file,
+            &plug::Config::new(&name, &host)),
+        #[cfg(feature = "sqlite")]
+        Some(cli::Command::Query { filter }) => {
+            config::Config::load().and_then(|app_config| match app_config.local_store {
+                Some(store_config) => store::print(&store_config, &filter.into()),
+                None => Err("there is no 'local_store' in the config".to_string()),
+            })
+        },
+        #[cfg(feature = "sqlite")]
+        Some(cli::Command::Export { output, filter }) => {
+            config::Config::load().and_then(|app_config| match app_config.local_store {
+                Some(store_config) => transfer::export(&store_config, &filter.into(), &output),
+                None => Err("there is no 'local_store' in the config".to_string()),
+            })
+        },
+        #[cfg(feature = "sqlite")]
+        Some(cli::Command::Sync { chunk, restart }) => config::Config::load()
+            .and_then(|app_config| transfer::sync(&app_config, chunk, restart)),
+        #[cfg(feature = "sqlite")]
+        Some(cli::Command::Devices { settings }) => {
+            config::Config::load().and_then(|app_config| match app_config.device_inventory {
+                Some(inventory_config) => inventory::print(&inventory_config, settings),
+                None => Err("there is no 'device_inventory' in the config".to_string()),
+            })
+        },
+        Some(cli::Command::Import { file }) => config::Config::load()
+            .and_then(|app_config| transfer::import(&app_config, &file)),
+        Some(cli::Command::Relay { device, state }) => config::Config::load()
+            .and_then(|app_config| relay::command(&app_config, &device,
+                state == cli::RelayState::On)),
+        Some(cli::Command::Audit) => {
+            config::Config::load().and_then(|app_config| match app_config.audit_log {
+                Some(audit_config) => audit::print(&audit_config),
+                None => Err("there is no 'audit_log' in the config".to_string()),
+            })
+        },
+        Some(cli::Command::Migrate { dry_run }) => migrate::command(config::path(), dry_run),
+        #[cfg(feature = "sqlite")]
+        Some(cli::Command::Report { command: cli::ReportCommand::Export { month, output } }) =>
+            config::Config::load().and_then(|app_config|
+                report::export(&app_config, month, output.as_deref())),
+        #[cfg(feature = "reports")]
+        Some(cli::Command::Report { command: cli::ReportCommand::Html { period, day, output } }) =>
+            config::Config::load().and_then(|app_config|
+                report::html(&app_config, period, day, output.as_deref())),
+    };
+
+    if let Err(msg) = result {
+        eprintln!("{msg}");
+        std::process::exit(1);
+    }
+}
+
+/// Run the logger until all threads finish
+fn run() -> Result<(), String> {
+    let app_config = config::Config::load()?.with_unique_plugs()?;
+    debug!("{:?}", app_config);
+    signals::listen()?;
+    runtime::use_worker_threads(app_config.worker_threads);
+
+    // Before any thread is spawned, so that all of them are restricted
+    if let Some(sandbox_config) = &app_config.sandbox {
+        sandbox::restrict(sandbox_config, app_config.data_paths(), app_config.executables())?;
+    }
+
+    // Refuse devices outside of the allowed networks, before contacting any
+    let polled_plugs = app_config.polled_plugs();
+    check_networks(&app_config, &polled_plugs)?;
+    let client = app_config.device_client();
+
+    // Find out which devices are alive, without waiting for the dead ones
+    let found = runtime::block_on(probe::probe_all(&polled_plugs,
+        &client, app_config.startup_probe_budget()));
+    debug!("{} of {} devices responded to the startup probe",
+        found.len(), polled_plugs.len());
+    let found_macs: Vec<String> = found.values()
+        .map(|device_info| probe::normalized_mac(&device_info.mac))
+        .collect();
+
+    // Keep the metadata of the devices for the inventory
+    if let Some(inventory_config) = &app_config.device_inventory {
+        #[cfg(feature = "sqlite")]
+        inventory::spawn_refresher(inventory_config.clone(), polled_plugs.clone(),
+            client.clone(), found)?;
+        #[cfg(not(feature = "sqlite"))]
+        return Err(format!("device inventory {} needs the 'sqlite' feature",
+            inventory_config.path.display()));
+    }
 
-    // Spawn all meter threads!
+    // Write the reports of the periods as they end
+    if let Some(html_config) = &app_config.report.html {
+        #[cfg(feature = "reports")]
+        report::spawn_html(html_config.clone(), app_config.clone())?;
+        #[cfg(not(feature = "reports"))]
+        return Err(format!("HTML reports into {} need the 'reports' feature",
+            html_config.directory.display()));
+    }
+
+    // Spawn all sinks
+    let state = state::State::load(app_config.state_file.as_deref(),
+        app_config.calendar).shared();
+    let health = health::Health::new(app_config.database.as_ref()
+        .filter(|_| app_config.is_sink_selected("database"))
+        .map(|database_config| database_config.backend.to_string())).shared();
     let mut join_handles: Vec<JoinHandle<Result<(),String>>> = vec![];
-    for shelly_plug_config in &app_config.shelly_plugs {
-        
-        // Metering per minute
-        join_handles.push(plug::MinuteMeter::spawn(
-            shelly_plug_config,
-            app_config.network_timeout(), 
-            tx.clone()));
-
-        // Instantaneous metering
-        plug::InstantaneousMeter::spawn(
+    let mut sinks: Vec<Sender<point::Datum>> = vec![];
+    let context = sink::Context { app_config: &app_config, state: &state, health: &health };
+    for (section, sink) in sink::configured(&app_config)? {
+        let (tx, rx) = channel::<point::Datum>();
+        join_handles.push(sink.spawn(&context, rx)?);
+        sinks.push(tx);
+        debug!("'{}' sink started", section);
+    }
+
+    // Only one of a pair of instances writes at a time
+    let standby_gate = match &app_config.high_availability {
+        Some(ha_config) => ha::start(ha_config, &health)?,
+        None => None,
+    };
+
+    // Serve the health of the logger itself
+    if let Some(health_config) = &app_config.health {
+        #[cfg(feature = "health")]
+        health::spawn(health_config.clone(), health.clone())?;
+        #[cfg(not(feature = "health"))]
+        return Err(format!("health endpoint on {} needs the 'health' feature",
+            health_config.listen_address));
+    }
+
+    // The listeners of the sinks are bound by now
+    if let Some(sandbox_config) = &app_config.sandbox {
+        sandbox::drop_privileges(sandbox_config)?;
+    }
+
+    //
+    let (tx, rx) = unbounded_channel::<point::Datum>();
+    let pricing = app_config.tariff.clone().map(|tariff_config| {
+        info!("Computing the cost of the consumption at {} {} per kWh{}",
+            tariff_config.price_per_kwh,
+            tariff_config.currency.as_deref().unwrap_or("(no currency)"),
+            if tariff_config.schedule.is_empty() { "" } else { " outside of the schedule" });
+        tariff::Pricing::new(tariff_config, app_config.calendar)
+    });
+    join_handles.push(spawn_fan_out(rx, sinks, standby_gate, pricing, health.clone()));
+
+    // Keep the local files within their limits
+    if let Some(archive_config) = &app_config.response_archive {
+        if let Some(encryption_config) = &archive_config.encryption {
+            crypto::Cipher::new(encryption_config)
+                .map_err(|err| format!("responses can not be archived: {}", err))?;
+        }
+        retention::spawn_pruner(vec![retention::Target {
+            directory: archive_config.directory.clone(),
+            policy: archive_config.retention.clone(),
+            prunable: Box::new(archive::is_response),
+        }]);
+    }
+
+    // Schedule all meters on the shared runtime
+    let scheduler = scheduler::Scheduler::default();
+    let meter: reload::MeterFactory = {
+        let client = client.clone();
+        let app_config = app_config.clone();
+        let state = state.clone();
+        let health = health.clone();
+        let tx = tx.clone();
+        Box::new(move |shelly_plug_config| {
+            let mut meters: Vec<Box<dyn scheduler::Task>> = vec![];
+            let failures = app_config.failure_log(shelly_plug_config)
+                .reporting_to(health::Reporter::new(&health,
+                    shelly_plug_config.name.clone(), shelly_plug_config.host.clone()));
+            match shelly_plug_config.device_type {
+                plug::DeviceType::Plug => meters.push(Box::new(plug::DeviceMeter::new(
+                    shelly_plug_config,
+                    client.clone(),
+                    app_config.response_archive.as_ref(),
+                    app_config.audit_log.as_ref(),
+                    failures,
+                    state.clone(),
+                    tx.clone()))),
+                plug::DeviceType::Emeter => meters.push(Box::new(emeter::EnergyMeter::new(
+                    shelly_plug_config,
+                    client.clone(),
+                    failures,
+                    tx.clone()))),
+            }
+            if let Some(interval) = shelly_plug_config.status_meter_interval() {
+                meters.push(Box::new(status::StatusMeter::new(
+                    shelly_plug_config,
+                    interval,
+                    client.clone(),
+                    app_config.failure_log(shelly_plug_config),
+                    tx.clone())));
+            }
+            meters
+        })
+    };
+    let mut reloader = reload::Reloader::new(app_config.clone(), meter, scheduler.intake());
+    let mut tasks = reloader.meters(&polled_plugs);
+
+    // Meter also the devices found on the network, as they are found
+    if let Some(discovery_config) = app_config.discovery.as_ref()
+        .filter(|discovery_config| discovery_config.enabled) {
+        let meter: discovery::MeterFactory = {
+            let client = client.clone();
+            let archive_config = app_config.response_archive.clone();
+            let audit_config = app_config.audit_log.clone();
+            let error_after_failing = app_config.error_after_failing();
+            let retry_policy = app_config.retry.clone();
+            let state = state.clone();
+            let health = health.clone();
+            let tx = tx.clone();
+            Box::new(move |shelly_plug_config| Box::new(plug::DeviceMeter::new(
                 shelly_plug_config,
-                app_config.network_timeout(),
-                 tx.clone())
-            .map(|handle| { join_handles.push(handle) });
+                client.clone(),
+                archive_config.as_ref(),
+                audit_config.as_ref(),
+                log_limit::FailureLog::new(error_after_failing,
+                    shelly_plug_config.retry_policy(&retry_policy))
+                    .reporting_to(health::Reporter::new(&health,
+                        shelly_plug_config.name.clone(), shelly_plug_config.host.clone())),
+                state.clone(),
+                tx.clone())))
+        };
+        tasks.push(Box::new(discovery::Discovery::new(discovery_config.clone(), client.clone(),
+            &app_config.shelly_plugs, found_macs, meter, scheduler.intake())));
+    }
+
+    // Plugs publishing their telemetry are fed by the broker instead
+    if polled_plugs.len() < app_config.shelly_plugs.len() {
+        for shelly_plug_config in &app_config.shelly_plugs {
+            if shelly_plug_config.mode() != plug::Mode::Mqtt {
+                continue;
+            }
+            if shelly_plug_config.device_type == plug::DeviceType::Emeter {
+                return Err(format!("{} is an energy meter, which can not be fed by MQTT",
+                    shelly_plug_config.name));
+            }
+            if shelly_plug_config.mqtt_topic.is_none() {
+                return Err(format!("{} is fed by the MQTT broker, which needs its 'mqtt_topic'",
+                    shelly_plug_config.name));
+            }
+        }
+        match &app_config.mqtt_source {
+            #[cfg(feature = "mqtt")]
+            Some(source_config) => join_handles.push(mqtt_source::Subscriber::spawn(
+                source_config.clone(), app_config.shelly_plugs.clone(),
+                state.clone(), tx.clone())),
+            #[cfg(not(feature = "mqtt"))]
+            Some(source_config) => return Err(format!("MQTT broker {} needs the 'mqtt' feature",
+                source_config.host)),
+            None => return Err("devices fed by MQTT need \
+                'mqtt_source' in the config".to_string()),
+        }
     }
-    
-    debug!("{} meter threads were started", join_handles.len());
+    drop(tx);
+
+    // Meter the devices as they are added to the config, if it is reloaded
+    tasks.push(Box::new(reloader));
 
-    join_handles.push(influx::Pump::spawn(
-        app_config.influxdb2.clone(), rx));
+    debug!("{} meters were scheduled", tasks.len());
+    join_handles.push(scheduler.spawn(tasks));
 
     // Wait for all threads to finish
     for join_handle in join_handles {
@@ -46,4 +354,89 @@ fn main() {
                 be joined; internal error likely"),
         }
     }
+    // The last changes may not be saved yet
+    state.lock().expect("internal error, state lock poisoned").save();
+    if signals::terminating() {
+        info!("Stopped.");
+    }
+    Ok(())
+}
+
+/// Check the config and probe the devices, then stop without metering them
+fn check() -> Result<(), String> {
+    let app_config = config::Config::load()?.with_unique_plugs()?;
+    let polled_plugs = app_config.polled_plugs();
+    check_networks(&app_config, &polled_plugs)?;
+    let found = runtime::block_on(probe::probe_all(&polled_plugs,
+        &app_config.device_client(), app_config.startup_probe_budget()));
+    for shelly_plug_config in &app_config.shelly_plugs {
+        if shelly_plug_config.mode() == plug::Mode::Mqtt {
+            println!("{} is fed by the MQTT broker", shelly_plug_config.name);
+            continue;
+        }
+        match found.get(&shelly_plug_config.host) {
+            Some(device_info) => println!("{} at {} is {} (generation {}, firmware {})",
+                shelly_plug_config.name, shelly_plug_config.host,
+                device_info.model.as_deref().unwrap_or("unknown model"),
+                device_info.generation(),
+                device_info.fw.as_deref().unwrap_or("unknown")),
+            None => println!("{} at {} did not respond", shelly_plug_config.name,
+                shelly_plug_config.host),
+        }
+    }
+    println!("{} is valid, {} of {} polled devices responded", config::path().display(),
+        polled_plugs.iter().filter(|shelly_plug_config|
+            found.contains_key(&shelly_plug_config.host)).count(),
+        polled_plugs.len());
+    Ok(())
+}
+
+/// Refuse devices outside of the allowed networks
+fn check_networks(app_config: &config::Config, polled_plugs: &[plug::Config])
+-> Result<(), String>
+{
+    if let Some(allowed_networks) = &app_config.allowed_networks {
+        for shelly_plug_config in polled_plugs {
+            network::check_host(&shelly_plug_config.host, allowed_networks)
+                .map_err(|err| format!("{} can not be polled: {}", shelly_plug_config.name, err))?;
+        }
+    }
+    Ok(())
+}
+
+/// Forward each data-point (and its cost, if priced) to all sinks, once the
+/// system clock is plausible
+fn spawn_fan_out(mut data_receiver: UnboundedReceiver<point::Datum>, sinks: Vec<Sender<point::Datum>>,
+    mut standby_gate: Option<ha::StandbyGate>, pricing: Option<tariff::Pricing>,
+    health: health::SharedHealth)
+-> JoinHandle<Result<(),String>>
+{
+    std::thread::spawn(move || {
+        // Nothing is written with a clock that was not set yet
+        let mut clock_gate = clock::ClockGate::new();
+        while let Some(datum) = data_receiver.blocking_recv() {
+            for datum in clock_gate.pass(datum) {
+                let mut passed = match &mut standby_gate {
+                    Some(standby_gate) => standby_gate.pass(datum),
+                    None => vec![datum],
+                };
+                if let Some(pricing) = &pricing {
+                    let costs: Vec<point::Datum> = passed.iter()
+                        .filter_map(|datum| pricing.cost(datum))
+                        .collect();
+                    passed.extend(costs);
+                }
+                health.lock().expect("internal error, health lock poisoned")
+                    .record_measured(passed.len());
+                for datum in passed {
+                    for sink in &sinks {
+                        if sink.send(datum.clone()).is_err() {
+                            return Err("some sink stopped, stopping".to_string());
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    })
 }
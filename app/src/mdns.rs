@@ -0,0 +1,72 @@
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+
+/// Services advertised by Shelly devices; Gen1 devices only advertise "_http._tcp"
+pub const SHELLY_SERVICES: [&str; 2] = ["_http._tcp.local", "_shelly._tcp.local"];
+
+/// Multicast group and port of mDNS
+const MDNS_GROUP: (Ipv4Addr, u16) = (Ipv4Addr::new(224, 0, 0, 251), 5353);
+
+/// DNS record type PTR
+const TYPE_PTR: u16 = 12;
+
+/// DNS class IN, with the bit asking for a unicast response
+const CLASS_IN_UNICAST: u16 = 0x8001;
+
+/// Addresses of the hosts answering a query of the services within `timeout`
+///
+/// The query is sent from an ephemeral port, so the responders answer it by
+/// unicast to that port (the "legacy unicast" of RFC 6762). The answers are
+/// not parsed: it is their senders, which are looked for.
+pub async fn responders(services: &[&str], timeout: Duration) -> Result<Vec<IpAddr>, String> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await
+        .map_err(|err| format!("mDNS socket can not be opened: {}", err))?;
+    socket.set_multicast_ttl_v4(255)
+        .map_err(|err| format!("mDNS socket can not be configured: {}", err))?;
+    socket.send_to(&query(services), MDNS_GROUP).await
+        .map_err(|err| format!("mDNS query can not be sent: {}", err))?;
+
+    let deadline = Instant::now() + timeout;
+    let mut found = vec![];
+    let mut buffer = [0u8; 9000];
+    loop {
+        let (length, sender) = match tokio::time::timeout_at(deadline,
+            socket.recv_from(&mut buffer)).await {
+            Ok(Ok(received)) => received,
+            // Timed out
+            _ => break,
+        };
+        if is_answer(&buffer[..length]) && !found.contains(&sender.ip()) {
+            found.push(sender.ip());
+        }
+    }
+    Ok(found)
+}
+
+/// Query of the PTR records of the services
+fn query(services: &[&str]) -> Vec<u8> {
+    let mut packet = vec![];
+    // Header: id 0, standard query, questions, no records
+    packet.extend_from_slice(&[0, 0, 0, 0]);
+    packet.extend_from_slice(&(services.len() as u16).to_be_bytes());
+    packet.extend_from_slice(&[0; 6]);
+    for service in services {
+        for label in service.split('.').filter(|label| !label.is_empty()) {
+            packet.push(label.len() as u8);
+            packet.extend_from_slice(label.as_bytes());
+        }
+        packet.push(0);
+        packet.extend_from_slice(&TYPE_PTR.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN_UNICAST.to_be_bytes());
+    }
+    packet
+}
+
+/// Whether the packet is a response with at least one answer
+fn is_answer(packet: &[u8]) -> bool {
+    packet.len() >= 12
+        && packet[2] & 0x80 != 0
+        && u16::from_be_bytes([packet[6], packet[7]]) > 0
+}
@@ -0,0 +1,266 @@
+use crate::config;
+use serde_json::Value;
+use std::path::Path;
+
+/// Version of the config schema read by this build
+pub const CONFIG_VERSION: u64 = 2;
+
+/// Upgrade of the config schema to the version `to`, from the one before
+struct Migration {
+    to: u64,
+    /// Fields renamed: objects having them (a JSON pointer, "*" for all
+    /// items of an array), the old and the new name
+    renamed: &'static [(&'static str, &'static str, &'static str)],
+    /// Fields filled with their former default, as the default changed:
+    /// objects having them, the name and the value (as JSON)
+    filled: &'static [(&'static str, &'static str, &'static str)],
+    /// Fields no longer read: objects having them, the name and what replaced it
+    removed: &'static [(&'static str, &'static str, &'static str)],
+}
+
+/// Upgrades of the config schema, oldest first
+const MIGRATIONS: &[Migration] = &[
+    // Unversioned configs, which are version 0
+    Migration {
+        to: 1,
+        renamed: &[
+            ("/local_store", "retention_days", "max_age_days"),
+            ("/response_archive", "retention_days", "max_age_days"),
+        ],
+        filled: &[],
+        removed: &[],
+    },
+    Migration {
+        to: 2,
+        renamed: &[
+            ("", "influxdb2", "database"),
+        ],
+        filled: &[],
+        removed: &[],
+    },
+];
+
+/// Version of the config schema, 0 if it is not versioned
+pub fn version_of(config: &Value) -> u64 {
+    config.get("version").and_then(Value::as_u64).unwrap_or(0)
+}
+
+/// Upgrade the config to the current schema; returns the changes made
+/// and the warnings about the options, which the user has to replace
+pub fn upgrade(config: &mut Value) -> Result<(Vec<String>, Vec<String>), String> {
+    let version = version_of(config);
+    if version > CONFIG_VERSION {
+        return Err(format!("the config is of version {}, newer than version {} \
+            of this logger", version, CONFIG_VERSION));
+    }
+    let mut changes = vec![];
+    let mut warnings = vec![];
+    for migration in MIGRATIONS.iter().filter(|migration| migration.to > version) {
+        for (pointer, old, new) in migration.renamed {
+            for (path, object) in objects(config, pointer) {
+                if let Some(value) = object.remove(*old) {
+                    if object.contains_key(*new) {
+                        warnings.push(format!("{}/{} is dropped, as {}/{} is set",
+                            path, old, path, new));
+                    } else {
+                        object.insert(new.to_string(), value);
+                        changes.push(format!("{}/{} renamed to {}", path, old, new));
+                    }
+                }
+            }
+        }
+        for (pointer, name, value) in migration.filled {
+            for (path, object) in objects(config, pointer) {
+                if !object.contains_key(*name) {
+                    object.insert(name.to_string(),
+                        serde_json::from_str(value).expect("migration values are JSON"));
+                    changes.push(format!("{}/{} set to {}, its former default", path, name, value));
+                }
+            }
+        }
+        for (pointer, name, replacement) in migration.removed {
+            for (path, object) in objects(config, pointer) {
+                if object.contains_key(*name) {
+                    warnings.push(format!("{}/{} is no longer read, {}", path, name, replacement));
+                }
+            }
+        }
+    }
+    if version < CONFIG_VERSION {
+        let object = config.as_object_mut().ok_or("the config is not a JSON object")?;
+        // First, where it is seen
+        let mut versioned = serde_json::Map::new();
+        versioned.insert("version".to_string(), CONFIG_VERSION.into());
+        versioned.extend(std::mem::take(object).into_iter().filter(|(name, _)| name != "version"));
+        *object = versioned;
+        changes.push(format!("version {} set to {}", version, CONFIG_VERSION));
+    }
+    Ok((changes, warnings))
+}
+
+/// Objects at the pointer, with their paths
+fn objects<'a>(config: &'a mut Value, pointer: &str)
+-> Vec<(String, &'a mut serde_json::Map<String, Value>)> {
+    let mut found = vec![(String::new(), config)];
+    for token in pointer.split('/').skip(1) {
+        found = found.into_iter().flat_map(|(path, value)| match (token, value) {
+            ("*", Value::Array(items)) => items.iter_mut().enumerate()
+                .map(|(index, item)| (format!("{}/{}", path, index), item))
+                .collect(),
+            (_, Value::Object(object)) => object.get_mut(token)
+                .map(|value| (format!("{}/{}", path, token), value))
+                .into_iter()
+                .collect(),
+            _ => vec![],
+        }).collect();
+    }
+    found.into_iter()
+        .filter_map(|(path, value)| value.as_object_mut().map(|object| (path, object)))
+        .collect()
+}
+
+/// Upgrade the config file to the current schema, keeping the original
+/// next to it (e.g. "config.json.v0"); only prints the changes if `dry_run`
+pub fn command(path: &Path, dry_run: bool) -> Result<(), String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|err| format!("{} can not be read: {}", path.display(), err))?;
+    let mut config: Value = config::read_settings(path)?;
+    let version = version_of(&config);
+    let (changes, warnings) = upgrade(&mut config)?;
+    for change in &changes {
+        println!("{}", change);
+    }
+    for warning in &warnings {
+        println!("warning: {}", warning);
+    }
+    if changes.is_empty() {
+        println!("{} is of the current version {}", path.display(), CONFIG_VERSION);
+        return Ok(());
+    }
+    if dry_run {
+        return Ok(());
+    }
+    // Rewriting the other formats would lose their comments
+    if config::Format::of(path) != config::Format::Json {
+        return Err(format!("{} is not rewritten, as only JSON configs are; \
+            make the changes above by hand", path.display()));
+    }
+
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(format!(".v{}", version));
+    std::fs::write(&backup, &text)
+        .map_err(|err| format!("{} can not be written: {}", Path::new(&backup).display(), err))?;
+    let mut upgraded = serde_json::to_string_pretty(&config).expect("JSON can be serialized");
+    upgraded.push('\n');
+    std::fs::write(path, upgraded)
+        .map_err(|err| format!("{} can not be written: {}", path.display(), err))?;
+    println!("{} upgraded to version {}, the original kept as {}", path.display(),
+        CONFIG_VERSION, Path::new(&backup).display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Config of version 0, in each format
+    const V0_JSON: &str = r#"{"local_store": {"path": "shelly.db", "retention_days": 30},
+        "influxdb2": {"host": "localhost", "port": 8086}}"#;
+    #[cfg(feature = "toml")]
+    const V0_TOML: &str = "[local_store]\npath = \"shelly.db\"\nretention_days = 30\n\n\
+        [influxdb2]\nhost = \"localhost\"\nport = 8086\n";
+    #[cfg(feature = "yaml")]
+    const V0_YAML: &str = "local_store:\n  path: shelly.db\n  retention_days: 30\n\
+        influxdb2:\n  host: localhost\n  port: 8086\n";
+
+    fn upgraded_v0() -> Value {
+        json!({"version": 2, "local_store": {"path": "shelly.db", "max_age_days": 30},
+            "database": {"host": "localhost", "port": 8086}})
+    }
+
+    /// Config file of the test, with the extension of its format
+    fn file(name: &str, text: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir()
+            .join(format!("shelly-logger-migrate-{}-{}", std::process::id(), name));
+        std::fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn version_0_is_upgraded_to_the_current_one() {
+        let mut config: Value = serde_json::from_str(V0_JSON).unwrap();
+        let (changes, warnings) = upgrade(&mut config).unwrap();
+        assert_eq!(config, upgraded_v0());
+        assert_eq!(changes, [
+            "/local_store/retention_days renamed to max_age_days",
+            "/influxdb2 renamed to database",
+            "version 0 set to 2",
+        ]);
+        assert!(warnings.is_empty());
+        // The version comes first, where it is seen
+        assert_eq!(config.as_object().unwrap().keys().next().unwrap(), "version");
+    }
+
+    #[test]
+    fn version_1_is_upgraded_to_the_current_one() {
+        let mut config = json!({"version": 1, "influxdb2": {"host": "localhost", "port": 8086}});
+        upgrade(&mut config).unwrap();
+        assert_eq!(config, json!({"version": 2, "database": {"host": "localhost", "port": 8086}}));
+    }
+
+    #[test]
+    fn renamed_options_set_already_are_kept() {
+        let mut config = json!({"version": 1, "influxdb2": {"port": 1}, "database": {"port": 2}});
+        let (_, warnings) = upgrade(&mut config).unwrap();
+        assert_eq!(config, json!({"version": 2, "database": {"port": 2}}));
+        assert_eq!(warnings, ["/influxdb2 is dropped, as /database is set"]);
+    }
+
+    #[test]
+    fn current_version_is_unchanged() {
+        let mut config = upgraded_v0();
+        let (changes, warnings) = upgrade(&mut config).unwrap();
+        assert_eq!(config, upgraded_v0());
+        assert!(changes.is_empty() && warnings.is_empty());
+        assert!(upgrade(&mut json!({"version": CONFIG_VERSION + 1})).is_err());
+    }
+
+    #[test]
+    fn json_files_are_rewritten_keeping_the_original() {
+        let path = file("config.json", V0_JSON);
+        command(&path, false).unwrap();
+        assert_eq!(config::read_settings(&path).unwrap(), upgraded_v0());
+        let mut backup = path.clone().into_os_string();
+        backup.push(".v0");
+        assert_eq!(std::fs::read_to_string(&backup).unwrap(), V0_JSON);
+        // Upgraded already
+        command(&path, false).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&backup).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "toml")]
+    fn toml_files_are_upgraded_but_not_rewritten() {
+        let path = file("config.toml", V0_TOML);
+        let mut config = config::read_settings(&path).unwrap();
+        upgrade(&mut config).unwrap();
+        assert_eq!(config, upgraded_v0());
+        assert!(command(&path, false).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), V0_TOML);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "yaml")]
+    fn yaml_files_are_upgraded_but_not_rewritten() {
+        let path = file("config.yaml", V0_YAML);
+        let mut config = config::read_settings(&path).unwrap();
+        upgrade(&mut config).unwrap();
+        assert_eq!(config, upgraded_v0());
+        assert!(command(&path, false).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), V0_YAML);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,303 @@
+use crate::point::Datum;
+use crate::secret::Secret;
+use crate::sink::{self, Sink};
+use serde::Deserialize;
+use std::sync::mpsc::Receiver;
+
+#[cfg(feature = "mqtt")]
+use {
+    crate::plug,
+    crate::point::Measurement,
+    crate::signals,
+    log::{debug, info, warn},
+    rumqttc::{Client, Event, MqttOptions, Outgoing, Packet, QoS},
+    std::collections::{HashMap, HashSet},
+    std::sync::Arc,
+    std::sync::mpsc::channel,
+    std::thread::JoinHandle,
+    std::time::Duration,
+};
+
+/// MQTT sink configuration
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(not(feature = "mqtt"), allow(dead_code))]
+pub struct Config {
+
+    /// Host-name or IP of the broker
+    pub host: String,
+
+    #[serde(default = "Config::default_port")]
+    pub port: u16,
+
+    #[serde(default = "Config::default_client_id")]
+    pub client_id: String,
+
+    pub username: Option<String>,
+
+    pub password: Option<Secret>,
+
+    /// Value of the "{prefix}" placeholder of the topic template
+    #[serde(default = "Config::default_topic_prefix")]
+    pub topic_prefix: String,
+
+    /// Topic of the data-points, with the placeholders "{prefix}", "{group}",
+    /// "{device}", "{host}" and "{measurement}"
+    #[serde(default = "Config::default_topic")]
+    pub topic: String,
+
+    /// Shape of the published data-points
+    #[serde(default)]
+    pub payload: Payload,
+
+    /// Publish Home Assistant discovery configs, so that the devices
+    /// appear in Home Assistant (and its Energy dashboard) automatically
+    #[serde(default = "Config::default_home_assistant_discovery")]
+    pub home_assistant_discovery: bool,
+
+    /// Topic prefix of the Home Assistant discovery
+    #[serde(default = "Config::default_discovery_prefix")]
+    pub discovery_prefix: String,
+}
+
+impl Config {
+
+    fn default_port() -> u16 { 1883 }
+
+    fn default_client_id() -> String { "shelly-logger".to_string() }
+
+    fn default_topic_prefix() -> String { "shelly-logger".to_string() }
+
+    fn default_topic() -> String { "{prefix}/{device}/{measurement}".to_string() }
+
+    fn default_home_assistant_discovery() -> bool { true }
+
+    fn default_discovery_prefix() -> String { "homeassistant".to_string() }
+}
+
+/// Shape of the published data-points
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Payload {
+    /// Just the value, e.g. "12.5"
+    #[default]
+    Value,
+    /// JSON object with the value, time, device, group and measurement
+    Json,
+}
+
+/// Group of devices without a configured one
+#[cfg(feature = "mqtt")]
+const DEFAULT_GROUP: &str = "default";
+
+/// Requests queued for the broker before publishing blocks
+#[cfg(feature = "mqtt")]
+const QUEUE_CAPACITY: usize = 100;
+
+/// Delay before reconnecting to an unavailable broker
+#[cfg(feature = "mqtt")]
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Time allowed for publishing the queued messages when stopping
+#[cfg(feature = "mqtt")]
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Make the name usable as an MQTT topic level and a Home Assistant object id
+#[cfg(feature = "mqtt")]
+fn object_id(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+        .collect()
+}
+
+/// Home Assistant classification of the measurement:
+/// device class, unit (if any) and state class
+#[cfg(feature = "mqtt")]
+fn home_assistant_class(measurement: Measurement)
+-> (Option<&'static str>, Option<&'static str>, &'static str)
+{
+    match measurement {
+        Measurement::instantaneous_consumption_in_w => (Some("power"), Some("W"), "measurement"),
+        // Energy of a single minute is not a total, so it is not an energy sensor
+        Measurement::last_minute_consumption_in_wh => (None, Some("Wh"), "measurement"),
+        // Counters which reset (on reboot, at midnight) are handled by "total_increasing"
+        Measurement::consumption_since_reboot_in_wh =>
+            (Some("energy"), Some("Wh"), "total_increasing"),
+        Measurement::consumption_today_in_wh => (Some("energy"), Some("Wh"), "total_increasing"),
+        Measurement::power_delta_w_per_s => (None, Some("W/s"), "measurement"),
+        // The currency is known only from the config
+        Measurement::last_minute_cost => (None, None, "measurement"),
+        Measurement::voltage_in_v => (Some("voltage"), Some("V"), "measurement"),
+        Measurement::temperature_in_c => (Some("temperature"), Some("°C"), "measurement"),
+        Measurement::current_in_a => (Some("current"), Some("A"), "measurement"),
+        Measurement::power_factor => (Some("power_factor"), None, "measurement"),
+        Measurement::returned_since_reboot_in_wh =>
+            (Some("energy"), Some("Wh"), "total_increasing"),
+        // 1 or 0
+        Measurement::overtemperature | Measurement::relay_on => (None, None, "measurement"),
+    }
+}
+
+impl Sink for Config {
+    #[cfg_attr(not(feature = "mqtt"), allow(unused_variables))]
+    fn spawn(self: Box<Self>, context: &sink::Context, data_receiver: Receiver<Datum>)
+    -> Result<sink::Handle, String>
+    {
+        #[cfg(feature = "mqtt")]
+        return Publisher::spawn(*self, &context.app_config.shelly_plugs, data_receiver);
+        #[cfg(not(feature = "mqtt"))]
+        return Err(format!("MQTT broker {} needs the 'mqtt' feature", self.host));
+    }
+}
+
+/// Publishes data-points to an MQTT broker
+#[cfg(feature = "mqtt")]
+pub struct Publisher {
+    mqtt_config: Config,
+    client: Client,
+    /// Group of each device, by name
+    groups: HashMap<Arc<str>, String>,
+    /// Devices and measurements whose discovery config was published
+    discovered: HashSet<(Arc<str>, Measurement)>,
+}
+
+#[cfg(feature = "mqtt")]
+impl Publisher {
+
+    pub fn spawn(mqtt_config: Config, shelly_plug_configs: &[plug::Config],
+        data_receiver: Receiver<Datum>)
+    -> Result<JoinHandle<Result<(),String>>, String>
+    {
+        for placeholder in ["{device}", "{measurement}"] {
+            if !mqtt_config.topic.contains(placeholder) {
+                return Err(format!("MQTT topic '{}' must contain {}, \
+                    so that each device and measurement has its own topic",
+                    mqtt_config.topic, placeholder));
+            }
+        }
+        let groups = shelly_plug_configs.iter()
+            .map(|shelly_plug_config| (shelly_plug_config.name.clone(),
+                shelly_plug_config.group.clone().unwrap_or_else(|| DEFAULT_GROUP.to_string())))
+            .collect();
+
+        let mut options = MqttOptions::new(
+            mqtt_config.client_id.clone(), mqtt_config.host.clone(), mqtt_config.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        if let Some(username) = &mqtt_config.username {
+            options.set_credentials(username.clone(),
+                mqtt_config.password.as_ref().map(Secret::expose).unwrap_or_default());
+        }
+        let (client, mut connection) = Client::new(options, QUEUE_CAPACITY);
+
+        // The connection makes progress (and reconnects) only while iterated
+        let host = mqtt_config.host.clone();
+        let (disconnected_sender, disconnected) = channel::<()>();
+        std::thread::spawn(move || {
+            for event in connection.iter() {
+                match event {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) =>
+                        info!("Connection to MQTT broker {} established.", host),
+                    // Sent after all the queued messages
+                    Ok(Event::Outgoing(Outgoing::Disconnect)) => {
+                        let _ = disconnected_sender.send(());
+                        return;
+                    },
+                    Ok(_) => (),
+                    Err(err) => {
+                        warn!("MQTT broker {} is not available, reconnecting in {}s: {}",
+                            host, RECONNECT_DELAY.as_secs(), err);
+                        signals::sleep(RECONNECT_DELAY);
+                    }
+                }
+            }
+        });
+
+        let mut publisher = Publisher {
+            mqtt_config, client, groups, discovered: HashSet::new() };
+        Ok(std::thread::spawn(move || {
+            for datum in data_receiver {
+                publisher.publish(&datum)?;
+            }
+            debug!("all meters stopped, stopping");
+            let published = publisher.client.disconnect().is_ok()
+                && disconnected.recv_timeout(FLUSH_TIMEOUT).is_ok();
+            if !published {
+                warn!("not all data-points could be published to MQTT broker {} before stopping",
+                    publisher.mqtt_config.host);
+            }
+            Ok(())
+        }))
+    }
+
+    /// Group of the device
+    fn group(&self, datum: &Datum) -> &str {
+        self.groups.get(&datum.device_name).map(String::as_str).unwrap_or(DEFAULT_GROUP)
+    }
+
+    /// Topic with the values of the device's measurement
+    fn state_topic(&self, datum: &Datum) -> String {
+        self.mqtt_config.topic
+            .replace("{prefix}", &self.mqtt_config.topic_prefix)
+            .replace("{group}", &object_id(self.group(datum)))
+            .replace("{device}", &object_id(&datum.device_name))
+            .replace("{host}", &object_id(&datum.device_host))
+            .replace("{measurement}", &datum.measurement.to_string())
+    }
+
+    /// Message with the data-point
+    fn payload(&self, datum: &Datum) -> String {
+        match self.mqtt_config.payload {
+            Payload::Value => datum.value.to_string(),
+            Payload::Json => serde_json::json!({
+                "value": datum.value,
+                "measured_on": datum.measured_on.to_rfc3339(),
+                "measurement": datum.measurement.to_string(),
+                "device": datum.device_name.as_ref(),
+                "host": datum.device_host.as_ref(),
+                "group": self.group(datum),
+                "valid": datum.valid,
+            }).to_string(),
+        }
+    }
+
+    /// Publish the data-point, preceded by its discovery config the first time
+    fn publish(&mut self, datum: &Datum) -> Result<(), String> {
+        if self.mqtt_config.home_assistant_discovery
+            && self.discovered.insert((datum.device_name.clone(), datum.measurement)) {
+            self.publish_discovery(datum)?;
+        }
+        self.client.publish(self.state_topic(datum), QoS::AtLeastOnce, true,
+                self.payload(datum))
+            .map_err(|err| format!("MQTT client stopped: {}", err))
+    }
+
+    /// Publish the Home Assistant discovery config of the device's measurement
+    fn publish_discovery(&self, datum: &Datum) -> Result<(), String> {
+        let device_id = object_id(&datum.device_name);
+        let (device_class, unit, state_class) = home_assistant_class(datum.measurement);
+        let mut discovery = serde_json::json!({
+            "name": datum.measurement.to_string().replace('_', " "),
+            "unique_id": format!("shelly_logger_{}_{}", device_id, datum.measurement),
+            "state_topic": self.state_topic(datum),
+            "state_class": state_class,
+            "device": {
+                "identifiers": [format!("shelly_logger_{}", device_id)],
+                "name": datum.device_name.as_ref(),
+                "manufacturer": "Shelly",
+                "configuration_url": format!("http://{}/", datum.device_host),
+            },
+        });
+        if let Some(device_class) = device_class {
+            discovery["device_class"] = device_class.into();
+        }
+        if let Some(unit) = unit {
+            discovery["unit_of_measurement"] = unit.into();
+        }
+        if self.mqtt_config.payload == Payload::Json {
+            discovery["value_template"] = "{{ value_json.value }}".into();
+        }
+        let topic = format!("{}/sensor/{}/{}/config",
+            self.mqtt_config.discovery_prefix, device_id, datum.measurement);
+        self.client.publish(topic, QoS::AtLeastOnce, true, discovery.to_string())
+            .map_err(|err| format!("MQTT client stopped: {}", err))
+    }
+}
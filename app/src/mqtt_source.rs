@@ -0,0 +1,237 @@
+use crate::secret::Secret;
+use serde::Deserialize;
+
+#[cfg(feature = "mqtt")]
+use {
+    crate::plug,
+    crate::point::{Datum, Measurement::*},
+    crate::signals,
+    crate::state::SharedState,
+    chrono::Utc,
+    log::{debug, info, warn},
+    rumqttc::{Client, Event, MqttOptions, Packet, QoS, RecvTimeoutError},
+    std::collections::HashMap,
+    std::thread::JoinHandle,
+    std::time::Duration,
+    tokio::sync::mpsc::UnboundedSender,
+};
+
+/// Configuration of the broker, to which the plugs publish their telemetry
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(not(feature = "mqtt"), allow(dead_code))]
+pub struct Config {
+
+    /// Host-name or IP of the broker
+    pub host: String,
+
+    #[serde(default = "Config::default_port")]
+    pub port: u16,
+
+    #[serde(default = "Config::default_client_id")]
+    pub client_id: String,
+
+    pub username: Option<String>,
+
+    pub password: Option<Secret>,
+}
+
+impl Config {
+
+    fn default_port() -> u16 { 1883 }
+
+    fn default_client_id() -> String { "shelly-logger-source".to_string() }
+}
+
+/// Delay before reconnecting to an unavailable broker
+#[cfg(feature = "mqtt")]
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Telemetry of a channel, published under the topic prefix of the plug
+#[cfg(feature = "mqtt")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Telemetry {
+    /// Gen1 instantaneous power in W
+    Gen1Power,
+    /// Gen1 energy counter in Watt-minutes
+    Gen1Energy,
+    /// Gen2 "Switch.GetStatus" object
+    Gen2Status,
+}
+
+#[cfg(feature = "mqtt")]
+impl Telemetry {
+
+    const ALL: [Telemetry; 3] = [Telemetry::Gen1Power, Telemetry::Gen1Energy, Telemetry::Gen2Status];
+
+    /// Topic of the telemetry of the channel, after the prefix
+    fn topic(&self, channel: u32) -> String {
+        match self {
+            Telemetry::Gen1Power => format!("relay/{}/power", channel),
+            Telemetry::Gen1Energy => format!("relay/{}/energy", channel),
+            Telemetry::Gen2Status => format!("status/switch:{}", channel),
+        }
+    }
+}
+
+/// Plug fed by the broker
+#[cfg(feature = "mqtt")]
+struct Plug {
+    config: plug::Config,
+    /// Minute counters sent, to send each minute once
+    minute_counters: plug::MinuteCounters,
+    /// Fields missing in the status, which were warned about
+    missing: Vec<&'static str>,
+    power_delta: plug::PowerDelta,
+}
+
+/// Derives data-points from the telemetry, which Shelly plugs publish
+/// to an MQTT broker, instead of polling them over HTTP
+#[cfg(feature = "mqtt")]
+pub struct Subscriber {
+    plugs: Vec<Plug>,
+    /// Plugs (their index) and telemetry by topic
+    topics: HashMap<String, (usize, Telemetry)>,
+    state: SharedState,
+    data_sender: UnboundedSender<Datum>,
+}
+
+#[cfg(feature = "mqtt")]
+impl Subscriber {
+
+    pub fn spawn(source_config: Config, shelly_plug_configs: Vec<plug::Config>,
+        state: SharedState, data_sender: UnboundedSender<Datum>)
+    -> JoinHandle<Result<(),String>>
+    {
+        let mut options = MqttOptions::new(
+            source_config.client_id.clone(), source_config.host.clone(), source_config.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        if let Some(username) = &source_config.username {
+            options.set_credentials(username.clone(),
+                source_config.password.as_ref().map(Secret::expose).unwrap_or_default());
+        }
+        let (client, mut connection) = Client::new(options, 100);
+
+        let plugs: Vec<Plug> = shelly_plug_configs.into_iter()
+            .filter(|config| config.mode() == plug::Mode::Mqtt)
+            .map(|config| Plug { config, minute_counters: plug::MinuteCounters::default(),
+                missing: vec![],
+                power_delta: plug::PowerDelta::default() })
+            .collect();
+        let topics = plugs.iter().enumerate()
+            .flat_map(|(index, plug)| {
+                let prefix = plug.config.mqtt_topic.as_deref().unwrap_or_default()
+                    .trim_end_matches('/').to_string();
+                Telemetry::ALL.map(|telemetry| (
+                    format!("{}/{}", prefix, telemetry.topic(plug.config.channel)),
+                    (index, telemetry)))
+            })
+            .collect();
+        let mut subscriber = Subscriber { plugs, topics, state, data_sender };
+        std::thread::spawn(move || {
+            loop {
+                if signals::terminating() {
+                    debug!("terminating, no longer subscribed to {}", source_config.host);
+                    return Ok(());
+                }
+                let event = match connection.recv_timeout(signals::TERMINATION_CHECK_INTERVAL) {
+                    Ok(event) => event,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => return Ok(()),
+                };
+                match event {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        info!("Subscribing to the telemetry of {} plugs at {}",
+                            subscriber.plugs.len(), source_config.host);
+                        // Subscriptions do not survive reconnects of a clean session
+                        for topic in subscriber.topics.keys() {
+                            if let Err(err) = client.subscribe(topic, QoS::AtMostOnce) {
+                                return Err(format!("MQTT client stopped: {}", err));
+                            }
+                        }
+                    },
+                    Ok(Event::Incoming(Packet::Publish(publish))) => {
+                        for datum in subscriber.datums(&publish.topic, &publish.payload) {
+                            if subscriber.data_sender.send(datum).is_err() {
+                                debug!("channel to the DB thread closed, stopping");
+                                return Ok(());
+                            }
+                        }
+                    },
+                    Ok(_) => (),
+                    Err(err) => {
+                        warn!("MQTT broker {} is not available, reconnecting in {}s: {}",
+                            source_config.host, RECONNECT_DELAY.as_secs(), err);
+                        signals::sleep(RECONNECT_DELAY);
+                    }
+                }
+            }
+        })
+    }
+
+    /// Data-points derived from a message of a plug
+    fn datums(&mut self, topic: &str, payload: &[u8]) -> Vec<Datum> {
+        let (plug, telemetry) = match self.topics.get(topic) {
+            Some((index, telemetry)) => (&mut self.plugs[*index], *telemetry),
+            None => return vec![],
+        };
+        let text = String::from_utf8_lossy(payload);
+        let instantaneous = plug.config.instantaneous_meter_interval().is_some();
+
+        let mut datums = vec![];
+        match telemetry {
+            Telemetry::Gen1Power if instantaneous => match text.trim().parse::<f32>() {
+                Ok(power) => datums.push(plug.config.datum(instantaneous_consumption_in_w, power)),
+                Err(_) => warn!("{} published unexpected power '{}'", topic, text),
+            },
+            Telemetry::Gen1Power => (),
+            Telemetry::Gen1Energy => match text.trim().parse::<f32>() {
+                Ok(watt_minutes) => {
+                    let total_wh = watt_minutes / 60.0;
+                    datums.push(plug.config.datum(consumption_since_reboot_in_wh, total_wh));
+                    let day_total_wh = self.state.lock()
+                        .expect("internal error, state lock poisoned")
+                        .record_total(&plug.config.name, Utc::now(), total_wh);
+                    datums.push(plug.config.datum(consumption_today_in_wh, day_total_wh as f32));
+                },
+                Err(_) => warn!("{} published unexpected energy '{}'", topic, text),
+            },
+            Telemetry::Gen2Status => match plug::Measurement::parse(payload, plug.config.channel) {
+                // Published data can not be backed off from, only skipped
+                Ok((_, measurement)) if !measurement.is_valid()
+                    && plug.config.invalid_samples != plug::InvalidSamples::Flag =>
+                    debug!("{} published an invalid measurement, skipped", topic),
+                Ok((_, measurement)) => {
+                    measurement.report_missing(topic, &mut plug.missing);
+                    if instantaneous {
+                        datums.extend(measurement.datum(&plug.config, instantaneous_consumption_in_w));
+                    }
+                    // Status is published on every change, the counters change once a minute
+                    let counters_updated_on = measurement.counters_minute(&plug.config, Utc::now());
+                    if let Some(minutes) = plug.minute_counters
+                        .unsent(&plug.config, &measurement, counters_updated_on) {
+                        datums.extend(minutes);
+                        datums.extend(measurement.datum(&plug.config, consumption_since_reboot_in_wh));
+                        if let Some(total_wh) = measurement.consumption_since_reboot_in_wh() {
+                            let day_total_wh = self.state.lock()
+                                .expect("internal error, state lock poisoned")
+                                .record_total(&plug.config.name, counters_updated_on, total_wh);
+                            let mut day_total = plug.config.datum(
+                                consumption_today_in_wh, day_total_wh as f32);
+                            day_total.measured_on = counters_updated_on;
+                            day_total.valid = measurement.is_valid();
+                            datums.push(day_total);
+                        }
+                    }
+                },
+                Err(err) => warn!("{} published unexpected status: {}", topic, err),
+            },
+        }
+        if plug.config.power_delta {
+            let delta = datums.iter()
+                .find(|datum| datum.measurement == instantaneous_consumption_in_w)
+                .and_then(|instantaneous| plug.power_delta.datum(&plug.config, instantaneous));
+            datums.extend(delta);
+        }
+        datums
+    }
+}
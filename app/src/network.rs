@@ -0,0 +1,554 @@
+use crate::plug;
+use crate::secret::Secret;
+use base64::Engine;
+use log::warn;
+use serde::{Deserialize, Deserializer};
+use sha2::{Digest, Sha256};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// Range of IP addresses in the CIDR notation, e.g. `192.168.1.0/24`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Network {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+
+    /// Whether the address is in the range
+    pub fn contains(&self, address: IpAddr) -> bool {
+        // IPv4 addresses may be mapped into IPv6 ones
+        let address = match address {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(address),
+            v4 => v4,
+        };
+        match (self.address, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) =>
+                prefix_matches(&network.octets(), &address.octets(), self.prefix),
+            (IpAddr::V6(network), IpAddr::V6(address)) =>
+                prefix_matches(&network.octets(), &address.octets(), self.prefix),
+            _ => false,
+        }
+    }
+}
+
+/// Whether the first `prefix` bits are the same
+fn prefix_matches(network: &[u8], address: &[u8], prefix: u8) -> bool {
+    let prefix = prefix as usize;
+    let whole_bytes = prefix / 8;
+    if network[..whole_bytes] != address[..whole_bytes] {
+        return false;
+    }
+    let remaining_bits = prefix % 8;
+    if remaining_bits == 0 {
+        return true;
+    }
+    let mask = 0xFFu8 << (8 - remaining_bits);
+    network[whole_bytes] & mask == address[whole_bytes] & mask
+}
+
+impl std::str::FromStr for Network {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Network, String> {
+        let (address, prefix) = text.split_once('/')
+            .ok_or_else(|| format!("'{}' is not a network like 192.168.1.0/24", text))?;
+        let address: IpAddr = address.parse()
+            .map_err(|_| format!("'{}' is not an IP address", address))?;
+        let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+        let prefix: u8 = prefix.parse().ok()
+            .filter(|prefix| *prefix <= max_prefix)
+            .ok_or_else(|| format!("'{}' is not a prefix length up to {}", prefix, max_prefix))?;
+        Ok(Network { address, prefix })
+    }
+}
+
+impl std::fmt::Display for Network {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+impl<'de> Deserialize<'de> for Network {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Network, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Async HTTP client for the devices, which only connects to the allowed networks
+#[derive(Clone)]
+pub struct DeviceClient {
+    client: reqwest::Client,
+    /// Networks the devices must be in, if restricted
+    allowed_networks: Option<Arc<Vec<Network>>>,
+    timeout: Duration,
+    /// Login of the device the client is for, if it has one
+    login: Option<Arc<Login>>,
+    /// Whether the device is reached over HTTPS
+    https: bool,
+}
+
+impl DeviceClient {
+
+    /// Client connecting anywhere if there are no `allowed_networks`
+    pub fn new(timeout: Duration, allowed_networks: Option<Vec<Network>>) -> DeviceClient {
+        let allowed_networks = allowed_networks.map(Arc::new);
+        // Redirects (e.g. to an IP address, which is not resolved) and proxies
+        // would reach hosts the allowed networks were not checked for
+        let mut client_builder = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .no_proxy();
+        if let Some(allowed_networks) = &allowed_networks {
+            // Checked when connecting, so that a changed DNS record is also caught
+            client_builder = client_builder.dns_resolver(Arc::new(
+                AllowedResolver(allowed_networks.clone())));
+        }
+        let client = client_builder.build()
+            .expect("internal error, device client could not be built");
+        DeviceClient { client, allowed_networks, timeout, login: None, https: false }
+    }
+
+    /// Same client with another timeout
+    pub fn with_timeout(&self, timeout: Duration) -> DeviceClient {
+        DeviceClient { timeout, ..self.clone() }
+    }
+
+    /// Same client for the device, with its login and scheme
+    pub fn for_device(&self, shelly_plug_config: &plug::Config) -> DeviceClient {
+        let login = shelly_plug_config.password.as_ref().map(|password| Arc::new(Login {
+            username: shelly_plug_config.username.clone()
+                .unwrap_or_else(|| DEFAULT_USERNAME.to_string()),
+            password: password.clone(),
+            scheme: Mutex::default(),
+        }));
+        DeviceClient { login, https: shelly_plug_config.https, ..self.clone() }
+    }
+
+    /// URL of the path (e.g. "/meter/0") on the device at the host
+    pub fn url(&self, host: &str, path: &str) -> String {
+        format!("{}://{}{}", if self.https { "https" } else { "http" }, host, path)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// GET request to the device
+    pub fn get(&self, url: &str) -> Request {
+        Request { client: self.clone(), url: url.to_string(), query: vec![] }
+    }
+}
+
+/// Resolver of the host names of the devices, refusing those outside of
+/// the allowed networks
+struct AllowedResolver(Arc<Vec<Network>>);
+
+impl reqwest::dns::Resolve for AllowedResolver {
+    fn resolve(&self, name: hyper::client::connect::dns::Name) -> reqwest::dns::Resolving {
+        let allowed_networks = self.0.clone();
+        Box::pin(async move {
+            let addresses: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0)).await?
+                .collect();
+            let addresses = allowed(name.as_str(), addresses, &allowed_networks)?;
+            Ok(Box::new(addresses.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+/// User name of the devices, unless configured otherwise (Gen2 devices
+/// have no other)
+const DEFAULT_USERNAME: &str = "admin";
+
+/// Login of a device, with the way the device asked for it
+struct Login {
+    username: String,
+    password: Secret,
+    /// Scheme of the last challenge of the device, answered right away by
+    /// the next requests; none before the first one
+    scheme: Mutex<Option<Scheme>>,
+}
+
+/// Authentication scheme asked for by a device
+enum Scheme {
+    /// Basic authentication of Gen1 devices
+    Basic,
+    /// Digest authentication (SHA-256) of Gen2 devices
+    Digest(Challenge),
+}
+
+/// Digest challenge of a device
+struct Challenge {
+    realm: String,
+    nonce: String,
+    opaque: Option<String>,
+    /// Requests authorized by the nonce so far
+    count: u32,
+}
+
+impl Login {
+
+    /// Learn the scheme from the "WWW-Authenticate" header of a refused request
+    fn challenged(&self, header: &str) -> Result<(), String> {
+        let (scheme, params) = header.trim().split_once(' ').unwrap_or((header, ""));
+        let scheme = if scheme.eq_ignore_ascii_case("basic") {
+            Scheme::Basic
+        } else if scheme.eq_ignore_ascii_case("digest") {
+            let params = digest_params(params);
+            let param = |name: &str| params.iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.clone());
+            let algorithm = param("algorithm").unwrap_or_else(|| "MD5".to_string());
+            if !algorithm.eq_ignore_ascii_case("SHA-256") {
+                return Err(format!("digest algorithm {} is not supported", algorithm));
+            }
+            Scheme::Digest(Challenge {
+                realm: param("realm").unwrap_or_default(),
+                nonce: param("nonce").ok_or("digest challenge has no nonce")?,
+                opaque: param("opaque"),
+                count: 0,
+            })
+        } else {
+            return Err(format!("authentication '{}' is not supported", scheme));
+        };
+        *self.scheme.lock().expect("internal error, login lock poisoned") = Some(scheme);
+        Ok(())
+    }
+
+    /// "Authorization" header of the request, by the last challenge
+    fn authorization(&self, request: &reqwest::Request) -> Option<String> {
+        let mut scheme = self.scheme.lock().expect("internal error, login lock poisoned");
+        match scheme.as_mut()? {
+            Scheme::Basic => Some(format!("Basic {}", base64::engine::general_purpose::STANDARD
+                .encode(format!("{}:{}", self.username, self.password.expose())))),
+            Scheme::Digest(challenge) => {
+                let url = request.url();
+                let uri = match url.query() {
+                    Some(query) => format!("{}?{}", url.path(), query),
+                    None => url.path().to_string(),
+                };
+                challenge.count += 1;
+                let count = format!("{:08x}", challenge.count);
+                let client_nonce = format!("{:08x}", SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().subsec_nanos());
+                let response = self.digest_response(challenge, request.method().as_str(), &uri,
+                    &count, &client_nonce);
+                let mut authorization = format!("Digest username=\"{}\", realm=\"{}\", \
+                    nonce=\"{}\", uri=\"{}\", algorithm=SHA-256, response=\"{}\", qop=auth, \
+                    nc={}, cnonce=\"{}\"", self.username, challenge.realm, challenge.nonce, uri,
+                    response, count, client_nonce);
+                if let Some(opaque) = &challenge.opaque {
+                    authorization += &format!(", opaque=\"{}\"", opaque);
+                }
+                Some(authorization)
+            },
+        }
+    }
+
+    /// Response to the digest challenge (with "qop=auth") for the request
+    fn digest_response(&self, challenge: &Challenge, method: &str, uri: &str, count: &str,
+        client_nonce: &str) -> String
+    {
+        let user_hash = sha256_hex(&format!("{}:{}:{}",
+            self.username, challenge.realm, self.password.expose()));
+        let request_hash = sha256_hex(&format!("{}:{}", method, uri));
+        sha256_hex(&format!("{}:{}:{}:{}:auth:{}",
+            user_hash, challenge.nonce, count, client_nonce, request_hash))
+    }
+}
+
+/// Parameters of a digest challenge, e.g. `realm="shellyplus1-a8032ab1a2b3", nonce="60dc59c6"`
+fn digest_params(params: &str) -> Vec<(String, String)> {
+    let mut parsed = vec![];
+    let mut rest = params.trim();
+    while let Some((key, value)) = rest.split_once('=') {
+        let key = key.trim().trim_start_matches(',').trim().to_string();
+        let value = value.trim_start();
+        let (value, remainder) = match value.strip_prefix('"') {
+            Some(quoted) => quoted.split_once('"').unwrap_or((quoted, "")),
+            None => value.split_once(',').unwrap_or((value, "")),
+        };
+        parsed.push((key, value.trim().to_string()));
+        rest = remainder.trim_start().trim_start_matches(',');
+    }
+    parsed
+}
+
+/// SHA-256 hash of the text, as hexadecimal digits
+fn sha256_hex(text: &str) -> String {
+    Sha256::digest(text.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// GET request to a device, which logs in when the device asks for it
+pub struct Request {
+    client: DeviceClient,
+    url: String,
+    query: Vec<(String, String)>,
+}
+
+/// Request to a device which failed
+#[derive(Debug)]
+pub enum Error {
+    /// The device responded with an error status
+    Status(Box<reqwest::Response>),
+    /// The device could not be reached, or responded with no HTTP
+    Transport(String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Status(response) => write!(f, "{}: status code {}",
+                response.url(), response.status().as_u16()),
+            Error::Transport(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Error {
+        Error::Transport(err.to_string())
+    }
+}
+
+impl Request {
+
+    pub fn query(mut self, param: &str, value: &str) -> Request {
+        self.query.push((param.to_string(), value.to_string()));
+        self
+    }
+
+    /// Send the request, answering the last challenge of the device right
+    /// away; it is sent again if the device challenges it (anew)
+    pub async fn call(self) -> Result<reqwest::Response, Error> {
+        let DeviceClient { client, allowed_networks, timeout, login, .. } = &self.client;
+        let request = client.get(&self.url).query(&self.query).timeout(*timeout).build()?;
+        // The resolver is not asked for IP addresses
+        if let (Some(allowed_networks), Some(host)) = (allowed_networks, request.url().host_str()) {
+            if let Ok(address) = host.trim_start_matches('[').trim_end_matches(']').parse() {
+                allowed(host, vec![SocketAddr::new(address, 0)], allowed_networks)
+                    .map_err(Error::Transport)?;
+            }
+        }
+        let login = match login {
+            Some(login) => login,
+            None => return checked(client.execute(request).await?),
+        };
+        let authorized = |request: &reqwest::Request| {
+            let mut request = request.try_clone()
+                .expect("internal error, GET request can not be cloned");
+            if let Some(authorization) = login.authorization(&request) {
+                if let Ok(authorization) = authorization.parse() {
+                    request.headers_mut().insert(reqwest::header::AUTHORIZATION, authorization);
+                }
+            }
+            request
+        };
+        let refused = match checked(client.execute(authorized(&request)).await?) {
+            Err(Error::Status(refused)) if refused.status() == reqwest::StatusCode::UNAUTHORIZED =>
+                refused,
+            result => return result,
+        };
+        let challenge = refused.headers().get(reqwest::header::WWW_AUTHENTICATE)
+            .and_then(|header| header.to_str().ok())
+            .map(|header| login.challenged(header));
+        match challenge {
+            Some(Ok(())) => checked(client.execute(authorized(&request)).await?),
+            Some(Err(err)) => {
+                warn!("{} can not log in: {}", self.url, err);
+                Err(Error::Status(refused))
+            },
+            None => Err(Error::Status(refused)),
+        }
+    }
+}
+
+/// Response, unless its status is an error or a redirect, which is not followed
+fn checked(response: reqwest::Response) -> Result<reqwest::Response, Error> {
+    if response.status().is_redirection() || response.status().is_client_error()
+        || response.status().is_server_error() {
+        return Err(Error::Status(Box::new(response)));
+    }
+    Ok(response)
+}
+
+/// Addresses of the host in the allowed networks; refuses the host if there are none
+fn allowed(netloc: &str, addresses: Vec<SocketAddr>, allowed_networks: &[Network])
+-> Result<Vec<SocketAddr>, String>
+{
+    let (allowed, refused): (Vec<SocketAddr>, Vec<SocketAddr>) = addresses.into_iter()
+        .partition(|address| allowed_networks.iter().any(|network| network.contains(address.ip())));
+    if allowed.is_empty() {
+        let refused: Vec<String> = refused.iter().map(|address| address.ip().to_string()).collect();
+        return Err(format!("{} ({}) is outside the allowed networks", netloc, refused.join(", ")));
+    }
+    Ok(allowed)
+}
+
+/// Check the host (which may include a port) before starting; hosts which
+/// do not resolve now are checked when connecting
+pub fn check_host(host: &str, allowed_networks: &[Network]) -> Result<(), String> {
+    let addresses: Vec<SocketAddr> = match host.to_socket_addrs()
+        .or_else(|_| (host, 80).to_socket_addrs()) {
+        Ok(addresses) => addresses.collect(),
+        Err(_) => return Ok(()),
+    };
+    allowed(host, addresses, allowed_networks).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn login(username: &str, password: &str) -> Login {
+        Login {
+            username: username.to_string(),
+            password: serde_json::from_value(serde_json::Value::from(password)).unwrap(),
+            scheme: Mutex::default(),
+        }
+    }
+
+    fn get(url: &str) -> reqwest::Request {
+        reqwest::Request::new(reqwest::Method::GET, url.parse().unwrap())
+    }
+
+    /// Parameter of the "Authorization" header
+    fn param(authorization: &str, name: &str) -> Option<String> {
+        digest_params(authorization.strip_prefix("Digest ")?).into_iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value)
+    }
+
+    #[test]
+    fn digest_response_matches_rfc_7616() {
+        // Example of section 3.9.1, with SHA-256
+        let login = login("Mufasa", "Circle of Life");
+        login.challenged("Digest realm=\"http-auth@example.org\", qop=\"auth, auth-int\", \
+            algorithm=SHA-256, nonce=\"7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v\", \
+            opaque=\"FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS\"").unwrap();
+        let scheme = login.scheme.lock().unwrap();
+        let challenge = match scheme.as_ref() {
+            Some(Scheme::Digest(challenge)) => challenge,
+            _ => panic!("digest challenge was not learned"),
+        };
+        assert_eq!(login.digest_response(challenge, "GET", "/dir/index.html", "00000001",
+            "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ"),
+            "753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1");
+    }
+
+    #[test]
+    fn digest_authorization_answers_the_challenge() {
+        let login = login("admin", "secret");
+        login.challenged("Digest qop=\"auth\", realm=\"shellyplus1-a8032ab1a2b3\", \
+            nonce=\"60dc59c6\", algorithm=SHA-256, opaque=\"x1\"").unwrap();
+        let first = login.authorization(&get("http://192.0.2.1/rpc/Switch.GetStatus?id=0"))
+            .unwrap();
+        assert_eq!(param(&first, "username").as_deref(), Some("admin"));
+        assert_eq!(param(&first, "realm").as_deref(), Some("shellyplus1-a8032ab1a2b3"));
+        assert_eq!(param(&first, "uri").as_deref(), Some("/rpc/Switch.GetStatus?id=0"));
+        assert_eq!(param(&first, "nc").as_deref(), Some("00000001"));
+        assert_eq!(param(&first, "opaque").as_deref(), Some("x1"));
+        let second = login.authorization(&get("http://192.0.2.1/rpc/Shelly.GetStatus")).unwrap();
+        assert_eq!(param(&second, "nc").as_deref(), Some("00000002"));
+    }
+
+    #[test]
+    fn digest_params_keep_commas_in_quoted_values() {
+        assert_eq!(digest_params("realm=\"a, b\", qop=\"auth,auth-int\", algorithm=SHA-256, \
+            nonce=\"x=y,z\""), vec![
+            ("realm".to_string(), "a, b".to_string()),
+            ("qop".to_string(), "auth,auth-int".to_string()),
+            ("algorithm".to_string(), "SHA-256".to_string()),
+            ("nonce".to_string(), "x=y,z".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn basic_challenge_is_answered_by_the_password() {
+        let login = login("admin", "pw");
+        assert!(login.authorization(&get("http://192.0.2.1/meter/0")).is_none());
+        login.challenged("Basic realm=\"shelly\"").unwrap();
+        assert_eq!(login.authorization(&get("http://192.0.2.1/meter/0")).as_deref(),
+            Some("Basic YWRtaW46cHc="));
+    }
+
+    #[test]
+    fn other_digest_algorithms_are_refused() {
+        let login = login("admin", "pw");
+        assert!(login.challenged("Digest realm=\"x\", nonce=\"1\", algorithm=MD5").is_err());
+        assert!(login.challenged("Digest realm=\"x\", nonce=\"1\"").is_err());
+        assert!(login.challenged("Bearer realm=\"x\"").is_err());
+    }
+
+    fn network(text: &str) -> Network {
+        text.parse().unwrap()
+    }
+
+    fn address(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_are_in_ipv4_networks() {
+        let network = network("192.168.1.0/24");
+        assert!(network.contains(address("::ffff:192.168.1.20")));
+        assert!(!network.contains(address("::ffff:192.168.2.20")));
+        assert!(!network.contains(address("2001:db8::1")));
+    }
+
+    #[test]
+    fn whole_and_single_address_prefixes() {
+        assert!(network("0.0.0.0/0").contains(address("203.0.113.7")));
+        assert!(!network("0.0.0.0/0").contains(address("2001:db8::1")));
+        assert!(network("::/0").contains(address("2001:db8::1")));
+        assert!(network("192.168.1.20/32").contains(address("192.168.1.20")));
+        assert!(!network("192.168.1.20/32").contains(address("192.168.1.21")));
+        assert!(network("2001:db8::1/128").contains(address("2001:db8::1")));
+        assert!(!network("2001:db8::1/128").contains(address("2001:db8::2")));
+    }
+
+    #[test]
+    fn prefixes_not_on_byte_boundaries() {
+        assert!(network("10.0.0.0/13").contains(address("10.7.255.255")));
+        assert!(!network("10.0.0.0/13").contains(address("10.8.0.0")));
+        assert!(prefix_matches(&[0b1010_0000], &[0b1011_1111], 3));
+        assert!(!prefix_matches(&[0b1010_0000], &[0b1000_0000], 3));
+        assert!(network("2001:db8::/33").contains(address("2001:db8:7fff::1")));
+        assert!(!network("2001:db8::/33").contains(address("2001:db8:8000::1")));
+    }
+
+    #[test]
+    fn networks_are_parsed_with_their_prefix() {
+        assert_eq!(network("192.168.1.0/24").to_string(), "192.168.1.0/24");
+        assert_eq!(network("2001:db8::/32").to_string(), "2001:db8::/32");
+        assert!("192.168.1.0".parse::<Network>().is_err());
+        assert!("192.168.1.0/33".parse::<Network>().is_err());
+        assert!("2001:db8::/129".parse::<Network>().is_err());
+        assert!("192.168.1/24".parse::<Network>().is_err());
+        assert!("192.168.1.0/x".parse::<Network>().is_err());
+    }
+
+    #[test]
+    fn redirects_outside_the_allowed_networks_are_not_followed() {
+        use std::io::{Read, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        let device = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).unwrap();
+            stream.write_all(b"HTTP/1.1 302 Found\r\nLocation: http://192.0.2.1/status\r\n\
+                Content-Length: 0\r\nConnection: close\r\n\r\n").unwrap();
+        });
+
+        let client = DeviceClient::new(Duration::from_secs(5), Some(vec![network("127.0.0.0/8")]));
+        let url = client.url(&host, "/status");
+        let result = crate::runtime::block_on(client.get(&url).call());
+        device.join().unwrap();
+        match result {
+            Err(Error::Status(response)) => assert_eq!(response.status().as_u16(), 302),
+            other => panic!("redirect was followed: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use crate::point::Datum;
+use crate::secret::Secret;
+use crate::sink::{self, Sink};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::mpsc::Receiver;
+
+#[cfg(feature = "openhab")]
+use {
+    log::{debug, info, warn},
+    std::thread::JoinHandle,
+    std::time::Duration,
+};
+
+/// openHAB sink configuration
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(not(feature = "openhab"), allow(dead_code))]
+pub struct Config {
+
+    /// Base URL of openHAB, e.g. "http://openhab.local:8080"
+    pub url: String,
+
+    /// API token, if openHAB requires authentication
+    pub token: Option<Secret>,
+
+    /// Items updated with the data-points, by plug name and measurement;
+    /// other data-points are not sent
+    pub items: HashMap<String, HashMap<String, String>>,
+}
+
+impl Sink for Config {
+    #[cfg_attr(not(feature = "openhab"), allow(unused_variables))]
+    fn spawn(self: Box<Self>, _context: &sink::Context, data_receiver: Receiver<Datum>)
+    -> Result<sink::Handle, String>
+    {
+        #[cfg(feature = "openhab")]
+        return Ok(Updater::spawn(*self, data_receiver));
+        #[cfg(not(feature = "openhab"))]
+        return Err(format!("openHAB at {} needs the 'openhab' feature", self.url));
+    }
+}
+
+/// Updates the state of openHAB items with the latest data-points
+#[cfg(feature = "openhab")]
+pub struct Updater {
+    openhab_config: Config,
+    agent: ureq::Agent,
+}
+
+#[cfg(feature = "openhab")]
+impl Updater {
+
+    pub fn spawn(openhab_config: Config, data_receiver: Receiver<Datum>)
+    -> JoinHandle<Result<(),String>>
+    {
+        let updater = Updater {
+            agent: ureq::AgentBuilder::new().timeout(Duration::from_secs(10)).build(),
+            openhab_config,
+        };
+        std::thread::spawn(move || {
+            info!("Updating openHAB items at {}", updater.openhab_config.url);
+            for datum in data_receiver {
+                updater.update(&datum);
+            }
+            debug!("all meters stopped, stopping");
+            Ok(())
+        })
+    }
+
+    /// Item updated with the data-point, if any
+    fn item(&self, datum: &Datum) -> Option<&str> {
+        self.openhab_config.items.get(datum.device_name.as_ref())?
+            .get(&datum.measurement.to_string())
+            .map(String::as_str)
+    }
+
+    /// Set the state of the item of the data-point
+    fn update(&self, datum: &Datum) {
+        let item = match self.item(datum) {
+            Some(item) => item,
+            None => return,
+        };
+        let url = format!("{}/rest/items/{}/state",
+            self.openhab_config.url.trim_end_matches('/'), item);
+        let mut request = self.agent.put(&url)
+            .set("Content-Type", "text/plain")
+            .set("Accept", "application/json");
+        if let Some(token) = &self.openhab_config.token {
+            request = request.set("Authorization", &format!("Bearer {}", token.expose()));
+        }
+        if let Err(err) = request.send_string(&datum.value.to_string()) {
+            warn!("openHAB item {} could not be updated: {}", item, err);
+        }
+    }
+}
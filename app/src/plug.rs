@@ -1,295 +1,1255 @@
+use crate::archive;
+use crate::audit;
+use crate::archive::Archive;
+use crate::log_limit::FailureLog;
+use crate::network::{self, DeviceClient};
+use crate::point;
 use crate::point::Datum;
 use crate::point::Measurement::*;
-use chrono::{NaiveDateTime, Timelike};
+use crate::probe;
+use crate::relay;
+use crate::retry;
+use crate::schedule::Alignment;
+use crate::secret::Secret;
+use crate::scheduler::{Poll, Task};
+use crate::state::SharedState;
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
 use log::{debug, info, warn, error};
 use serde::Deserialize;
-use std::time::Duration;
-use std::thread::JoinHandle;
-use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Shortest interval between measurements of instantaneous power, so that
+/// a device is not polled faster than it can respond
+const MIN_INSTANTANEOUS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Shortest interval between measurements of the device status
+const MIN_STATUS_INTERVAL: Duration = Duration::from_secs(1);
 
 /// Configuration of 1 Shelly Plug (S) device
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct Config {
 
     /// Name of this device
-    pub name: String,
+    pub name: Arc<str>,
 
     /// Host-name or IP of the device
-    pub host: String,
+    pub host: Arc<str>,
 
-    /// Interval between measurements of instantaneous power
-    pub instantaneous_meter_interval_in_s: i32,
+    /// Channel (meter and relay) of a device with several, e.g. 1 for the second
+    /// one of a Shelly 2PM; each channel is configured as a device of its own,
+    /// named after what it measures (e.g. "dishwasher")
+    #[serde(default)]
+    pub channel: u32,
+
+    /// Group of devices (e.g. a room), used by the MQTT topic template
+    #[serde(default)]
+    #[cfg_attr(not(feature = "mqtt"), allow(dead_code))]
+    pub group: Option<String>,
+
+    /// Interval between measurements of instantaneous power, in seconds
+    /// (e.g. 0.25 for 4 per second); not measured if negative
+    pub instantaneous_meter_interval_in_s: f64,
+
+    /// Topic prefix of the telemetry, which the device publishes to the
+    /// broker of `mqtt_source` (e.g. "shellies/shellyplug-s-C45BBE")
+    #[serde(default)]
+    pub mqtt_topic: Option<String>,
+
+    /// Whether the device is polled or fed by its telemetry; fed by it if
+    /// not set and the device has an `mqtt_topic`
+    #[serde(default)]
+    pub mode: Option<Mode>,
+
+    /// Alignment of the per-minute polls to the device clock
+    #[serde(default)]
+    pub minute_alignment: Alignment,
+
+    /// Clock by which the per-minute counters are timestamped
+    #[serde(default)]
+    pub timestamp_source: TimestampSource,
+
+    /// What to do with samples the device marks as invalid
+    #[serde(default)]
+    pub invalid_samples: InvalidSamples,
+
+    /// MAC address of the device (e.g. "C45BBE6F1A2B"), by which it is found
+    /// again when its IP changes; learned from the device if not set
+    #[serde(default)]
+    pub mac: Option<String>,
+
+    /// Polling faster while the power changes, if enabled
+    #[serde(default)]
+    pub adaptive_polling: Option<AdaptivePolling>,
+
+    /// Whether to derive the rate of change of the instantaneous power
+    #[serde(default)]
+    pub power_delta: bool,
+
+    /// Reboot the device once it returns invalid or unparseable responses
+    /// for this long, in seconds; never rebooted if not set
+    #[serde(default)]
+    pub reboot_after_s: Option<u64>,
+
+    /// Interval between measurements of the device status (relay state,
+    /// temperature, voltage), in seconds; not measured if not set
+    #[serde(default)]
+    pub status_meter_interval_in_s: Option<f64>,
+
+    /// Type of the device, by which it is metered
+    #[serde(default)]
+    pub device_type: DeviceType,
+
+    /// Names of the phases of an energy meter, in the order of its channels;
+    /// each phase is metered as a device named "{name}-{phase}"
+    #[serde(default = "Config::default_phases")]
+    pub phases: Vec<String>,
+
+    /// User name of the login of the device, "admin" if not set
+    #[serde(default)]
+    pub username: Option<String>,
+
+    /// Password of the login of the device, if it is enabled
+    #[serde(default)]
+    pub password: Option<Secret>,
+
+    /// Whether the device is reached over HTTPS
+    #[serde(default)]
+    pub https: bool,
+
+    /// Delays of the retries when the device fails, if other than the global ones
+    #[serde(default)]
+    pub retry: Option<retry::Policy>,
 }
 
 impl Config {
 
-    /// URL of the (only) meter endpoint
-    pub fn meter_endpoint_url(&self) -> String {
-        format!("http://{}/meter/0", self.host)
+    fn default_phases() -> Vec<String> { vec!["L1".into(), "L2".into(), "L3".into()] }
+
+    /// Device with the settings the config leaves out at their defaults,
+    /// not measuring instantaneous power
+    pub fn new(name: &str, host: &str) -> Config {
+        Config {
+            name: name.into(),
+            host: host.into(),
+            channel: 0,
+            group: None,
+            instantaneous_meter_interval_in_s: -1.0,
+            mqtt_topic: None,
+            mode: None,
+            minute_alignment: Alignment::default(),
+            timestamp_source: TimestampSource::default(),
+            invalid_samples: InvalidSamples::default(),
+            mac: None,
+            adaptive_polling: None,
+            power_delta: false,
+            reboot_after_s: None,
+            status_meter_interval_in_s: None,
+            device_type: DeviceType::default(),
+            phases: Config::default_phases(),
+            username: None,
+            password: None,
+            https: false,
+            retry: None,
+        }
+    }
+
+    /// Data-point of this device measured now
+    pub fn datum(&self, measurement: point::Measurement, value: f32) -> Datum {
+        Datum {
+            measured_on: chrono::Utc::now(),
+            measurement,
+            device_name: self.name.clone(),
+            device_host: self.host.clone(),
+            value,
+            valid: true,
+        }
     }
 
     /// Interval between measurements of instantaneous power
     pub fn instantaneous_meter_interval(&self) -> Option<Duration> {
-        if self.instantaneous_meter_interval_in_s < 0 {
+        if self.instantaneous_meter_interval_in_s < 0.0 {
             None
         } else {
-            Some(Duration::from_secs(self.instantaneous_meter_interval_in_s as u64))
+            Some(Duration::try_from_secs_f64(self.instantaneous_meter_interval_in_s)
+                .unwrap_or(Duration::MAX)
+                .max(MIN_INSTANTANEOUS_INTERVAL))
         }
     }
+
+    /// Retry policy of the device, or the `global` one
+    pub fn retry_policy(&self, global: &retry::Policy) -> retry::Policy {
+        self.retry.clone().unwrap_or_else(|| global.clone())
+    }
+
+    /// Whether the device is polled or fed by its telemetry
+    pub fn mode(&self) -> Mode {
+        self.mode.unwrap_or(match self.mqtt_topic {
+            Some(_) => Mode::Mqtt,
+            None => Mode::Poll,
+        })
+    }
+
+    /// Interval between measurements of the device status, if measured
+    pub fn status_meter_interval(&self) -> Option<Duration> {
+        self.status_meter_interval_in_s.map(|interval_s|
+            Duration::try_from_secs_f64(interval_s.max(0.0))
+                .unwrap_or(Duration::MAX)
+                .max(MIN_STATUS_INTERVAL))
+    }
 }
 
+/// How the measurements of a device are obtained
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    /// Polled over HTTP
+    Poll,
+    /// Fed by the telemetry, which the device publishes to the broker of
+    /// `mqtt_source` under its `mqtt_topic`
+    Mqtt,
+}
 
-/// Response from the Shelly Plug's "/meter/0" endpoint
-#[derive(Deserialize)]
+/// Type of a device, by which it is metered
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceType {
+    /// Plug or switch with a meter per channel, e.g. Shelly Plug S or Shelly 2PM
+    #[default]
+    Plug,
+    /// Energy meter with a channel per phase, e.g. Shelly EM or Shelly 3EM
+    Emeter,
+}
+
+/// Polling of the instantaneous power faster while it changes, e.g. when an
+/// appliance turns on or off, and slower while it is stable
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct AdaptivePolling {
+
+    /// Interval while the power changes, in seconds
+    pub fastest_interval_in_s: f64,
+
+    /// Change of the power between two measurements, from which it is changing, in W
+    pub change_in_w: f32,
+}
+
+impl AdaptivePolling {
+
+    /// Interval while the power changes, not below the safety floor
+    pub fn fastest_interval(&self) -> Duration {
+        Duration::try_from_secs_f64(self.fastest_interval_in_s.max(0.0))
+            .unwrap_or(Duration::MAX)
+            .max(MIN_INSTANTANEOUS_INTERVAL)
+    }
+}
+
+/// Derives the rate of change of the power from consecutive instantaneous measurements
+#[derive(Default)]
+pub struct PowerDelta {
+    /// Last instantaneous measurement: its time and power
+    last: Option<(DateTime<Utc>, f32)>,
+}
+
+impl PowerDelta {
+
+    /// Rate of change since the previous instantaneous data-point, if any
+    pub fn datum(&mut self, config: &Config, instantaneous: &Datum) -> Option<Datum> {
+        let last = self.last.replace((instantaneous.measured_on, instantaneous.value));
+        let (last_measured_on, last_power_w) = last?;
+        let elapsed_s = (instantaneous.measured_on - last_measured_on)
+            .num_milliseconds() as f32 / 1000.0;
+        if elapsed_s <= 0.0 {
+            return None;
+        }
+        let mut datum = config.datum(power_delta_w_per_s,
+            (instantaneous.value - last_power_w) / elapsed_s);
+        datum.measured_on = instantaneous.measured_on;
+        datum.valid = instantaneous.valid;
+        Some(datum)
+    }
+}
+
+/// Minute counters sent of a device, so that a minute read twice (e.g. by a
+/// poll before the device updated its counters) is sent once, and the minutes
+/// missed (e.g. by a late poll or a short outage) are sent from the earlier counters
+#[derive(Default)]
+pub struct MinuteCounters {
+    /// Minute boundary of the last minute counter sent
+    last_minute: Option<DateTime<Utc>>,
+}
+
+impl MinuteCounters {
+
+    /// Data-points of the minute counters in the response not sent yet, the
+    /// oldest first; none if its last minute was sent already
+    ///
+    /// Only minutes timestamped by the device clock are backfilled, as the
+    /// minutes of the server clock may skip one without the device missing it.
+    pub fn unsent(&mut self, config: &Config, m: &Measurement, counters_updated_on: DateTime<Utc>)
+    -> Option<Vec<Datum>>
+    {
+        let last_minute = self.last_minute.replace(counters_updated_on);
+        if last_minute == Some(counters_updated_on) {
+            return None;
+        }
+        let mut datums = vec![];
+        if let (Some(last_minute), Some(counters), TimestampSource::Device)
+            = (last_minute, m.counters, config.timestamp_source) {
+            let missed = (counters_updated_on - last_minute).num_minutes() - 1;
+            // Only the counters in the response, which may hold fewer than 3
+            let backfilled = missed.clamp(0, counters.len as i64 - 1) as usize;
+            if missed > backfilled as i64 {
+                info!("{} missed {} minute counters up to {}, their consumption is only \
+                    in the totals", config.host, missed - backfilled as i64,
+                    (counters_updated_on - chrono::Duration::minutes(backfilled as i64 + 1))
+                        .to_rfc3339());
+            }
+            if backfilled > 0 {
+                debug!("{} missed {} minute counters, sending them from the earlier ones",
+                    config.host, backfilled);
+            }
+            for minutes_ago in (1..=backfilled).rev() {
+                let mut datum = config.datum(last_minute_consumption_in_wh,
+                    counters.values[minutes_ago] / 60.0);
+                datum.measured_on = counters_updated_on
+                    - chrono::Duration::minutes(minutes_ago as i64);
+                datum.valid = m.is_valid;
+                datums.push(datum);
+            }
+        }
+        datums.extend(m.datum(config, last_minute_consumption_in_wh));
+        Some(datums)
+    }
+}
+
+/// Clock by which the per-minute counters are timestamped
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TimestampSource {
+    /// Minute reported by the device, corrected by its time-zone offset
+    #[default]
+    Device,
+    /// Minute of the server clock in which the counters were received,
+    /// for devices with a wrong clock
+    Server,
+}
+
+/// Handling of samples, which the device marks as invalid (`is_valid: false`)
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum InvalidSamples {
+    /// Discard the sample and poll the device again after the delay of its
+    /// `retry` policy, as after a failure
+    #[default]
+    BackOff,
+    /// Discard the sample, but keep polling at the normal rate
+    Skip,
+    /// Keep the sample, with its data-points tagged `quality=invalid`
+    Flag,
+}
+
+/// Generation of the Shelly device API
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Generation {
+    /// Original REST API, e.g. Shelly Plug S
+    Gen1,
+    /// JSON-RPC API, e.g. Shelly Plus Plug S
+    Gen2,
+}
+
+impl std::fmt::Display for Generation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Generation::Gen1 => write!(f, "Gen1"),
+            Generation::Gen2 => write!(f, "Gen2"),
+        }
+    }
+}
+
+/// Time-zone offsets are whole quarter-hours
+const TIME_ZONE_GRANULARITY_S: i64 = 15 * 60;
+
+/// Largest time-zone offset in use
+const MAX_TIME_ZONE_OFFSET_S: i64 = 14 * 3600;
+
+/// Measurement of a device, derived from the response of any supported firmware
+///
+/// Firmware versions add, rename and omit fields, so every field may be
+/// missing (unknown fields are ignored); only the measurements derived from
+/// the missing ones are skipped.
 pub struct Measurement {
     /// Current real AC power being drawn, in Watts
-    power: f32,
+    power: Option<f32>,
     /// Whether power metering self-checks OK
     is_valid: bool,
-    /// Value in Watts, on which an overpower condition is detected
-    #[allow(dead_code)]
-    overpower: f32,
     /// Timestamp of the last energy counter value, with the applied timezone
-    timestamp: i64,
-    /// Energy counter value for the last 3 round minutes in Watt-minute
-    counters: Vec<f32>,
+    timestamp: Option<i64>,
+    /// Energy counter value for the last (up to) 3 round minutes in Watt-minute
+    counters: Option<Counters>,
     /// Total energy consumed by the attached electrical appliance in Watt-minute
-    total: f32,
+    total: Option<f32>,
+    /// Fields which were missing in the response
+    missing: Vec<&'static str>,
+}
+
+/// Response from the Gen1 "/meter/0" endpoint
+#[derive(Deserialize)]
+struct MeterStatus {
+    power: Option<f32>,
+    is_valid: Option<bool>,
+    timestamp: Option<i64>,
+    counters: Option<Counters>,
+    total: Option<f32>,
+}
+
+/// Response from the Gen2 "Switch.GetStatus" method
+#[derive(Deserialize)]
+struct SwitchStatus {
+    /// Current real AC power being drawn, in Watts
+    apower: Option<f32>,
+    /// Energy counters of the switch
+    aenergy: Option<ActiveEnergy>,
+    /// Error conditions reported by the switch, if any
+    #[serde(default)]
+    errors: Vec<String>,
+}
+
+/// Energy counters in the Gen2 "Switch.GetStatus" response
+#[derive(Deserialize, Default)]
+struct ActiveEnergy {
+    /// Total energy consumed in Watt-hours
+    total: Option<f32>,
+    /// Energy consumption for the last 3 round minutes in milliwatt-hours
+    by_minute: Option<Counters>,
+    /// UNIX timestamp of the first second of the last minute
+    minute_ts: Option<i64>,
+}
+
+/// Up to 3 minute counters, the last minute first
+#[derive(Clone, Copy)]
+struct Counters {
+    values: [f32; 3],
+    /// Number of the counters in the response, of which `values` holds at most 3
+    len: usize,
+}
+
+impl Counters {
+
+    /// Counters converted to another unit
+    fn map(self, convert: impl Fn(f32) -> f32) -> Counters {
+        Counters { values: self.values.map(convert), len: self.len }
+    }
+}
+
+impl<'de> Deserialize<'de> for Counters {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Counters, D::Error> {
+        deserialize_counters(deserializer)
+    }
+}
+
+/// Names of the fields missing in a response
+#[derive(Default)]
+struct Missing(Vec<&'static str>);
+
+impl Missing {
+
+    /// The value, noting its name if it is missing
+    fn check<T>(&mut self, name: &'static str, value: Option<T>) -> Option<T> {
+        if value.is_none() {
+            self.0.push(name);
+        }
+        value
+    }
+}
+
+impl From<MeterStatus> for Measurement {
+    fn from(status: MeterStatus) -> Measurement {
+        let mut missing = Missing::default();
+        Measurement {
+            power: missing.check("power", status.power),
+            is_valid: status.is_valid.unwrap_or(true),
+            timestamp: missing.check("timestamp", status.timestamp),
+            counters: missing.check("counters", status.counters),
+            total: missing.check("total", status.total),
+            missing: missing.0,
+        }
+    }
+}
+
+impl From<SwitchStatus> for Measurement {
+    fn from(status: SwitchStatus) -> Measurement {
+        let mut missing = Missing::default();
+        let aenergy = status.aenergy.unwrap_or_default();
+        Measurement {
+            power: missing.check("apower", status.apower),
+            is_valid: status.errors.is_empty(),
+            timestamp: missing.check("aenergy.minute_ts", aenergy.minute_ts),
+            // Convert mWh to Watt-minutes used by Gen1 devices
+            counters: missing.check("aenergy.by_minute", aenergy.by_minute)
+                .map(|by_minute| by_minute.map(|mwh| mwh * 60.0 / 1000.0)),
+            total: missing.check("aenergy.total", aenergy.total).map(|wh| wh * 60.0),
+            missing: missing.0,
+        }
+    }
 }
 
 impl Measurement {
 
-    /// Local time on the remote device
-    pub fn local_device_time(&self) -> NaiveDateTime {
-        NaiveDateTime::from_timestamp_opt(self.timestamp, 0)
-                .expect("Shelly plug's time is not a UNIX time-stamp")
+    /// Parse a response of either a Gen1 "/meter/{channel}" endpoint or a Gen2
+    /// "Switch.GetStatus" method (bare or JSON-RPC wrapped); the meter of the
+    /// channel may also be nested in the status of the whole device (Gen1
+    /// "/status", Gen2 "Shelly.GetStatus"), as some firmware versions publish it
+    pub fn parse(data: &[u8], channel: u32) -> Result<(Generation, Measurement), String> {
+        let mut value: serde_json::Value = serde_json::from_slice(data)
+            .map_err(|err| err.to_string())?;
+        if let Some(result) = value.get_mut("result") {
+            value = result.take();
+        }
+        for pointer in [format!("/meters/{}", channel), format!("/switch:{}", channel),
+            format!("/pm1:{}", channel)] {
+            if let Some(meter) = value.pointer_mut(&pointer) {
+                value = meter.take();
+                break;
+            }
+        }
+        let (generation, measurement): (Generation, Measurement) =
+            if value.get("apower").is_some() || value.get("aenergy").is_some() {
+                let status: SwitchStatus = serde_json::from_value(value)
+                    .map_err(|err| err.to_string())?;
+                (Generation::Gen2, status.into())
+            } else {
+                let status: MeterStatus = serde_json::from_value(value)
+                    .map_err(|err| err.to_string())?;
+                (Generation::Gen1, status.into())
+            };
+        if measurement.power.is_none() && measurement.counters.is_none()
+            && measurement.total.is_none() {
+            return Err("there is neither power nor energy in the response".to_string());
+        }
+        Ok((generation, measurement))
+    }
+
+    /// Fields which were missing in the response
+    pub fn missing(&self) -> &[&'static str] {
+        &self.missing
+    }
+
+    /// Warn about the missing fields, unless they were `reported` already
+    pub fn report_missing(&self, host: &str, reported: &mut Vec<&'static str>) {
+        if self.missing == *reported {
+            return;
+        }
+        if self.missing.is_empty() {
+            info!("{} response is complete again", host);
+        } else {
+            warn!("{} response lacks {} (a different firmware version?), \
+                the measurements derived from it are skipped", host, self.missing.join(", "));
+        }
+        *reported = self.missing.clone();
+    }
+
+    /// Derive a single data-point from this measurement, if the response had it
+    ///
+    /// The counters are timestamped by the minute boundary they describe,
+    /// so that the same response always gives the same data-points.
+    /// Panics for measurements which are not part of the response.
+    pub fn datum(&self, config: &Config, measurement: point::Measurement) -> Option<Datum> {
+        let mut datum = config.datum(measurement, match measurement {
+            last_minute_consumption_in_wh => self.last_minute_consumption_in_wh()?,
+            instantaneous_consumption_in_w => self.instantaneous_consumption_in_w()?,
+            consumption_since_reboot_in_wh => self.consumption_since_reboot_in_wh()?,
+            consumption_today_in_wh | power_delta_w_per_s | last_minute_cost =>
+                panic!("{} is not measured directly", measurement),
+            voltage_in_v | temperature_in_c | overtemperature | relay_on | current_in_a
+            | power_factor | returned_since_reboot_in_wh =>
+                panic!("{} is not in the meter response", measurement),
+        });
+        if measurement != instantaneous_consumption_in_w {
+            datum.measured_on = self.counters_minute(config, datum.measured_on);
+        }
+        datum.valid = self.is_valid;
+        Some(datum)
+    }
+
+    /// Minute boundary by which the counters are timestamped, by the clock of
+    /// the device or of the server (`now`), as configured
+    pub fn counters_minute(&self, config: &Config, now: DateTime<Utc>) -> DateTime<Utc> {
+        match config.timestamp_source {
+            TimestampSource::Device => self.counters_updated_on(now),
+            TimestampSource::Server => {
+                let now_s = now.timestamp();
+                Utc.timestamp_opt(now_s - now_s.rem_euclid(60), 0).single().unwrap_or(now)
+            },
+        }
+    }
+
+    /// Minute boundary (in UTC) at which the device last updated its counters,
+    /// i.e. the end of the minute described by the last minute counter
+    ///
+    /// Gen1 devices report their local time, so the time-zone offset is
+    /// estimated from the difference to `now`, rounded to whole quarter-hours.
+    /// Device times too far from `now` (e.g. of old responses) are taken as UTC.
+    /// Without the device time, it is the last minute boundary before `now`.
+    pub fn counters_updated_on(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let timestamp = self.timestamp.unwrap_or_else(|| now.timestamp());
+        let offset_s = timestamp - now.timestamp();
+        let time_zone_s = if offset_s.abs() <= MAX_TIME_ZONE_OFFSET_S {
+            (offset_s as f64 / TIME_ZONE_GRANULARITY_S as f64).round() as i64
+                * TIME_ZONE_GRANULARITY_S
+        } else {
+            0
+        };
+        let utc_s = timestamp - time_zone_s;
+        Utc.timestamp_opt(utc_s - utc_s.rem_euclid(60), 0).single().unwrap_or(now)
+    }
+
+    /// Whether power metering self-checks OK
+    pub fn is_valid(&self) -> bool {
+        self.is_valid
+    }
+
+    /// Local time on the remote device, if it reported a valid one
+    pub fn local_device_time(&self) -> Option<NaiveDateTime> {
+        self.timestamp.and_then(|timestamp| NaiveDateTime::from_timestamp_opt(timestamp, 0))
     }
 
-    /// Duration till the next update of the 'counters' variable
-    pub fn time_to_next_update(&self) -> Duration {
-        let time = self.local_device_time();
-        let seconds: u64 = time.second() as u64;
-        let millis: u64 = time.nanosecond() as u64 / 1000;
-        return Duration::from_secs(60) // time till next minute;
-             - Duration::from_secs(seconds) // elapsed in the ...
-             - Duration::from_millis(millis) // ... current minute;
-             + Duration::from_secs(10) // some slack for time offsets
+    /// Duration till the next update of the 'counters' variable; by the clock
+    /// of the server if the device did not report its time
+    pub fn time_to_next_update(&self, alignment: &Alignment) -> Duration {
+        alignment.time_to_next(self.local_device_time()
+            .unwrap_or_else(|| Utc::now().naive_utc()))
     }
 
     // Instantaneous power consumption
-    pub fn instantaneous_consumption_in_w(&self) -> f32 {
+    pub fn instantaneous_consumption_in_w(&self) -> Option<f32> {
         self.power
     }
 
     /// Consumption during the last 1 round minute
-    pub fn last_minute_consumption_in_wh(&self) -> f32 {
-        let value_in_ws: f32 = *self.counters.first()
-            .expect("The 'counters' array is expected to have 3 entries");
-        value_in_ws / 60.0
+    pub fn last_minute_consumption_in_wh(&self) -> Option<f32> {
+        self.counters.map(|counters| counters.values[0] / 60.0)
     }
 
     /// Consumption since the plug has restarted
-    pub fn consumption_since_reboot_in_wh(&self) -> f32 {
-        self.total / 60.0
+    pub fn consumption_since_reboot_in_wh(&self) -> Option<f32> {
+        self.total.map(|total| total / 60.0)
     }
 }
 
+/// Deserialize up to 3 counters into a fixed array, without allocating
+fn deserialize_counters<'de, D>(deserializer: D) -> Result<Counters, D::Error>
+where D: serde::Deserializer<'de>
+{
+    struct CountersVisitor;
+
+    impl<'de> serde::de::Visitor<'de> for CountersVisitor {
+        type Value = Counters;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "a non-empty array of numbers")
+        }
+
+        fn visit_seq<A>(self, mut seq: A) -> Result<Counters, A::Error>
+        where A: serde::de::SeqAccess<'de>
+        {
+            let mut counters = [0.0; 3];
+            let mut count = 0;
+            while let Some(value) = seq.next_element::<f32>()? {
+                if count < counters.len() {
+                    counters[count] = value;
+                }
+                count += 1;
+            }
+            if count == 0 {
+                return Err(serde::de::Error::invalid_length(0, &self));
+            }
+            Ok(Counters { values: counters, len: count.min(counters.len()) })
+        }
+    }
+
+    deserializer.deserialize_seq(CountersVisitor)
+}
+
 /// Measurement was not possible
 enum MeterError {
     Recoverable(Duration),
     Unrecoverable(String)
 }
 
+/// Largest accepted response of a device
+const MAX_RESPONSE_BYTES: usize = 64 * 1024;
+
+/// How often is the identity (MAC address) of a responding device checked
+const IDENTITY_CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Least time between two searches for a device, which stopped responding
+const SEARCH_INTERVAL: Duration = Duration::from_secs(300);
+
 // Meter measures the power consumption via a HTTP request
 struct Meter {
 
     config: Config,
 
-    timeout: Duration,
+    client: DeviceClient,
+
+    /// Response body, reused between polls
+    buffer: Vec<u8>,
+
+    /// Archive of raw responses, if enabled
+    archive: Option<Archive>,
+
+    /// Fields missing in the responses, which were warned about
+    missing: Vec<&'static str>,
+
+    /// Whether the last measurement was invalid, to warn only once
+    /// about skipped or flagged samples
+    invalid: bool,
+
+    /// Current address of the device, the configured host until it moves
+    address: Arc<str>,
+
+    /// MAC address of the device, configured or learned
+    mac: Option<String>,
+
+    /// When was the device at `address` last found to have the MAC address
+    identified_on: Option<Instant>,
+
+    /// When was the device last searched for
+    searched_on: Option<Instant>,
+
+    /// Failures since the last successful poll
+    failures: FailureLog,
+
+    /// Since when does the device return invalid or unparseable responses
+    unhealthy_since: Option<Instant>,
+
+    /// When was the device rebooted to remedy that, if it was
+    rebooted_on: Option<Instant>,
+
+    /// Whether the device did not recover even after the reboot
+    alerted: bool,
+
+    /// Log of the reboots, if any
+    audit_config: Option<audit::Config>,
 }
 
 impl Meter {
 
     /// Create a new meter
     pub fn new(shelly_plug_config: &Config,
-        network_timeout: Duration) -> Meter
+        client: DeviceClient,
+        archive_config: Option<&archive::Config>,
+        audit_config: Option<&audit::Config>,
+        failures: FailureLog) -> Meter
     {
+        let archive = archive_config.and_then(|archive_config| {
+            Archive::open(archive_config, &shelly_plug_config.name)
+                .map_err(|err| warn!("{} responses will not be archived: {}",
+                    shelly_plug_config.host, err))
+                .ok()
+        });
         Meter {
             config: shelly_plug_config.clone(),
-            timeout: network_timeout,
+            client: client.for_device(shelly_plug_config),
+            buffer: Vec::new(),
+            archive,
+            missing: Vec::new(),
+            invalid: false,
+            address: shelly_plug_config.host.clone(),
+            mac: shelly_plug_config.mac.as_deref().map(probe::normalized_mac),
+            identified_on: None,
+            searched_on: None,
+            failures,
+            unhealthy_since: None,
+            rebooted_on: None,
+            alerted: false,
+            audit_config: audit_config.cloned(),
+        }
+    }
+
+    /// Record a valid response, ending the remediation
+    fn healthy(&mut self) {
+        if self.rebooted_on.is_some() {
+            info!("{} returns valid data again after its reboot", self.config.host);
+        }
+        self.unhealthy_since = None;
+        self.rebooted_on = None;
+        self.alerted = false;
+    }
+
+    /// Record an invalid or unparseable response; once they last `reboot_after_s`,
+    /// reboot the device, and if they still last as long after the reboot, alert
+    async fn unhealthy(&mut self) {
+        let reboot_after = match self.config.reboot_after_s {
+            Some(reboot_after_s) => Duration::from_secs(reboot_after_s),
+            None => return,
+        };
+        let unhealthy_for = self.unhealthy_since.get_or_insert_with(Instant::now).elapsed();
+        match self.rebooted_on {
+            None if unhealthy_for >= reboot_after => self.reboot(unhealthy_for).await,
+            Some(rebooted_on) if !self.alerted && rebooted_on.elapsed() >= reboot_after => {
+                error!("{} still returns invalid data {}s after its reboot; \
+                    it needs attention", self.config.host, rebooted_on.elapsed().as_secs());
+                self.alerted = true;
+            },
+            _ => (),
+        }
+    }
+
+    /// Reboot the device (only once per remediation), recording it in the audit log
+    async fn reboot(&mut self, unhealthy_for: Duration) {
+        self.rebooted_on = Some(Instant::now());
+        if let Err(err) = relay::reboot(&self.address, &self.client).await {
+            error!("{} returns invalid data for {}s, but could not be rebooted: {}",
+                self.config.host, unhealthy_for.as_secs(), err);
+            return;
+        }
+        warn!("{} returns invalid data for {}s, rebooted it",
+            self.config.host, unhealthy_for.as_secs());
+        let audit_config = match &self.audit_config {
+            Some(audit_config) => audit_config,
+            None => return,
+        };
+        let recorded = audit::AuditLog::open(audit_config).and_then(|mut audit_log|
+            audit_log.append(audit::Action {
+                time: Utc::now(),
+                actor: "remediation".to_string(),
+                rule: Some(format!("invalid data for {}s", unhealthy_for.as_secs())),
+                device: self.config.name.to_string(),
+                host: self.config.host.to_string(),
+                command: Some("reboot".to_string()),
+                was_on: None,
+                is_on: None,
+            }));
+        if let Err(err) = recorded {
+            warn!("{} was rebooted, but not recorded: {}", self.config.host, err);
+        }
+    }
+
+    /// URL of the meter endpoint of the channel
+    fn meter_endpoint_url(&self) -> String {
+        self.client.url(&self.address, &format!("/meter/{}", self.config.channel))
+    }
+
+    /// Check that the device at the address is still the one with the MAC
+    /// address (e.g. the IP was not handed to another device), or learn it;
+    /// fails with the time to measure again if it is another device
+    async fn check_identity(&mut self) -> Result<(), MeterError> {
+        if self.identified_on.is_some_and(|on| on.elapsed() < IDENTITY_CHECK_INTERVAL) {
+            return Ok(());
+        }
+        let found_mac = match probe::probe_host(&self.address, &self.client).await {
+            Ok(device_info) => probe::normalized_mac(&device_info.mac),
+            Err(err) => {
+                debug!("{} identity could not be checked: {}", self.config.host, err);
+                return Ok(());
+            },
+        };
+        match &self.mac {
+            None => {
+                info!("{} has MAC address {}", self.config.host, found_mac);
+                self.mac = Some(found_mac);
+            },
+            Some(mac) if *mac == found_mac => (),
+            Some(mac) => {
+                warn!("{} is now another device (MAC address {} instead of {}); \
+                    searching for the device", self.address, found_mac, mac);
+                self.identified_on = None;
+                if self.search().await {
+                    return Err(MeterError::Recoverable(Duration::ZERO));
+                }
+                return Err(MeterError::Recoverable(
+                    self.failures.failed(&self.config.host, "was not found")));
+            },
+        }
+        self.identified_on = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Search for the device by its MAC address, at most once per `SEARCH_INTERVAL`;
+    /// true if found, and the address updated
+    async fn search(&mut self) -> bool {
+        let mac = match &self.mac {
+            Some(mac) => mac,
+            None => return false,
+        };
+        if self.searched_on.is_some_and(|on| on.elapsed() < SEARCH_INTERVAL) {
+            return false;
+        }
+        self.searched_on = Some(Instant::now());
+        match probe::find_by_mac(mac, &self.client).await {
+            Ok(Some(address)) => {
+                info!("{} (MAC address {}) moved from {} to {}",
+                    self.config.name, mac, self.address, address);
+                self.address = address.to_string().into();
+                self.identified_on = Some(Instant::now());
+                true
+            },
+            Ok(None) => {
+                debug!("{} (MAC address {}) not found by mDNS", self.config.name, mac);
+                false
+            },
+            Err(err) => {
+                warn!("{} could not be searched for: {}", self.config.name, err);
+                false
+            },
         }
     }
 
     /// Parse the measurement from the HTTP response
-    fn parse_http_response(&self, response: ureq::Response) -> Result<Measurement,MeterError>
+    async fn parse_http_response(&mut self, mut response: reqwest::Response)
+    -> Result<Measurement,MeterError>
     {
-        let message: Measurement = match response.into_json() {
-            Ok(parsed) => parsed,
-            Err(_error) => {
+        self.buffer.clear();
+        while self.buffer.len() < MAX_RESPONSE_BYTES {
+            match response.chunk().await {
+                Ok(Some(chunk)) => self.buffer.extend_from_slice(
+                    &chunk[..chunk.len().min(MAX_RESPONSE_BYTES - self.buffer.len())]),
+                Ok(None) => break,
+                Err(err) => return Err(MeterError::Recoverable(self.failures.failed(
+                    &self.config.host, &format!("response could not be read ({})", err)))),
+            }
+        }
+        if let Some(archive) = &mut self.archive {
+            archive.store(&self.buffer);
+        }
+
+        let message = match Measurement::parse(&self.buffer, self.config.channel) {
+            Ok((_, parsed)) => parsed,
+            // A device which can be rebooted may recover
+            Err(err) if self.config.reboot_after_s.is_some() => {
+                let delay = self.failures.failed(&self.config.host, &format!("did not return \
+                    JSON with the expected grammar ({})", err));
+                self.unhealthy().await;
+                return Err(MeterError::Recoverable(delay));
+            },
+            Err(err) => {
                 return Err(MeterError::Unrecoverable(format!(
-                    "{} did not return JSON with the expected grammar. \
-                    Measurements are stopped.", self.config.host)));
+                    "{} did not return JSON with the expected grammar ({}). \
+                    Measurements are stopped.", self.config.host, err)));
             }
         };
+        message.report_missing(&self.config.host, &mut self.missing);
 
-        let time_measured = chrono::Utc::now();
-        let device_local_time = message.local_device_time();
-        debug!("{} reports local time {}, server local time is {}, offset is {}ms",
-            self.config.host, device_local_time, time_measured.naive_local(),
-            (device_local_time - time_measured.naive_local()).num_milliseconds(),
-        );
+        if let Some(device_local_time) = message.local_device_time() {
+            let time_measured = chrono::Utc::now();
+            debug!("{} reports local time {}, server local time is {}, offset is {}ms",
+                self.config.host, device_local_time, time_measured.naive_local(),
+                (device_local_time - time_measured.naive_local()).num_milliseconds(),
+            );
+        }
 
         Ok(message)
     }
 
-    pub fn measure(&self) -> Result<Measurement,MeterError> {
-        let url = self.config.meter_endpoint_url();
-        match ureq::get(&url).timeout(self.timeout).call() {
+    pub async fn measure(&mut self) -> Result<Measurement,MeterError> {
+        let url = self.meter_endpoint_url();
+        match self.client.get(&url).call().await {
 
             Ok(http_response) => {
-                let message = self.parse_http_response(http_response)?;
+                let message = self.parse_http_response(http_response).await?;
+                self.check_identity().await?;
 
+                let format = |value: Option<f32>, precision: usize| value
+                    .map_or_else(|| "?".to_string(), |value| format!("{:.*}", precision, value));
                 debug!("{} \
-                        instant={:.2}W \
-                        last_min={:.2}Wh \
-                        since_reboot={:.1}Wh",
+                        instant={}W \
+                        last_min={}Wh \
+                        since_reboot={}Wh",
                     self.config.host,
-                    message.instantaneous_consumption_in_w(),
-                    message.last_minute_consumption_in_wh(),
-                    message.consumption_since_reboot_in_wh(),
+                    format(message.instantaneous_consumption_in_w(), 2),
+                    format(message.last_minute_consumption_in_wh(), 2),
+                    format(message.consumption_since_reboot_in_wh(), 1),
                 );
 
                 if message.is_valid {
-                    Ok(message)
-                } else {
-                    error!("{} last measurement was invalid; \
-                        retrying in 10 minutes", self.config.host);
-                    Err(MeterError::Recoverable(Duration::from_secs(600)))
+                    if self.invalid {
+                        info!("{} measurements are valid again", self.config.host);
+                        self.invalid = false;
+                    }
+                    self.healthy();
+                    return Ok(message);
+                }
+                self.unhealthy().await;
+                match self.config.invalid_samples {
+                    InvalidSamples::BackOff => {
+                        return Err(MeterError::Recoverable(self.failures.failed(&self.config.host,
+                            "last measurement was invalid")));
+                    },
+                    InvalidSamples::Skip if !self.invalid => warn!("{} last measurement \
+                        was invalid; skipping invalid measurements", self.config.host),
+                    InvalidSamples::Flag if !self.invalid => warn!("{} last measurement \
+                        was invalid; flagging invalid measurements", self.config.host),
+                    _ => (),
                 }
+                self.invalid = true;
+                Ok(message)
             }
 
-            Err(ureq::Error::Status(status, response)) => {
-                warn!("{} responded with HTTP status \
-                    {} {}; retrying in 10 minutes (GET {})",
-                    self.config.host, status, response.status_text(), url);
-                Err(MeterError::Recoverable(Duration::from_secs(600)))
+            Err(network::Error::Status(response)) => {
+                Err(MeterError::Recoverable(self.failures.failed(&self.config.host,
+                    &format!("responded with HTTP status {} (GET {})",
+                    response.status(), url))))
             }
 
-            Err(ureq::Error::Transport(err)) => {
-                warn!("{} not connected; \
-                    retrying in 1 minute ({})",
-                    self.config.host, err.to_string() );
-                Err(MeterError::Recoverable(Duration::from_secs(60)))
+            Err(network::Error::Transport(err)) => {
+                self.identified_on = None;
+                if self.search().await {
+                    return Err(MeterError::Recoverable(Duration::ZERO));
+                }
+                Err(MeterError::Recoverable(self.failures.failed(&self.config.host,
+                    &format!("not connected ({})", err))))
             }
         }
     }
 }
 
-/// Measure the cumulative consumption over the last minute
-pub struct MinuteMeter;
-impl MinuteMeter {
+/// Polls one device and derives all measurements from each response
+///
+/// The per-minute counters are sent whenever the device has updated them,
+/// the instantaneous consumption (if enabled) on every poll.
+pub struct DeviceMeter {
+    meter: Meter,
+    instantaneous_interval: Option<Duration>,
+    /// Interval of the adaptive polling, between the fastest and the configured one
+    adaptive_interval: Option<Duration>,
+    /// Instantaneous power last measured, to detect changes
+    last_power_w: Option<f32>,
+    power_delta: PowerDelta,
+    minute_counters: MinuteCounters,
+    next_minute_update: Instant,
+    state: SharedState,
+    data_sender: UnboundedSender<Datum>,
+}
+
+impl DeviceMeter {
 
-    pub fn spawn(
+    pub fn new(
         shelly_plug_config: &Config,
-        network_timeout: Duration,
-        data_sender: Sender<Datum>)
-    -> JoinHandle<Result<(),String>>
+        client: DeviceClient,
+        archive_config: Option<&archive::Config>,
+        audit_config: Option<&audit::Config>,
+        failures: FailureLog,
+        state: SharedState,
+        data_sender: UnboundedSender<Datum>)
+    -> DeviceMeter
     {
-        let meter = Meter::new(&shelly_plug_config, network_timeout);
-        std::thread::spawn(move || {
-            loop {
-                let sleep_duration = match meter.measure() {
-                    Ok(m) => {
-
-                        let d1 = Datum{
-                            measured_on: chrono::Utc::now(),
-                            measurement: last_minute_consumption_in_wh,
-                            device_name: meter.config.name.clone(),
-                            device_host: meter.config.host.clone(),
-                            value: m.last_minute_consumption_in_wh(),
-                        };
-
-                        let d2 = Datum{
-                            measured_on: chrono::Utc::now(),
-                            measurement: consumption_since_reboot_in_wh,
-                            device_name: meter.config.name.clone(),
-                            device_host: meter.config.host.clone(),
-                            value: m.consumption_since_reboot_in_wh(),
-                        };
-
-                        if data_sender.send(d1).is_err() || data_sender.send(d2).is_err() {
+        let instantaneous_interval = shelly_plug_config.instantaneous_meter_interval();
+        match instantaneous_interval {
+            None => info!("{} will not measure instantaneous consumption \
+                (instantaneous_meter_interval_in_s < 0)",
+                shelly_plug_config.host),
+            Some(interval) if interval.as_secs_f64()
+                > shelly_plug_config.instantaneous_meter_interval_in_s =>
+                warn!("{} will measure instantaneous consumption every {}ms at most",
+                    shelly_plug_config.host, interval.as_millis()),
+            Some(_) => (),
+        }
+
+        DeviceMeter {
+            meter: Meter::new(shelly_plug_config, client, archive_config, audit_config,
+                failures),
+            instantaneous_interval,
+            adaptive_interval: None,
+            last_power_w: None,
+            power_delta: PowerDelta::default(),
+            minute_counters: MinuteCounters::default(),
+            next_minute_update: Instant::now(),
+            state,
+            data_sender,
+        }
+    }
+
+    /// Interval till the next instantaneous measurement; with adaptive polling,
+    /// the fastest one when the power changed, then doubling back to the configured one
+    fn next_interval(&mut self, m: &Measurement) -> Option<Duration> {
+        let interval = self.instantaneous_interval?;
+        let adaptive_polling = match &self.meter.config.adaptive_polling {
+            Some(adaptive_polling) => adaptive_polling,
+            None => return Some(interval),
+        };
+        let power_w = m.instantaneous_consumption_in_w();
+        let changed = match (self.last_power_w, power_w) {
+            (Some(last_power_w), Some(power_w)) =>
+                (power_w - last_power_w).abs() >= adaptive_polling.change_in_w,
+            _ => false,
+        };
+        self.last_power_w = power_w;
+        let fastest = adaptive_polling.fastest_interval().min(interval);
+        let adaptive_interval = match self.adaptive_interval {
+            _ if changed => fastest,
+            Some(adaptive_interval) => (adaptive_interval * 2).min(interval),
+            None => interval,
+        };
+        if changed && self.adaptive_interval != Some(fastest) {
+            debug!("{} power is changing, measuring every {}ms",
+                self.meter.config.host, fastest.as_millis());
+        }
+        self.adaptive_interval = Some(adaptive_interval);
+        Some(adaptive_interval)
+    }
+
+    /// Data-points derived from the response
+    fn datums(&mut self, m: &Measurement) -> Vec<Datum> {
+        let mut datums = vec![];
+        if !m.is_valid() && self.meter.config.invalid_samples != InvalidSamples::Flag {
+            return datums;
+        }
+        if self.instantaneous_interval.is_some() {
+            if let Some(instantaneous) = m.datum(&self.meter.config, instantaneous_consumption_in_w) {
+                if self.meter.config.power_delta {
+                    datums.extend(self.power_delta.datum(&self.meter.config, &instantaneous));
+                }
+                datums.push(instantaneous);
+            }
+        }
+        if Instant::now() >= self.next_minute_update {
+            let counters_updated_on = m.counters_minute(&self.meter.config, Utc::now());
+            match self.minute_counters.unsent(&self.meter.config, m, counters_updated_on) {
+                Some(minutes) => datums.extend(minutes),
+                None => {
+                    debug!("{} counters of {} were sent already", self.meter.config.host,
+                        counters_updated_on.to_rfc3339());
+                    self.next_minute_update = Instant::now()
+                        + m.time_to_next_update(&self.meter.config.minute_alignment);
+                    return datums;
+                },
+            }
+            datums.extend(m.datum(&self.meter.config, consumption_since_reboot_in_wh));
+
+            if let Some(total_wh) = m.consumption_since_reboot_in_wh() {
+                let day_total_wh = self.state.lock()
+                    .expect("internal error, state lock poisoned")
+                    .record_total(&self.meter.config.name, counters_updated_on, total_wh);
+                let mut day_total = self.meter.config.datum(consumption_today_in_wh, day_total_wh as f32);
+                day_total.measured_on = counters_updated_on;
+                day_total.valid = m.is_valid();
+                datums.push(day_total);
+            }
+            self.next_minute_update = Instant::now()
+                + m.time_to_next_update(&self.meter.config.minute_alignment);
+        }
+        datums
+    }
+}
+
+impl Task for DeviceMeter {
+
+    fn poll(&mut self) -> Poll<'_> {
+        Box::pin(async move {
+            match self.meter.measure().await {
+                Ok(m) => {
+                    self.meter.failures.succeeded(&self.meter.config.host);
+                    for datum in self.datums(&m) {
+                        if self.data_sender.send(datum).is_err() {
                             debug!("channel to the DB thread closed, stopping");
-                            return Ok(());
+                            return Ok(None);
                         }
+                    }
 
-                        // Sleep until the next minute
-                        m.time_to_next_update()
-                    },
-                    Err(MeterError::Recoverable(sleep_time)) => sleep_time,
-                    Err(MeterError::Unrecoverable(message)) => return Err(message),
-                };
+                    // Sleep until the next minute or instantaneous measurement
+                    let till_minute_update = self.next_minute_update
+                        .saturating_duration_since(Instant::now());
+                    Ok(Some(match self.next_interval(&m) {
+                        Some(interval) => interval.min(till_minute_update),
+                        None => till_minute_update,
+                    }))
+                },
+
+                // error prescribes sleep duration
+                Err(MeterError::Recoverable(sleep_time)) => Ok(Some(sleep_time)),
 
-                debug!("meter thread is going to sleep for {}ms",
-                    sleep_duration.as_millis()); 
-                std::thread::sleep(sleep_duration);
+                Err(MeterError::Unrecoverable(message)) => Err(message),
             }
         })
     }
+
+    fn resume(&mut self) {
+        // The monotonic clock stopped, so the minute counters would be read late
+        self.next_minute_update = Instant::now();
+        self.state.lock()
+            .expect("internal error, state lock poisoned")
+            .record_gap(&self.meter.config.name, Utc::now());
+    }
 }
 
-/// Measure the instantaneous consumption
-pub struct InstantaneousMeter;
-impl InstantaneousMeter {
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    /// Spawn the metering thread and return its handle
-    pub fn spawn(
-        shelly_plug_config: &Config,
-        network_timeout: Duration,
-        data_sender: Sender<Datum>)
-    -> Option<JoinHandle<Result<(),String>>>
-    {
-        shelly_plug_config.instantaneous_meter_interval().map_or_else(
-            || {
-                info!("{} will not measure instantaneous consumption \
-                    (instantaneous_meter_interval_in_s < 0)",
-                    shelly_plug_config.host);
-                return None;
-            },           
-
-            |instantaneous_meter_interval| {
-                let meter = Meter::new(&shelly_plug_config, network_timeout);
-                Some(std::thread::spawn(move || {
-                    loop {
-                        let sleep_duration = match meter.measure() {
-                            Ok(m) => { 
-                                let datum = Datum{
-                                    measured_on: chrono::Utc::now(),
-                                    measurement: instantaneous_consumption_in_w,
-                                    device_name: meter.config.name.clone(),
-                                    device_host: meter.config.host.clone(),
-                                    value: m.instantaneous_consumption_in_w(),
-                                };
-                            
-                                if data_sender.send(datum).is_err() {
-                                    debug!("channel to the DB thread closed, stopping");
-                                    return Ok(());
-                                }
-                            
-                                // sleep according to the config file
-                                instantaneous_meter_interval
-                            },
-
-                            // error prescribes sleep duration
-                            Err(MeterError::Recoverable(sleep_time)) => sleep_time,
-
-                            Err(MeterError::Unrecoverable(message)) => return Err(message),
-                        };
-
-                        debug!("meter thread is going to sleep for {}ms",
-                                sleep_duration.as_millis());
-                        std::thread::sleep(sleep_duration);
-                    }
-                }))    
-            })
+    /// Minute boundary at which the device updated its counters
+    const UPDATED_ON_S: i64 = 1_680_000_000;
+
+    fn config() -> Config {
+        serde_json::from_str(r#"{"name": "fridge", "host": "192.0.2.1",
+            "instantaneous_meter_interval_in_s": 10}"#).unwrap()
+    }
+
+    #[test]
+    fn new_configs_have_the_defaults_of_the_config_file() {
+        let parsed: Config = serde_json::from_str(r#"{"name": "fridge", "host": "192.0.2.1",
+            "instantaneous_meter_interval_in_s": -1}"#).unwrap();
+        assert_eq!(Config::new("fridge", "192.0.2.1"), parsed);
+    }
+
+    /// Gen1 measurement with the counters updated `minutes` after `UPDATED_ON_S`
+    fn measurement(minutes: i64, counters: &str) -> (Measurement, DateTime<Utc>) {
+        let timestamp = UPDATED_ON_S + minutes * 60;
+        let response = format!(r#"{{"power": 60.0, "is_valid": true, "timestamp": {},
+            "counters": {}, "total": 6000}}"#, timestamp, counters);
+        let (_, measurement) = Measurement::parse(response.as_bytes(), 0).unwrap();
+        let updated_on = measurement.counters_updated_on(Utc.timestamp_opt(timestamp + 5, 0).unwrap());
+        (measurement, updated_on)
+    }
+
+    fn values(datums: &[Datum]) -> Vec<(i64, f32)> {
+        datums.iter().map(|datum| (datum.measured_on.timestamp(), datum.value)).collect()
+    }
+
+    #[test]
+    fn minute_read_twice_is_sent_once() {
+        let config = config();
+        let mut sent = MinuteCounters::default();
+        let (m, updated_on) = measurement(0, "[60.0, 60.0, 60.0]");
+        assert_eq!(sent.unsent(&config, &m, updated_on).map(|datums| datums.len()), Some(1));
+        assert!(sent.unsent(&config, &m, updated_on).is_none());
+    }
+
+    #[test]
+    fn missed_minutes_are_sent_from_earlier_counters() {
+        let config = config();
+        let mut sent = MinuteCounters::default();
+        let (m, updated_on) = measurement(0, "[60.0, 60.0, 60.0]");
+        sent.unsent(&config, &m, updated_on);
+        let (m, updated_on) = measurement(3, "[180.0, 120.0, 60.0]");
+        let datums = sent.unsent(&config, &m, updated_on).unwrap();
+        assert_eq!(values(&datums), vec![
+            (UPDATED_ON_S + 60, 1.0), (UPDATED_ON_S + 120, 2.0), (UPDATED_ON_S + 180, 3.0)]);
+    }
+
+    #[test]
+    fn only_counters_in_the_response_are_backfilled() {
+        let config = config();
+        let mut sent = MinuteCounters::default();
+        let (m, updated_on) = measurement(0, "[60.0]");
+        sent.unsent(&config, &m, updated_on);
+        let (m, updated_on) = measurement(3, "[180.0, 120.0]");
+        let datums = sent.unsent(&config, &m, updated_on).unwrap();
+        assert_eq!(values(&datums), vec![(UPDATED_ON_S + 120, 2.0), (UPDATED_ON_S + 180, 3.0)]);
+        let (m, updated_on) = measurement(6, "[240.0]");
+        let datums = sent.unsent(&config, &m, updated_on).unwrap();
+        assert_eq!(values(&datums), vec![(UPDATED_ON_S + 360, 4.0)]);
+    }
+
+    #[test]
+    fn server_minutes_are_not_backfilled() {
+        let mut config = config();
+        config.timestamp_source = TimestampSource::Server;
+        let mut sent = MinuteCounters::default();
+        let (m, updated_on) = measurement(0, "[60.0, 60.0, 60.0]");
+        sent.unsent(&config, &m, updated_on);
+        let (m, updated_on) = measurement(3, "[180.0, 120.0, 60.0]");
+        assert_eq!(sent.unsent(&config, &m, updated_on).map(|datums| datums.len()), Some(1));
     }
 }
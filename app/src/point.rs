@@ -1,11 +1,40 @@
 use chrono::DateTime;
 use chrono::Utc;
+use std::sync::Arc;
 
 #[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Measurement {
     last_minute_consumption_in_wh,
     instantaneous_consumption_in_w,
     consumption_since_reboot_in_wh,
+    consumption_today_in_wh,
+    power_delta_w_per_s,
+    voltage_in_v,
+    temperature_in_c,
+    overtemperature,
+    relay_on,
+    current_in_a,
+    power_factor,
+    returned_since_reboot_in_wh,
+    last_minute_cost,
+}
+
+impl Measurement {
+    /// All measurements derived from a single meter response
+    pub const ALL: [Measurement; 3] = [
+        Measurement::last_minute_consumption_in_wh,
+        Measurement::instantaneous_consumption_in_w,
+        Measurement::consumption_since_reboot_in_wh,
+    ];
+
+    /// All measurements derived from a single status response
+    pub const STATUS: [Measurement; 4] = [
+        Measurement::voltage_in_v,
+        Measurement::temperature_in_c,
+        Measurement::overtemperature,
+        Measurement::relay_on,
+    ];
 }
 
 impl std::fmt::Display for Measurement {
@@ -17,14 +46,80 @@ impl std::fmt::Display for Measurement {
                 write!(f, "instantaneous_consumption_in_w"),
             Measurement::consumption_since_reboot_in_wh =>
                 write!(f, "consumption_since_reboot_in_wh"),
+            Measurement::consumption_today_in_wh =>
+                write!(f, "consumption_today_in_wh"),
+            Measurement::power_delta_w_per_s =>
+                write!(f, "power_delta_w_per_s"),
+            Measurement::voltage_in_v =>
+                write!(f, "voltage_in_v"),
+            Measurement::temperature_in_c =>
+                write!(f, "temperature_in_c"),
+            Measurement::overtemperature =>
+                write!(f, "overtemperature"),
+            Measurement::relay_on =>
+                write!(f, "relay_on"),
+            Measurement::current_in_a =>
+                write!(f, "current_in_a"),
+            Measurement::power_factor =>
+                write!(f, "power_factor"),
+            Measurement::returned_since_reboot_in_wh =>
+                write!(f, "returned_since_reboot_in_wh"),
+            Measurement::last_minute_cost =>
+                write!(f, "last_minute_cost"),
+        }
+    }
+}
+
+impl std::str::FromStr for Measurement {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Measurement, String> {
+        match name {
+            "last_minute_consumption_in_wh" => Ok(Measurement::last_minute_consumption_in_wh),
+            "instantaneous_consumption_in_w" => Ok(Measurement::instantaneous_consumption_in_w),
+            "consumption_since_reboot_in_wh" => Ok(Measurement::consumption_since_reboot_in_wh),
+            "consumption_today_in_wh" => Ok(Measurement::consumption_today_in_wh),
+            "power_delta_w_per_s" => Ok(Measurement::power_delta_w_per_s),
+            "voltage_in_v" => Ok(Measurement::voltage_in_v),
+            "temperature_in_c" => Ok(Measurement::temperature_in_c),
+            "overtemperature" => Ok(Measurement::overtemperature),
+            "relay_on" => Ok(Measurement::relay_on),
+            "current_in_a" => Ok(Measurement::current_in_a),
+            "power_factor" => Ok(Measurement::power_factor),
+            "returned_since_reboot_in_wh" => Ok(Measurement::returned_since_reboot_in_wh),
+            "last_minute_cost" => Ok(Measurement::last_minute_cost),
+            _ => Err(format!("'{}' is not a known measurement", name)),
         }
     }
 }
 
+/// Single measured value
+///
+/// Device name and host are shared with the device configuration,
+/// so that creating a datum does not allocate.
+#[derive(Clone)]
 pub struct Datum {
     pub measured_on: DateTime<Utc>,    
     pub measurement: Measurement,
-    pub device_name: String,
-    pub device_host: String,
+    pub device_name: Arc<str>,
+    pub device_host: Arc<str>,
     pub value: f32,
+    /// False if the device flagged its metering as faulty
+    pub valid: bool,
+}
+
+impl Datum {
+
+    /// JSON object with the data-point, e.g. for a JSON line
+    #[cfg_attr(not(any(feature = "exec", feature = "files")), allow(dead_code))]
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "measured_on": self.measured_on.to_rfc3339(),
+            "measurement": self.measurement.to_string(),
+            "device": self.device_name.as_ref(),
+            "host": self.device_host.as_ref(),
+            "value": self.value,
+            "valid": self.valid,
+        })
+    }
 }
@@ -0,0 +1,114 @@
+use crate::point::Datum;
+use crate::secret::Secret;
+use chrono::{DateTime, Utc};
+use log::debug;
+use postgres::error::SqlState;
+use postgres::{Client, NoTls};
+use std::time::Duration;
+
+/// Longest wait for the server to accept the connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Longest wait for the server to answer whether the connection is usable
+const READY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Connection to PostgreSQL (or TimescaleDB), which inserts the data-points
+/// into a table with the columns of the local store
+///
+/// The server is connected to by the first write (or readiness check), and
+/// again after the connection broke, so that the logger starts without it.
+pub struct Connection {
+    postgres_config: postgres::Config,
+    table: String,
+    client: Option<Client>,
+}
+
+impl Connection {
+
+    pub fn new(host: &str, port: u32, username: &str, password: Option<&Secret>,
+        database: &str, table: &str)
+    -> Result<Connection, String>
+    {
+        // Spliced into the statements, as tables can not be bound as parameters
+        if table.is_empty() || !table.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.') {
+            return Err(format!("'{}' is not a name of a table (letters, digits, '_' \
+                and '.' before the table of a schema)", table));
+        }
+        let port = u16::try_from(port)
+            .map_err(|_| format!("{} is not a port of PostgreSQL", port))?;
+        let mut postgres_config = postgres::Config::new();
+        postgres_config.host(host)
+            .port(port)
+            .user(username)
+            .dbname(database)
+            .application_name("shelly-logger")
+            // Not noticing that the table exists already, on each connection
+            .options("-c client_min_messages=warning")
+            .connect_timeout(CONNECT_TIMEOUT);
+        if let Some(password) = password {
+            postgres_config.password(password.expose());
+        }
+        Ok(Connection { postgres_config, table: table.to_string(), client: None })
+    }
+
+    /// Client connected to the server, with the table created if it did not exist
+    fn client(&mut self) -> Result<&mut Client, postgres::Error> {
+        if self.client.as_ref().is_none_or(Client::is_closed) {
+            let mut client = self.postgres_config.connect(NoTls)?;
+            client.batch_execute(&format!("CREATE TABLE IF NOT EXISTS {} (
+                measured_on TIMESTAMPTZ NOT NULL,
+                measurement TEXT NOT NULL,
+                device_name TEXT NOT NULL,
+                device_host TEXT NOT NULL,
+                value REAL NOT NULL,
+                valid BOOLEAN NOT NULL,
+                PRIMARY KEY (device_name, measurement, measured_on))", self.table))?;
+            debug!("Connected to PostgreSQL, writing into {}", self.table);
+            self.client = Some(client);
+        }
+        Ok(self.client.as_mut().expect("connected above"))
+    }
+
+    /// Insert the data-points in one statement; those inserted before (e.g.
+    /// sent again after an outage) are left as they are
+    pub fn write(&mut self, datums: &[Datum]) -> Result<(), postgres::Error> {
+        if datums.is_empty() {
+            return Ok(());
+        }
+        let measured_on: Vec<DateTime<Utc>> = datums.iter().map(|datum| datum.measured_on).collect();
+        let measurement: Vec<String> = datums.iter().map(|datum| datum.measurement.to_string()).collect();
+        let device_name: Vec<&str> = datums.iter().map(|datum| datum.device_name.as_ref()).collect();
+        let device_host: Vec<&str> = datums.iter().map(|datum| datum.device_host.as_ref()).collect();
+        let value: Vec<f32> = datums.iter().map(|datum| datum.value).collect();
+        let valid: Vec<bool> = datums.iter().map(|datum| datum.valid).collect();
+        let statement = format!("INSERT INTO {} \
+            (measured_on, measurement, device_name, device_host, value, valid) \
+            SELECT * FROM UNNEST($1::TIMESTAMPTZ[], $2::TEXT[], $3::TEXT[], $4::TEXT[], \
+                $5::REAL[], $6::BOOLEAN[]) \
+            ON CONFLICT DO NOTHING", self.table);
+        let result = self.client()?.execute(&statement,
+            &[&measured_on, &measurement, &device_name, &device_host, &value, &valid]);
+        if result.is_err() {
+            // A broken connection is not always noticed as closed
+            self.client = None;
+        }
+        result.map(|_| ())
+    }
+
+    /// Check whether the server is ready to accept writes, connecting to it if needed
+    pub fn is_ready(&mut self) -> bool {
+        let ready = self.client()
+            .and_then(|client| client.is_valid(READY_TIMEOUT));
+        if let Err(err) = &ready {
+            debug!("PostgreSQL is not ready: {}", err);
+            self.client = None;
+        }
+        ready.is_ok()
+    }
+}
+
+/// Whether the server refused the password
+pub fn is_unauthorized(err: &(dyn std::error::Error + 'static)) -> bool {
+    matches!(err.downcast_ref::<postgres::Error>().and_then(postgres::Error::code),
+        Some(&SqlState::INVALID_PASSWORD))
+}
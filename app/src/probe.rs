@@ -0,0 +1,138 @@
+use crate::mdns;
+use crate::network::DeviceClient;
+use crate::plug;
+use log::{debug, info, warn};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinSet;
+
+/// Response of the "/shelly" endpoint, which all generations provide
+#[derive(Deserialize, Debug, Clone)]
+pub struct DeviceInfo {
+    /// Model of the device ("type" in Gen1, "model" in Gen2)
+    #[serde(rename = "type", alias = "model")]
+    pub model: Option<String>,
+    /// MAC address of the device
+    pub mac: String,
+    /// Firmware version ("fw" in Gen1, "fw_id" in Gen2)
+    #[serde(alias = "fw_id")]
+    pub fw: Option<String>,
+    /// API generation, only reported by Gen2+ devices
+    pub gen: Option<u8>,
+    /// Device ID (e.g. "shellyplusplugs-e86beae8a1b4"), only reported by Gen2+ devices
+    pub id: Option<String>,
+}
+
+impl DeviceInfo {
+
+    /// Generation of the device API
+    pub fn generation(&self) -> plug::Generation {
+        match self.gen {
+            Some(gen) if gen >= 2 => plug::Generation::Gen2,
+            _ => plug::Generation::Gen1,
+        }
+    }
+}
+
+/// How long to wait for the answers to an mDNS query
+const MDNS_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Fetch the device information
+pub async fn probe(shelly_plug_config: &plug::Config, client: &DeviceClient)
+-> Result<DeviceInfo, String>
+{
+    probe_host(&shelly_plug_config.host, &client.for_device(shelly_plug_config)).await
+}
+
+/// Fetch the information of the device at the host
+pub async fn probe_host(host: &str, client: &DeviceClient) -> Result<DeviceInfo, String> {
+    let url = client.url(host, "/shelly");
+    client.get(&url).call().await
+        .map_err(|err| err.to_string())?
+        .json().await
+        .map_err(|err| format!("{} returned unexpected data: {}", url, err))
+}
+
+/// MAC address as reported by the devices, i.e. upper-case without separators
+pub fn normalized_mac(mac: &str) -> String {
+    mac.chars()
+        .filter(char::is_ascii_hexdigit)
+        .map(|digit| digit.to_ascii_uppercase())
+        .collect()
+}
+
+/// Search the local network (by mDNS) for the device with the MAC address;
+/// returns its IP address, if found
+pub async fn find_by_mac(mac: &str, client: &DeviceClient) -> Result<Option<IpAddr>, String> {
+    for address in mdns::responders(&mdns::SHELLY_SERVICES, MDNS_TIMEOUT).await? {
+        match probe_host(&address.to_string(), client).await {
+            Ok(device_info) if normalized_mac(&device_info.mac) == mac => return Ok(Some(address)),
+            Ok(_) => (),
+            Err(err) => debug!("{} answered mDNS, but is not a Shelly device: {}", address, err),
+        }
+    }
+    Ok(None)
+}
+
+/// Fetch a snapshot of the device settings, as JSON
+#[cfg(feature = "sqlite")]
+pub async fn settings(shelly_plug_config: &plug::Config, generation: plug::Generation,
+    client: &DeviceClient) -> Result<String, String>
+{
+    let client = client.for_device(shelly_plug_config);
+    let url = match generation {
+        plug::Generation::Gen1 => client.url(&shelly_plug_config.host, "/settings"),
+        plug::Generation::Gen2 => client.url(&shelly_plug_config.host, "/rpc/Shelly.GetConfig"),
+    };
+    client.get(&url).call().await
+        .map_err(|err| err.to_string())?
+        .text().await
+        .map_err(|err| format!("{} returned unreadable data: {}", url, err))
+}
+
+/// Probe all devices in parallel, waiting at most `budget` for all of them;
+/// returns the information of the devices which responded, by host
+pub async fn probe_all(shelly_plug_configs: &[plug::Config],
+    client: &DeviceClient, budget: Duration)
+-> HashMap<Arc<str>, DeviceInfo>
+{
+    let client = client.with_timeout(client.timeout().min(budget));
+    let mut probes = JoinSet::new();
+    for shelly_plug_config in shelly_plug_configs {
+        let shelly_plug_config = shelly_plug_config.clone();
+        let client = client.clone();
+        probes.spawn(async move {
+            let result = probe(&shelly_plug_config, &client).await;
+            (shelly_plug_config.host, result)
+        });
+    }
+
+    let mut found = HashMap::new();
+    let deadline = tokio::time::Instant::now() + budget;
+    loop {
+        match tokio::time::timeout_at(deadline, probes.join_next()).await {
+            Ok(Some(Ok((host, Ok(device_info))))) => {
+                info!("{} is {} (generation {}, firmware {}, MAC {})",
+                    host,
+                    device_info.model.as_deref().unwrap_or("unknown model"),
+                    device_info.generation(),
+                    device_info.fw.as_deref().unwrap_or("unknown"),
+                    device_info.mac);
+                found.insert(host, device_info);
+            },
+            Ok(Some(Ok((host, Err(err))))) => warn!("{} could not be probed: {}", host, err),
+            Ok(Some(Err(err))) => warn!("a device could not be probed: {}", err),
+            Ok(None) => break,
+            Err(_) => {
+                warn!("{} device(s) did not respond within {}ms of startup; \
+                    polling them anyway", probes.len(), budget.as_millis());
+                // The probes left are cancelled
+                break;
+            },
+        }
+    }
+    found
+}
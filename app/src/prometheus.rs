@@ -0,0 +1,145 @@
+use crate::point::Datum;
+use crate::sink::{self, Sink};
+use crate::tls;
+use serde::Deserialize;
+use std::sync::mpsc::Receiver;
+
+#[cfg(feature = "prometheus")]
+use {
+    crate::httpd,
+    crate::point::Measurement,
+    log::debug,
+    std::collections::BTreeMap,
+    std::fmt::Write,
+    std::sync::{Arc, Mutex},
+    std::thread::JoinHandle,
+};
+
+/// Prometheus exporter configuration
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(not(feature = "prometheus"), allow(dead_code))]
+pub struct Config {
+
+    /// Address of the HTTP endpoint, e.g. "0.0.0.0:9808"
+    pub listen_address: String,
+
+    /// TLS of the endpoint, if any
+    pub tls: Option<tls::ServerTls>,
+}
+
+/// Metrics exported, with the measurement they are the latest value of
+#[cfg(feature = "prometheus")]
+const METRICS: [(Measurement, &str, &str, &str); 10] = [
+    (Measurement::instantaneous_consumption_in_w,
+        "shelly_instantaneous_consumption_in_w", "gauge",
+        "Instantaneous power consumption in Watts"),
+    (Measurement::last_minute_consumption_in_wh,
+        "shelly_last_minute_consumption_in_wh", "gauge",
+        "Energy consumed in the last complete minute in Watt-hours"),
+    (Measurement::consumption_since_reboot_in_wh,
+        "shelly_consumption_since_reboot_in_wh_total", "counter",
+        "Energy consumed since the device rebooted in Watt-hours"),
+    (Measurement::voltage_in_v,
+        "shelly_voltage_in_v", "gauge",
+        "Supply voltage in Volts"),
+    (Measurement::temperature_in_c,
+        "shelly_temperature_in_c", "gauge",
+        "Internal temperature of the device in degrees Celsius"),
+    (Measurement::overtemperature,
+        "shelly_overtemperature", "gauge",
+        "Whether the device is overheated (1) or not (0)"),
+    (Measurement::relay_on,
+        "shelly_relay_on", "gauge",
+        "Whether the relay is on (1) or off (0)"),
+    (Measurement::current_in_a,
+        "shelly_current_in_a", "gauge",
+        "Current in Amperes"),
+    (Measurement::power_factor,
+        "shelly_power_factor", "gauge",
+        "Power factor"),
+    (Measurement::returned_since_reboot_in_wh,
+        "shelly_returned_since_reboot_in_wh_total", "counter",
+        "Energy returned to the grid since the device rebooted in Watt-hours"),
+];
+
+/// Latest values by device name and host, in the order of `METRICS`
+#[cfg(feature = "prometheus")]
+type Values = Arc<Mutex<BTreeMap<(Arc<str>, Arc<str>), [Option<f32>; METRICS.len()]>>>;
+
+impl Sink for Config {
+    #[cfg_attr(not(feature = "prometheus"), allow(unused_variables))]
+    fn spawn(self: Box<Self>, _context: &sink::Context, data_receiver: Receiver<Datum>)
+    -> Result<sink::Handle, String>
+    {
+        #[cfg(feature = "prometheus")]
+        return Exporter::spawn(*self, data_receiver);
+        #[cfg(not(feature = "prometheus"))]
+        return Err(format!("Prometheus exporter on {} needs the 'prometheus' feature", self.listen_address));
+    }
+}
+
+/// Serves the latest values of the plugs to be scraped by Prometheus
+#[cfg(feature = "prometheus")]
+pub struct Exporter;
+
+#[cfg(feature = "prometheus")]
+impl Exporter {
+
+    pub fn spawn(prometheus_config: Config, data_receiver: Receiver<Datum>)
+    -> Result<JoinHandle<Result<(),String>>, String>
+    {
+        let values: Values = Arc::default();
+        let served = values.clone();
+        httpd::spawn(&prometheus_config.listen_address, "Prometheus exporter",
+            prometheus_config.tls.as_ref(), move |request| Exporter::respond(&served, request))?;
+
+        Ok(std::thread::spawn(move || {
+            for datum in data_receiver {
+                // A faulty reading is not exported, so the previous value stays
+                let index = match METRICS.iter()
+                    .position(|(measurement, ..)| *measurement == datum.measurement) {
+                    Some(index) if datum.valid => index,
+                    _ => continue,
+                };
+                values.lock().expect("internal error, Prometheus lock poisoned")
+                    .entry((datum.device_name.clone(), datum.device_host.clone()))
+                    .or_default()[index] = Some(datum.value);
+            }
+            debug!("all meters stopped, stopping");
+            Ok(())
+        }))
+    }
+
+    /// "/metrics" in the text exposition format
+    fn respond(values: &Values, request: &httpd::Request) -> httpd::Response {
+        if request.method != "GET" {
+            return httpd::Response::text(405, "only GET is supported\n");
+        }
+        if request.path != "/metrics" {
+            return httpd::Response::not_found();
+        }
+        let values = values.lock().expect("internal error, Prometheus lock poisoned");
+        let mut text = String::new();
+        for (index, (_, name, kind, help)) in METRICS.iter().enumerate() {
+            let _ = writeln!(text, "# HELP {} {}", name, help);
+            let _ = writeln!(text, "# TYPE {} {}", name, kind);
+            for ((device_name, device_host), latest) in values.iter() {
+                if let Some(value) = latest[index] {
+                    let _ = writeln!(text, "{}{{device_name=\"{}\",device_host=\"{}\"}} {}",
+                        name, escape(device_name), escape(device_host), value);
+                }
+            }
+        }
+        httpd::Response {
+            status: 200,
+            content_type: "text/plain; version=0.0.4; charset=utf-8",
+            body: text.into_bytes(),
+        }
+    }
+}
+
+/// Label value with the backslashes, quotes and new lines escaped
+#[cfg(feature = "prometheus")]
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -0,0 +1,102 @@
+use crate::audit;
+use crate::config;
+use crate::network::DeviceClient;
+use crate::plug;
+use crate::probe;
+use crate::runtime;
+use serde::Deserialize;
+
+/// Result of switching a relay
+pub struct Switch {
+    /// State before the switch, if the device reported it
+    pub was_on: Option<bool>,
+    /// State after the switch
+    pub is_on: bool,
+}
+
+/// Gen1 "/relay/{channel}" response
+#[derive(Deserialize)]
+struct RelayStatus {
+    ison: bool,
+}
+
+/// Gen2 "Switch.Set" response
+#[derive(Deserialize)]
+struct SwitchSetResult {
+    was_on: bool,
+}
+
+/// Switch the relay of the channel of the device on or off
+pub async fn switch(shelly_plug_config: &plug::Config, on: bool, client: &DeviceClient)
+-> Result<Switch, String>
+{
+    let host = &shelly_plug_config.host;
+    let channel = shelly_plug_config.channel.to_string();
+    let client = &client.for_device(shelly_plug_config);
+    match probe::probe(shelly_plug_config, client).await?.generation() {
+        plug::Generation::Gen1 => {
+            let url = client.url(host, &format!("/relay/{}", channel));
+            let was_on = get::<RelayStatus>(client, &url, &[]).await.ok()
+                .map(|status| status.ison);
+            let status: RelayStatus = get(client, &url,
+                &[("turn", if on { "on" } else { "off" })]).await?;
+            Ok(Switch { was_on, is_on: status.ison })
+        },
+        plug::Generation::Gen2 => {
+            let url = client.url(host, "/rpc/Switch.Set");
+            let result: SwitchSetResult = get(client, &url,
+                &[("id", &channel), ("on", if on { "true" } else { "false" })]).await?;
+            Ok(Switch { was_on: Some(result.was_on), is_on: on })
+        },
+    }
+}
+
+/// Reboot the device at the host, by the client for the device
+pub async fn reboot(host: &str, client: &DeviceClient) -> Result<(), String> {
+    let url = match probe::probe_host(host, client).await?.generation() {
+        plug::Generation::Gen1 => client.url(host, "/reboot"),
+        plug::Generation::Gen2 => client.url(host, "/rpc/Shelly.Reboot"),
+    };
+    client.get(&url).call().await.map_err(|err| err.to_string())?;
+    Ok(())
+}
+
+/// Switch the relay of the configured device from the command line,
+/// recording it in the audit log
+pub fn command(app_config: &config::Config, device: &str, on: bool) -> Result<(), String> {
+    let shelly_plug_config = app_config.shelly_plugs.iter()
+        .find(|shelly_plug_config| &*shelly_plug_config.name == device)
+        .ok_or_else(|| format!("there is no device '{}' in the config", device))?;
+
+    // Opened first, so that the relay is not switched without a record
+    let mut audit_log = app_config.audit_log.as_ref().map(audit::AuditLog::open).transpose()?;
+
+    let switch = runtime::block_on(switch(shelly_plug_config, on, &app_config.device_client()))?;
+    println!("{} is {}", device, if switch.is_on { "on" } else { "off" });
+    if let Some(audit_log) = &mut audit_log {
+        audit_log.append(audit::Action {
+            time: chrono::Utc::now(),
+            actor: audit::cli_actor(),
+            rule: None,
+            device: device.to_string(),
+            host: shelly_plug_config.host.to_string(),
+            command: None,
+            was_on: switch.was_on,
+            is_on: Some(switch.is_on),
+        }).map_err(|err| format!("{} was switched, but not recorded: {}", device, err))?;
+    }
+    Ok(())
+}
+
+async fn get<T: serde::de::DeserializeOwned>(client: &DeviceClient, url: &str, query: &[(&str, &str)])
+-> Result<T, String>
+{
+    let mut request = client.get(url);
+    for (name, value) in query {
+        request = request.query(name, value);
+    }
+    request.call().await
+        .map_err(|err| err.to_string())?
+        .json().await
+        .map_err(|err| format!("{} returned unexpected data: {}", url, err))
+}
@@ -0,0 +1,172 @@
+use crate::config;
+use crate::network;
+use crate::plug;
+use crate::scheduler::{Intake, Poll, Task};
+use crate::signals;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
+
+/// Meters of a device in the config, e.g. of its power and of its status
+pub type MeterFactory = Box<dyn Fn(&plug::Config) -> Vec<Box<dyn Task>> + Send>;
+
+/// Meter which finishes at its next run once its device is removed from
+/// the config (or changed, and metered anew)
+struct Revocable {
+    task: Box<dyn Task>,
+    revoked: Arc<AtomicBool>,
+}
+
+impl Task for Revocable {
+
+    fn poll(&mut self) -> Poll<'_> {
+        if self.revoked.load(Ordering::Relaxed) {
+            return Box::pin(std::future::ready(Ok(None)));
+        }
+        self.task.poll()
+    }
+
+    fn resume(&mut self) {
+        self.task.resume()
+    }
+}
+
+/// Device metered by its config
+struct Metered {
+    shelly_plug_config: plug::Config,
+    /// Set to stop its meters
+    revoked: Arc<AtomicBool>,
+}
+
+/// Checks whether the config file changed (or SIGHUP was received), and
+/// then meters the devices added to it and stops the meters of those
+/// removed; a changed device is metered anew
+///
+/// Other settings take effect only after a restart, so the sinks keep
+/// running (and writing) meanwhile.
+pub struct Reloader {
+    /// Config of the start, to which the other settings are compared
+    app_config: config::Config,
+    /// Modification time of the config file when it was last read
+    modified: Option<SystemTime>,
+    hangups: u64,
+    /// Devices polled by the config, by name
+    metered: HashMap<Arc<str>, Metered>,
+    meter: MeterFactory,
+    intake: Intake,
+}
+
+impl Reloader {
+
+    pub fn new(app_config: config::Config, meter: MeterFactory, intake: Intake) -> Reloader {
+        if let Some(interval) = app_config.config_reload_interval() {
+            info!("Reloading the devices whenever {} changes (checked every {}s)",
+                config::path().display(), interval.as_secs());
+        }
+        Reloader { app_config, modified: modified(), hangups: signals::hangups(),
+            metered: HashMap::new(), meter, intake }
+    }
+
+    /// Meters of the devices, which stop once they are removed from the config
+    pub fn meters(&mut self, shelly_plug_configs: &[plug::Config]) -> Vec<Box<dyn Task>> {
+        let mut meters: Vec<Box<dyn Task>> = vec![];
+        for shelly_plug_config in shelly_plug_configs {
+            let revoked = Arc::new(AtomicBool::new(false));
+            meters.extend((self.meter)(shelly_plug_config).into_iter()
+                .map(|task| Box::new(Revocable { task, revoked: revoked.clone() }) as Box<dyn Task>));
+            self.metered.insert(shelly_plug_config.name.clone(),
+                Metered { shelly_plug_config: shelly_plug_config.clone(), revoked });
+        }
+        meters
+    }
+
+    /// Meter the devices as configured now; false if the scheduler finished
+    fn reload(&mut self) -> Result<bool, String> {
+        let app_config = config::Config::read(config::path())?
+            .with_unique_plugs()?;
+        if fixed_settings(&app_config) != fixed_settings(&self.app_config) {
+            warn!("{} changed besides the polled devices, which takes effect \
+                only after a restart", config::path().display());
+        }
+        let polled_plugs = app_config.polled_plugs();
+
+        let mut changed = vec![];
+        self.metered.retain(|name, metered| {
+            let shelly_plug_config = polled_plugs.iter()
+                .find(|shelly_plug_config| shelly_plug_config.name == *name);
+            match shelly_plug_config {
+                // Equal configs include equal passwords, as secrets compare by their values
+                Some(shelly_plug_config) if *shelly_plug_config == metered.shelly_plug_config =>
+                    return true,
+                Some(_) => {
+                    info!("{} was changed in the config, metering it anew", name);
+                    changed.push(name.clone());
+                },
+                None => info!("{} was removed from the config, it is no longer metered", name),
+            }
+            metered.revoked.store(true, Ordering::Relaxed);
+            false
+        });
+
+        for shelly_plug_config in &polled_plugs {
+            if self.metered.contains_key(&shelly_plug_config.name) {
+                continue;
+            }
+            if let Some(allowed_networks) = &self.app_config.allowed_networks {
+                if let Err(err) = network::check_host(&shelly_plug_config.host, allowed_networks) {
+                    warn!("{} can not be polled: {}", shelly_plug_config.name, err);
+                    continue;
+                }
+            }
+            if !changed.contains(&shelly_plug_config.name) {
+                info!("{} was added to the config, metering it", shelly_plug_config.name);
+            }
+            for meter in self.meters(std::slice::from_ref(shelly_plug_config)) {
+                if !self.intake.add(meter) {
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl Task for Reloader {
+
+    fn poll(&mut self) -> Poll<'_> {
+        Box::pin(async move {
+            let interval = match self.app_config.config_reload_interval() {
+                Some(interval) => interval,
+                None => return Ok(None),
+            };
+            let modified = modified();
+            let hangups = signals::hangups();
+            if modified != self.modified || hangups != self.hangups {
+                self.modified = modified;
+                self.hangups = hangups;
+                match self.reload() {
+                    Ok(true) => (),
+                    Ok(false) => return Ok(None),
+                    Err(err) => warn!("devices were not reloaded, {}", err),
+                }
+            }
+            Ok(Some(interval))
+        })
+    }
+}
+
+/// Modification time of the config file, if it can be found out
+fn modified() -> Option<SystemTime> {
+    std::fs::metadata(config::path())
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Settings which take effect only after a restart, i.e. all but the polled devices
+fn fixed_settings(app_config: &config::Config) -> config::Config {
+    let mut app_config = app_config.clone();
+    app_config.shelly_plugs.retain(|shelly_plug_config| shelly_plug_config.mode() == plug::Mode::Mqtt);
+    app_config
+}
@@ -0,0 +1,486 @@
+use crate::calendar::Calendar;
+use chrono::{Datelike, Duration, NaiveDate};
+use serde::Deserialize;
+use std::path::PathBuf;
+
+#[cfg(feature = "sqlite")]
+use {
+    crate::config,
+    crate::point::Measurement::{consumption_today_in_wh, last_minute_consumption_in_wh},
+    crate::store,
+    crate::tariff,
+    chrono::{TimeZone, Utc},
+    std::collections::{BTreeMap, BTreeSet},
+    std::io::Write,
+    std::path::Path,
+};
+
+#[cfg(feature = "reports")]
+use {
+    log::{debug, info, warn},
+    serde::Serialize,
+};
+
+/// Interval between the checks whether a period ended, so that its report is due
+#[cfg(feature = "reports")]
+const HTML_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(600);
+
+/// Template of the HTML reports, unless another one is configured
+#[cfg(feature = "reports")]
+const DEFAULT_TEMPLATE: &str = include_str!("../templates/report.html");
+
+/// Configuration of the consumption reports, which are priced by the `tariff`
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Config {
+
+    /// Deprecated, the `tariff.price_per_kwh` if there is no `tariff`
+    pub price_per_kwh: Option<f64>,
+
+    /// Deprecated, the `tariff.currency` if there is no `tariff`
+    pub currency: Option<String>,
+
+    /// HTML reports written at the end of each period, if any
+    pub html: Option<HtmlConfig>,
+}
+
+/// Configuration of the HTML reports
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(not(feature = "reports"), allow(dead_code))]
+pub struct HtmlConfig {
+
+    /// Directory into which the reports are written
+    pub directory: PathBuf,
+
+    /// Tera template of the reports; a built-in one if not set
+    pub template: Option<PathBuf>,
+
+    /// Periods after which a report is written
+    #[serde(default = "HtmlConfig::default_periods")]
+    periods: Vec<Period>,
+
+    /// Number of the top consumers listed
+    #[serde(default = "HtmlConfig::default_top")]
+    top: usize,
+}
+
+impl HtmlConfig {
+    fn default_periods() -> Vec<Period> { vec![Period::Day, Period::Week, Period::Month] }
+    fn default_top() -> usize { 5 }
+}
+
+/// Period covered by a report, of the days (and billing cycles) of the `calendar`
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+#[cfg_attr(not(feature = "reports"), allow(dead_code))]
+pub enum Period {
+    Day,
+    /// Monday to Sunday
+    Week,
+    /// Billing cycle, a calendar month unless it starts on another day
+    Month,
+}
+
+#[cfg_attr(not(feature = "reports"), allow(dead_code))]
+impl Period {
+
+    /// First and last day of the period containing the day
+    pub fn range(&self, day: NaiveDate, calendar: &Calendar) -> (NaiveDate, NaiveDate) {
+        match self {
+            Period::Day => (day, day),
+            Period::Week => {
+                let monday = day - Duration::days(day.weekday().num_days_from_monday() as i64);
+                (monday, monday + Duration::days(6))
+            },
+            Period::Month => calendar.billing_cycle_of(day),
+        }
+    }
+
+    /// Name of the period starting on the day, e.g. "2024-W27" for a week
+    pub fn name(&self, first: NaiveDate) -> String {
+        match self {
+            Period::Day => first.format("%Y-%m-%d").to_string(),
+            Period::Week => first.format("%G-W%V").to_string(),
+            Period::Month => first.format("%Y-%m").to_string(),
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Period::Day => "day",
+            Period::Week => "week",
+            Period::Month => "month",
+        }
+    }
+}
+
+/// Consumption of the devices by day, in kWh
+#[cfg(feature = "sqlite")]
+pub struct Consumption {
+    pub devices: BTreeSet<String>,
+    pub days: BTreeMap<NaiveDate, BTreeMap<String, f64>>,
+    /// Cost of the consumption by day, if priced by a `tariff`
+    costs: Option<BTreeMap<NaiveDate, BTreeMap<String, f64>>>,
+    /// Currency of the costs
+    pub currency: Option<String>,
+}
+
+#[cfg(feature = "sqlite")]
+impl Consumption {
+
+    /// Daily consumption in the days from `first` to `last`, by the calendar of
+    /// the config; a day is totalled by its last (largest) `consumption_today_in_wh`
+    ///
+    /// With a `tariff`, the consumption of a day is priced by the average price
+    /// of its minutes (`last_minute_consumption_in_wh`) weighted by their
+    /// consumption, or by the price outside of the schedule if they are not stored.
+    pub fn read(app_config: &config::Config, first: NaiveDate, last: NaiveDate)
+    -> Result<Consumption, String> {
+        let store_config = app_config.local_store.as_ref()
+            .ok_or("there is no 'local_store' in the config")?;
+        let store = store::Store::open(&store_config.path)?;
+        let query_error = |err: rusqlite::Error|
+            format!("{} can not be queried: {}", store_config.path.display(), err);
+        // Days may start up to a day off the UTC dates, by the time zone and hour
+        let mut filter = store::Filter {
+            device_name: None,
+            measurement: Some(consumption_today_in_wh.to_string()),
+            since: Some(Utc.from_utc_datetime(&(first - Duration::days(2)).and_hms_opt(0, 0, 0)
+                .expect("midnight exists"))),
+            until: Some(Utc.from_utc_datetime(&(last + Duration::days(2)).and_hms_opt(0, 0, 0)
+                .expect("midnight exists"))),
+        };
+        let mut consumption = Consumption {
+            devices: BTreeSet::new(),
+            days: BTreeMap::new(),
+            costs: None,
+            currency: app_config.tariff.as_ref().and_then(|tariff_config| tariff_config.currency.clone()),
+        };
+        store.query(&filter, |row| {
+            let day = app_config.calendar.day_of(row.measured_on);
+            if !row.valid || day < first || day > last {
+                return;
+            }
+            let kwh = row.value / 1000.0;
+            let day_total = consumption.days.entry(day).or_default()
+                .entry(row.device_name.clone()).or_default();
+            *day_total = day_total.max(kwh);
+            consumption.devices.insert(row.device_name);
+        }).map_err(query_error)?;
+
+        let pricing = match &app_config.tariff {
+            Some(tariff_config) => tariff::Pricing::new(tariff_config.clone(), app_config.calendar),
+            None => return Ok(consumption),
+        };
+        // Consumption (in kWh) and its cost of the stored minutes, by day and device
+        let mut minutes: BTreeMap<(NaiveDate, String), (f64, f64)> = BTreeMap::new();
+        if !pricing.is_flat() {
+            filter.measurement = Some(last_minute_consumption_in_wh.to_string());
+            store.query(&filter, |row| {
+                let day = app_config.calendar.day_of(row.measured_on);
+                if !row.valid || day < first || day > last {
+                    return;
+                }
+                let kwh = row.value / 1000.0;
+                let (minutes_kwh, minutes_cost) = minutes.entry((day, row.device_name)).or_default();
+                *minutes_kwh += kwh;
+                *minutes_cost += kwh * pricing.price_at(row.measured_on);
+            }).map_err(query_error)?;
+        }
+        consumption.costs = Some(consumption.days.iter().map(|(day, devices)| {
+            let costs = devices.iter().map(|(device, kwh)| {
+                let price = match minutes.get(&(*day, device.clone())) {
+                    Some((minutes_kwh, minutes_cost)) if *minutes_kwh > 0.0 => minutes_cost / minutes_kwh,
+                    _ => pricing.base_price(),
+                };
+                (device.clone(), kwh * price)
+            }).collect();
+            (*day, costs)
+        }).collect());
+        Ok(consumption)
+    }
+
+    /// Consumption of the device in all the days
+    pub fn total(&self, device: &str) -> f64 {
+        sum(self.days.values().filter_map(|devices| devices.get(device)).copied())
+    }
+
+    /// Whether the costs are known, by a `tariff`
+    pub fn is_priced(&self) -> bool {
+        self.costs.is_some()
+    }
+
+    /// Cost of the consumption of the device in all the days, if priced
+    pub fn total_cost(&self, device: &str) -> Option<f64> {
+        self.costs.as_ref()
+            .map(|costs| sum(costs.values().filter_map(|devices| devices.get(device)).copied()))
+    }
+
+    /// Cost of the consumption of all devices in the day, if priced
+    pub fn day_cost(&self, day: NaiveDate) -> Option<f64> {
+        self.costs.as_ref()
+            .map(|costs| sum(costs.get(&day).into_iter().flat_map(|devices| devices.values()).copied()))
+    }
+}
+
+/// Write the daily consumption of the devices in the billing cycle starting
+/// in the month (its first day) as CSV: a row per day and a column per device,
+/// with the totals (and the cost, if priced) in the last column and rows
+#[cfg(feature = "sqlite")]
+pub fn export(app_config: &config::Config, month: NaiveDate, output: Option<&Path>)
+-> Result<(), String>
+{
+    let (first, last) = app_config.calendar.billing_cycle_starting_in(month);
+    let consumption = Consumption::read(app_config, first, last)?;
+    let priced = consumption.is_priced();
+    let cost_header = match &consumption.currency {
+        Some(currency) => format!("cost_{}", currency),
+        None => "cost".to_string(),
+    };
+
+    let mut csv = String::new();
+    let mut header = vec!["date".to_string()];
+    header.extend(consumption.devices.iter().map(|device| format!("{}_kwh", device)));
+    header.push("total_kwh".to_string());
+    if priced {
+        header.push(cost_header.clone());
+    }
+    push_row(&mut csv, &header);
+
+    let mut day = first;
+    while day <= last {
+        let devices = consumption.days.get(&day);
+        let kwh: Vec<Option<f64>> = consumption.devices.iter()
+            .map(|device| devices.and_then(|devices| devices.get(device)).copied())
+            .collect();
+        let total = sum(kwh.iter().flatten().copied());
+        let mut row = vec![day.to_string()];
+        row.extend(kwh.iter().map(|kwh| kwh.map(format_kwh).unwrap_or_default()));
+        row.push(format_kwh(total));
+        row.extend(consumption.day_cost(day).map(format_cost));
+        push_row(&mut csv, &row);
+        day = day.succ_opt().expect("dates do not run out");
+    }
+
+    let totals: Vec<f64> = consumption.devices.iter()
+        .map(|device| consumption.total(device))
+        .collect();
+    let total = sum(totals.iter().copied());
+    let mut row = vec!["total".to_string()];
+    row.extend(totals.iter().copied().map(format_kwh));
+    row.push(format_kwh(total));
+    let costs: Vec<f64> = consumption.devices.iter()
+        .filter_map(|device| consumption.total_cost(device))
+        .collect();
+    let total_cost = sum(costs.iter().copied());
+    if priced {
+        row.push(format_cost(total_cost));
+    }
+    push_row(&mut csv, &row);
+    if priced {
+        let mut row = vec![cost_header];
+        row.extend(costs.iter().copied().map(format_cost));
+        row.push(String::new());
+        row.push(format_cost(total_cost));
+        push_row(&mut csv, &row);
+    }
+
+    match output {
+        Some(output) => std::fs::write(output, csv)
+            .map_err(|err| format!("{} can not be written: {}", output.display(), err)),
+        None => std::io::stdout().write_all(csv.as_bytes())
+            .map_err(|err| format!("report can not be written: {}", err)),
+    }
+}
+
+/// Device in an HTML report
+#[cfg(feature = "reports")]
+#[derive(Serialize, Clone)]
+struct DeviceRow {
+    name: String,
+    kwh: String,
+    cost: Option<String>,
+    /// Share in the total consumption, in percent
+    share: String,
+}
+
+/// Day in an HTML report
+#[cfg(feature = "reports")]
+#[derive(Serialize)]
+struct DayRow {
+    date: String,
+    kwh: String,
+    cost: Option<String>,
+}
+
+/// Values available to the template of an HTML report
+#[cfg(feature = "reports")]
+#[derive(Serialize)]
+struct HtmlContext {
+    /// "day", "week" or "month"
+    period: &'static str,
+    /// Name of the period, e.g. "2024-W27"
+    name: String,
+    first: String,
+    last: String,
+    generated_on: String,
+    currency: Option<String>,
+    total_kwh: String,
+    total_cost: Option<String>,
+    /// All devices, by name
+    devices: Vec<DeviceRow>,
+    /// Devices with the largest consumption, largest first
+    top: Vec<DeviceRow>,
+    days: Vec<DayRow>,
+}
+
+/// Render the HTML report of the period containing the day
+#[cfg(feature = "reports")]
+pub fn render_html(app_config: &config::Config, period: Period, day: NaiveDate)
+-> Result<String, String>
+{
+    let (first, last) = period.range(day, &app_config.calendar);
+    let consumption = Consumption::read(app_config, first, last)?;
+    let report_config = &app_config.report;
+    let html_config = report_config.html.as_ref();
+
+    let template = match html_config.and_then(|html_config| html_config.template.as_ref()) {
+        Some(path) => std::fs::read_to_string(path)
+            .map_err(|err| format!("{} can not be read: {}", path.display(), err))?,
+        None => DEFAULT_TEMPLATE.to_string(),
+    };
+    let mut tera = tera::Tera::default();
+    // Named .html, so that the values are escaped
+    tera.add_raw_template("report.html", &template)
+        .map_err(|err| format!("report template is not valid: {}", err))?;
+
+    let total_kwh = sum(consumption.devices.iter().map(|device| consumption.total(device)));
+    let devices: Vec<DeviceRow> = consumption.devices.iter().map(|device| {
+        let kwh = consumption.total(device);
+        DeviceRow {
+            name: device.clone(),
+            kwh: format_kwh(kwh),
+            cost: consumption.total_cost(device).map(format_cost),
+            share: format!("{:.1}", if total_kwh > 0.0 { kwh / total_kwh * 100.0 } else { 0.0 }),
+        }
+    }).collect();
+    let mut top: Vec<(f64, &DeviceRow)> = consumption.devices.iter()
+        .map(|device| consumption.total(device))
+        .zip(&devices)
+        .collect();
+    top.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+    let mut days = vec![];
+    let mut date = first;
+    while date <= last {
+        let kwh = sum(consumption.days.get(&date).into_iter().flat_map(|devices| devices.values()).copied());
+        days.push(DayRow {
+            date: date.to_string(),
+            kwh: format_kwh(kwh),
+            cost: consumption.day_cost(date).map(format_cost),
+        });
+        date = date.succ_opt().expect("dates do not run out");
+    }
+
+    let context = HtmlContext {
+        period: period.as_str(),
+        name: period.name(first),
+        first: first.to_string(),
+        last: last.to_string(),
+        generated_on: Utc::now().to_rfc3339(),
+        currency: consumption.currency.clone(),
+        total_kwh: format_kwh(total_kwh),
+        total_cost: consumption.is_priced()
+            .then(|| format_cost(sum(consumption.devices.iter()
+                .filter_map(|device| consumption.total_cost(device))))),
+        top: top.into_iter()
+            .take(html_config.map_or(HtmlConfig::default_top(), |html_config| html_config.top))
+            .map(|(_, device)| device.clone())
+            .collect(),
+        devices,
+        days,
+    };
+    let context = tera::Context::from_serialize(&context)
+        .map_err(|err| format!("report can not be rendered: {}", err))?;
+    tera.render("report.html", &context)
+        .map_err(|err| format!("report can not be rendered: {}", err))
+}
+
+/// Write the HTML report of the period containing the day
+#[cfg(feature = "reports")]
+pub fn html(app_config: &config::Config, period: Period, day: NaiveDate, output: Option<&Path>)
+-> Result<(), String>
+{
+    let html = render_html(app_config, period, day)?;
+    match output {
+        Some(output) => std::fs::write(output, html)
+            .map_err(|err| format!("{} can not be written: {}", output.display(), err)),
+        None => std::io::stdout().write_all(html.as_bytes())
+            .map_err(|err| format!("report can not be written: {}", err)),
+    }
+}
+
+/// Write the HTML report of each period once it ended, in a background
+/// thread; the reports already in the directory are not written again
+#[cfg(feature = "reports")]
+pub fn spawn_html(html_config: HtmlConfig, app_config: config::Config) -> Result<(), String> {
+    std::fs::create_dir_all(&html_config.directory).map_err(|err| format!(
+        "{} can not be created: {}", html_config.directory.display(), err))?;
+    info!("Writing HTML reports into {}", html_config.directory.display());
+    std::thread::spawn(move || loop {
+        let ended = app_config.calendar.day_of(Utc::now()).pred_opt()
+            .expect("dates do not run out");
+        for period in &html_config.periods {
+            let (first, last) = period.range(ended, &app_config.calendar);
+            if last != ended {
+                continue;
+            }
+            let path = html_config.directory.join(
+                format!("{}-{}.html", period.as_str(), period.name(first)));
+            if path.exists() {
+                continue;
+            }
+            match render_html(&app_config, *period, ended)
+                .and_then(|html| std::fs::write(&path, html)
+                    .map_err(|err| format!("{} can not be written: {}", path.display(), err))) {
+                Ok(()) => debug!("report {} written", path.display()),
+                Err(err) => warn!("{} report of {} not written: {}", period.as_str(),
+                    period.name(first), err),
+            }
+        }
+        std::thread::sleep(HTML_CHECK_INTERVAL);
+    });
+    Ok(())
+}
+
+/// Sum of the values, 0 (not -0) if there are none
+#[cfg(feature = "sqlite")]
+fn sum(values: impl Iterator<Item = f64>) -> f64 {
+    values.fold(0.0, |sum, value| sum + value)
+}
+
+#[cfg(feature = "sqlite")]
+fn format_kwh(kwh: f64) -> String {
+    format!("{:.3}", kwh)
+}
+
+#[cfg(feature = "sqlite")]
+fn format_cost(cost: f64) -> String {
+    format!("{:.2}", cost)
+}
+
+/// Append a CSV row, quoting the fields which need it
+#[cfg(any(feature = "sqlite", feature = "files"))]
+pub fn push_row(csv: &mut String, fields: &[String]) {
+    for (index, field) in fields.iter().enumerate() {
+        if index > 0 {
+            csv.push(',');
+        }
+        if field.contains([',', '"', '\n', '\r']) {
+            csv.push('"');
+            csv.push_str(&field.replace('"', "\"\""));
+            csv.push('"');
+        } else {
+            csv.push_str(field);
+        }
+    }
+    csv.push_str("\r\n");
+}
@@ -0,0 +1,144 @@
+use log::{debug, info, warn};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// How often are the retention policies applied
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Limits of the data kept in local files
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Policy {
+
+    /// Data older than this many days is deleted
+    #[serde(alias = "retention_days")]
+    pub max_age_days: Option<u32>,
+
+    /// The oldest data is deleted to keep the disk usage under this many megabytes
+    pub max_size_mb: Option<u64>,
+}
+
+impl Policy {
+
+    /// Whether the policy limits anything at all
+    pub fn is_unlimited(&self) -> bool {
+        self.max_age_days.is_none() && self.max_size_mb.is_none()
+    }
+
+    /// Maximal age of the data
+    pub fn max_age(&self) -> Option<Duration> {
+        self.max_age_days.map(|days| Duration::from_secs(days as u64 * 24 * 3600))
+    }
+
+    /// Maximal disk usage in bytes
+    pub fn max_size(&self) -> Option<u64> {
+        self.max_size_mb.map(|mb| mb * 1024 * 1024)
+    }
+
+    /// Delete the prunable files in the directory (recursively), which are too
+    /// old or over the size limit, oldest first; returns the number deleted
+    pub fn apply(&self, directory: &Path, prunable: &dyn Fn(&Path) -> bool) -> usize {
+        let mut files: Vec<(SystemTime, u64, PathBuf)> = vec![];
+        list_files(directory, prunable, &mut files);
+        files.sort();
+
+        let now = SystemTime::now();
+        let mut total_size: u64 = files.iter().map(|(_, size, _)| size).sum();
+        let mut deleted = 0;
+        for (modified, size, path) in files {
+            let too_old = self.max_age().is_some_and(|max_age|
+                now.duration_since(modified).unwrap_or_default() > max_age);
+            let too_big = self.max_size().is_some_and(|max_size| total_size > max_size);
+            if !too_old && !too_big {
+                break; // files are sorted from the oldest
+            }
+            match std::fs::remove_file(&path) {
+                Ok(_) => {
+                    total_size -= size;
+                    deleted += 1;
+                },
+                Err(err) => warn!("{} can not be deleted: {}", path.display(), err),
+            }
+        }
+        deleted
+    }
+}
+
+/// Collect modification times, sizes and paths of the prunable files in the directory
+fn list_files(directory: &Path, prunable: &dyn Fn(&Path) -> bool,
+    files: &mut Vec<(SystemTime, u64, PathBuf)>)
+{
+    let entries = match std::fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(err) => {
+            debug!("{} can not be listed: {}", directory.display(), err);
+            return;
+        }
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => list_files(&path, prunable, files),
+            Ok(_) if !prunable(&path) => (),
+            Ok(metadata) => files.push((
+                metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                metadata.len(), path)),
+            Err(err) => debug!("{} can not be inspected: {}", path.display(), err),
+        }
+    }
+}
+
+/// Directory kept within the limits of a policy
+pub struct Target {
+    pub directory: PathBuf,
+    pub policy: Policy,
+    /// Whether the file may be deleted, i.e. it was written by the logger
+    /// (the directory may be shared with other files) and is not being written
+    pub prunable: Box<dyn Fn(&Path) -> bool + Send>,
+}
+
+/// Apply retention policies to directories periodically, in a background thread
+pub fn spawn_pruner(targets: Vec<Target>) {
+    let targets: Vec<Target> = targets.into_iter()
+        .filter(|target| !target.policy.is_unlimited())
+        .collect();
+    if targets.is_empty() {
+        return;
+    }
+    std::thread::spawn(move || loop {
+        for target in &targets {
+            let deleted = target.policy.apply(&target.directory, &target.prunable);
+            if deleted > 0 {
+                info!("{} old files deleted from {}", deleted, target.directory.display());
+            }
+        }
+        std::thread::sleep(PRUNE_INTERVAL);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_prunable_files_are_deleted() {
+        let directory = std::env::temp_dir()
+            .join(format!("shelly-logger-retention-{}", std::process::id()));
+        std::fs::create_dir_all(directory.join("device")).unwrap();
+        let day_file = directory.join("2024-07-01.csv");
+        let writing = directory.join("2024-07-02.csv");
+        let unrelated = [directory.join("state.json"), directory.join("device").join("spill.lp")];
+        for path in unrelated.iter().chain([&day_file, &writing]) {
+            std::fs::write(path, "data").unwrap();
+        }
+
+        let policy = Policy { max_age_days: None, max_size_mb: Some(0) };
+        let deleted = policy.apply(&directory, &|path| path.extension()
+            .is_some_and(|extension| extension == "csv") && path != writing);
+        assert_eq!(deleted, 1);
+        assert!(!day_file.exists());
+        assert!(writing.exists());
+        assert!(unrelated.iter().all(|path| path.exists()));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
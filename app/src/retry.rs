@@ -0,0 +1,54 @@
+use serde::Deserialize;
+use std::time::Duration;
+
+/// Delays of the retries of a failing device: the initial delay after the
+/// first failure, growing by the backoff factor with each consecutive one,
+/// up to the maximal delay
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Policy {
+
+    /// Delay after the first failure, in seconds
+    #[serde(default = "Policy::default_initial_delay_s")]
+    pub initial_delay_s: f64,
+
+    /// Factor by which the delay grows with each consecutive failure
+    #[serde(default = "Policy::default_backoff_factor")]
+    pub backoff_factor: f64,
+
+    /// Longest delay, in seconds
+    #[serde(default = "Policy::default_max_delay_s")]
+    pub max_delay_s: f64,
+
+    /// Consecutive failures after which the device is marked down; never if not set
+    #[serde(default)]
+    pub down_after_failures: Option<u64>,
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy {
+            initial_delay_s: Policy::default_initial_delay_s(),
+            backoff_factor: Policy::default_backoff_factor(),
+            max_delay_s: Policy::default_max_delay_s(),
+            down_after_failures: None,
+        }
+    }
+}
+
+impl Policy {
+
+    fn default_initial_delay_s() -> f64 { 60.0 }
+
+    fn default_backoff_factor() -> f64 { 2.0 }
+
+    fn default_max_delay_s() -> f64 { 600.0 }
+
+    /// Delay of the retry after the consecutive failures (1 for the first one)
+    pub fn delay(&self, failures: u64) -> Duration {
+        let exponent = failures.saturating_sub(1).min(i32::MAX as u64) as i32;
+        let delay_s = self.initial_delay_s.max(0.0)
+            * self.backoff_factor.max(1.0).powi(exponent);
+        Duration::try_from_secs_f64(delay_s.min(self.max_delay_s.max(0.0)))
+            .unwrap_or(Duration::MAX)
+    }
+}
@@ -0,0 +1,31 @@
+use std::future::Future;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::runtime::{Builder, Runtime};
+
+/// Worker threads of the runtime, set before it is started
+static WORKER_THREADS: AtomicUsize = AtomicUsize::new(4);
+
+/// Runtime shared by the meters and the clients of the sinks
+static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+/// Number of worker threads of the runtime, before it is first used
+pub fn use_worker_threads(count: usize) {
+    WORKER_THREADS.store(count.max(1), Ordering::Relaxed);
+}
+
+/// Runtime shared by the whole logger, started when first used
+pub fn get() -> &'static Runtime {
+    RUNTIME.get_or_init(|| Builder::new_multi_thread()
+        .worker_threads(WORKER_THREADS.load(Ordering::Relaxed))
+        .thread_name("runtime-worker")
+        .enable_all()
+        .build()
+        .expect("the runtime could not be started"))
+}
+
+/// Run the future on the shared runtime, from a thread outside of it
+/// (e.g. the command line or a sink), waiting for its result
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    get().block_on(future)
+}
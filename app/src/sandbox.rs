@@ -0,0 +1,356 @@
+use serde::Deserialize;
+use std::path::PathBuf;
+
+#[cfg(feature = "sandbox")]
+use {
+    crate::config,
+    log::{info, warn},
+    std::ffi::CString,
+    std::fs::OpenOptions,
+    std::os::unix::fs::OpenOptionsExt,
+    std::os::unix::io::AsRawFd,
+    std::path::Path,
+};
+
+/// Hardening of the process, on Linux
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "sandbox"), allow(dead_code))]
+pub struct Config {
+
+    /// User to switch to once the listeners are bound, if any
+    pub user: Option<String>,
+
+    /// Group to switch to, the primary group of the `user` if not set
+    pub group: Option<String>,
+
+    /// Whether to limit the access to files by Landlock
+    #[serde(default = "Config::default_restrict_files")]
+    pub restrict_files: bool,
+
+    /// Files or directories which may be read, in addition to the system ones
+    #[serde(default)]
+    pub readable_paths: Vec<PathBuf>,
+
+    /// Files or directories which may be written, in addition to the configured data
+    #[serde(default)]
+    pub writable_paths: Vec<PathBuf>,
+
+    /// Whether to block system calls which the logger never needs by seccomp
+    #[serde(default = "Config::default_restrict_syscalls")]
+    pub restrict_syscalls: bool,
+}
+
+impl Config {
+
+    fn default_restrict_files() -> bool { true }
+
+    fn default_restrict_syscalls() -> bool { true }
+}
+
+/// System paths needed for name resolution, TLS and shared libraries
+#[cfg(feature = "sandbox")]
+const SYSTEM_READABLE_PATHS: &[&str] = &["/etc", "/usr", "/lib", "/lib64", "/dev/urandom", "/dev/null"];
+
+/// System paths with the programs (and their interpreters) which may be executed,
+/// if the logger executes any
+#[cfg(feature = "sandbox")]
+const SYSTEM_EXECUTABLE_PATHS: &[&str] = &["/usr", "/bin", "/sbin", "/lib", "/lib64"];
+
+/// Restrict the files and system calls available to the process;
+/// must be called before any thread is spawned, as Landlock only applies
+/// to the calling thread and the threads it spawns later
+#[cfg(feature = "sandbox")]
+pub fn restrict(sandbox_config: &Config, data_paths: Vec<PathBuf>, executables: Vec<PathBuf>)
+-> Result<(), String>
+{
+    // Needed by both Landlock and seccomp for unprivileged processes
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(format!("no_new_privs can not be set: {}", std::io::Error::last_os_error()));
+    }
+
+    if sandbox_config.restrict_files {
+        let mut readable_paths: Vec<PathBuf> = SYSTEM_READABLE_PATHS.iter().map(PathBuf::from).collect();
+        // The config file is read from the working directory, unless given elsewhere;
+        // its directory is readable, as editors replace the file when saving it
+        readable_paths.push(PathBuf::from("."));
+        readable_paths.extend(config::path().parent()
+            .filter(|directory| !directory.as_os_str().is_empty())
+            .map(Path::to_path_buf));
+        readable_paths.extend(sandbox_config.readable_paths.iter().cloned());
+        let mut writable_paths = data_paths;
+        writable_paths.extend(sandbox_config.writable_paths.iter().cloned());
+        let mut executable_paths = vec![];
+        if !executables.is_empty() {
+            executable_paths.extend(SYSTEM_EXECUTABLE_PATHS.iter().map(PathBuf::from));
+            executable_paths.extend(executables.iter().cloned());
+        }
+        landlock::restrict(&readable_paths, &writable_paths, &executable_paths)?;
+    }
+
+    if sandbox_config.restrict_syscalls {
+        seccomp::restrict(!executables.is_empty())?;
+    }
+    Ok(())
+}
+
+/// Restrict the files and system calls available to the process, which is not compiled in
+#[cfg(not(feature = "sandbox"))]
+pub fn restrict(_sandbox_config: &Config, _data_paths: Vec<PathBuf>, _executables: Vec<PathBuf>)
+-> Result<(), String>
+{
+    Err("the sandbox needs the 'sandbox' feature".to_string())
+}
+
+/// Switch to the configured user and group, if any
+#[cfg(feature = "sandbox")]
+pub fn drop_privileges(sandbox_config: &Config) -> Result<(), String> {
+    let user = match &sandbox_config.user {
+        Some(user) => user,
+        None => return Ok(()),
+    };
+    let name = CString::new(user.as_str()).map_err(|_| format!("invalid user '{}'", user))?;
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        return Err(format!("user '{}' does not exist", user));
+    }
+    let (uid, mut gid) = unsafe { ((*passwd).pw_uid, (*passwd).pw_gid) };
+
+    if let Some(group) = &sandbox_config.group {
+        let name = CString::new(group.as_str()).map_err(|_| format!("invalid group '{}'", group))?;
+        let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+        if entry.is_null() {
+            return Err(format!("group '{}' does not exist", group));
+        }
+        gid = unsafe { (*entry).gr_gid };
+    }
+
+    // The libc applies these to all threads of the process
+    let os_error = |call: &str| format!("{} failed when switching to user '{}': {}",
+        call, user, std::io::Error::last_os_error());
+    if unsafe { libc::setgroups(1, &gid) } != 0 {
+        return Err(os_error("setgroups"));
+    }
+    if unsafe { libc::setgid(gid) } != 0 {
+        return Err(os_error("setgid"));
+    }
+    if unsafe { libc::setuid(uid) } != 0 {
+        return Err(os_error("setuid"));
+    }
+    if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+        return Err(format!("privileges could be regained after switching to user '{}'", user));
+    }
+    info!("Running as user {} (uid {}, gid {}).", user, uid, gid);
+    Ok(())
+}
+
+/// Switch to the configured user and group, which is not compiled in
+#[cfg(not(feature = "sandbox"))]
+pub fn drop_privileges(_sandbox_config: &Config) -> Result<(), String> {
+    Err("the sandbox needs the 'sandbox' feature".to_string())
+}
+
+#[cfg(feature = "sandbox")]
+mod landlock {
+    use super::*;
+
+    const CREATE_RULESET_VERSION: u32 = 1;
+    const RULE_PATH_BENEATH: u32 = 1;
+
+    const EXECUTE: u64 = 1 << 0;
+    const WRITE_FILE: u64 = 1 << 1;
+    const READ_FILE: u64 = 1 << 2;
+    const READ_DIR: u64 = 1 << 3;
+    const REMOVE_DIR: u64 = 1 << 4;
+    const REMOVE_FILE: u64 = 1 << 5;
+    const MAKE_CHAR: u64 = 1 << 6;
+    const MAKE_DIR: u64 = 1 << 7;
+    const MAKE_REG: u64 = 1 << 8;
+    const MAKE_SOCK: u64 = 1 << 9;
+    const MAKE_FIFO: u64 = 1 << 10;
+    const MAKE_BLOCK: u64 = 1 << 11;
+    const MAKE_SYM: u64 = 1 << 12;
+    const REFER: u64 = 1 << 13;
+    const TRUNCATE: u64 = 1 << 14;
+
+    /// Rights which apply to files, not only to directories
+    const FILE_RIGHTS: u64 = EXECUTE | WRITE_FILE | READ_FILE | TRUNCATE;
+
+    const READ_RIGHTS: u64 = READ_FILE | READ_DIR;
+
+    const WRITE_RIGHTS: u64 = READ_RIGHTS | WRITE_FILE | REMOVE_DIR | REMOVE_FILE
+        | MAKE_DIR | MAKE_REG | REFER | TRUNCATE;
+
+    /// Scripts are read by their interpreters
+    const EXECUTE_RIGHTS: u64 = READ_RIGHTS | EXECUTE;
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    /// Rights handled by the ABI version of the kernel
+    fn handled_rights(abi: i64) -> u64 {
+        let mut rights = EXECUTE | WRITE_FILE | READ_FILE | READ_DIR | REMOVE_DIR | REMOVE_FILE
+            | MAKE_CHAR | MAKE_DIR | MAKE_REG | MAKE_SOCK | MAKE_FIFO | MAKE_BLOCK | MAKE_SYM;
+        if abi >= 2 {
+            rights |= REFER;
+        }
+        if abi >= 3 {
+            rights |= TRUNCATE;
+        }
+        rights
+    }
+
+    pub fn restrict(readable_paths: &[PathBuf], writable_paths: &[PathBuf],
+        executable_paths: &[PathBuf]) -> Result<(), String> {
+        let abi = unsafe { libc::syscall(libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(), 0, CREATE_RULESET_VERSION) };
+        if abi < 1 {
+            warn!("Landlock is not available in the kernel, access to files is not restricted: {}",
+                std::io::Error::last_os_error());
+            return Ok(());
+        }
+        let handled = handled_rights(abi);
+
+        let attr = RulesetAttr { handled_access_fs: handled };
+        let ruleset = unsafe { libc::syscall(libc::SYS_landlock_create_ruleset,
+            &attr, std::mem::size_of::<RulesetAttr>(), 0) };
+        if ruleset < 0 {
+            return Err(format!("Landlock ruleset can not be created: {}",
+                std::io::Error::last_os_error()));
+        }
+        let ruleset = ruleset as i32;
+
+        let result = add_rules(ruleset, readable_paths, READ_RIGHTS & handled, false)
+            .and_then(|_| add_rules(ruleset, writable_paths, WRITE_RIGHTS & handled, true))
+            .and_then(|_| add_rules(ruleset, executable_paths, EXECUTE_RIGHTS & handled, false))
+            .and_then(|_| match unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset, 0) } {
+                0 => Ok(()),
+                _ => Err(format!("Landlock can not be enforced: {}",
+                    std::io::Error::last_os_error())),
+            });
+        unsafe { libc::close(ruleset) };
+        result
+    }
+
+    /// Allow the access beneath the paths; the missing ones are skipped,
+    /// or created as directories if they are to be written
+    fn add_rules(ruleset: i32, paths: &[PathBuf], rights: u64, create: bool) -> Result<(), String> {
+        for path in paths {
+            if !path.exists() {
+                if !create {
+                    continue;
+                }
+                std::fs::create_dir_all(path)
+                    .map_err(|err| format!("{} can not be created: {}", path.display(), err))?;
+            }
+            add_rule(ruleset, path, rights)?;
+        }
+        Ok(())
+    }
+
+    fn add_rule(ruleset: i32, path: &Path, rights: u64) -> Result<(), String> {
+        let file = OpenOptions::new().read(true).custom_flags(libc::O_PATH).open(path)
+            .map_err(|err| format!("{} can not be opened: {}", path.display(), err))?;
+        let is_dir = file.metadata().map(|metadata| metadata.is_dir()).unwrap_or(false);
+        let attr = PathBeneathAttr {
+            allowed_access: if is_dir { rights } else { rights & FILE_RIGHTS },
+            parent_fd: file.as_raw_fd(),
+        };
+        match unsafe { libc::syscall(libc::SYS_landlock_add_rule, ruleset, RULE_PATH_BENEATH, &attr, 0) } {
+            0 => Ok(()),
+            _ => Err(format!("Landlock rule for {} can not be added: {}",
+                path.display(), std::io::Error::last_os_error())),
+        }
+    }
+}
+
+#[cfg(feature = "sandbox")]
+mod seccomp {
+    use libc::{sock_filter, sock_fprog, BPF_ABS, BPF_JEQ, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W};
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xC000_003E;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xC000_00B7;
+
+    /// Offsets in `struct seccomp_data`
+    const NR_OFFSET: u32 = 0;
+    const ARCH_OFFSET: u32 = 4;
+
+    /// System calls executing programs, which only the `exec` sink makes
+    const EXECUTING: &[libc::c_long] = &[libc::SYS_execve, libc::SYS_execveat];
+
+    /// System calls for taking over the host, or other processes, which
+    /// the logger never makes (but for `EXECUTING`); they fail with EPERM
+    const BLOCKED: &[libc::c_long] = &[
+        libc::SYS_execve, libc::SYS_execveat, libc::SYS_ptrace,
+        libc::SYS_process_vm_readv, libc::SYS_process_vm_writev,
+        libc::SYS_mount, libc::SYS_umount2, libc::SYS_pivot_root, libc::SYS_chroot,
+        libc::SYS_unshare, libc::SYS_setns, libc::SYS_personality,
+        libc::SYS_init_module, libc::SYS_finit_module, libc::SYS_delete_module,
+        libc::SYS_kexec_load, libc::SYS_reboot, libc::SYS_swapon, libc::SYS_swapoff,
+        libc::SYS_bpf, libc::SYS_perf_event_open, libc::SYS_userfaultfd,
+        libc::SYS_add_key, libc::SYS_request_key, libc::SYS_keyctl,
+        libc::SYS_open_by_handle_at, libc::SYS_name_to_handle_at,
+        libc::SYS_acct, libc::SYS_quotactl, libc::SYS_syslog,
+        libc::SYS_settimeofday, libc::SYS_clock_settime, libc::SYS_adjtimex,
+        libc::SYS_sethostname, libc::SYS_setdomainname,
+    ];
+
+    fn statement(code: u32, k: u32) -> sock_filter {
+        sock_filter { code: code as u16, jt: 0, jf: 0, k }
+    }
+
+    fn jump_if_equal(k: u32, jt: u8, jf: u8) -> sock_filter {
+        sock_filter { code: (BPF_JMP | BPF_JEQ | BPF_K) as u16, jt, jf, k }
+    }
+
+    /// Block the system calls, but for executing programs if `allow_exec`
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    pub fn restrict(allow_exec: bool) -> Result<(), String> {
+        let deny = statement(BPF_RET | BPF_K, libc::SECCOMP_RET_ERRNO | libc::EPERM as u32);
+        let mut program = vec![
+            // System calls of other architectures (e.g. 32-bit ones) have other numbers
+            statement(BPF_LD | BPF_W | BPF_ABS, ARCH_OFFSET),
+            jump_if_equal(AUDIT_ARCH, 1, 0),
+            deny,
+            statement(BPF_LD | BPF_W | BPF_ABS, NR_OFFSET),
+        ];
+        #[cfg(target_arch = "x86_64")]
+        {
+            // The x32 ABI shares the architecture, but sets this bit of the number
+            const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+            program.push(sock_filter {
+                code: (BPF_JMP | libc::BPF_JGE | BPF_K) as u16, jt: 0, jf: 1, k: X32_SYSCALL_BIT });
+            program.push(deny);
+        }
+        for &number in BLOCKED.iter().filter(|number| !(allow_exec && EXECUTING.contains(number))) {
+            program.push(jump_if_equal(number as u32, 0, 1));
+            program.push(deny);
+        }
+        program.push(statement(BPF_RET | BPF_K, libc::SECCOMP_RET_ALLOW));
+
+        let fprog = sock_fprog { len: program.len() as u16, filter: program.as_mut_ptr() };
+        let result = unsafe { libc::syscall(libc::SYS_seccomp, libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_TSYNC, &fprog) };
+        if result != 0 {
+            return Err(format!("seccomp filter can not be installed: {}",
+                std::io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    pub fn restrict(_allow_exec: bool) -> Result<(), String> {
+        Err("restricting system calls is only supported on x86_64 and aarch64, \
+            set 'restrict_syscalls' to false".to_string())
+    }
+}
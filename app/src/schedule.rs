@@ -0,0 +1,100 @@
+use chrono::NaiveDateTime;
+use serde::Deserialize;
+use std::time::Duration;
+
+/// Alignment of polls to round periods of the device clock
+///
+/// Shelly devices update their energy counters on round minutes of their own
+/// clock. Polling shortly after such a boundary yields a fresh counter value.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Alignment {
+
+    /// Length of the aligned period in seconds
+    #[serde(default = "Alignment::default_period_s")]
+    pub period_s: u64,
+
+    /// Delay after the period boundary, which tolerates clock offsets
+    /// between the device and the time it updates its counters
+    #[serde(default = "Alignment::default_slack_ms")]
+    pub slack_ms: u64,
+}
+
+impl Default for Alignment {
+    fn default() -> Alignment {
+        Alignment {
+            period_s: Alignment::default_period_s(),
+            slack_ms: Alignment::default_slack_ms(),
+        }
+    }
+}
+
+impl Alignment {
+
+    fn default_period_s() -> u64 { 60 }
+
+    fn default_slack_ms() -> u64 { 10_000 }
+
+    /// Length of the aligned period (at least 1 second)
+    pub fn period(&self) -> Duration {
+        Duration::from_secs(self.period_s.max(1))
+    }
+
+    /// Delay after the period boundary
+    pub fn slack(&self) -> Duration {
+        Duration::from_millis(self.slack_ms)
+    }
+
+    /// Time elapsed since the last period boundary at `now`
+    pub fn elapsed_in_period(&self, now: NaiveDateTime) -> Duration {
+        let period_s = self.period().as_secs() as i64;
+        let seconds = now.timestamp().rem_euclid(period_s) as u64;
+        Duration::from_secs(seconds)
+            + Duration::from_nanos(now.timestamp_subsec_nanos() as u64)
+    }
+
+    /// Duration from `now` till the next period boundary plus the slack
+    pub fn time_to_next(&self, now: NaiveDateTime) -> Duration {
+        self.period() - self.elapsed_in_period(now) + self.slack()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn at(hour: u32, minute: u32, second: u32, milli: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2023, 4, 1).unwrap()
+            .and_hms_milli_opt(hour, minute, second, milli).unwrap()
+    }
+
+    #[test]
+    fn default_is_minute_with_ten_seconds_slack() {
+        let alignment = Alignment::default();
+        assert_eq!(alignment.time_to_next(at(12, 0, 15, 0)), Duration::from_secs(55));
+    }
+
+    #[test]
+    fn subsecond_part_is_subtracted() {
+        let alignment = Alignment { period_s: 60, slack_ms: 0 };
+        assert_eq!(alignment.time_to_next(at(12, 0, 59, 250)), Duration::from_millis(750));
+    }
+
+    #[test]
+    fn exactly_on_boundary_waits_a_full_period() {
+        let alignment = Alignment { period_s: 60, slack_ms: 500 };
+        assert_eq!(alignment.time_to_next(at(12, 1, 0, 0)), Duration::from_millis(60_500));
+    }
+
+    #[test]
+    fn longer_periods_align_to_their_own_boundary() {
+        let alignment = Alignment { period_s: 300, slack_ms: 0 };
+        assert_eq!(alignment.time_to_next(at(12, 3, 0, 0)), Duration::from_secs(120));
+    }
+
+    #[test]
+    fn zero_period_is_treated_as_one_second() {
+        let alignment = Alignment { period_s: 0, slack_ms: 0 };
+        assert_eq!(alignment.time_to_next(at(12, 3, 0, 400)), Duration::from_millis(600));
+    }
+}
@@ -0,0 +1,167 @@
+use crate::clock::SuspendDetector;
+use crate::runtime;
+use crate::signals;
+use log::{debug, error, info, warn};
+use std::future::Future;
+use std::pin::Pin;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+
+/// One run of a task, resolving to the delay till the next one
+pub type Poll<'a> = Pin<Box<dyn Future<Output = Result<Option<Duration>, String>> + Send + 'a>>;
+
+/// Recurring job, e.g. polling of a device
+pub trait Task: Send {
+
+    /// Perform the job once and return the delay till the next run;
+    /// `None` means the task is finished, `Err` that it failed for good
+    fn poll(&mut self) -> Poll<'_>;
+
+    /// The system was suspended, so the state kept between the polls may be
+    /// stale; the task is run right after
+    fn resume(&mut self) {}
+}
+
+/// Adds tasks to the running scheduler, which runs them right away
+#[derive(Clone)]
+pub struct Intake(UnboundedSender<Box<dyn Task>>);
+
+impl Intake {
+
+    /// Add the task; false if the scheduler finished
+    pub fn add(&self, task: Box<dyn Task>) -> bool {
+        self.0.send(task).is_ok()
+    }
+}
+
+/// Why the tasks waiting for their next run are woken up
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Wake {
+    /// The system was suspended, so all tasks are run right away
+    Resumed,
+    /// The logger is terminating, so the tasks are not run again
+    Terminating,
+}
+
+/// Runs each task as an async task of the shared runtime, whenever it is due
+///
+/// The tasks wait for their next run without a thread of their own, so the
+/// number of threads (the `worker_threads` of the runtime) does not depend on
+/// the number of devices.
+pub struct Scheduler {
+    sender: UnboundedSender<Box<dyn Task>>,
+    receiver: UnboundedReceiver<Box<dyn Task>>,
+}
+
+impl Default for Scheduler {
+    fn default() -> Scheduler {
+        let (sender, receiver) = unbounded_channel();
+        Scheduler { sender, receiver }
+    }
+}
+
+impl Scheduler {
+
+    /// Intake of tasks added once the scheduler runs
+    pub fn intake(&self) -> Intake {
+        Intake(self.sender.clone())
+    }
+
+    /// Spawn the dispatcher thread, which runs the tasks on the shared
+    /// runtime; the returned handle finishes when no task remains
+    pub fn spawn(self, tasks: Vec<Box<dyn Task>>) -> JoinHandle<Result<(),String>> {
+        let Scheduler { sender, receiver } = self;
+        drop(sender);
+        std::thread::spawn(move || runtime::block_on(Scheduler::dispatch(tasks, receiver)))
+    }
+
+    /// Run the tasks and those added, until all of them are finished
+    async fn dispatch(tasks: Vec<Box<dyn Task>>, mut intake: UnboundedReceiver<Box<dyn Task>>)
+    -> Result<(),String>
+    {
+        let (wake, woken) = watch::channel(Wake::Resumed);
+        let mut running = JoinSet::new();
+        let mut count = 0;
+        for task in tasks {
+            running.spawn(Scheduler::run(count, task, woken.clone()));
+            count += 1;
+        }
+        let mut suspend_detector = SuspendDetector::new();
+        let mut checks = tokio::time::interval(signals::TERMINATION_CHECK_INTERVAL);
+        let mut panicked = false;
+
+        while !running.is_empty() {
+            tokio::select! {
+                Some(task) = intake.recv() => {
+                    if *wake.borrow() == Wake::Terminating {
+                        continue;
+                    }
+                    debug!("task {} was added", count);
+                    running.spawn(Scheduler::run(count, task, woken.clone()));
+                    count += 1;
+                },
+                Some(joined) = running.join_next() => if let Err(err) = joined {
+                    error!("scheduler task panicked: {}", err);
+                    panicked = true;
+                },
+                _ = checks.tick() => {
+                    // Run all tasks right away after a suspend, their schedules are stale
+                    if let Some(duration) = suspend_detector.check()
+                        .filter(|_| *wake.borrow() != Wake::Terminating) {
+                        warn!("the system was suspended for {}s (or its clock was set forward), \
+                            no data-points were measured meanwhile, resynchronizing",
+                            duration.as_secs());
+                        wake.send_replace(Wake::Resumed);
+                    }
+                    // Once terminating, the tasks are not run again, only those
+                    // running are waited for
+                    if signals::terminating() && *wake.borrow() != Wake::Terminating {
+                        info!("Terminating, {} meters stop after their running measurement",
+                            running.len());
+                        wake.send_replace(Wake::Terminating);
+                    }
+                },
+            }
+        }
+        if panicked {
+            return Err("scheduler task panicked".to_string());
+        }
+        Ok(())
+    }
+
+    /// Poll the task whenever it is due, until it finishes
+    async fn run(id: usize, mut task: Box<dyn Task>, mut woken: watch::Receiver<Wake>) {
+        loop {
+            let delay = match task.poll().await {
+                Ok(Some(_)) if signals::terminating() => {
+                    debug!("task {} stopped", id);
+                    return;
+                },
+                Ok(Some(delay)) => delay,
+                Ok(None) => {
+                    debug!("task {} finished", id);
+                    return;
+                },
+                Err(msg) => {
+                    error!("{msg}");
+                    return;
+                },
+            };
+            debug!("task {} is going to run again in {}ms", id, delay.as_millis());
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => (),
+                changed = woken.changed() => match changed.map(|_| *woken.borrow_and_update()) {
+                    Ok(Wake::Resumed) => task.resume(),
+                    // Terminating, or the scheduler is gone
+                    _ => {
+                        debug!("task {} stopped", id);
+                        return;
+                    },
+                },
+            }
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Password, token or API key in the config
+///
+/// Given either as the value itself, or as its source, which is read when
+/// the config is loaded:
+/// - `{ "file": "/path" }` reads the file,
+/// - `{ "credential": "name" }` reads the credential passed by systemd
+///   (`LoadCredential=` or `SetCredentialEncrypted=` in the unit),
+/// - `{ "keyring": { "service": "...", "user": "..." } }` reads the entry
+///   of the operating system keyring (needs the `keyring` feature).
+#[derive(Clone)]
+pub struct Secret {
+    value: String,
+    /// Where the value was read from, unless given in the config
+    source: Option<Source>,
+}
+
+impl Secret {
+
+    /// The secret value itself
+    pub fn expose(&self) -> &str {
+        &self.value
+    }
+
+    /// Read the secret again from its source, e.g. after it was rotated;
+    /// nothing if it is given in the config or did not change
+    pub fn reread(&self) -> Result<Option<Secret>, String> {
+        let source = match &self.source {
+            Some(source) => source,
+            None => return Ok(None),
+        };
+        let value = source.read()?;
+        if value == self.value {
+            return Ok(None);
+        }
+        Ok(Some(Secret { value, source: Some(source.clone()) }))
+    }
+}
+
+/// Secret values are never printed, so that configs can be logged
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("\"***\"")
+    }
+}
+
+/// Secrets are equal by their values, wherever they were read from
+impl PartialEq for Secret {
+    fn eq(&self, other: &Secret) -> bool {
+        self.value == other.value
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("***")
+    }
+}
+
+/// Secret as written in the config
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Given {
+    Value(String),
+    /// Number, e.g. a PIN overriding a setting missing in the config file
+    Number(serde_json::Number),
+    Source(Source),
+}
+
+/// Source of a secret
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+enum Source {
+    File(PathBuf),
+    Credential(String),
+    Keyring(KeyringEntry),
+}
+
+impl Source {
+
+    fn read(&self) -> Result<String, String> {
+        match self {
+            Source::File(path) => read_file(path),
+            Source::Credential(name) => read_credential(name),
+            Source::Keyring(entry) => read_keyring(entry),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Secret, D::Error> {
+        match Given::deserialize(deserializer)? {
+            Given::Value(value) => Ok(Secret { value, source: None }),
+            Given::Number(value) => Ok(Secret { value: value.to_string(), source: None }),
+            Given::Source(source) => {
+                let value = source.read().map_err(serde::de::Error::custom)?;
+                Ok(Secret { value, source: Some(source) })
+            },
+        }
+    }
+}
+
+/// Entry of the operating system keyring
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct KeyringEntry {
+    pub service: String,
+    pub user: String,
+}
+
+/// Read the secret from a file, without the trailing newline
+fn read_file(path: &Path) -> Result<String, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|err| format!("{} can not be read: {}", path.display(), err))?;
+    Ok(text.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Read the credential passed by systemd
+fn read_credential(name: &str) -> Result<String, String> {
+    if name.contains('/') {
+        return Err(format!("credential name '{}' must not contain '/'", name));
+    }
+    let directory = std::env::var_os("CREDENTIALS_DIRECTORY")
+        .ok_or_else(|| format!("credential '{}' can not be read, $CREDENTIALS_DIRECTORY \
+            is not set (is the logger run by systemd with LoadCredential=?)", name))?;
+    read_file(&Path::new(&directory).join(name))
+}
+
+/// Read the secret from the operating system keyring
+#[cfg(feature = "keyring")]
+pub fn read_keyring(entry: &KeyringEntry) -> Result<String, String> {
+    keyring::Entry::new(&entry.service, &entry.user)
+        .and_then(|keyring_entry| keyring_entry.get_password())
+        .map_err(|err| format!("secret {}/{} can not be read from the keyring: {}",
+            entry.service, entry.user, err))
+}
+
+/// Read the secret from the operating system keyring
+#[cfg(not(feature = "keyring"))]
+pub fn read_keyring(entry: &KeyringEntry) -> Result<String, String> {
+    Err(format!("secret {}/{} can not be read, the keyring needs the 'keyring' feature",
+        entry.service, entry.user))
+}
@@ -0,0 +1,60 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Number of SIGHUPs received so far
+static HANGUPS: AtomicU64 = AtomicU64::new(0);
+
+/// Whether SIGTERM or SIGINT was received
+static TERMINATING: AtomicBool = AtomicBool::new(false);
+
+/// How often the waiting threads check whether the logger is terminating
+pub const TERMINATION_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Count the SIGHUPs instead of terminating, so that threads can react
+/// to them (e.g. by reading rotated secrets again); on SIGTERM or SIGINT,
+/// let the threads finish writing, unless the signal is sent again
+pub fn listen() -> Result<(), String> {
+    // The handlers only touch atomics and exit, which is safe in a signal handler
+    unsafe {
+        signal_hook_registry::register(libc::SIGHUP, || {
+            HANGUPS.fetch_add(1, Ordering::Relaxed);
+        })
+    }
+    .map_err(|err| format!("SIGHUP can not be handled: {}", err))?;
+    for (signal, name) in [(libc::SIGTERM, "SIGTERM"), (libc::SIGINT, "SIGINT")] {
+        unsafe {
+            signal_hook_registry::register(signal, move || {
+                if TERMINATING.swap(true, Ordering::Relaxed) {
+                    libc::_exit(128 + signal);
+                }
+            })
+        }
+        .map_err(|err| format!("{} can not be handled: {}", name, err))?;
+    }
+    Ok(())
+}
+
+/// Whether the logger is terminating, so that the threads should finish
+/// writing what they have and stop
+pub fn terminating() -> bool {
+    TERMINATING.load(Ordering::Relaxed)
+}
+
+/// Sleep, unless the logger is terminating; false if it is
+pub fn sleep(duration: Duration) -> bool {
+    let deadline = Instant::now() + duration;
+    while !terminating() {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return true;
+        }
+        std::thread::sleep(remaining.min(TERMINATION_CHECK_INTERVAL));
+    }
+    false
+}
+
+/// Number of SIGHUPs received so far; a thread compares it with the number
+/// it saw before to find out whether there was a new one
+pub fn hangups() -> u64 {
+    HANGUPS.load(Ordering::Relaxed)
+}
@@ -0,0 +1,90 @@
+use crate::config;
+use crate::health::SharedHealth;
+use crate::point::Datum;
+use crate::state::SharedState;
+use log::info;
+use std::sync::mpsc::Receiver;
+use std::thread::JoinHandle;
+
+/// Thread of a sink, ending by its error if it failed
+pub type Handle = JoinHandle<Result<(), String>>;
+
+/// What the sinks may need besides their own configuration
+pub struct Context<'a> {
+    #[cfg_attr(not(any(feature = "mqtt", feature = "icinga", feature = "files")), allow(dead_code))]
+    pub app_config: &'a config::Config,
+    pub state: &'a SharedState,
+    pub health: &'a SharedHealth,
+}
+
+/// Destination of the data-points, by the configuration of its section
+pub trait Sink {
+
+    /// Start writing the data-points received by the channel, in a thread of
+    /// its own; fails if the sink can not be started, e.g. as it is not compiled in
+    fn spawn(self: Box<Self>, context: &Context, data_receiver: Receiver<Datum>)
+    -> Result<Handle, String>;
+}
+
+/// Sink with the section of the config which configures it
+pub type Section = (&'static str, Box<dyn Sink>);
+
+/// Sections of the config which configure sinks, in the order they are started
+pub const SECTIONS: &[&str] = &["database", "local_store", "mqtt", "domoticz", "openhab",
+    "zabbix", "icinga", "evcc", "prometheus", "grafana_live", "emoncms", "exec", "files"];
+
+/// Former names of the sections, still accepted in the `sinks`
+const FORMER_SECTIONS: &[(&str, &str)] = &[("influxdb2", "database")];
+
+/// Section by its current name, e.g. "database" for the former "influxdb2"
+pub fn current_section(name: &str) -> &str {
+    FORMER_SECTIONS.iter()
+        .find(|(former, _)| *former == name)
+        .map_or(name, |(_, current)| current)
+}
+
+/// Sinks configured (and selected by `sinks`, if set), with their sections
+pub fn configured(app_config: &config::Config) -> Result<Vec<Section>, String> {
+    if let Some(selected) = &app_config.sinks {
+        if let Some(unknown) = selected.iter().find(|name| !SECTIONS.contains(&current_section(name))) {
+            return Err(format!("'{}' in 'sinks' is not a sink, but one of: {}",
+                unknown, SECTIONS.join(", ")));
+        }
+    }
+    fn boxed(sink: impl Sink + 'static) -> Box<dyn Sink> {
+        Box::new(sink)
+    }
+    let mut sections: Vec<Section> = vec![];
+    sections.extend(app_config.database.clone().map(|sink| ("database", boxed(sink))));
+    sections.extend(app_config.local_store.clone().map(|sink| ("local_store", boxed(sink))));
+    sections.extend(app_config.mqtt.clone().map(|sink| ("mqtt", boxed(sink))));
+    sections.extend(app_config.domoticz.clone().map(|sink| ("domoticz", boxed(sink))));
+    sections.extend(app_config.openhab.clone().map(|sink| ("openhab", boxed(sink))));
+    sections.extend(app_config.zabbix.clone().map(|sink| ("zabbix", boxed(sink))));
+    sections.extend(app_config.icinga.clone().map(|sink| ("icinga", boxed(sink))));
+    sections.extend(app_config.evcc.clone().map(|sink| ("evcc", boxed(sink))));
+    sections.extend(app_config.prometheus.clone().map(|sink| ("prometheus", boxed(sink))));
+    sections.extend(app_config.grafana_live.clone().map(|sink| ("grafana_live", boxed(sink))));
+    sections.extend(app_config.emoncms.clone().map(|sink| ("emoncms", boxed(sink))));
+    sections.extend(app_config.exec.clone().map(|sink| ("exec", boxed(sink))));
+    sections.extend(app_config.files.iter().cloned().map(|sink| ("files", boxed(sink))));
+
+    let mut sinks = vec![];
+    for (section, sink) in sections {
+        if app_config.is_sink_selected(section) {
+            sinks.push((section, sink));
+        } else {
+            info!("'{}' is configured, but not in the 'sinks', so it is not written to", section);
+        }
+    }
+    if let Some(selected) = &app_config.sinks {
+        if let Some(missing) = selected.iter().find(|name| !sinks.iter().any(|(section, _)| *section == current_section(name))) {
+            return Err(format!("'{}' is in the 'sinks', but it is not configured", missing));
+        }
+    }
+    if sinks.is_empty() {
+        return Err(format!("no sink is configured, add one of {} to the config",
+            SECTIONS.iter().map(|section| format!("'{}'", section)).collect::<Vec<_>>().join(", ")));
+    }
+    Ok(sinks)
+}
@@ -0,0 +1,320 @@
+use crate::crypto::{self, Cipher};
+use crate::state::write_durably;
+use base64::Engine;
+use log::{info, warn};
+use std::borrow::Cow;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Append-only journal of the lines which a sink could not write, replayed
+/// in order once the sink is available again
+///
+/// Once the journal reaches its size limit, further lines are dropped (and
+/// counted), so that the disk does not fill up during a long outage.
+///
+/// With a cipher, the lines appended at once are journaled as one encrypted
+/// record, in base64 on a line of its own; lines journaled in plain text
+/// before the key was configured are still replayed.
+///
+/// The offset of the lines replayed already is kept next to the journal
+/// (e.g. "spill.lp.offset"), so that a replay interrupted by a crash does
+/// not write them again.
+pub struct Spill {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    cipher: Option<Cipher>,
+    /// Bytes at the start of the journal written already, which a replay skips
+    replayed: u64,
+    /// File with the `replayed` offset
+    offset_path: PathBuf,
+    /// Lines dropped since the journal was last emptied
+    dropped: u64,
+}
+
+impl Spill {
+
+    /// Open the journal, keeping the lines journaled before (e.g. by the
+    /// previous run of the logger)
+    pub fn open(path: &Path, max_size: u64, cipher: Option<Cipher>) -> Result<Spill, String> {
+        let file = OpenOptions::new().create(true).append(true).read(true).open(path)
+            .map_err(|err| format!("{} can not be opened: {}", path.display(), err))?;
+        let size = file.metadata()
+            .map_err(|err| format!("{} can not be read: {}", path.display(), err))?
+            .len();
+        let mut offset_path = path.as_os_str().to_owned();
+        offset_path.push(".offset");
+        let offset_path = PathBuf::from(offset_path);
+        let replayed = match std::fs::read_to_string(&offset_path) {
+            Ok(offset) => match offset.trim().parse::<u64>() {
+                // The journal was emptied after its offset was saved
+                Ok(offset) if offset <= size => offset,
+                _ => {
+                    warn!("{} is not an offset within {}, replaying it all",
+                        offset_path.display(), path.display());
+                    0
+                },
+            },
+            Err(_) => 0,
+        };
+        if size > replayed {
+            info!("{} holds {} kB of data-points from before, to be written", path.display(),
+                (size - replayed) / 1024);
+        }
+        Ok(Spill { path: path.to_path_buf(), file, size, max_size, cipher, replayed, offset_path,
+            dropped: 0 })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /// Journal the lines (each ending by a new line), unless the journal is full
+    pub fn append(&mut self, lines: &str) {
+        let record = match &self.cipher {
+            Some(cipher) => {
+                let mut record = base64::engine::general_purpose::STANDARD
+                    .encode(cipher.encrypt(lines.as_bytes()));
+                record.push('\n');
+                Cow::Owned(record)
+            },
+            None => Cow::Borrowed(lines),
+        };
+        let written = if self.size + record.len() as u64 > self.max_size {
+            Err("it is full".to_string())
+        } else {
+            self.file.write_all(record.as_bytes()).map_err(|err| err.to_string())
+        };
+        match written {
+            Ok(()) => self.size += record.len() as u64,
+            Err(err) => {
+                if self.dropped == 0 {
+                    warn!("data-points dropped, as they can not be journaled into {}: {}",
+                        self.path.display(), err);
+                }
+                self.dropped += lines.lines().count() as u64;
+            },
+        }
+    }
+
+    /// Pass the journaled lines to `write` in chunks of about `chunk_lines`
+    /// (an encrypted record is not split), oldest first, and empty the journal
+    /// once all are written; if a chunk fails, the next replay starts by it,
+    /// skipping those written before
+    pub fn replay(&mut self, chunk_lines: usize, mut write: impl FnMut(&str) -> Result<(), String>)
+    -> Result<(), String>
+    {
+        let path = self.path.clone();
+        let read_error = |err: std::io::Error| format!("{} can not be read: {}", path.display(), err);
+        let mut file = File::open(&self.path).map_err(read_error)?;
+        file.seek(SeekFrom::Start(self.replayed)).map_err(read_error)?;
+        let mut reader = BufReader::new(file);
+        let mut chunk = String::new();
+        let mut count = 0;
+        // End of the journal lines in the `chunk`
+        let mut chunk_end = self.replayed;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line).map_err(read_error)?;
+            if read == 0 {
+                break;
+            }
+            chunk_end += read as u64;
+            if let Some(lines) = self.decrypt(line.trim_end_matches('\n')) {
+                for line in lines.lines() {
+                    chunk.push_str(line);
+                    chunk.push('\n');
+                    count += 1;
+                }
+            }
+            if count >= chunk_lines.max(1) {
+                write(&chunk)?;
+                self.save_replayed(chunk_end);
+                chunk.clear();
+                count = 0;
+            }
+        }
+        if !chunk.is_empty() {
+            write(&chunk)?;
+        }
+        self.file.set_len(0)
+            .map_err(|err| format!("{} can not be emptied: {}", self.path.display(), err))?;
+        self.size = 0;
+        self.save_replayed(0);
+        if self.dropped > 0 {
+            warn!("{} data-points were dropped, as {} was full", self.dropped, self.path.display());
+            self.dropped = 0;
+        }
+        Ok(())
+    }
+
+    /// Record the offset of the lines written, on disk as well
+    fn save_replayed(&mut self, replayed: u64) {
+        self.replayed = replayed;
+        if let Err(err) = write_durably(&self.offset_path, replayed.to_string().as_bytes()) {
+            warn!("{} can not be saved, a replay after a crash may write data-points \
+                again: {}", self.offset_path.display(), err);
+        }
+    }
+
+    /// Lines of a line of the journal: the lines of an encrypted record, or the
+    /// line itself if it is plain text (which has spaces, unlike base64); none
+    /// if the record can not be decrypted
+    fn decrypt<'a>(&self, line: &'a str) -> Option<Cow<'a, str>> {
+        if line.contains(' ') {
+            return Some(Cow::Borrowed(line));
+        }
+        let record = base64::engine::general_purpose::STANDARD.decode(line).ok()
+            .filter(|record| crypto::is_encrypted(record));
+        let decrypted = match (record, &self.cipher) {
+            (Some(record), Some(cipher)) => cipher.decrypt(&record)
+                .and_then(|lines| String::from_utf8(lines)
+                    .map_err(|_| "record is not text".to_string())),
+            (Some(_), None) => Err("record is encrypted, but there is no \
+                'database.spill_encryption'".to_string()),
+            (None, _) => Err("line is neither line protocol nor an encrypted record".to_string()),
+        };
+        match decrypted {
+            Ok(lines) => Some(Cow::Owned(lines)),
+            Err(err) => {
+                warn!("data-points in {} skipped: {}", self.path.display(), err);
+                None
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Empty journal in a temporary file of the test
+    fn open(name: &str, max_size: u64, cipher: Option<Cipher>) -> Spill {
+        let path = std::env::temp_dir()
+            .join(format!("shelly-logger-spill-{}-{}.lp", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(path.with_extension("lp.offset"));
+        Spill::open(&path, max_size, cipher).unwrap()
+    }
+
+    /// Delete the journal of a test and its offset
+    fn remove(path: &Path) {
+        std::fs::remove_file(path).unwrap();
+        let _ = std::fs::remove_file(path.with_extension("lp.offset"));
+    }
+
+    /// Chunks passed to the write by a replay
+    fn replay(spill: &mut Spill, chunk_lines: usize) -> Vec<String> {
+        let mut chunks = vec![];
+        spill.replay(chunk_lines, |chunk| {
+            chunks.push(chunk.to_string());
+            Ok(())
+        }).unwrap();
+        chunks
+    }
+
+    #[test]
+    fn lines_are_replayed_in_order_by_chunks() {
+        let mut spill = open("order", 1024, None);
+        spill.append("power a=1 1\npower a=2 2\n");
+        spill.append("power a=3 3\n");
+        assert!(!spill.is_empty());
+        assert_eq!(replay(&mut spill, 2), vec!["power a=1 1\npower a=2 2\n", "power a=3 3\n"]);
+        assert!(spill.is_empty());
+        assert!(replay(&mut spill, 2).is_empty());
+        remove(spill.path());
+    }
+
+    #[test]
+    fn failed_replay_continues_by_the_failed_chunk() {
+        let mut spill = open("resume", 1024, None);
+        spill.append("power a=1 1\n");
+        spill.append("power a=2 2\n");
+        spill.append("power a=3 3\n");
+        let mut written = vec![];
+        let result = spill.replay(1, |chunk| {
+            if written.len() == 1 {
+                return Err("server is gone".to_string());
+            }
+            written.push(chunk.to_string());
+            Ok(())
+        });
+        assert!(result.is_err());
+        assert_eq!(written, vec!["power a=1 1\n"]);
+        spill.append("power a=4 4\n");
+        assert_eq!(replay(&mut spill, 2), vec!["power a=2 2\npower a=3 3\n", "power a=4 4\n"]);
+        remove(spill.path());
+    }
+
+    #[test]
+    fn replay_after_a_crash_continues_after_the_chunks_written() {
+        let mut spill = open("crash", 1024, None);
+        spill.append("power a=1 1\npower a=2 2\npower a=3 3\n");
+        let path = spill.path().to_path_buf();
+        let mut written = 0;
+        assert!(spill.replay(1, |_| {
+            written += 1;
+            if written == 3 { Err("crashed".to_string()) } else { Ok(()) }
+        }).is_err());
+        drop(spill);
+        let mut spill = Spill::open(&path, 1024, None).unwrap();
+        assert_eq!(replay(&mut spill, 10), vec!["power a=3 3\n"]);
+        // Emptied, so the next journal is replayed from its start
+        drop(spill);
+        let mut spill = Spill::open(&path, 1024, None).unwrap();
+        spill.append("power a=4 4\n");
+        assert_eq!(replay(&mut spill, 10), vec!["power a=4 4\n"]);
+        remove(&path);
+    }
+
+    #[test]
+    fn lines_over_the_size_limit_are_dropped() {
+        let mut spill = open("full", 24, None);
+        spill.append("power a=1 1\n");
+        spill.append("power a=2 2\n");
+        spill.append("power a=3 3\n");
+        assert_eq!(spill.dropped, 1);
+        assert_eq!(replay(&mut spill, 10), vec!["power a=1 1\npower a=2 2\n"]);
+        assert_eq!(spill.dropped, 0);
+        spill.append("power a=3 3\n");
+        assert_eq!(replay(&mut spill, 10), vec!["power a=3 3\n"]);
+        remove(spill.path());
+    }
+
+    #[test]
+    fn lines_journaled_before_a_restart_are_kept() {
+        let mut spill = open("restart", 1024, None);
+        spill.append("power a=1 1\n");
+        let path = spill.path().to_path_buf();
+        drop(spill);
+        let mut spill = Spill::open(&path, 1024, None).unwrap();
+        assert!(!spill.is_empty());
+        assert_eq!(replay(&mut spill, 10), vec!["power a=1 1\n"]);
+        remove(&path);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn records_are_encrypted_and_plain_lines_still_replayed() {
+        let encryption_config: crypto::Config = serde_json::from_str(
+            r#"{"key": "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f"}"#).unwrap();
+        let mut spill = open("encrypted", 1024, None);
+        spill.append("power a=1 1\n");
+        let path = spill.path().to_path_buf();
+        drop(spill);
+        let mut spill = Spill::open(&path, 1024, Some(Cipher::new(&encryption_config).unwrap())).unwrap();
+        spill.append("power a=2 2\npower a=3 3\n");
+        let journal = std::fs::read_to_string(&path).unwrap();
+        assert!(journal.starts_with("power a=1 1\n"));
+        assert!(!journal.contains("a=2"));
+        assert_eq!(replay(&mut spill, 10), vec!["power a=1 1\npower a=2 2\npower a=3 3\n"]);
+        remove(&path);
+    }
+}
@@ -0,0 +1,208 @@
+use crate::calendar::Calendar;
+use crate::point::Datum;
+use chrono::{DateTime, NaiveDate, Utc};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often is the state written to disk at most
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// State of one device, which survives restarts of the logger
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct DeviceState {
+
+    /// Energy counter (since reboot of the device) last seen, in Wh
+    pub last_total_wh: Option<f32>,
+
+    /// Time when the energy counter was last seen
+    pub last_poll: Option<DateTime<Utc>>,
+
+    /// Day (of the calendar) to which `day_total_wh` belongs
+    pub day: Option<NaiveDate>,
+
+    /// Consumption during `day`, in Wh
+    #[serde(default)]
+    pub day_total_wh: f64,
+
+    /// Time of the newest data-point confirmed written, by sink and measurement;
+    /// a journal replayed after a crash skips the data-points up to it
+    #[serde(default)]
+    pub checkpoints: HashMap<String, HashMap<String, DateTime<Utc>>>,
+}
+
+impl DeviceState {
+
+    /// Energy consumed since the counter was last seen; a lower counter
+    /// means that the device rebooted and started counting from zero
+    fn consumed_since_last_total(&self, total_wh: f32) -> f64 {
+        match self.last_total_wh {
+            Some(last_total_wh) if total_wh >= last_total_wh => (total_wh - last_total_wh) as f64,
+            Some(_) => total_wh as f64,
+            None => 0.0,
+        }
+    }
+}
+
+/// File format of the state
+#[derive(Serialize, Deserialize, Default)]
+struct StateFile {
+    devices: HashMap<String, DeviceState>,
+}
+
+/// State of all devices, optionally persisted in a file
+pub struct State {
+    path: Option<PathBuf>,
+    calendar: Calendar,
+    devices: HashMap<String, DeviceState>,
+    last_saved: Option<Instant>,
+}
+
+/// State shared by all meters
+pub type SharedState = Arc<Mutex<State>>;
+
+impl State {
+
+    /// Load the state from the file; start afresh if it does not exist
+    pub fn load(path: Option<&Path>, calendar: Calendar) -> State {
+        let devices = match path {
+            None => HashMap::new(),
+            Some(path) => match std::fs::read_to_string(path) {
+                Ok(text) => match serde_json::from_str::<StateFile>(&text) {
+                    Ok(file) => {
+                        info!("State of {} devices loaded from {}",
+                            file.devices.len(), path.display());
+                        for (device_name, device) in &file.devices {
+                            for (sink, checkpoints) in &device.checkpoints {
+                                for (measurement, measured_on) in checkpoints {
+                                    debug!("{} {} was last written to {} at {}",
+                                        device_name, measurement, sink, measured_on);
+                                }
+                            }
+                        }
+                        file.devices
+                    },
+                    Err(err) => {
+                        warn!("{} is corrupt, starting afresh: {}", path.display(), err);
+                        HashMap::new()
+                    }
+                },
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+                Err(err) => {
+                    warn!("{} can not be read, starting afresh: {}", path.display(), err);
+                    HashMap::new()
+                }
+            },
+        };
+        State { path: path.map(Path::to_path_buf), calendar, devices, last_saved: None }
+    }
+
+    /// Wrap the state for sharing between meters
+    pub fn shared(self) -> SharedState {
+        Arc::new(Mutex::new(self))
+    }
+
+    /// Record a successful poll with the device's energy counter;
+    /// returns the consumption of the device during the current day in Wh
+    pub fn record_total(&mut self, device_name: &str, now: DateTime<Utc>, total_wh: f32) -> f64 {
+        let today = self.calendar.day_of(now);
+        let device = self.devices.entry(device_name.to_string()).or_default();
+        let consumed = device.consumed_since_last_total(total_wh);
+        if device.day != Some(today) {
+            device.day = Some(today);
+            device.day_total_wh = 0.0;
+        }
+        device.day_total_wh += consumed;
+        device.last_total_wh = Some(total_wh);
+        device.last_poll = Some(now);
+        let day_total_wh = device.day_total_wh;
+
+        self.save_if_due();
+        day_total_wh
+    }
+
+    /// Record that the device was not polled since the last poll till `now`
+    /// (e.g. the system was suspended); if that spans the start of a day, the
+    /// consumption meanwhile is not added to the day total, as its day is not known
+    pub fn record_gap(&mut self, device_name: &str, now: DateTime<Utc>) {
+        let device = match self.devices.get_mut(device_name) {
+            Some(device) => device,
+            None => return,
+        };
+        if let Some(last_poll) = device.last_poll {
+            if self.calendar.day_of(last_poll) != self.calendar.day_of(now)
+                && device.last_total_wh.is_some() {
+                info!("{} was not polled from {} to {}, its consumption meanwhile is not \
+                    counted in the day total", device_name, last_poll.to_rfc3339(), now.to_rfc3339());
+                device.last_total_wh = None;
+            }
+        }
+    }
+
+    /// Record that the sink confirmed writing the data-points; written to disk
+    /// with the rest of the state (at least on stopping), as the journal keeps
+    /// its own offset of the replayed data-points
+    pub fn record_written(&mut self, sink: &str, datums: &[Datum]) {
+        for datum in datums {
+            let checkpoint = self.devices.entry(datum.device_name.to_string()).or_default()
+                .checkpoints.entry(sink.to_string()).or_default()
+                .entry(datum.measurement.to_string())
+                .or_insert(datum.measured_on);
+            *checkpoint = (*checkpoint).max(datum.measured_on);
+        }
+        self.save_if_due();
+    }
+
+    /// Whether the sink confirmed writing the data-point (or a newer one of
+    /// its device and measurement); the data-points of a device and measurement
+    /// are written in the order they are measured (see `spawn_encoders`)
+    pub fn is_written(&self, sink: &str, datum: &Datum) -> bool {
+        self.devices.get(datum.device_name.as_ref())
+            .and_then(|device| device.checkpoints.get(sink))
+            .and_then(|checkpoints| checkpoints.get(&datum.measurement.to_string()))
+            .is_some_and(|checkpoint| datum.measured_on <= *checkpoint)
+    }
+
+    /// Write the state, unless it was written recently
+    fn save_if_due(&mut self) {
+        if self.last_saved.is_none_or(|at| at.elapsed() >= SAVE_INTERVAL) {
+            self.save();
+        }
+    }
+
+    /// Write the state to its file (atomically), if there is one
+    pub fn save(&mut self) {
+        self.last_saved = Some(Instant::now());
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        let file = StateFile { devices: self.devices.clone() };
+        let text = serde_json::to_string_pretty(&file)
+            .expect("state is always serializable");
+        match write_durably(path, text.as_bytes()) {
+            Ok(_) => debug!("state saved to {}", path.display()),
+            Err(err) => warn!("state can not be saved to {}: {}", path.display(), err),
+        }
+    }
+}
+
+/// Replace the file atomically by the contents, which are synced to the
+/// disk first, so that a crash leaves either the old or the new contents
+pub fn write_durably(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let temporary = path.with_extension("tmp");
+    let mut file = File::create(&temporary)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    std::fs::rename(&temporary, path)?;
+    // The rename itself is durable once the directory is synced
+    if let Some(directory) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        File::open(directory).and_then(|directory| directory.sync_all())?;
+    }
+    Ok(())
+}
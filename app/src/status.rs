@@ -0,0 +1,210 @@
+use crate::log_limit::FailureLog;
+use crate::network::DeviceClient;
+use crate::plug;
+use crate::point;
+use crate::point::Datum;
+use crate::point::Measurement::*;
+use crate::probe;
+use crate::scheduler::{Poll, Task};
+use log::debug;
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Status of a device besides its metering, derived from the response of any
+/// supported firmware; fields not reported by the firmware (e.g. the voltage
+/// of a Shelly Plug S) are missing, and their measurements skipped
+pub struct Status {
+    /// Supply voltage, in Volts
+    voltage: Option<f32>,
+    /// Internal temperature, in °C
+    temperature: Option<f32>,
+    /// Whether the device is overheated, which turns its relay off
+    overtemperature: Option<bool>,
+    /// Whether the relay of the channel is on
+    relay_on: Option<bool>,
+}
+
+/// Response from the Gen1 "/status" endpoint
+#[derive(Deserialize)]
+struct DeviceStatus {
+    #[serde(default)]
+    relays: Vec<RelayStatus>,
+    temperature: Option<f32>,
+    overtemperature: Option<bool>,
+    voltage: Option<f32>,
+}
+
+/// Relay in the Gen1 "/status" response
+#[derive(Deserialize)]
+struct RelayStatus {
+    ison: Option<bool>,
+    /// Reported per relay by some devices (e.g. Shelly 1PM)
+    overtemperature: Option<bool>,
+}
+
+/// Response from the Gen2 "Switch.GetStatus" method
+#[derive(Deserialize)]
+struct SwitchStatus {
+    /// Whether the relay is on
+    output: Option<bool>,
+    temperature: Option<Temperature>,
+    voltage: Option<f32>,
+    /// Error conditions reported by the switch, e.g. "overtemp"
+    #[serde(default)]
+    errors: Vec<String>,
+}
+
+/// Temperature in the Gen2 "Switch.GetStatus" response
+#[derive(Deserialize)]
+struct Temperature {
+    #[serde(rename = "tC")]
+    celsius: Option<f32>,
+}
+
+impl From<SwitchStatus> for Status {
+    fn from(status: SwitchStatus) -> Status {
+        Status {
+            voltage: status.voltage,
+            temperature: status.temperature.and_then(|temperature| temperature.celsius),
+            overtemperature: Some(status.errors.iter().any(|error| error == "overtemp")),
+            relay_on: status.output,
+        }
+    }
+}
+
+impl Status {
+
+    /// Parse a response of either a Gen1 "/status" endpoint or a Gen2
+    /// "Switch.GetStatus" method (bare, JSON-RPC wrapped or nested in the
+    /// "Shelly.GetStatus" of the whole device)
+    pub fn parse(data: &[u8], channel: u32) -> Result<Status, String> {
+        let mut value: serde_json::Value = serde_json::from_slice(data)
+            .map_err(|err| err.to_string())?;
+        if let Some(result) = value.get_mut("result") {
+            value = result.take();
+        }
+        if let Some(switch) = value.pointer_mut(&format!("/switch:{}", channel)) {
+            value = switch.take();
+        }
+        let status: Status = if value.get("output").is_some() {
+            let status: SwitchStatus = serde_json::from_value(value)
+                .map_err(|err| err.to_string())?;
+            status.into()
+        } else {
+            let status: DeviceStatus = serde_json::from_value(value)
+                .map_err(|err| err.to_string())?;
+            let relay = status.relays.get(channel as usize);
+            Status {
+                voltage: status.voltage,
+                temperature: status.temperature,
+                overtemperature: status.overtemperature
+                    .or_else(|| relay.and_then(|relay| relay.overtemperature)),
+                relay_on: relay.and_then(|relay| relay.ison),
+            }
+        };
+        if status.voltage.is_none() && status.temperature.is_none()
+            && status.overtemperature.is_none() && status.relay_on.is_none() {
+            return Err("there is neither voltage, temperature nor relay state \
+                in the response".to_string());
+        }
+        Ok(status)
+    }
+
+    /// Data-point of the measurement, if the response had it;
+    /// the flags are 1 if set, 0 otherwise
+    pub fn datum(&self, config: &plug::Config, measurement: point::Measurement) -> Option<Datum> {
+        let flag = |flag: bool| if flag { 1.0 } else { 0.0 };
+        Some(config.datum(measurement, match measurement {
+            voltage_in_v => self.voltage?,
+            temperature_in_c => self.temperature?,
+            overtemperature => flag(self.overtemperature?),
+            relay_on => flag(self.relay_on?),
+            _ => panic!("{} is not in the status response", measurement),
+        }))
+    }
+}
+
+/// Polls the status of one device, on an interval of its own
+pub struct StatusMeter {
+    config: plug::Config,
+    client: DeviceClient,
+    interval: Duration,
+    /// URL of the status, once the generation of the device is known
+    url: Option<String>,
+    /// Failures since the last successful poll
+    failures: FailureLog,
+    data_sender: UnboundedSender<Datum>,
+}
+
+impl StatusMeter {
+
+    pub fn new(
+        shelly_plug_config: &plug::Config,
+        interval: Duration,
+        client: DeviceClient,
+        failures: FailureLog,
+        data_sender: UnboundedSender<Datum>)
+    -> StatusMeter
+    {
+        StatusMeter {
+            config: shelly_plug_config.clone(),
+            client: client.for_device(shelly_plug_config),
+            interval,
+            url: None,
+            failures,
+            data_sender,
+        }
+    }
+
+    /// URL of the status of the channel, by the generation of the device
+    async fn url(&mut self) -> Result<String, String> {
+        if let Some(url) = &self.url {
+            return Ok(url.clone());
+        }
+        let url = match probe::probe(&self.config, &self.client).await?.generation() {
+            plug::Generation::Gen1 => self.client.url(&self.config.host, "/status"),
+            plug::Generation::Gen2 => self.client.url(&self.config.host,
+                &format!("/rpc/Switch.GetStatus?id={}", self.config.channel)),
+        };
+        self.url = Some(url.clone());
+        Ok(url)
+    }
+
+    /// Response body of the status
+    async fn fetch(&mut self) -> Result<String, String> {
+        let url = self.url().await?;
+        self.client.get(&url).call().await
+            .map_err(|err| err.to_string())?
+            .text().await
+            .map_err(|err| err.to_string())
+    }
+}
+
+impl Task for StatusMeter {
+
+    fn poll(&mut self) -> Poll<'_> {
+        Box::pin(async move {
+            let body = match self.fetch().await {
+                Ok(body) => body,
+                Err(err) => {
+                    return Ok(Some(self.failures.failed(&self.config.host,
+                        &format!("status could not be measured ({})", err))));
+                },
+            };
+            let status = Status::parse(body.as_bytes(), self.config.channel).map_err(|err| format!(
+                "{} did not return the status with the expected grammar ({}). \
+                Status measurements are stopped.", self.config.host, err))?;
+            self.failures.succeeded(&self.config.host);
+            for measurement in point::Measurement::STATUS {
+                if let Some(datum) = status.datum(&self.config, measurement) {
+                    if self.data_sender.send(datum).is_err() {
+                        debug!("channel to the DB thread closed, stopping");
+                        return Ok(None);
+                    }
+                }
+            }
+            Ok(Some(self.interval))
+        })
+    }
+}
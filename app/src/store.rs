@@ -0,0 +1,334 @@
+use crate::point::Datum;
+use crate::retention;
+use crate::sink::{self, Sink};
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::sync::mpsc::Receiver;
+
+#[cfg(feature = "sqlite")]
+use {
+    chrono::{DateTime, TimeZone, Utc},
+    log::{debug, info, warn},
+    rusqlite::types::Value,
+    std::path::Path,
+    std::thread::JoinHandle,
+    std::time::{Duration, Instant},
+};
+
+/// Local storage configuration
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Config {
+
+    /// Path of the SQLite database file
+    pub path: PathBuf,
+
+    /// Limits of the stored data; kept forever if not set
+    #[serde(flatten)]
+    #[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+    pub retention: retention::Policy,
+}
+
+/// Most data-points written in one transaction
+#[cfg(feature = "sqlite")]
+const MAX_POINTS_PER_TRANSACTION: usize = 1000;
+
+/// How often is the retention policy applied
+#[cfg(feature = "sqlite")]
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Data-points deleted at once when the store is over its size limit
+#[cfg(feature = "sqlite")]
+const PRUNE_BATCH: usize = 1000;
+
+/// Data-point read back from the store
+#[cfg(feature = "sqlite")]
+pub struct Row {
+    pub measured_on: DateTime<Utc>,
+    pub measurement: String,
+    pub device_name: String,
+    pub device_host: String,
+    pub value: f64,
+    pub valid: bool,
+}
+
+#[cfg(feature = "sqlite")]
+impl Row {
+
+    /// Data-point of the row; fails for measurements unknown to this version
+    pub fn to_datum(&self) -> Result<Datum, String> {
+        Ok(Datum {
+            measured_on: self.measured_on,
+            measurement: self.measurement.parse()?,
+            device_name: self.device_name.as_str().into(),
+            device_host: self.device_host.as_str().into(),
+            value: self.value as f32,
+            valid: self.valid,
+        })
+    }
+}
+
+/// Selection of stored data-points; unset fields match everything
+#[cfg(feature = "sqlite")]
+#[derive(Default, Debug)]
+pub struct Filter {
+    pub device_name: Option<String>,
+    pub measurement: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+/// Embedded time-series database
+#[cfg(feature = "sqlite")]
+pub struct Store {
+    connection: rusqlite::Connection,
+}
+
+#[cfg(feature = "sqlite")]
+impl Store {
+
+    /// Open (or create) the database file
+    pub fn open(path: &Path) -> Result<Store, String> {
+        let connection = rusqlite::Connection::open(path)
+            .map_err(|err| format!("{} can not be opened: {}", path.display(), err))?;
+        connection.execute_batch("
+            PRAGMA journal_mode = WAL;
+            CREATE TABLE IF NOT EXISTS datum (
+                measured_on INTEGER NOT NULL,
+                measurement TEXT NOT NULL,
+                device_name TEXT NOT NULL,
+                device_host TEXT NOT NULL,
+                value REAL NOT NULL,
+                valid INTEGER NOT NULL DEFAULT 1
+            );
+            CREATE INDEX IF NOT EXISTS datum_by_time ON datum (measured_on);
+            CREATE TABLE IF NOT EXISTS sync_marker (
+                target TEXT PRIMARY KEY,
+                last_rowid INTEGER NOT NULL
+            );
+        ").map_err(|err| format!("{} can not be initialized: {}", path.display(), err))?;
+        // Stores created by older versions have no quality column
+        let has_valid: bool = connection.query_row("SELECT count(*) > 0 \
+            FROM pragma_table_info('datum') WHERE name = 'valid'", [], |row| row.get(0))
+            .map_err(|err| format!("{} can not be initialized: {}", path.display(), err))?;
+        if !has_valid {
+            connection.execute("ALTER TABLE datum ADD COLUMN valid INTEGER NOT NULL DEFAULT 1", [])
+                .map_err(|err| format!("{} can not be upgraded: {}", path.display(), err))?;
+        }
+        Ok(Store { connection })
+    }
+
+    /// Store data-points in a single transaction
+    pub fn insert(&mut self, datums: &[Datum]) -> Result<(), rusqlite::Error> {
+        let transaction = self.connection.transaction()?;
+        {
+            let mut statement = transaction.prepare_cached(
+                "INSERT INTO datum (measured_on, measurement, device_name, device_host, value, valid) \
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)")?;
+            for datum in datums {
+                statement.execute((
+                    datum.measured_on.timestamp(),
+                    datum.measurement.to_string(),
+                    datum.device_name.as_ref(),
+                    datum.device_host.as_ref(),
+                    datum.value as f64,
+                    datum.valid,
+                ))?;
+            }
+        }
+        transaction.commit()
+    }
+
+    /// Delete data-points measured before the given time; returns their count
+    pub fn prune(&self, older_than: DateTime<Utc>) -> Result<usize, rusqlite::Error> {
+        self.connection.execute("DELETE FROM datum WHERE measured_on < ?1",
+            [older_than.timestamp()])
+    }
+
+    /// Bytes occupied by the data-points, excluding free pages of the file
+    pub fn used_bytes(&self) -> Result<u64, rusqlite::Error> {
+        self.connection.query_row("SELECT (page_count - freelist_count) * page_size \
+            FROM pragma_page_count(), pragma_freelist_count(), pragma_page_size()",
+            [], |row| row.get(0))
+    }
+
+    /// Delete the given number of the oldest data-points; returns their count
+    pub fn prune_oldest(&self, count: usize) -> Result<usize, rusqlite::Error> {
+        self.connection.execute("DELETE FROM datum WHERE rowid IN \
+            (SELECT rowid FROM datum ORDER BY measured_on LIMIT ?1)", [count as i64])
+    }
+
+    /// Delete data-points violating the retention policy; returns their count
+    pub fn apply(&self, policy: &retention::Policy) -> Result<usize, rusqlite::Error> {
+        let mut deleted = 0;
+        if let Some(max_age) = policy.max_age() {
+            let older_than = Utc::now() - chrono::Duration::from_std(max_age)
+                .unwrap_or_else(|_| chrono::Duration::max_value());
+            deleted += self.prune(older_than)?;
+        }
+        if let Some(max_size) = policy.max_size() {
+            // Free pages are reused, so the file stops growing
+            while self.used_bytes()? > max_size {
+                let count = self.prune_oldest(PRUNE_BATCH)?;
+                if count == 0 {
+                    break;
+                }
+                deleted += count;
+            }
+        }
+        Ok(deleted)
+    }
+
+    /// Last data-point (by row id) uploaded to the target by `sync`; 0 if none
+    pub fn sync_marker(&self, target: &str) -> Result<i64, rusqlite::Error> {
+        self.connection.query_row("SELECT coalesce(max(last_rowid), 0) FROM sync_marker \
+            WHERE target = ?1", [target], |row| row.get(0))
+    }
+
+    /// Mark data-points up to the row id as uploaded to the target
+    pub fn set_sync_marker(&self, target: &str, last_rowid: i64) -> Result<(), rusqlite::Error> {
+        self.connection.execute("INSERT INTO sync_marker (target, last_rowid) VALUES (?1, ?2) \
+            ON CONFLICT (target) DO UPDATE SET last_rowid = excluded.last_rowid",
+            (target, last_rowid))?;
+        Ok(())
+    }
+
+    /// Number of data-points stored after the row id
+    pub fn count_after(&self, rowid: i64) -> Result<u64, rusqlite::Error> {
+        self.connection.query_row("SELECT count(*) FROM datum WHERE rowid > ?1",
+            [rowid], |row| row.get(0))
+    }
+
+    /// Call `consumer` with the row id and data-point of at most `limit`
+    /// data-points stored after the row id, in the order of storing
+    pub fn read_after(&self, rowid: i64, limit: usize, mut consumer: impl FnMut(i64, Row))
+    -> Result<(), rusqlite::Error> {
+        let mut statement = self.connection.prepare_cached("SELECT rowid, measured_on, \
+            measurement, device_name, device_host, value, valid FROM datum \
+            WHERE rowid > ?1 ORDER BY rowid LIMIT ?2")?;
+        let mut rows = statement.query((rowid, limit as i64))?;
+        while let Some(row) = rows.next()? {
+            consumer(row.get(0)?, Row {
+                measured_on: Utc.timestamp_opt(row.get(1)?, 0).single()
+                    .unwrap_or_default(),
+                measurement: row.get(2)?,
+                device_name: row.get(3)?,
+                device_host: row.get(4)?,
+                value: row.get(5)?,
+                valid: row.get(6)?,
+            });
+        }
+        Ok(())
+    }
+
+    /// Call `consumer` with each stored data-point matching the filter, oldest first
+    pub fn query(&self, filter: &Filter, mut consumer: impl FnMut(Row))
+    -> Result<(), rusqlite::Error> {
+        let mut sql = String::from("SELECT measured_on, measurement, device_name, \
+            device_host, value, valid FROM datum WHERE 1 = 1");
+        let mut params: Vec<Value> = vec![];
+        if let Some(device_name) = &filter.device_name {
+            sql.push_str(" AND device_name = ?");
+            params.push(Value::Text(device_name.clone()));
+        }
+        if let Some(measurement) = &filter.measurement {
+            sql.push_str(" AND measurement = ?");
+            params.push(Value::Text(measurement.clone()));
+        }
+        if let Some(since) = filter.since {
+            sql.push_str(" AND measured_on >= ?");
+            params.push(Value::Integer(since.timestamp()));
+        }
+        if let Some(until) = filter.until {
+            sql.push_str(" AND measured_on < ?");
+            params.push(Value::Integer(until.timestamp()));
+        }
+        sql.push_str(" ORDER BY measured_on");
+
+        let mut statement = self.connection.prepare(&sql)?;
+        let mut rows = statement.query(rusqlite::params_from_iter(params))?;
+        while let Some(row) = rows.next()? {
+            consumer(Row {
+                measured_on: Utc.timestamp_opt(row.get(0)?, 0).single()
+                    .unwrap_or_default(),
+                measurement: row.get(1)?,
+                device_name: row.get(2)?,
+                device_host: row.get(3)?,
+                value: row.get(4)?,
+                valid: row.get(5)?,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Print the stored data-points matching the filter
+#[cfg(feature = "sqlite")]
+pub fn print(store_config: &Config, filter: &Filter) -> Result<(), String> {
+    let store = Store::open(&store_config.path)?;
+    store.query(filter, |row| println!("{} {} {} {} {}{}",
+            row.measured_on.to_rfc3339(), row.measurement,
+            row.device_name, row.device_host, row.value,
+            if row.valid { "" } else { " invalid" }))
+        .map_err(|err| format!("{} can not be queried: {}",
+            store_config.path.display(), err))
+}
+
+impl Sink for Config {
+    #[cfg_attr(not(feature = "sqlite"), allow(unused_variables))]
+    fn spawn(self: Box<Self>, _context: &sink::Context, data_receiver: Receiver<Datum>)
+    -> Result<sink::Handle, String>
+    {
+        #[cfg(feature = "sqlite")]
+        return Ok(Writer::spawn(*self, data_receiver));
+        #[cfg(not(feature = "sqlite"))]
+        return Err(format!("local store {} needs the 'sqlite' feature", self.path.display()));
+    }
+}
+
+/// Writes data-points into the local store
+#[cfg(feature = "sqlite")]
+pub struct Writer;
+
+#[cfg(feature = "sqlite")]
+impl Writer {
+
+    pub fn spawn(store_config: Config, data_receiver: Receiver<Datum>)
+    -> JoinHandle<Result<(),String>>
+    {
+        std::thread::spawn(move || {
+            let mut store = Store::open(&store_config.path)?;
+            info!("Storing data-points locally in {}", store_config.path.display());
+
+            let mut last_pruned: Option<Instant> = None;
+            loop {
+                let mut datums = match data_receiver.recv() {
+                    Ok(datum) => vec![datum],
+                    Err(_) => {
+                        debug!("all meters stopped, stopping");
+                        return Ok(());
+                    }
+                };
+                // Write everything that is waiting in one transaction
+                while datums.len() < MAX_POINTS_PER_TRANSACTION {
+                    match data_receiver.try_recv() {
+                        Ok(datum) => datums.push(datum),
+                        Err(_) => break,
+                    }
+                }
+
+                if let Err(err) = store.insert(&datums) {
+                    warn!("{} data-points could not be stored locally: {}", datums.len(), err);
+                }
+
+                if !store_config.retention.is_unlimited()
+                    && last_pruned.is_none_or(|at| at.elapsed() >= PRUNE_INTERVAL) {
+                    match store.apply(&store_config.retention) {
+                        Ok(count) => debug!("{} old data-points deleted locally", count),
+                        Err(err) => warn!("old data-points could not be deleted: {}", err),
+                    }
+                    last_pruned = Some(Instant::now());
+                }
+            }
+        })
+    }
+}
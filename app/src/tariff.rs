@@ -0,0 +1,101 @@
+use crate::calendar::Calendar;
+use crate::point::{Datum, Measurement};
+use chrono::{DateTime, NaiveTime, Utc};
+use serde::{Deserialize, Deserializer};
+
+/// Price of the consumed energy, by which its cost is computed
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Config {
+
+    /// Price of 1 kWh outside of the `schedule` (or always, without one)
+    pub price_per_kwh: f64,
+
+    /// Currency of the prices, e.g. "CZK", named in the reports
+    pub currency: Option<String>,
+
+    /// Times of the day with another price, e.g. a low tariff at night;
+    /// the first period containing the time applies
+    #[serde(default)]
+    pub schedule: Vec<Period>,
+}
+
+/// Time of the day with its own price
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Period {
+
+    /// Local time at which the period starts, e.g. "22:00"
+    #[serde(deserialize_with = "deserialize_time")]
+    pub from: NaiveTime,
+
+    /// Local time at which the period ends, e.g. "06:00"; a period ending
+    /// before it starts spans midnight, one ending when it starts the whole day
+    #[serde(deserialize_with = "deserialize_time")]
+    pub to: NaiveTime,
+
+    /// Price of 1 kWh in the period
+    pub price_per_kwh: f64,
+}
+
+impl Period {
+
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.from < self.to {
+            self.from <= time && time < self.to
+        } else {
+            self.from <= time || time < self.to
+        }
+    }
+}
+
+/// Derives the cost of the consumption from its data-points
+pub struct Pricing {
+    tariff_config: Config,
+    /// Time zone of the `schedule`
+    calendar: Calendar,
+}
+
+impl Pricing {
+
+    pub fn new(tariff_config: Config, calendar: Calendar) -> Pricing {
+        Pricing { tariff_config, calendar }
+    }
+
+    /// Whether 1 kWh costs the same at any time
+    #[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+    pub fn is_flat(&self) -> bool {
+        self.tariff_config.schedule.is_empty()
+    }
+
+    /// Price of 1 kWh outside of the schedule
+    #[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+    pub fn base_price(&self) -> f64 {
+        self.tariff_config.price_per_kwh
+    }
+
+    /// Price of 1 kWh at the time, by the local time of the calendar
+    pub fn price_at(&self, time: DateTime<Utc>) -> f64 {
+        let time = self.calendar.local(time).time();
+        self.tariff_config.schedule.iter()
+            .find(|period| period.contains(time))
+            .map_or(self.tariff_config.price_per_kwh, |period| period.price_per_kwh)
+    }
+
+    /// Cost of the consumption in the last minute, by the tariff when it was
+    /// measured; none for other data-points
+    pub fn cost(&self, datum: &Datum) -> Option<Datum> {
+        if datum.measurement != Measurement::last_minute_consumption_in_wh {
+            return None;
+        }
+        let price = self.price_at(datum.measured_on);
+        let mut cost = datum.clone();
+        cost.measurement = Measurement::last_minute_cost;
+        cost.value = (datum.value as f64 / 1000.0 * price) as f32;
+        Some(cost)
+    }
+}
+
+fn deserialize_time<'de, D: Deserializer<'de>>(deserializer: D) -> Result<NaiveTime, D::Error> {
+    let time = String::deserialize(deserializer)?;
+    NaiveTime::parse_from_str(&time, "%H:%M")
+        .map_err(|_| serde::de::Error::custom(format!("{} is not a time of the day (HH:MM)", time)))
+}
@@ -0,0 +1,252 @@
+use serde::Deserialize;
+use std::path::PathBuf;
+
+#[cfg(any(feature = "client-certificates", feature = "https"))]
+use {
+    std::path::Path,
+    std::sync::Arc,
+};
+
+#[cfg(feature = "https")]
+use {
+    base64::Engine,
+    log::{info, warn},
+    rustls::server::{AllowAnyAuthenticatedClient, NoClientAuth},
+    std::io::Write,
+    std::net::TcpStream,
+    std::sync::Mutex,
+    std::time::SystemTime,
+};
+
+#[cfg(not(feature = "https"))]
+use std::net::TcpStream;
+
+/// Client certificate presented to a server requiring mutual TLS
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "client-certificates"), allow(dead_code))]
+pub struct ClientCertificate {
+
+    /// PEM file with the certificate, optionally followed by its chain
+    pub cert_file: PathBuf,
+
+    /// PEM file with the private key of the certificate, in PKCS#8
+    pub key_file: PathBuf,
+
+    /// PEM file with the CA certificate of the server, if the system does not trust it
+    pub ca_file: Option<PathBuf>,
+}
+
+impl ClientCertificate {
+
+    /// Make the agent present the certificate
+    #[cfg(feature = "client-certificates")]
+    pub fn apply(&self, agent_builder: ureq::AgentBuilder) -> Result<ureq::AgentBuilder, String> {
+        let identity = native_tls::Identity::from_pkcs8(
+            &read(&self.cert_file)?, &read(&self.key_file)?)
+            .map_err(|err| format!("{} and {} are not a certificate with its key: {}",
+                self.cert_file.display(), self.key_file.display(), err))?;
+
+        let mut connector = native_tls::TlsConnector::builder();
+        connector.identity(identity);
+        if let Some(ca_file) = &self.ca_file {
+            let ca_certificate = native_tls::Certificate::from_pem(&read(ca_file)?)
+                .map_err(|err| format!("{} is not a certificate: {}", ca_file.display(), err))?;
+            connector.add_root_certificate(ca_certificate);
+        }
+        let connector = connector.build()
+            .map_err(|err| format!("TLS with {} can not be set up: {}",
+                self.cert_file.display(), err))?;
+
+        Ok(agent_builder.tls_connector(Arc::new(connector)))
+    }
+
+    /// Make the agent present the certificate, which is not compiled in
+    #[cfg(not(feature = "client-certificates"))]
+    pub fn apply(&self, _agent_builder: ureq::AgentBuilder) -> Result<ureq::AgentBuilder, String> {
+        Err("client certificates need the 'client-certificates' feature".to_string())
+    }
+}
+
+/// TLS of an HTTP endpoint of the logger
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(all(feature = "https", any(feature = "evcc", feature = "prometheus"))),
+    allow(dead_code))]
+pub struct ServerTls {
+
+    /// PEM file with the certificate of the endpoint, optionally followed by its chain
+    pub cert_file: PathBuf,
+
+    /// PEM file with the private key of the certificate
+    pub key_file: PathBuf,
+
+    /// PEM file with the CA certificates of the clients; if set, clients
+    /// without a certificate signed by one of them are refused
+    pub client_ca_file: Option<PathBuf>,
+}
+
+/// Makes TLS sessions of the accepted connections; the certificate is
+/// loaded again when its files change, so that it can be rotated
+#[cfg(feature = "https")]
+#[cfg_attr(not(any(feature = "evcc", feature = "prometheus")), allow(dead_code))]
+pub struct Acceptor {
+    server_tls: ServerTls,
+    loaded: Mutex<Loaded>,
+}
+
+/// Server config with the modification times of the files it was made of
+#[cfg(feature = "https")]
+#[cfg_attr(not(any(feature = "evcc", feature = "prometheus")), allow(dead_code))]
+struct Loaded {
+    modified: Vec<Option<SystemTime>>,
+    server_config: Arc<rustls::ServerConfig>,
+}
+
+#[cfg(feature = "https")]
+#[cfg_attr(not(any(feature = "evcc", feature = "prometheus")), allow(dead_code))]
+impl Acceptor {
+
+    pub fn new(server_tls: &ServerTls) -> Result<Acceptor, String> {
+        let modified = server_tls.modified();
+        let server_config = server_tls.server_config()?;
+        Ok(Acceptor {
+            server_tls: server_tls.clone(),
+            loaded: Mutex::new(Loaded { modified, server_config }),
+        })
+    }
+
+    /// Serve the connection in a TLS session
+    pub fn session<F>(&self, stream: TcpStream, serve: F) -> Result<(), String>
+    where F: FnOnce(&mut rustls::StreamOwned<rustls::ServerConnection, TcpStream>) -> Result<(), String>
+    {
+        let connection = rustls::ServerConnection::new(self.server_config())
+            .map_err(|err| err.to_string())?;
+        let mut session = rustls::StreamOwned::new(connection, stream);
+        serve(&mut session)?;
+        session.conn.send_close_notify();
+        session.flush().map_err(|err| err.to_string())
+    }
+
+    /// Current server config, loaded again if the files changed
+    fn server_config(&self) -> Arc<rustls::ServerConfig> {
+        let mut loaded = self.loaded.lock().expect("internal error, TLS lock poisoned");
+        let modified = self.server_tls.modified();
+        if modified != loaded.modified {
+            // Files are tried once per change, a half-written rotation is retried when completed
+            loaded.modified = modified;
+            match self.server_tls.server_config() {
+                Ok(server_config) => {
+                    info!("{} loaded again", self.server_tls.cert_file.display());
+                    loaded.server_config = server_config;
+                },
+                Err(err) => warn!("{}, the previous certificate is kept", err),
+            }
+        }
+        loaded.server_config.clone()
+    }
+}
+
+/// TLS is not compiled in, so there are no acceptors
+#[cfg(not(feature = "https"))]
+pub enum Acceptor {}
+
+#[cfg(not(feature = "https"))]
+#[cfg_attr(not(any(feature = "evcc", feature = "prometheus")), allow(dead_code))]
+impl Acceptor {
+
+    pub fn new(_server_tls: &ServerTls) -> Result<Acceptor, String> {
+        Err("TLS of the HTTP endpoints needs the 'https' feature".to_string())
+    }
+
+    pub fn session<F>(&self, _stream: TcpStream, _serve: F) -> Result<(), String>
+    where F: FnOnce(&mut TcpStream) -> Result<(), String>
+    {
+        match *self {}
+    }
+}
+
+#[cfg(feature = "https")]
+#[cfg_attr(not(any(feature = "evcc", feature = "prometheus")), allow(dead_code))]
+impl ServerTls {
+
+    fn files(&self) -> impl Iterator<Item = &Path> {
+        [self.cert_file.as_path(), self.key_file.as_path()].into_iter()
+            .chain(self.client_ca_file.as_deref())
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        self.files()
+            .map(|path| std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok())
+            .collect()
+    }
+
+    fn server_config(&self) -> Result<Arc<rustls::ServerConfig>, String> {
+        let certificates: Vec<rustls::Certificate> = pem_blocks(&self.cert_file)?.into_iter()
+            .filter(|(label, _)| label == "CERTIFICATE")
+            .map(|(_, der)| rustls::Certificate(der))
+            .collect();
+        if certificates.is_empty() {
+            return Err(format!("{} has no certificate", self.cert_file.display()));
+        }
+        // PKCS#8, PKCS#1 (RSA) or SEC1 (EC)
+        let key = pem_blocks(&self.key_file)?.into_iter()
+            .find(|(label, _)| label.ends_with("PRIVATE KEY"))
+            .map(|(_, der)| rustls::PrivateKey(der))
+            .ok_or_else(|| format!("{} has no private key", self.key_file.display()))?;
+
+        let client_verifier = match &self.client_ca_file {
+            Some(client_ca_file) => {
+                let mut roots = rustls::RootCertStore::empty();
+                for (_, der) in pem_blocks(client_ca_file)?.into_iter()
+                    .filter(|(label, _)| label == "CERTIFICATE") {
+                    roots.add(&rustls::Certificate(der))
+                        .map_err(|err| format!("{} has an invalid certificate: {}",
+                            client_ca_file.display(), err))?;
+                }
+                if roots.is_empty() {
+                    return Err(format!("{} has no certificate", client_ca_file.display()));
+                }
+                AllowAnyAuthenticatedClient::new(roots)
+            },
+            None => NoClientAuth::new(),
+        };
+
+        rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(client_verifier)
+            .with_single_cert(certificates, key)
+            .map(Arc::new)
+            .map_err(|err| format!("{} and {} are not a certificate with its key: {}",
+                self.cert_file.display(), self.key_file.display(), err))
+    }
+}
+
+/// Labels and contents of the PEM blocks in the file
+#[cfg(feature = "https")]
+#[cfg_attr(not(any(feature = "evcc", feature = "prometheus")), allow(dead_code))]
+fn pem_blocks(path: &Path) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|err| format!("{} can not be read: {}", path.display(), err))?;
+    let mut blocks = vec![];
+    let mut block: Option<(String, String)> = None;
+    for line in text.lines().map(str::trim) {
+        if let Some(label) = line.strip_prefix("-----BEGIN ").and_then(|line| line.strip_suffix("-----")) {
+            block = Some((label.to_string(), String::new()));
+        } else if line.starts_with("-----END ") {
+            if let Some((label, data)) = block.take() {
+                let der = base64::engine::general_purpose::STANDARD.decode(data)
+                    .map_err(|err| format!("{} is not PEM: {}", path.display(), err))?;
+                blocks.push((label, der));
+            }
+        } else if let Some((_, data)) = &mut block {
+            data.push_str(line);
+        }
+    }
+    Ok(blocks)
+}
+
+#[cfg(feature = "client-certificates")]
+fn read(path: &Path) -> Result<Vec<u8>, String> {
+    std::fs::read(path).map_err(|err| format!("{} can not be read: {}", path.display(), err))
+}
@@ -0,0 +1,212 @@
+use crate::config;
+use crate::influx;
+use crate::line_protocol;
+use crate::line_protocol::Encoder;
+use crate::point::Datum;
+use log::warn;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+#[cfg(feature = "sqlite")]
+use {
+    crate::store,
+    std::io::{BufWriter, Write},
+};
+
+/// Data-points written to the sinks at once during an import
+const IMPORT_CHUNK: usize = 5000;
+
+/// Export data-points from the local store into a line-protocol file
+#[cfg(feature = "sqlite")]
+pub fn export(store_config: &store::Config, filter: &store::Filter, output: &Path)
+-> Result<(), String>
+{
+    let store = store::Store::open(&store_config.path)?;
+    let file = std::fs::File::create(output)
+        .map_err(|err| format!("{} can not be created: {}", output.display(), err))?;
+    let mut writer = BufWriter::new(file);
+    let mut encoder = Encoder::default();
+    let mut line = String::new();
+    let mut exported: usize = 0;
+    let mut skipped: usize = 0;
+    let mut write_error = None;
+
+    store.query(filter, |row| {
+        if write_error.is_some() {
+            return;
+        }
+        match row.to_datum() {
+            Ok(datum) => {
+                line.clear();
+                encoder.encode(&datum, &mut line);
+                match writer.write_all(line.as_bytes()) {
+                    Ok(_) => exported += 1,
+                    Err(err) => write_error = Some(err),
+                }
+            },
+            Err(err) => {
+                skipped += 1;
+                warn!("data-point skipped: {}", err);
+            }
+        }
+    }).map_err(|err| format!("{} can not be queried: {}", store_config.path.display(), err))?;
+
+    if let Some(err) = write_error {
+        return Err(format!("{} can not be written: {}", output.display(), err));
+    }
+    writer.flush()
+        .map_err(|err| format!("{} can not be written: {}", output.display(), err))?;
+    println!("{} data-points exported into {}, {} skipped", exported, output.display(), skipped);
+    Ok(())
+}
+
+/// Upload data-points from the local store to the database in chunks;
+/// the last uploaded data-point is marked in the store, so that an
+/// interrupted sync continues where it stopped
+#[cfg(feature = "sqlite")]
+pub fn sync(app_config: &config::Config, chunk: usize, restart: bool) -> Result<(), String> {
+    let store_config = app_config.local_store.as_ref()
+        .ok_or("there is no 'local_store' in the config")?;
+    let database_config = app_config.database.as_ref()
+        .ok_or("there is no 'database' in the config")?;
+    let store = store::Store::open(&store_config.path)?;
+    let mut connection = influx::Connection::new(database_config)?;
+    let target = database_config.target();
+    let query_error = |err: rusqlite::Error|
+        format!("{} can not be queried: {}", store_config.path.display(), err);
+
+    let mut marker = if restart { 0 } else { store.sync_marker(&target).map_err(query_error)? };
+    let total = store.count_after(marker).map_err(query_error)?;
+    if total == 0 {
+        println!("Everything is already uploaded to {}", target);
+        return Ok(());
+    }
+    if marker > 0 {
+        println!("Resuming the upload to {}", target);
+    }
+
+    let mut encoder = Encoder::with_precision(influx::PRECISION);
+    let mut datums = vec![];
+    let mut body = String::new();
+    let mut uploaded: u64 = 0;
+    loop {
+        datums.clear();
+        body.clear();
+        let mut last_rowid = marker;
+        let mut read: u64 = 0;
+        store.read_after(marker, chunk.max(1), |rowid, row| {
+            last_rowid = rowid;
+            read += 1;
+            match row.to_datum() {
+                Ok(datum) => {
+                    encoder.encode(&datum, &mut body);
+                    datums.push(datum);
+                },
+                Err(err) => warn!("data-point skipped: {}", err),
+            }
+        }).map_err(query_error)?;
+        if read == 0 {
+            break;
+        }
+
+        if !body.is_empty() {
+            connection.write(&datums, &body).map_err(|err| format!("writing to {} \
+                failed after {} of {} data-points: {}; run 'sync' again to resume",
+                database_config.backend, uploaded, total, err))?;
+        }
+        store.set_sync_marker(&target, last_rowid)
+            .map_err(|err| format!("{} can not be updated: {}", store_config.path.display(), err))?;
+        marker = last_rowid;
+        uploaded += read;
+        println!("{} of {} data-points uploaded ({:.0}%)",
+            uploaded, total, 100.0 * uploaded as f64 / total.max(uploaded) as f64);
+    }
+    Ok(())
+}
+
+/// Import a line-protocol file into all configured sinks
+pub fn import(app_config: &config::Config, file: &Path) -> Result<(), String> {
+    let reader = std::fs::File::open(file)
+        .map(BufReader::new)
+        .map_err(|err| format!("{} can not be opened: {}", file.display(), err))?;
+    let mut sinks = Sinks::open(app_config)?;
+
+    let mut chunk: Vec<Datum> = Vec::with_capacity(IMPORT_CHUNK);
+    let mut imported: usize = 0;
+    let mut skipped: usize = 0;
+    for (index, line) in reader.lines().enumerate() {
+        let line = line.map_err(|err| format!("{} can not be read: {}", file.display(), err))?;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line_protocol::parse_line(&line) {
+            Ok(datum) => chunk.push(datum),
+            Err(err) => {
+                skipped += 1;
+                warn!("{} line {} skipped: {}", file.display(), index + 1, err);
+            }
+        }
+        if chunk.len() >= IMPORT_CHUNK {
+            sinks.write(&chunk).map_err(|err| format!("{} (after {} data-points \
+                were imported)", err, imported))?;
+            imported += chunk.len();
+            chunk.clear();
+        }
+    }
+    sinks.write(&chunk).map_err(|err| format!("{} (after {} data-points \
+        were imported)", err, imported))?;
+    imported += chunk.len();
+
+    println!("{} data-points imported from {}, {} skipped", imported, file.display(), skipped);
+    Ok(())
+}
+
+/// Configured sinks, written synchronously
+struct Sinks {
+    influx: Option<influx::Connection>,
+    encoder: Encoder,
+    #[cfg(feature = "sqlite")]
+    store: Option<store::Store>,
+}
+
+impl Sinks {
+
+    fn open(app_config: &config::Config) -> Result<Sinks, String> {
+        let sinks = Sinks {
+            influx: app_config.database.as_ref().map(influx::Connection::new).transpose()?,
+            encoder: Encoder::with_precision(influx::PRECISION),
+            #[cfg(feature = "sqlite")]
+            store: app_config.local_store.as_ref()
+                .map(|store_config| store::Store::open(&store_config.path))
+                .transpose()?,
+        };
+        #[cfg(feature = "sqlite")]
+        let has_store = sinks.store.is_some();
+        #[cfg(not(feature = "sqlite"))]
+        let has_store = false;
+        if sinks.influx.is_none() && !has_store {
+            return Err("no sink is configured".to_string());
+        }
+        Ok(sinks)
+    }
+
+    fn write(&mut self, datums: &[Datum]) -> Result<(), String> {
+        if datums.is_empty() {
+            return Ok(());
+        }
+        if let Some(connection) = &mut self.influx {
+            let mut body = String::new();
+            for datum in datums {
+                self.encoder.encode(datum, &mut body);
+            }
+            connection.write(datums, &body)
+                .map_err(|err| format!("writing to the database failed: {}", err))?;
+        }
+        #[cfg(feature = "sqlite")]
+        if let Some(store) = &mut self.store {
+            store.insert(datums)
+                .map_err(|err| format!("writing to the local store failed: {}", err))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,45 @@
+use crate::config;
+use crate::crypto;
+use crate::plug;
+use crate::plug::Measurement;
+use crate::point;
+use std::path::Path;
+
+/// Run a saved device response through the parser and print the result
+pub fn parse(file: &Path, device_config: &plug::Config) -> Result<(), String> {
+    let mut data = std::fs::read(file)
+        .map_err(|err| format!("{} can not be read: {}", file.display(), err))?;
+    if crypto::is_encrypted(&data) {
+        data = decrypt(&data).map_err(|err| format!("{}: {}", file.display(), err))?;
+    }
+    let text = String::from_utf8(data)
+        .map_err(|_| format!("{} is not UTF-8 text", file.display()))?;
+
+    let (generation, message) = Measurement::parse(text.as_bytes(), device_config.channel)
+        .map_err(|err| format!("{} is not a valid response: {}",
+            file.display(), err))?;
+
+    println!("generation: {}", generation);
+    println!("is_valid: {}", message.is_valid());
+    println!("missing: {}", message.missing().join(", "));
+    println!("device_time: {}", message.local_device_time()
+        .map_or_else(|| "?".to_string(), |time| time.to_string()));
+    println!("counters_updated_on: {}", message.counters_updated_on(chrono::Utc::now()));
+    for measurement in point::Measurement::ALL {
+        match message.datum(device_config, measurement) {
+            Some(datum) => println!("{},device_name={},device_host={} value={}",
+                datum.measurement, datum.device_name, datum.device_host, datum.value),
+            None => println!("{} is missing", measurement),
+        }
+    }
+    Ok(())
+}
+
+/// Decrypt an archived response with the key of the archive in the config
+fn decrypt(data: &[u8]) -> Result<Vec<u8>, String> {
+    let encryption_config = config::Config::load()?
+        .response_archive.and_then(|archive_config| archive_config.encryption)
+        .ok_or("response is encrypted, but the config has no \
+            'response_archive.encryption'")?;
+    crypto::Cipher::new(&encryption_config)?.decrypt(data)
+}
@@ -0,0 +1,179 @@
+use crate::point::Datum;
+use crate::sink::{self, Sink};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::mpsc::Receiver;
+
+#[cfg(feature = "zabbix")]
+use {
+    log::{debug, info, warn},
+    serde::Serialize,
+    std::io::{Read, Write},
+    std::net::TcpStream,
+    std::thread::JoinHandle,
+    std::time::Duration,
+};
+
+/// Zabbix sink configuration
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(not(feature = "zabbix"), allow(dead_code))]
+pub struct Config {
+
+    /// Host-name or IP of the Zabbix server (or proxy)
+    pub server: String,
+
+    #[serde(default = "Config::default_port")]
+    pub port: u16,
+
+    /// Zabbix host of each plug, by plug name; the plug name if not listed
+    #[serde(default)]
+    pub hosts: HashMap<String, String>,
+
+    /// Item keys are this prefix followed by the measurement
+    #[serde(default = "Config::default_key_prefix")]
+    pub key_prefix: String,
+}
+
+impl Config {
+
+    fn default_port() -> u16 { 10051 }
+
+    fn default_key_prefix() -> String { "shelly.".to_string() }
+}
+
+/// Header of the Zabbix protocol, followed by the length of the data
+#[cfg(feature = "zabbix")]
+const HEADER: &[u8] = b"ZBXD\x01";
+
+/// Most values sent in one request
+#[cfg(feature = "zabbix")]
+const MAX_VALUES_PER_REQUEST: usize = 250;
+
+/// Largest accepted response of the server
+#[cfg(feature = "zabbix")]
+const MAX_RESPONSE_BYTES: u64 = 64 * 1024;
+
+/// Value of a trapper item
+#[cfg(feature = "zabbix")]
+#[derive(Serialize)]
+struct Value<'a> {
+    host: &'a str,
+    key: String,
+    value: String,
+    clock: i64,
+}
+
+/// Request of the "sender data" type
+#[cfg(feature = "zabbix")]
+#[derive(Serialize)]
+struct Request<'a> {
+    request: &'static str,
+    data: Vec<Value<'a>>,
+}
+
+/// Response of the server
+#[cfg(feature = "zabbix")]
+#[derive(Deserialize)]
+struct Response {
+    response: String,
+    #[serde(default)]
+    info: String,
+}
+
+impl Sink for Config {
+    #[cfg_attr(not(feature = "zabbix"), allow(unused_variables))]
+    fn spawn(self: Box<Self>, _context: &sink::Context, data_receiver: Receiver<Datum>)
+    -> Result<sink::Handle, String>
+    {
+        #[cfg(feature = "zabbix")]
+        return Ok(Sender::spawn(*self, data_receiver));
+        #[cfg(not(feature = "zabbix"))]
+        return Err(format!("Zabbix server {} needs the 'zabbix' feature", self.server));
+    }
+}
+
+/// Sends data-points to Zabbix trapper items
+#[cfg(feature = "zabbix")]
+pub struct Sender {
+    zabbix_config: Config,
+}
+
+#[cfg(feature = "zabbix")]
+impl Sender {
+
+    pub fn spawn(zabbix_config: Config, data_receiver: Receiver<Datum>)
+    -> JoinHandle<Result<(),String>>
+    {
+        let sender = Sender { zabbix_config };
+        std::thread::spawn(move || {
+            info!("Sending data-points to Zabbix at {}:{}",
+                sender.zabbix_config.server, sender.zabbix_config.port);
+            loop {
+                let mut datums = match data_receiver.recv() {
+                    Ok(datum) => vec![datum],
+                    Err(_) => {
+                        debug!("all meters stopped, stopping");
+                        return Ok(());
+                    }
+                };
+                // Send everything that is waiting in one request
+                while datums.len() < MAX_VALUES_PER_REQUEST {
+                    match data_receiver.try_recv() {
+                        Ok(datum) => datums.push(datum),
+                        Err(_) => break,
+                    }
+                }
+                match sender.send(&datums) {
+                    Ok(info) => debug!("Zabbix processed {} values: {}", datums.len(), info),
+                    Err(err) => warn!("{} data-points could not be sent to Zabbix: {}",
+                        datums.len(), err),
+                }
+            }
+        })
+    }
+
+    /// Zabbix host of the plug
+    fn host<'a>(&'a self, datum: &'a Datum) -> &'a str {
+        self.zabbix_config.hosts.get(datum.device_name.as_ref())
+            .map(String::as_str)
+            .unwrap_or(&datum.device_name)
+    }
+
+    /// Send the data-points in one request; returns the server's summary
+    fn send(&self, datums: &[Datum]) -> Result<String, String> {
+        let request = Request {
+            request: "sender data",
+            data: datums.iter().map(|datum| Value {
+                host: self.host(datum),
+                key: format!("{}{}", self.zabbix_config.key_prefix, datum.measurement),
+                value: datum.value.to_string(),
+                clock: datum.measured_on.timestamp(),
+            }).collect(),
+        };
+        let body = serde_json::to_vec(&request).expect("request is always serializable");
+
+        let address = (self.zabbix_config.server.as_str(), self.zabbix_config.port);
+        let mut stream = TcpStream::connect(address).map_err(|err| err.to_string())?;
+        stream.set_read_timeout(Some(Duration::from_secs(10))).map_err(|err| err.to_string())?;
+        let mut packet = Vec::with_capacity(HEADER.len() + 8 + body.len());
+        packet.extend_from_slice(HEADER);
+        packet.extend_from_slice(&(body.len() as u64).to_le_bytes());
+        packet.extend_from_slice(&body);
+        stream.write_all(&packet).map_err(|err| err.to_string())?;
+
+        let mut response = vec![];
+        stream.take(MAX_RESPONSE_BYTES).read_to_end(&mut response)
+            .map_err(|err| err.to_string())?;
+        let json = response.strip_prefix(HEADER)
+            .filter(|rest| rest.len() >= 8)
+            .map(|rest| &rest[8..])
+            .ok_or("server responded with an unexpected header")?;
+        let response: Response = serde_json::from_slice(json)
+            .map_err(|err| format!("server responded with unexpected data: {}", err))?;
+        if response.response == "success" {
+            Ok(response.info)
+        } else {
+            Err(format!("server responded '{}': {}", response.response, response.info))
+        }
+    }
+}